@@ -1,8 +1,10 @@
 use std::{env, fs, path::PathBuf};
 
+use sha2::{Digest, Sha384};
 use walkdir::WalkDir;
 
 static PICO_PREFIX: &str = "vendor/pico/scss";
+static VENDOR_SUMS: &str = "vendor/SHA384SUMS";
 
 #[cfg(target_os = "windows")]
 use winresource::WindowsResource;
@@ -102,10 +104,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // third-party files are checked against the hashes they were vendored with, so one
+    // that was changed or corrupted by accident fails the build
+    verify_vendored()?;
+
     // Tell cargo to rerun this script if the build script changes
     println!("cargo:rerun-if-changed=build.rs");
 
     println!("cargo:rerun-if-changed={PICO_PREFIX}");
+    println!("cargo:rerun-if-changed=vendor");
 
     #[cfg(target_os = "windows")]
     WindowsResource::new()
@@ -114,3 +121,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// check each file listed in vendor/SHA384SUMS, in the format sha384sum writes
+fn verify_vendored() -> Result<(), Box<dyn std::error::Error>> {
+    let sums = fs::read_to_string(VENDOR_SUMS)?;
+    for line in sums.lines().filter(|line| !line.trim().is_empty()) {
+        let (expected, name) = line
+            .split_once("  ")
+            .ok_or_else(|| format!("malformed line in {VENDOR_SUMS}: {line}"))?;
+        let bytes = fs::read(PathBuf::from("vendor").join(name))?;
+        let actual: String = Sha384::digest(&bytes)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        if actual != expected {
+            return Err(format!(
+                "vendor/{name} doesn't match {VENDOR_SUMS}; if it was updated on purpose, run \
+                 `sha384sum *.js > SHA384SUMS` in vendor"
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
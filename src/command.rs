@@ -1,9 +1,14 @@
 // pub mod render;
+mod bench;
+mod compile;
+mod defs;
+mod lsp;
 mod new;
 mod query;
 mod run;
 mod serve;
 mod shell;
+mod transfer;
 
 use clap::{Parser, Subcommand};
 use eyre::Result;
@@ -14,10 +19,15 @@ use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::Output;
 
+use bench::Bench;
+use compile::Compile;
+use defs::Defs;
+use lsp::Lsp;
 use new::New;
 use query::Query;
 use run::Run;
 use serve::Serve;
+use transfer::{Export, Import};
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -32,18 +42,51 @@ pub struct Args {
     /// timeout - when ctrl-c is pressed the app will wait no longer than this before exiting
     #[clap(short = 'T', long, default_value = "30")]
     pub timeout: u64,
+
+    /// change to this directory before doing anything else, like `make -C`
+    #[clap(short = 'C', long, global = true)]
+    pub chdir: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Config {
     pub shell: crate::repl::Config,
+
+    #[serde(default)]
+    pub fetch: crate::runtime::http::FetchConfig,
+
+    #[serde(default)]
+    pub template: crate::template::TemplateConfig,
+
+    #[serde(default)]
+    pub database: crate::database::DatabaseConfig,
+
+    #[serde(default)]
+    pub stdlib: crate::runtime::stdlib::StdlibConfig,
+
+    #[serde(default)]
+    pub websocket: crate::routes::socket_limits::WebSocketConfig,
+
+    #[serde(default)]
+    pub assets: crate::routes::assets::AssetsConfig,
+
+    #[serde(default)]
+    pub seo: crate::runtime::seo::SeoConfig,
+
+    #[serde(default)]
+    pub analytics: crate::routes::analytics::AnalyticsConfig,
+
+    #[serde(default)]
+    pub bots: crate::routes::bots::BotsConfig,
+
+    #[serde(default)]
+    pub firewall: crate::routes::firewall::FirewallConfig,
+
+    #[serde(default)]
+    pub uploads: crate::runtime::http::UploadsConfig,
 }
 
 impl Args {
-    pub fn new() -> Self {
-        Self::parse()
-    }
-
     fn config_path(&self) -> PathBuf {
         self.config_path
             .clone()
@@ -79,6 +122,10 @@ impl Args {
         tracker: TaskTracker,
         output: Output,
     ) -> Result<()> {
+        if let Some(dir) = &self.chdir {
+            std::env::set_current_dir(dir)
+                .map_err(|err| eyre::eyre!("could not change to {}: {err}", dir.display()))?;
+        }
         let config = Arc::new(self.read_config().await?);
 
         self.command.run(tracker, token, config, output).await
@@ -87,6 +134,24 @@ impl Args {
 
 #[derive(Debug, Subcommand)]
 pub enum Command {
+    /// load test a route of a running app
+    Bench(Bench),
+
+    /// compile the app's lua files to bytecode ahead of time
+    Compile(Compile),
+
+    /// write lua-language-server definitions for the runtime's globals
+    Defs(Defs),
+
+    /// run lua-language-server with the runtime's definitions, for editors
+    Lsp(Lsp),
+
+    /// write a global table as json or csv
+    Export(Export),
+
+    /// add rows from json or csv to a global table
+    Import(Import),
+
     /// initialize a new project
     New(New),
 
@@ -113,6 +178,30 @@ impl Command {
         output: Output,
     ) -> Result<()> {
         match self {
+            Command::Bench(bench) => {
+                bench.run(&token).await?;
+                token.cancel();
+            }
+            Command::Compile(compile) => {
+                compile.run().await?;
+                token.cancel();
+            }
+            Command::Defs(defs) => {
+                defs.run(&config).await?;
+                token.cancel();
+            }
+            Command::Export(export) => {
+                export.run().await?;
+                token.cancel();
+            }
+            Command::Import(import) => {
+                import.run().await?;
+                token.cancel();
+            }
+            Command::Lsp(lsp) => {
+                lsp.run(&config).await?;
+                token.cancel();
+            }
             Command::New(new) => {
                 new.run().await?;
                 token.cancel();
@@ -121,7 +210,7 @@ impl Command {
                 serve.run(&tracker, &token, &config, &output).await?;
             }
             Command::Run(run) => {
-                run.run(&tracker, &token).await?;
+                run.run(&tracker, &token, &config).await?;
                 token.cancel();
             }
             Command::Query(query) => {
@@ -0,0 +1,159 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use eyre::{eyre, Result};
+use parking_lot::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// load test a route of a running app and report latency percentiles
+#[derive(Debug, Parser)]
+pub struct Bench {
+    /// the path to request, such as /posts
+    pub path: String,
+
+    /// where the app is being served
+    #[clap(short, long, default_value = "http://127.0.0.1:8000")]
+    pub url: String,
+
+    /// concurrent connections
+    #[clap(short, long, default_value = "50")]
+    pub connections: usize,
+
+    /// how long to run, such as 10s, 500ms or 2m
+    #[clap(short, long, default_value = "10s", value_parser = parse_duration)]
+    pub duration: Duration,
+
+    /// the request method
+    #[clap(short = 'X', long, default_value = "GET")]
+    pub method: reqwest::Method,
+
+    /// extra request headers, as "name: value"
+    #[clap(short = 'H', long = "header")]
+    pub headers: Vec<String>,
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {value}"))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(format!("invalid duration unit: {unit}")),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid duration: {value}"))
+}
+
+#[derive(Debug, Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, usize>,
+    errors: usize,
+}
+
+impl Bench {
+    pub async fn run(self, token: &CancellationToken) -> Result<()> {
+        let url = format!(
+            "{}/{}",
+            self.url.trim_end_matches('/'),
+            self.path.trim_start_matches('/')
+        );
+        let mut headers = reqwest::header::HeaderMap::new();
+        for header in &self.headers {
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| eyre!("invalid header: {header}"))?;
+            headers.insert(
+                reqwest::header::HeaderName::try_from(name.trim())?,
+                value.trim().parse()?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.connections)
+            .build()?;
+
+        println!(
+            "{} {url} for {:?} with {} connections",
+            self.method, self.duration, self.connections
+        );
+        let results = Arc::new(Mutex::new(Results::default()));
+        let deadline = Instant::now() + self.duration;
+        let start = Instant::now();
+        let workers = (0..self.connections)
+            .map(|_| {
+                let request = client
+                    .request(self.method.clone(), &url)
+                    .headers(headers.clone());
+                let results = results.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    while Instant::now() < deadline && !token.is_cancelled() {
+                        let Some(request) = request.try_clone() else {
+                            break;
+                        };
+                        let sent = Instant::now();
+                        // read the whole body so the latency includes it
+                        let response = match request.send().await {
+                            Ok(response) => {
+                                let status = response.status().as_u16();
+                                response.bytes().await.map(|_| status)
+                            }
+                            Err(err) => Err(err),
+                        };
+                        let elapsed = sent.elapsed();
+                        let mut results = results.lock();
+                        match response {
+                            Ok(status) => {
+                                results.latencies.push(elapsed);
+                                *results.statuses.entry(status).or_default() += 1;
+                            }
+                            Err(_) => results.errors += 1,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.await?;
+        }
+        let elapsed = start.elapsed();
+
+        let mut results = std::mem::take(&mut *results.lock());
+        results.latencies.sort();
+        report(&results, elapsed);
+        Ok(())
+    }
+}
+
+fn report(results: &Results, elapsed: Duration) {
+    let count = results.latencies.len();
+    println!(
+        "{count} requests in {elapsed:.2?}, {:.1} requests/sec",
+        count as f64 / elapsed.as_secs_f64()
+    );
+    if count > 0 {
+        let percentile = |p: usize| results.latencies[(count * p / 100).min(count - 1)];
+        println!(
+            "latency p50 {:.2?}  p90 {:.2?}  p99 {:.2?}  max {:.2?}",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            results.latencies[count - 1]
+        );
+    }
+    for (status, count) in &results.statuses {
+        println!("{status}: {count}");
+    }
+    if results.errors > 0 {
+        println!("errors: {}", results.errors);
+    }
+}
@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use eyre::Result;
+
+use crate::runtime::{bytecode, file::app_root};
+
+/// compile the app's lua files to bytecode ahead of time, so the first start doesn't
+#[derive(Debug, Parser)]
+pub struct Compile {
+    #[clap(short, long, default_value = "app.lua")]
+    pub app: PathBuf,
+}
+
+impl Compile {
+    pub async fn run(self) -> Result<()> {
+        let root = app_root(&self.app)?;
+        let count = tokio::task::spawn_blocking(move || bytecode::compile_dir(&root)).await??;
+        println!("compiled {count} files");
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::BTreeSet, fmt::Write, path::PathBuf, sync::Arc};
+
+use clap::Parser;
+use eyre::Result;
+use mlua::prelude::*;
+
+use crate::{
+    command::Config,
+    runtime::{std_libs, Runtime},
+};
+
+/// write a lua-language-server definition file for the globals the runtime provides
+#[derive(Debug, Parser)]
+pub struct Defs {
+    #[clap(short, long, default_value = "app.lua")]
+    pub app: PathBuf,
+
+    /// where to write the definitions, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+impl Defs {
+    pub async fn run(self, config: &Arc<Config>) -> Result<()> {
+        let defs = generate_for_app(config, &self.app).await?;
+        match self.output {
+            Some(output) => tokio::fs::write(output, defs).await?,
+            None => print!("{defs}"),
+        }
+        Ok(())
+    }
+}
+
+/// build the runtime environment for app, without loading it or opening its database, and
+/// describe its globals
+pub async fn generate_for_app(config: &Arc<Config>, app: &std::path::Path) -> Result<String> {
+    let runtime = Runtime::new(config.clone());
+    runtime.start_scratch_services(app).await?;
+    let lua = runtime.new_env(app).await?;
+    let defs = generate(&lua)?;
+    runtime.shutdown().await;
+    Ok(defs)
+}
+
+/// the definitions are read from a live lua state, so they always match what the runtime
+/// registers. parameter names and types are not visible from lua, so functions take `...`.
+pub fn generate(lua: &Lua) -> LuaResult<String> {
+    let builtin = Lua::new_with(std_libs(), LuaOptions::default())?;
+    let builtin: BTreeSet<String> = builtin
+        .globals()
+        .pairs::<String, LuaValue>()
+        .map(|pair| pair.map(|(name, _)| name))
+        .collect::<LuaResult<_>>()?;
+
+    let mut globals = lua
+        .globals()
+        .pairs::<String, LuaValue>()
+        .collect::<LuaResult<Vec<_>>>()?;
+    globals.retain(|(name, _)| !builtin.contains(name));
+    globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut out = String::from("---@meta lilguy\n-- generated by `lilguy defs`, do not edit\n\n");
+    for (name, value) in globals {
+        describe(&mut out, &name, &value, 0)?;
+    }
+    Ok(out)
+}
+
+fn describe(out: &mut String, name: &str, value: &LuaValue, depth: usize) -> LuaResult<()> {
+    match value {
+        LuaValue::Function(_) => {
+            let _ = writeln!(out, "function {name}(...) end\n");
+        }
+        LuaValue::Table(table) => {
+            // tables named like classes (Request, Response, Context) hold methods
+            let class = depth == 0 && name.starts_with(|c: char| c.is_ascii_uppercase());
+            if depth == 0 {
+                let _ = writeln!(out, "---@class {name}");
+            }
+            let _ = writeln!(out, "{name} = {{}}\n");
+
+            let mut fields = table
+                .pairs::<LuaValue, LuaValue>()
+                .filter_map(|pair| match pair {
+                    Ok((LuaValue::String(key), value)) => {
+                        Some(key.to_str().map(|key| (key.to_string(), value)))
+                    }
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                })
+                .collect::<LuaResult<Vec<_>>>()?;
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (field, value) in fields {
+                if !is_identifier(&field) {
+                    continue;
+                }
+                match value {
+                    LuaValue::Function(_) if class => {
+                        let _ = writeln!(out, "function {name}:{field}(...) end\n");
+                    }
+                    value if depth < 2 => {
+                        describe(out, &format!("{name}.{field}"), &value, depth + 1)?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        LuaValue::UserData(ud) => {
+            let class = format!("lilguy.{name}");
+            let _ = writeln!(out, "---@class {class}");
+            let _ = writeln!(out, "{name} = {{}}\n");
+            for method in userdata_methods(ud) {
+                let _ = writeln!(out, "function {name}:{method}(...) end\n");
+            }
+        }
+        LuaValue::Boolean(_) => {
+            let _ = writeln!(out, "---@type boolean\n{name} = false\n");
+        }
+        LuaValue::Integer(_) | LuaValue::Number(_) => {
+            let _ = writeln!(out, "---@type number\n{name} = 0\n");
+        }
+        LuaValue::String(_) => {
+            let _ = writeln!(out, "---@type string\n{name} = \"\"\n");
+        }
+        _ => {
+            let _ = writeln!(out, "---@type any\n{name} = nil\n");
+        }
+    }
+    Ok(())
+}
+
+/// methods that lua can see through the userdata's __index table. userdata that
+/// compute __index (because they also have fields) do not expose their methods this way.
+fn userdata_methods(ud: &LuaAnyUserData) -> Vec<String> {
+    let Ok(metatable) = ud.metatable() else {
+        return vec![];
+    };
+    let Ok(LuaValue::Table(index)) = metatable.get::<LuaValue>("__index") else {
+        return vec![];
+    };
+    let mut methods: Vec<String> = index
+        .pairs::<String, LuaValue>()
+        .filter_map(|pair| pair.ok())
+        .filter(|(name, value)| value.is_function() && is_identifier(name))
+        .map(|(name, _)| name)
+        .collect();
+    methods.sort();
+    methods
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,85 @@
+use std::{path::PathBuf, process::Stdio, sync::Arc};
+
+use clap::Parser;
+use eyre::{eyre, Result};
+use serde_json::json;
+
+use crate::{
+    command::{defs::generate_for_app, Config},
+    runtime::LUA_FLAVOR,
+};
+
+/// run lua-language-server on stdio, configured for a lilguy project
+#[derive(Debug, Parser)]
+pub struct Lsp {
+    #[clap(short, long, default_value = "app.lua")]
+    pub app: PathBuf,
+
+    /// the lua-language-server executable
+    #[clap(
+        long,
+        env = "LILGUY_LUA_LANGUAGE_SERVER",
+        default_value = "lua-language-server"
+    )]
+    pub server: PathBuf,
+
+    /// extra arguments for lua-language-server
+    #[clap(allow_hyphen_values = true, trailing_var_arg = true)]
+    pub args: Vec<String>,
+}
+
+impl Lsp {
+    pub async fn run(self, config: &Arc<Config>) -> Result<()> {
+        // the definitions only depend on the runtime, but each project gets its own
+        // directory so the config can point at the project's package path.
+        let app = std::path::absolute(&self.app)?;
+        let project = app.parent().map(PathBuf::from).unwrap_or_default();
+        let dir = dirs::cache_dir()
+            .ok_or_else(|| eyre!("could not determine cache directory"))?
+            .join(env!("CARGO_PKG_NAME"))
+            .join("lsp")
+            .join(format!(
+                "{:08x}",
+                crc32fast::hash(project.to_string_lossy().as_bytes())
+            ));
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let defs = generate_for_app(config, &app).await?;
+        tokio::fs::write(dir.join("lilguy.lua"), defs).await?;
+
+        // same search path as the runtime's package.path
+        let settings = json!({
+            "runtime.version": runtime_version(LUA_FLAVOR),
+            "runtime.path": ["?.lua"],
+            "runtime.pathStrict": true,
+            "workspace.library": [dir],
+            "workspace.checkThirdParty": false,
+        });
+        let config_path = dir.join("luarc.json");
+        tokio::fs::write(&config_path, serde_json::to_vec_pretty(&settings)?).await?;
+
+        let status = tokio::process::Command::new(&self.server)
+            .arg(format!("--configpath={}", config_path.display()))
+            .args(&self.args)
+            .current_dir(&project)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .await
+            .map_err(|err| eyre!("could not run {}: {err}", self.server.display()))?;
+        if !status.success() {
+            return Err(eyre!("{} exited with {status}", self.server.display()));
+        }
+        Ok(())
+    }
+}
+
+/// the lua-language-server runtime closest to the lua lilguy was built with. it has no
+/// luau, which grew out of lua 5.1.
+fn runtime_version(flavor: &str) -> &'static str {
+    match flavor {
+        "luau" => "Lua 5.1",
+        _ => "LuaJIT",
+    }
+}
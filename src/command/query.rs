@@ -1,7 +1,7 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
-use eyre::Result;
+use eyre::{eyre, Result};
 use prettytable::{Cell, Row};
 use rusqlite::types::Value;
 
@@ -15,11 +15,20 @@ pub struct Query {
 
     /// sql query to run
     pub query: String,
+
+    /// seconds the query may run before it is stopped, 0 for no limit
+    #[clap(short, long, default_value = "30")]
+    pub timeout: f64,
 }
 
 impl Query {
     pub async fn run(self) -> Result<()> {
         let db = Database::open(self.app.with_extension("db"))?;
+        let timeout = (self.timeout > 0.0)
+            .then(|| Duration::try_from_secs_f64(self.timeout))
+            .transpose()
+            .map_err(|_| eyre!("invalid timeout: {}", self.timeout))?;
+        db.set_statement_timeout(timeout);
         let query = self.query.clone();
         db.call(move |conn| {
             let mut stmt = conn.prepare(&query)?;
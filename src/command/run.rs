@@ -1,9 +1,9 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use clap::Parser;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::runtime::Runtime;
+use crate::{command::Config, runtime::Runtime};
 
 #[derive(Debug, Parser)]
 pub struct Run {
@@ -14,6 +14,10 @@ pub struct Run {
     #[clap(default_value = "main")]
     pub func: String,
 
+    /// fail fetches that no fetch.mock() answers, instead of using the network
+    #[clap(long)]
+    pub offline: bool,
+
     /// additional arguments to pass to the script
     #[clap(allow_hyphen_values = true, trailing_var_arg = true)]
     pub args: Vec<String>,
@@ -24,8 +28,9 @@ impl Run {
         self,
         tracker: &TaskTracker,
         token: &CancellationToken,
+        config: &Arc<Config>,
     ) -> Result<(), eyre::Report> {
-        let runtime = Runtime::new();
+        let runtime = Runtime::new(config.clone()).with_offline(self.offline);
         runtime.start(tracker, token, &self.app, false).await?;
         runtime.run(self.func, self.args).await?;
 
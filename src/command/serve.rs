@@ -1,20 +1,36 @@
 use axum::{
-    body::Body,
-    extract::{self, ws::WebSocket, Request, State, WebSocketUpgrade},
-    http::{Response, StatusCode},
-    response::IntoResponse,
-    routing::any,
-    Router,
+    body::{to_bytes, Body, HttpBody},
+    extract::{
+        self, ws::WebSocket, FromRequestParts, OriginalUri, Query, Request, State, WebSocketUpgrade,
+    },
+    http::{
+        header::{
+            ALLOW, CACHE_CONTROL, CONTENT_TYPE, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+            LOCATION, RANGE, SET_COOKIE, UPGRADE,
+        },
+        HeaderMap, Method, Response, StatusCode,
+    },
+    middleware,
+    response::{Html, IntoResponse},
+    routing::{any, get, post},
+    Json, Router,
 };
 use bytes::Bytes;
 use clap::Parser;
-use eyre::Result;
+use eyre::{eyre, Result};
 use mlua::prelude::*;
-use std::{path::PathBuf, time::Duration};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{net::TcpListener, time::sleep};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::ServiceExt;
 use tower_http::{
-    services::ServeDir,
     timeout::TimeoutLayer,
     trace::{self, TraceLayer},
 };
@@ -23,19 +39,56 @@ use tracing::Level;
 use crate::{
     command::Config,
     repl,
-    routes::Routes,
+    routes::{
+        analytics::{self, Analytics},
+        assets,
+        bots::{self, Bots},
+        firewall::{self, Firewall},
+        live::{self, LIVE_PATH},
+        middleware as lua_middleware, mounts,
+        rooms::{self, POLL_PATH, ROOMS_PATH},
+        rpc,
+        socket_limits::SocketLimits,
+        socketio, spa,
+        yjs::{self, YjsRoute},
+    },
     runtime::{
-        http::{create_request, new_response, LuaCookieJar, LuaHeaders, LuaWebSocket},
+        activitypub::{self, ACTIVITY_JSON, AP_PATH, WEBFINGER_PATH},
+        blob::BlobReader,
+        cache::{response_cache, Cached, CachedResponse, MAX_CACHED_BODY},
+        channel, content,
+        debugger::Debugger,
+        file,
+        http::{
+            body::LuaBody,
+            client::ClientIp,
+            create_raw_request, create_request, decode,
+            deferred::{self, UntilSent},
+            multipart, new_response,
+            range::RangeResponse,
+            send_file::FileBody,
+            session,
+            sse::SseBody,
+            trailers::WithTrailers,
+            with_request_id, LuaCookieJar, LuaHeaders, LuaWebSocket,
+        },
+        payments,
+        profiler::Profiler,
+        seo, slug,
+        sync::{self, SEALED_CONTENT_TYPE, SYNC_PATH},
         Runtime,
     },
+    template,
+    workspace::{Workspace, WorkspaceApp, WORKSPACE_FILE},
     Output,
 };
 
 #[derive(Debug, Parser)]
 pub struct Serve {
-    /// the directory to serve files from
-    #[clap(short, long, default_value = "app.lua")]
-    pub app: PathBuf,
+    /// the app to serve, defaults to app.lua. given explicitly, it is served even when
+    /// ./lilguy.toml exists
+    #[clap(short, long, conflicts_with = "workspace")]
+    pub app: Option<PathBuf>,
 
     /// the address to bind to
     #[clap(short, long, default_value = "0.0.0.0:8000")]
@@ -53,6 +106,27 @@ pub struct Serve {
 
     #[clap(short, long)]
     pub interactive: bool,
+
+    /// accept debug adapter protocol clients on this port, for breakpoints in handlers
+    #[clap(long)]
+    pub debug_port: Option<u16>,
+
+    /// fail fetches that no fetch.mock() answers, instead of using the network
+    #[clap(long)]
+    pub offline: bool,
+
+    /// sample lua handlers, viewable at /_lilguy/profile
+    #[clap(long, conflicts_with = "debug_port")]
+    pub profile: bool,
+
+    /// serve the apps declared in a workspace file, defaults to ./lilguy.toml if it exists
+    #[clap(short, long)]
+    pub workspace: Option<PathBuf>,
+
+    /// serve a single page app from this directory, with its index.html for paths no
+    /// route matches
+    #[clap(long, conflicts_with = "workspace")]
+    pub spa: Option<PathBuf>,
     // todo: --secure option that will take a certifcate bundle or use acme to get a certificate
 }
 
@@ -62,24 +136,81 @@ impl Serve {
         self,
         tracker: &TaskTracker,
         token: &CancellationToken,
-        config: &Config,
+        config: &Arc<Config>,
         output: &Output,
     ) -> Result<()> {
-        let runtime = Runtime::new();
+        let apps = match self.workspace() {
+            Some(path) => Workspace::load(&path).await?.apps,
+            None => vec![WorkspaceApp::new(
+                self.app.clone().unwrap_or_else(|| PathBuf::from("app.lua")),
+            )],
+        };
+        let debugger = match self.debug_port {
+            Some(port) => {
+                let debugger = Debugger::default();
+                debugger.listen(SocketAddr::from(([127, 0, 0, 1], port)))?;
+                Some(debugger)
+            }
+            None => None,
+        };
+        let profiler = self.profile.then(Profiler::default);
+        let socket_limits = SocketLimits::new(&config.websocket);
+        let bots = Bots::new(&config.bots);
+        let firewall = Firewall::new(&config.firewall)?;
         let listener = TcpListener::bind(&self.listen).await?;
-        runtime
-            .start(tracker, token, &self.app, !self.no_reload)
-            .await?;
 
-        let assets_dir = self.app.with_file_name("assets");
+        // the first app is the one the profiler pages and the interactive shell use
+        let mut first = None;
+        let mut hosts = HashMap::new();
+        let mut mounted = Router::new();
+        for app in apps {
+            let mut runtime = Runtime::new(config.clone())
+                .with_offline(self.offline)
+                .with_spa(self.spa.clone())
+                .with_socket_limits(socket_limits.clone())
+                .with_bots(bots.clone())
+                .with_firewall(firewall.clone());
+            if let Some(debugger) = &debugger {
+                runtime = runtime.with_debugger(debugger.clone());
+            }
+            if let Some(profiler) = &profiler {
+                runtime = runtime.with_profiler(profiler.clone());
+            }
+            runtime
+                .start(tracker, token, &app.app, !self.no_reload)
+                .await?;
+            let router = app_router(runtime.clone(), &app.app, &config)?;
+            if first.is_none() {
+                first = Some(runtime);
+            }
+
+            if !app.hosts.is_empty() {
+                for host in app.hosts {
+                    hosts.insert(host, router.clone());
+                }
+                continue;
+            }
+            mounted = match app.prefix {
+                Some(prefix) => mounted.nest(&prefix, router),
+                None => mounted.merge(router),
+            };
+        }
+        let runtime = first.ok_or_else(|| eyre!("no apps to serve"))?;
 
-        let app = Router::new()
-            .nest_service("/assets", ServeDir::new(assets_dir))
-            .route("/ws/{*path}", any(handle_websocket_request))
-            .route("/ws", any(handle_websocket_request))
-            .route("/", any(handle_request))
-            .route("/{*path}", any(handle_request))
-            .with_state(runtime.clone())
+        let mut app = if hosts.is_empty() {
+            mounted
+        } else {
+            route_hosts(Arc::new(hosts), mounted)
+        };
+        if self.profile {
+            app = app.merge(
+                Router::new()
+                    .route("/_lilguy/profile", get(profile_report))
+                    .route("/_lilguy/profile.folded", get(profile_folded))
+                    .with_state(runtime.clone()),
+            );
+        }
+        let app = app
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
@@ -91,12 +222,15 @@ impl Serve {
         tracker.spawn({
             let token = token.clone();
             async move {
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
                 let server = axum::serve(listener, app).with_graceful_shutdown(async move {
                     token.cancelled().await;
+                    tracing::info!("shutdown: no longer accepting connections");
                 });
                 if let Err(err) = server.await {
                     tracing::error!(?err, "error serving application");
                 }
+                tracing::info!("shutdown: server stopped");
             }
         });
 
@@ -119,6 +253,85 @@ impl Serve {
 
         Ok(())
     }
+
+    /// the workspace file to serve, if --workspace was given, or ./lilguy.toml exists and
+    /// --app wasn't given
+    fn workspace(&self) -> Option<PathBuf> {
+        if self.app.is_some() {
+            return None;
+        }
+        self.workspace.clone().or_else(|| {
+            let path = PathBuf::from(WORKSPACE_FILE);
+            path.exists().then_some(path)
+        })
+    }
+}
+
+/// the routes for one app, with its assets and websockets
+fn app_router(runtime: Runtime, app: &Path, config: &Config) -> Result<Router> {
+    let assets_dir = app.with_file_name("assets");
+    let assets = assets::service(
+        assets_dir,
+        &config.assets,
+        runtime.template()?,
+        runtime.is_dev(),
+    );
+    let router = Router::new()
+        .nest_service("/assets", assets)
+        .route("/ws/{*path}", any(handle_websocket_request))
+        .route("/ws", any(handle_websocket_request))
+        .route(SYNC_PATH, get(sync_changes).post(sync_receive))
+        .route(LIVE_PATH, get(live_socket))
+        .route(&format!("{ROOMS_PATH}/{{room}}"), get(room_socket))
+        .route(&format!("{POLL_PATH}/{{room}}"), get(room_poll))
+        .route(rooms::SCRIPT_PATH, get(rooms_script))
+        .route(WEBFINGER_PATH, get(ap_webfinger))
+        .route(&format!("{AP_PATH}/users/{{name}}"), get(ap_actor))
+        .route(
+            &format!("{AP_PATH}/users/{{name}}/{{part}}"),
+            get(ap_collection).post(ap_inbox),
+        )
+        .route(payments::WEBHOOK_PATH, post(payments_webhook))
+        .route("/", any(handle_request))
+        .route("/{*path}", any(handle_request))
+        .with_state(runtime.clone());
+    let router = if config.analytics.enabled() {
+        let root = file::app_root(app)?;
+        let analytics = Analytics::new(&config.analytics, &root, runtime.database()?);
+        router
+            .merge(
+                Router::new()
+                    .route(analytics::DASHBOARD_PATH, get(analytics::dashboard))
+                    .with_state(analytics.clone()),
+            )
+            .layer(middleware::from_fn_with_state(analytics, analytics::record))
+    } else {
+        router
+    };
+    // outermost, so turned away requests never reach the rest
+    Ok(router.layer(middleware::from_fn_with_state(runtime, firewall::guard)))
+}
+
+/// send requests to the app for their Host header, or to the mounted apps
+fn route_hosts(hosts: Arc<HashMap<String, Router>>, mounted: Router) -> Router {
+    Router::new().fallback(move |request: Request<Body>| {
+        let hosts = hosts.clone();
+        let mounted = mounted.clone();
+        async move {
+            let host = request
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(|host| host.split(':').next().unwrap_or(host).to_ascii_lowercase());
+            let router = host
+                .and_then(|host| hosts.get(&host).cloned())
+                .unwrap_or(mounted);
+            match router.oneshot(request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            }
+        }
+    })
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -128,6 +341,9 @@ enum LuaServeError {
 
     #[error("lua error: {0}")]
     Lua(#[from] LuaError),
+
+    #[error("axum error: {0}")]
+    Axum(#[from] axum::Error),
 }
 
 impl IntoResponse for LuaServeError {
@@ -143,38 +359,591 @@ impl IntoResponse for LuaServeError {
 async fn handle_request(
     State(runtime): State<Runtime>,
     request: Request<Body>,
-) -> Result<LuaResponse, LuaServeError> {
+) -> Result<Response<Body>, LuaServeError> {
+    let requests = runtime.requests().clone();
+    let dev = runtime.is_dev();
+    let result = requests
+        .track_future(handle_lua_request(runtime, request))
+        .await;
+
+    if let Err(LuaServeError::Lua(ref err)) = result {
+        if multipart::is_too_large(err) {
+            return Ok((StatusCode::PAYLOAD_TOO_LARGE, "upload too large\n").into_response());
+        }
+    }
+    // during development, show template errors instead of a generic 500
+    if let Err(LuaServeError::Lua(ref err)) = result {
+        if let Some(err) = template::find_error(err).filter(|_| dev) {
+            tracing::error!(?err, "error rendering template");
+            let page = template::error_page(err);
+            return Ok((StatusCode::INTERNAL_SERVER_ERROR, Html(page)).into_response());
+        }
+    }
+    result
+}
+
+async fn profile_report(State(runtime): State<Runtime>) -> Response<Body> {
+    let report = runtime.profiler().map(Profiler::report).unwrap_or_default();
+    Html(report).into_response()
+}
+
+async fn profile_folded(State(runtime): State<Runtime>) -> Response<Body> {
+    let folded = runtime.profiler().map(Profiler::folded).unwrap_or_default();
+    ([(CONTENT_TYPE, "text/plain; charset=utf-8")], folded).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncQuery {
+    #[serde(default)]
+    since: i64,
+}
+
+/// GET /_lilguy/sync?since=seq for peers pulling our changes; see runtime/sync.rs
+async fn sync_changes(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    Query(query): Query<SyncQuery>,
+) -> Result<Response<Body>, LuaServeError> {
     let lua = runtime.lua()?;
-    let globals = lua.globals();
-    let routes = globals.get::<LuaUserDataRef<Routes>>("routes")?;
-    let (handler, path) = routes.find(request.uri().path());
-    let (route, params) = if let Some(ref path) = path {
-        (
-            LuaValue::String(lua.create_string(path.pattern())?),
-            LuaValue::Table(lua.create_table_from(path.params_iter())?),
+    let key = match sync::authorize(&lua, &headers) {
+        Ok(key) => key,
+        Err(status) => return Ok(status.into_response()),
+    };
+    let sealed = sync::changes(&lua, &key, query.since).await?;
+    Ok(([(CONTENT_TYPE, SEALED_CONTENT_TYPE)], sealed).into_response())
+}
+
+/// POST /_lilguy/sync for peers pushing their changes
+async fn sync_receive(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    let key = match sync::authorize(&lua, &headers) {
+        Ok(key) => key,
+        Err(status) => return Ok(status.into_response()),
+    };
+    let Some(changes) = sync::open_changes(&key, &body) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let applied = sync::receive(&lua, changes.changes).await?;
+    Ok(Json(serde_json::json!({ "applied": applied })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct WebfingerQuery {
+    resource: String,
+}
+
+/// GET /.well-known/webfinger?resource=acct:name@host; see runtime/activitypub.rs
+async fn ap_webfinger(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    Query(query): Query<WebfingerQuery>,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    match activitypub::webfinger(&lua, &headers, &query.resource)? {
+        Some(jrd) => Ok(([(CONTENT_TYPE, "application/jrd+json")], Json(jrd)).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+async fn ap_document(
+    runtime: Runtime,
+    headers: HeaderMap,
+    name: String,
+    part: Option<String>,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    match activitypub::document(&lua, &headers, &name, part.as_deref()).await? {
+        Some(document) => Ok(([(CONTENT_TYPE, ACTIVITY_JSON)], Json(document)).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// GET /_lilguy/ap/users/name
+async fn ap_actor(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    extract::Path(name): extract::Path<String>,
+) -> Result<Response<Body>, LuaServeError> {
+    ap_document(runtime, headers, name, None).await
+}
+
+/// GET /_lilguy/ap/users/name/outbox or followers
+async fn ap_collection(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    extract::Path((name, part)): extract::Path<(String, String)>,
+) -> Result<Response<Body>, LuaServeError> {
+    ap_document(runtime, headers, name, Some(part)).await
+}
+
+/// POST /_lilguy/ap/users/name/inbox, signed by the remote actor
+async fn ap_inbox(
+    State(runtime): State<Runtime>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    extract::Path((name, part)): extract::Path<(String, String)>,
+    body: Bytes,
+) -> Result<Response<Body>, LuaServeError> {
+    if part != "inbox" {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+    }
+    let lua = runtime.lua()?;
+    let path = uri
+        .path_and_query()
+        .map_or_else(|| uri.path(), |path| path.as_str());
+    let status = activitypub::inbox(&lua, &headers, &name, path, &body).await?;
+    Ok(status.into_response())
+}
+
+/// POST /_lilguy/payments/webhook; see runtime/payments.rs
+async fn payments_webhook(
+    State(runtime): State<Runtime>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    let status = payments::webhook(&lua, &headers, &body).await?;
+    Ok(status.into_response())
+}
+
+async fn handle_lua_request(
+    runtime: Runtime,
+    request: Request<Body>,
+) -> Result<Response<Body>, LuaServeError> {
+    let (lua, routes) = runtime.lua_with_routes()?;
+    if routes.blocks_bots(request.uri().path()) {
+        let ClientIp(client) = ClientIp::from_extensions(request.extensions());
+        let blocked = runtime
+            .bots()
+            .check(client, request.headers(), request.uri().path());
+        if let Some(response) = blocked {
+            return Ok(response);
+        }
+    }
+    if let Some(handler) = routes.socketio(request.uri().path()) {
+        return handle_socketio_request(&runtime, &lua, handler, request).await;
+    }
+    if is_websocket(&request) {
+        if let Some(route) = routes.yjs(request.uri().path()) {
+            return handle_yjs_request(&runtime, &lua, route, request).await;
+        }
+    }
+    if request.method() == Method::POST {
+        if let Some(handler) = routes.rpc(request.uri().path()) {
+            return Ok(rpc::serve(&lua, handler, request).await);
+        }
+    }
+    let found = routes.find(request.method().as_str(), request.uri().path());
+    if found.pattern.is_none() {
+        if !found.allowed.is_empty() {
+            let allow = found.allowed.join(", ");
+            return Ok((StatusCode::METHOD_NOT_ALLOWED, [(ALLOW, allow)]).into_response());
+        }
+        let get = matches!(*request.method(), Method::GET | Method::HEAD);
+        if let Some((prefix, mounted)) = routes.mount_for(request.uri().path()) {
+            if get && mounts::exists(&prefix, &mounted, request.uri().path()).await {
+                let template = runtime.template()?;
+                let dev = runtime.is_dev();
+                let config = runtime.config();
+                let response =
+                    mounts::serve(request, &prefix, &mounted, config, template, dev).await;
+                return Ok(response);
+            }
+        }
+        if let Some((content_type, body)) = routes.openapi(request.uri().path()) {
+            return Ok(([(CONTENT_TYPE, content_type)], body).into_response());
+        }
+        if seo::is_seo_file(request.uri().path()) {
+            let sitemap = routes.sitemap();
+            let blocked = routes.blocked_bots();
+            let robots = request.uri().path() == "/robots.txt" && !blocked.is_empty();
+            if !sitemap.is_empty() || robots {
+                let disallow: Vec<&str> = blocked.iter().map(|p| bots::disallow(p)).collect();
+                let (content_type, body) = seo::serve(
+                    &lua,
+                    request.uri().path(),
+                    sitemap,
+                    &disallow,
+                    request.headers(),
+                )
+                .await?;
+                return Ok(([(CONTENT_TYPE, content_type)], body).into_response());
+            }
+        }
+        if matches!(*request.method(), Method::GET | Method::HEAD) {
+            if let Some(page) = content::serve(&lua, request.uri().path(), runtime.is_dev()).await?
+            {
+                return Ok(Html(page).into_response());
+            }
+            if let Some((prefix, dir)) = routes.spa(request.uri().path()) {
+                return Ok(spa::serve(request, &prefix, &dir).await);
+            }
+        }
+    }
+    if let (Some(pattern), Some(param)) = (&found.pattern, &found.slug) {
+        if matches!(*request.method(), Method::GET | Method::HEAD) {
+            if let Some(location) =
+                renamed_slug(&runtime, pattern, param, &found.params, &request).await?
+            {
+                return Ok((StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response());
+            }
+        }
+    }
+    let method = request.method().clone();
+    let handler = found.handler;
+    let route_pattern = found.pattern;
+    let route = match route_pattern {
+        Some(ref pattern) => LuaValue::String(lua.create_string(&**pattern)?),
+        None => LuaValue::Nil,
+    };
+    let params = lua.create_table_from(found.params)?;
+    let raw = found.raw;
+    let cache_policy = found
+        .cache_policy
+        .filter(|_| matches!(*request.method(), Method::GET | Method::HEAD))
+        .filter(|policy| policy.cacheable(request.headers()));
+
+    let cache = response_cache(&lua)?;
+    let request_path = request.uri().path().to_string();
+    let cache_key = cache_policy.as_ref().map(|policy| {
+        policy.key(
+            request.method(),
+            &request_path,
+            request.uri().query(),
+            request.headers(),
         )
+    });
+    let revalidating = request.extensions().get::<Revalidate>().is_some();
+    match cache_key
+        .as_deref()
+        .filter(|_| !revalidating)
+        .and_then(|key| cache.get(key))
+    {
+        Some(Cached::Fresh(cached)) => return Ok(cached_response(cached)),
+        Some(Cached::Stale {
+            response,
+            revalidate,
+        }) => {
+            if revalidate {
+                let mut copy = Request::new(Body::empty());
+                *copy.method_mut() = request.method().clone();
+                *copy.uri_mut() = request.uri().clone();
+                *copy.headers_mut() = request.headers().clone();
+                copy.extensions_mut().insert(Revalidate);
+                let key = cache_key.clone().unwrap_or_default();
+                runtime
+                    .requests()
+                    .spawn(revalidate_page(runtime.clone(), copy, key));
+            }
+            return Ok(cached_response(response));
+        }
+        None => {}
+    }
+
+    let request = if raw {
+        request
+    } else {
+        match decode::decode(request) {
+            Ok(request) => request,
+            Err(status) => return Ok(status.into_response()),
+        }
+    };
+    let res = new_response(&lua)?;
+    let response = LuaResponse::new(res.clone(), &request);
+    let req = if raw {
+        create_raw_request(&lua, request)?
     } else {
-        (LuaValue::Nil, LuaValue::Table(lua.create_table()?))
+        create_request(&lua, request).await?
     };
-    drop(path);
-    let req = create_request(&lua, request).await?;
     req.set("route", route)?;
     req.set("params", params)?;
 
-    let res = new_response(&lua)?;
-    res.set("cookie_jar", req.get::<LuaAnyUserData>("cookie_jar")?)?;
+    res.set(
+        "cookie_jar",
+        req.get::<Option<LuaAnyUserData>>("cookie_jar")?,
+    )?;
+    res.set("request", &req)?;
+
+    let ctx = req.get::<LuaTable>("ctx")?;
+    let profile = runtime.profiler().map(|profiler| {
+        let route = format!(
+            "{method} {}",
+            route_pattern.as_deref().unwrap_or("(not found)")
+        );
+        profiler.handler(&handler, &route);
+        (profiler, route, Instant::now())
+    });
+    let request_id = req.get::<String>("id")?;
+    let middleware = routes.middleware(&request_path);
+    let session = (!raw).then(|| (req.clone(), res.clone()));
+    let result = with_request_id(
+        request_id,
+        lua_middleware::call(&lua, middleware, handler, req, res),
+    )
+    .await;
+    if let Some((profiler, route, start)) = profile {
+        profiler.time_route(&route, start.elapsed());
+    }
+    // deferred functions run once the response is sent, or now if there won't be one
+    let sent = deferred::after_response(runtime.requests(), ctx);
+    let until_sent = |response: Response<Body>| response.map(|body| UntilSent::wrap(body, sent));
+    result?;
+    if let Some((req, res)) = session {
+        session::save(&lua, &runtime.database()?, &req, &res).await?;
+    }
+
+    let response = response.into_response();
+    let (Some(policy), Some(key)) = (cache_policy, cache_key) else {
+        return Ok(until_sent(response));
+    };
+    if response.status() != StatusCode::OK || response.headers().contains_key(SET_COOKIE) {
+        return Ok(until_sent(response));
+    }
+    // a body that is too large, or streamed without a known length, goes out as it is
+    let small = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_CACHED_BODY);
+    if !small {
+        return Ok(until_sent(response));
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, MAX_CACHED_BODY as usize).await?;
+    cache.insert(
+        key,
+        &request_path,
+        &policy,
+        parts.status,
+        parts.headers.clone(),
+        body.clone(),
+    );
+
+    Ok(until_sent(Response::from_parts(parts, Body::from(body))))
+}
+
+/// where a routes:slug() request should go, if its slug was renamed
+async fn renamed_slug(
+    runtime: &Runtime,
+    pattern: &str,
+    param: &str,
+    params: &[(String, String)],
+    request: &Request<Body>,
+) -> Result<Option<String>, LuaServeError> {
+    let Some(old) = params
+        .iter()
+        .find(|(name, _)| name == param)
+        .and_then(|(_, value)| slug::decode(value))
+    else {
+        return Ok(None);
+    };
+    let database = runtime.database()?;
+    let Some(new) = slug::current(&database, pattern.to_string(), old)
+        .await
+        .into_lua_err()?
+    else {
+        return Ok(None);
+    };
+    let uri = request.uri();
+    Ok(slug::location(
+        uri.path(),
+        uri.query(),
+        pattern,
+        param,
+        &new,
+    ))
+}
+
+fn cached_response(cached: CachedResponse) -> Response<Body> {
+    let mut response = Response::new(Body::from(cached.body));
+    *response.status_mut() = cached.status;
+    *response.headers_mut() = cached.headers;
+    response
+}
+
+/// marks the copy of a request that renders a stale page again
+#[derive(Debug, Clone, Copy)]
+struct Revalidate;
+
+/// render a stale page again in the background. a render that fails or isn't cacheable
+/// leaves the stale page for the next request to retry. this is boxed because it calls
+/// handle_lua_request, which spawns it.
+fn revalidate_page(
+    runtime: Runtime,
+    request: Request<Body>,
+    key: String,
+) -> futures_util::future::BoxFuture<'static, ()> {
+    Box::pin(async move {
+        let path = request.uri().path().to_string();
+        let result = handle_lua_request(runtime.clone(), request).await;
+        if let Err(err) = &result {
+            tracing::warn!(?err, path, "could not render a stale page again");
+        }
+        if let Ok(lua) = runtime.lua() {
+            if let Ok(cache) = response_cache(&lua) {
+                cache.revalidated(&key);
+            }
+        }
+    })
+}
+
+fn is_websocket(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+/// a routes:yjs() websocket, if the route's authorize function allows it
+async fn handle_yjs_request(
+    runtime: &Runtime,
+    lua: &Lua,
+    route: YjsRoute,
+    request: Request<Body>,
+) -> Result<Response<Body>, LuaServeError> {
+    let (mut parts, body) = request.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(ws) => ws,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    let ClientIp(client) = ClientIp::from_extensions(&parts.extensions);
+    if let Some(authorize) = route.authorize {
+        let req = create_request(lua, Request::from_parts(parts, body)).await?;
+        req.set("route", route.pattern)?;
+        req.set("params", lua.create_table_from(route.params)?)?;
+        if !authorize.call_async::<bool>(req).await? {
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+    let database = runtime.database()?;
+    let rooms = runtime.yjs_rooms().clone();
+    Ok(runtime.socket_limits().upgrade(client, ws, move |socket| {
+        yjs::serve(socket, database, rooms, route.doc)
+    }))
+}
 
-    handler.call_async::<()>((req, &res)).await?;
+/// a routes:socketio() connection; see routes/socketio.rs
+async fn handle_socketio_request(
+    runtime: &Runtime,
+    lua: &Lua,
+    handler: LuaFunction,
+    request: Request<Body>,
+) -> Result<Response<Body>, LuaServeError> {
+    if let Some(error) = socketio::handshake_error(request.uri().query(), is_websocket(&request)) {
+        return Ok(error);
+    }
+    let (mut parts, body) = request.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(ws) => ws,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    let ClientIp(client) = ClientIp::from_extensions(&parts.extensions);
+    let req = create_request(lua, Request::from_parts(parts, body)).await?;
+    let lua = lua.clone();
+    Ok(runtime.socket_limits().upgrade(client, ws, move |socket| {
+        socketio::serve(socket, lua, handler, req)
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct LiveQuery {
+    #[serde(default)]
+    tables: String,
+}
 
-    Ok(LuaResponse { res })
+/// GET /_lilguy/live?tables=a,b for pages with live fragments; see routes/live.rs
+async fn live_socket(
+    State(runtime): State<Runtime>,
+    Query(query): Query<LiveQuery>,
+    ClientIp(client): ClientIp,
+    ws: WebSocketUpgrade,
+) -> Result<Response<Body>, LuaServeError> {
+    let database = runtime.database()?;
+    let tables = query
+        .tables
+        .split(',')
+        .filter(|table| !table.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(runtime.socket_limits().upgrade(client, ws, move |socket| {
+        live::serve(socket, database, tables)
+    }))
+}
+
+/// true if channel.authorize lets the request listen to room
+async fn room_authorized(lua: &Lua, request: Request<Body>, room: &str) -> LuaResult<bool> {
+    let Some(authorize) = channel::authorizer(lua)? else {
+        return Ok(true);
+    };
+    let req = create_request(lua, request).await?;
+    authorize.call_async::<bool>((req, room)).await
+}
+
+/// GET /_lilguy/rooms/{room}, a websocket with the room's messages; see routes/rooms.rs
+async fn room_socket(
+    State(runtime): State<Runtime>,
+    extract::Path(room): extract::Path<String>,
+    ClientIp(client): ClientIp,
+    ws: WebSocketUpgrade,
+    request: Request<Body>,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    if !room_authorized(&lua, request, &room).await? {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let rooms = runtime.rooms().clone();
+    Ok(runtime
+        .socket_limits()
+        .upgrade(client, ws, move |socket| rooms::serve(socket, rooms, room)))
+}
+
+#[derive(Debug, Deserialize)]
+struct PollQuery {
+    after: Option<u64>,
+}
+
+/// GET /_lilguy/poll/{room}?after=N, for pages that can't open a websocket
+async fn room_poll(
+    State(runtime): State<Runtime>,
+    extract::Path(room): extract::Path<String>,
+    Query(query): Query<PollQuery>,
+    request: Request<Body>,
+) -> Result<Response<Body>, LuaServeError> {
+    let lua = runtime.lua()?;
+    if !room_authorized(&lua, request, &room).await? {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    // the lua state isn't needed while waiting, and may be replaced by a reload
+    drop(lua);
+    let poll = runtime.rooms().poll(&room, query.after).await;
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json"),
+            (CACHE_CONTROL, "no-store"),
+        ],
+        poll.to_json(),
+    )
+        .into_response())
+}
+
+/// GET /_lilguy/rooms.js
+async fn rooms_script() -> Response<Body> {
+    ([(CONTENT_TYPE, "text/javascript")], rooms::SCRIPT).into_response()
 }
 
 async fn handle_websocket_request(
     extract::Path(path): extract::Path<String>,
+    ClientIp(client): ClientIp,
     ws: WebSocketUpgrade,
     State(runtime): State<Runtime>,
 ) -> Response<Body> {
-    ws.on_upgrade(move |socket| async move {
+    let limits = runtime.socket_limits().clone();
+    limits.upgrade(client, ws, move |socket| async move {
         if let Err(e) = handle_websocket(socket, path, runtime).await {
             tracing::error!(?e, "error handling websocket");
         }
@@ -199,11 +968,50 @@ async fn handle_websocket(socket: WebSocket, path: String, runtime: Runtime) ->
 #[derive(Debug, Clone)]
 pub struct LuaResponse {
     res: LuaTable,
+    /// the request's range and conditional headers, used for file and blob bodies
+    conditions: HeaderMap,
+}
+
+impl LuaResponse {
+    fn new(res: LuaTable, request: &Request<Body>) -> Self {
+        let conditions = [RANGE, IF_RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE]
+            .into_iter()
+            .filter_map(|name| {
+                let value = request.headers().get(&name)?.clone();
+                Some((name, value))
+            })
+            .collect();
+        Self { res, conditions }
+    }
+
+    fn range(
+        &self,
+        status: StatusCode,
+        size: usize,
+        modified: Option<SystemTime>,
+    ) -> RangeResponse {
+        if status != StatusCode::OK {
+            return RangeResponse::Full;
+        }
+        // If-Range: only send part of the body if it has not changed. file etags are weak,
+        // and a weak etag can't be used for this, so only a last-modified date is compared.
+        if let Some(if_range) = self.conditions.get(IF_RANGE) {
+            let since = if_range
+                .to_str()
+                .ok()
+                .and_then(|since| httpdate::parse_http_date(since).ok());
+            if since.is_none() || since != modified {
+                return RangeResponse::Full;
+            }
+        }
+        RangeResponse::new(self.conditions.get(RANGE), size)
+    }
 }
 
 impl IntoResponse for LuaResponse {
     fn into_response(self) -> Response<Body> {
         let status = self.res.get::<u16>("status").unwrap_or(200);
+        let mut status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         let mut headers = self
             .res
             .get::<LuaAnyUserData>("headers")
@@ -223,23 +1031,67 @@ impl IntoResponse for LuaResponse {
                 headers.append("set-cookie", value);
             }
         }
-        self.res
-            .get::<LuaString>("body")
-            .map(|body| Bytes::from(body.as_bytes().to_vec()))
-            .map(|body| {
-                let mut response: Response<Body> = Response::new(body.into());
-                *response.headers_mut() = headers;
-                *response.status_mut() =
-                    StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = match self.res.get::<LuaValue>("body") {
+            Ok(LuaValue::UserData(body)) if body.is::<BlobReader>() => {
+                body.take::<BlobReader>().map(|blob| {
+                    let size = blob.size();
+                    let range =
+                        self.range(status, size, None)
+                            .apply(&mut status, &mut headers, size);
+                    blob.into_body(range)
+                })
+            }
+            Ok(LuaValue::UserData(body)) if body.is::<FileBody>() => {
+                body.take::<FileBody>().map(|file| {
+                    if status == StatusCode::OK && file.not_modified(&self.conditions) {
+                        status = StatusCode::NOT_MODIFIED;
+                        return Body::empty();
+                    }
+                    let size = file.size();
+                    let range = self.range(status, size, file.modified()).apply(
+                        &mut status,
+                        &mut headers,
+                        size,
+                    );
+                    file.into_body(range)
+                })
+            }
+            Ok(LuaValue::UserData(body)) if body.is::<SseBody>() => {
+                body.take::<SseBody>().map(SseBody::into_body)
+            }
+            Ok(LuaValue::UserData(body)) if body.is::<LuaBody>() => body
+                .borrow::<LuaBody>()
+                .map(|body| Body::from(body.bytes())),
+            Ok(LuaValue::String(body)) => Ok(Body::from(Bytes::from(body.as_bytes().to_vec()))),
+            Ok(body @ (LuaValue::Integer(_) | LuaValue::Number(_))) => {
+                body.to_string().map(Body::from)
+            }
+            Ok(body) => Err(LuaError::runtime(format!(
+                "response body must be a string, not {}",
+                body.type_name()
+            ))),
+            Err(err) => Err(err),
+        };
+        let trailers = self
+            .res
+            .get::<LuaAnyUserData>("trailers")
+            .and_then(|trailers| trailers.take::<LuaHeaders>())
+            .map(|trailers| trailers.into_inner())
+            .unwrap_or_default();
+        body.map(|body| {
+            let body = WithTrailers::wrap(body, trailers, &mut headers);
+            let mut response: Response<Body> = Response::new(body);
+            *response.headers_mut() = headers;
+            *response.status_mut() = status;
 
-                response
-            })
-            .unwrap_or_else(|err| {
-                tracing::error!(?err, "error creating response body");
-                Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::empty())
-                    .expect("could not create response")
-            })
+            response
+        })
+        .unwrap_or_else(|err| {
+            tracing::error!(?err, "error creating response body");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .expect("could not create response")
+        })
     }
 }
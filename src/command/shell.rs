@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use eyre::Result;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
@@ -24,10 +24,10 @@ impl Shell {
         self,
         tracker: &TaskTracker,
         token: &CancellationToken,
-        config: &Config,
+        config: &Arc<Config>,
         output: &Output,
     ) -> Result<()> {
-        let runtime = Runtime::new();
+        let runtime = Runtime::new(config.clone());
         runtime
             .start(tracker, token, &self.app, !self.no_reload)
             .await?;
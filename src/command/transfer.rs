@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use eyre::{eyre, Result};
+use tokio::io::AsyncReadExt;
+
+use crate::database::{global::GlobalTable, transfer::Format, Database};
+
+/// write a global table as json or csv
+#[derive(Debug, Parser)]
+pub struct Export {
+    #[clap(short, long, default_value = "app.lua")]
+    pub app: PathBuf,
+
+    /// the global table to export
+    #[clap(short, long)]
+    pub table: String,
+
+    /// defaults to the output file's extension, or json
+    #[clap(short, long)]
+    pub format: Option<Format>,
+
+    /// where to write the table, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+impl Export {
+    pub async fn run(self) -> Result<()> {
+        let format = self
+            .format
+            .or_else(|| self.output.as_deref().and_then(Format::from_path))
+            .unwrap_or(Format::Json);
+        let database = Database::open(self.app.with_extension("db"))?;
+        let table = GlobalTable::open(self.table, database);
+        match self.output {
+            Some(output) => {
+                let mut file = tokio::fs::File::create(output).await?;
+                table.export(format, &mut file).await?;
+            }
+            None => table.export(format, &mut tokio::io::stdout()).await?,
+        }
+        Ok(())
+    }
+}
+
+/// add rows from json or csv to a global table, replacing rows with the same key
+#[derive(Debug, Parser)]
+pub struct Import {
+    #[clap(short, long, default_value = "app.lua")]
+    pub app: PathBuf,
+
+    /// the global table to import into
+    #[clap(short, long)]
+    pub table: String,
+
+    /// defaults to the input file's extension
+    #[clap(short, long)]
+    pub format: Option<Format>,
+
+    /// the file to read, or - for stdin
+    pub input: PathBuf,
+}
+
+impl Import {
+    pub async fn run(self) -> Result<()> {
+        let format = self
+            .format
+            .or_else(|| Format::from_path(&self.input))
+            .ok_or_else(|| {
+                eyre!(
+                    "cannot tell the format of {}, use --format",
+                    self.input.display()
+                )
+            })?;
+        let text = if self.input.as_os_str() == "-" {
+            let mut text = String::new();
+            tokio::io::stdin().read_to_string(&mut text).await?;
+            text
+        } else {
+            tokio::fs::read_to_string(&self.input).await?
+        };
+        let database = Database::open(self.app.with_extension("db"))?;
+        let table = GlobalTable::open(self.table, database);
+        let count = table.import(&text, format).await?;
+        println!("imported {count} rows into {}", table.name);
+        Ok(())
+    }
+}
@@ -1,16 +1,95 @@
 #![allow(unused)]
 // this was initially copied from tokio-rusqlite and modified to fit the needs of this project
+//
+// every call runs on one thread with one connection. each handle's calls run in the order
+// they were made, so a handler always reads what it wrote, but the thread takes one call
+// from each handle with calls waiting in turn, so one handle with many calls can't hold
+// up the others. Database::for_request makes a handle of its own for each request.
+// a call that runs longer than the statement timeout is interrupted, so a query that
+// scans too much fails instead of stopping everything else.
+pub mod activitypub;
+pub mod batch;
+pub mod crdt;
 pub mod global;
+pub mod payments;
+pub mod stats;
+pub mod sync;
+pub mod transfer;
 
 use mlua::prelude::*;
-use std::{path::Path, thread};
+use parking_lot::Mutex;
+use rusqlite::functions::FunctionFlags;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 use tokio::sync::{
+    broadcast,
     mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender},
     oneshot::{self},
 };
 
+use crate::runtime::paginate::{from_sql_value, to_sql_value};
+
 const BUG_TEXT: &str = "bug in lilguy::database";
 
+/// how many table changes are kept for subscribers that fall behind
+const CHANGES_CAPACITY: usize = 1024;
+
+/// how many sqlite instructions run between checks of the statement timeout
+const PROGRESS_OPS: i32 = 10_000;
+
+/// the statement timeout when config.toml doesn't give one
+const DEFAULT_STATEMENT_TIMEOUT: f64 = 30.0;
+
+/// the slow call threshold when config.toml doesn't give one
+const DEFAULT_SLOW_CALL: f64 = 0.1;
+
+/// the [database] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    /// seconds a database call may run before it is interrupted, 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statement_timeout: Option<f64>,
+
+    /// seconds a database call may take before it is logged as slow, 0 to log none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_call: Option<f64>,
+
+    /// log the sql of each slow statement, which costs a little for every statement
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub log_sql: bool,
+}
+
+impl DatabaseConfig {
+    pub fn statement_timeout(&self) -> eyre::Result<Option<Duration>> {
+        let timeout = self.statement_timeout.unwrap_or(DEFAULT_STATEMENT_TIMEOUT);
+        config_seconds("statement_timeout", timeout)
+    }
+
+    pub fn slow_call(&self) -> eyre::Result<Option<Duration>> {
+        let slow = self.slow_call.unwrap_or(DEFAULT_SLOW_CALL);
+        config_seconds("slow_call", slow)
+    }
+}
+
+/// seconds from the [database] section, where 0 or less turns the setting off
+fn config_seconds(name: &str, seconds: f64) -> eyre::Result<Option<Duration>> {
+    if seconds <= 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| {
+        eyre::eyre!("database.{name} in the config must be a number of seconds, not {seconds}")
+    })
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// The connection to the SQLite has been closed and cannot be queried any more.
@@ -38,14 +117,24 @@ pub type Result<T> = std::result::Result<T, Error>;
 type CallFn = Box<dyn FnOnce(&mut rusqlite::Connection) + Send + 'static>;
 
 enum Message {
-    Execute(CallFn),
-    Close(oneshot::Sender<std::result::Result<(), rusqlite::Error>>),
+    Execute(u64, CallFn),
+    Close(CloseSender),
 }
 
+/// the handle calls are queued under, handles from Database::for_request get their own
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
 /// A handle to call functions in background thread.
 #[derive(Debug, Clone)]
 pub struct Database {
     sender: UnboundedSender<Message>,
+    changes: broadcast::Sender<String>,
+    handle: u64,
+
+    /// how long each call may run, shared with the database thread
+    timeout: Arc<Mutex<Option<Duration>>>,
+
+    stats: Arc<stats::Stats>,
 }
 
 impl Database {
@@ -77,6 +166,36 @@ impl Database {
         })
     }
 
+    /// Interrupt calls that run longer than timeout, on every handle to this connection.
+    /// There is no limit until this is called.
+    pub fn set_statement_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.lock() = timeout;
+    }
+
+    /// Log calls slower than slow, and with log_sql the statements in them.
+    pub async fn set_slow_call(&self, slow: Option<Duration>, log_sql: bool) -> Result<()> {
+        self.stats.set_slow(slow);
+        self.call(move |conn| {
+            stats::log_sql(conn, slow.filter(|_| log_sql));
+            Ok(())
+        })
+        .await
+    }
+
+    /// How many calls are waiting, how long they waited and how long they ran.
+    pub fn stats(&self) -> &stats::Stats {
+        &self.stats
+    }
+
+    /// A handle to the same connection whose calls are queued separately, taking turns
+    /// with other handles.
+    pub fn for_request(&self) -> Self {
+        Self {
+            handle: NEXT_HANDLE.fetch_add(1, Ordering::Relaxed),
+            ..self.clone()
+        }
+    }
+
     /// Call a function in background thread and get the result
     /// asynchronously.
     ///
@@ -88,35 +207,52 @@ impl Database {
         F: FnOnce(&mut rusqlite::Connection) -> Result<R> + 'static + Send,
         R: Send + 'static,
     {
-        let (sender, receiver) = oneshot::channel::<Result<R>>();
-
-        self.sender
-            .send(Message::Execute(Box::new(move |conn| {
-                let value = function(conn);
-                let _ = sender.send(value);
-            })))
-            .map_err(|_| Error::ConnectionClosed)?;
-
+        let receiver = self.send(function)?;
         receiver.await.map_err(|_| Error::ConnectionClosed)?
     }
 
     pub fn blocking_call<F, R>(&self, function: F) -> Result<R>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        let receiver = self.send(function)?;
+        receiver
+            .blocking_recv()
+            .map_err(|_| Error::ConnectionClosed)?
+    }
+
+    /// queue function, timing how long it waits and runs
+    fn send<F, R>(&self, function: F) -> Result<oneshot::Receiver<Result<R>>>
     where
         F: FnOnce(&mut rusqlite::Connection) -> Result<R> + 'static + Send,
         R: Send + 'static,
     {
         let (sender, receiver) = oneshot::channel::<Result<R>>();
+        let stats = self.stats.clone();
+        let queued = stats.queued();
 
         self.sender
-            .send(Message::Execute(Box::new(move |conn| {
-                let value = function(conn);
-                let _ = sender.send(value);
-            })))
-            .map_err(|_| Error::ConnectionClosed)?;
+            .send(Message::Execute(
+                self.handle,
+                Box::new(move |conn| {
+                    let started = stats.started(queued);
+                    let value = function(conn);
+                    stats.finished(queued, started);
+                    let _ = sender.send(value);
+                }),
+            ))
+            .map_err(|_| {
+                self.stats.started(queued);
+                Error::ConnectionClosed
+            })?;
+
+        Ok(receiver)
+    }
 
-        receiver
-            .blocking_recv()
-            .map_err(|_| Error::ConnectionClosed)?
+    /// The names of tables as rows in them are inserted, updated or deleted, once per row.
+    pub fn changes(&self) -> broadcast::Receiver<String> {
+        self.changes.subscribe()
     }
 
     /// Close the database connection.
@@ -132,7 +268,7 @@ impl Database {
     ///
     /// Will return `Err` if the tokio-rusqlitederlying SQLite close call fails.
     pub async fn close(self) -> Result<()> {
-        let (sender, receiver) = oneshot::channel::<std::result::Result<(), rusqlite::Error>>();
+        let (sender, receiver) = oneshot::channel();
 
         if let Err(SendError(_)) = self.sender.send(Message::Close(sender)) {
             // If the channel is closed on the other side, it means the connection closed successfully
@@ -153,9 +289,19 @@ impl Database {
 impl From<rusqlite::Connection> for Database {
     fn from(conn: rusqlite::Connection) -> Self {
         let (sender, receiver) = unbounded_channel::<Message>();
-        thread::spawn(move || event_loop(conn, receiver));
-
-        Self { sender }
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        let timeout = Arc::new(Mutex::new(None));
+        watch(&conn, changes.clone());
+        let limit = timeout.clone();
+        thread::spawn(move || event_loop(conn, receiver, limit));
+
+        Self {
+            sender,
+            changes,
+            handle: 0,
+            timeout,
+            stats: Arc::default(),
+        }
     }
 }
 
@@ -165,7 +311,11 @@ where
 {
     let (sender, receiver) = unbounded_channel::<Message>();
     let (result_sender, result_receiver) = oneshot::channel();
+    let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+    let timeout = Arc::new(Mutex::new(None));
 
+    let watcher = changes.clone();
+    let limit = timeout.clone();
     thread::spawn(move || {
         let conn = match open() {
             Ok(c) => c,
@@ -178,45 +328,266 @@ where
         if let Err(_e) = result_sender.send(Ok(())) {
             return;
         }
+        watch(&conn, watcher);
 
-        event_loop(conn, receiver);
+        event_loop(conn, receiver, limit);
     });
 
     result_receiver
         .blocking_recv()
         .expect(BUG_TEXT)
-        .map(|_| Database { sender })
-}
-
-fn event_loop(mut conn: rusqlite::Connection, mut receiver: UnboundedReceiver<Message>) {
-    while let Some(message) = receiver.blocking_recv() {
-        match message {
-            Message::Execute(f) => f(&mut conn),
-            Message::Close(s) => {
-                let result = conn.close();
-
-                match result {
-                    Ok(v) => {
-                        s.send(Ok(v)).expect(BUG_TEXT);
-                        break;
-                    }
-                    Err((c, e)) => {
-                        conn = c;
-                        s.send(Err(e)).expect(BUG_TEXT);
-                    }
+        .map(|_| Database {
+            sender,
+            changes,
+            handle: 0,
+            timeout,
+            stats: Arc::default(),
+        })
+}
+
+/// send the name of each table that changes to changes
+fn watch(conn: &rusqlite::Connection, changes: broadcast::Sender<String>) {
+    conn.update_hook(Some(move |_, _: &str, table: &str, _| {
+        // without subscribers there is no one to tell
+        let _ = changes.send(table.to_string());
+    }));
+}
+
+/// the calls waiting for each handle, and the order handles take their turns in
+#[derive(Default)]
+struct Queues {
+    calls: HashMap<u64, VecDeque<CallFn>>,
+    turns: VecDeque<u64>,
+}
+
+impl Queues {
+    fn push(&mut self, handle: u64, call: CallFn) {
+        let calls = self.calls.entry(handle).or_default();
+        if calls.is_empty() {
+            self.turns.push_back(handle);
+        }
+        calls.push_back(call);
+    }
+
+    /// the next handle's oldest call
+    fn pop(&mut self) -> Option<CallFn> {
+        let handle = self.turns.pop_front()?;
+        let calls = self.calls.get_mut(&handle)?;
+        let call = calls.pop_front();
+        if calls.is_empty() {
+            self.calls.remove(&handle);
+        } else {
+            self.turns.push_back(handle);
+        }
+        call
+    }
+
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+fn event_loop(
+    mut conn: rusqlite::Connection,
+    mut receiver: UnboundedReceiver<Message>,
+    timeout: Arc<Mutex<Option<Duration>>>,
+) {
+    // sqlite checks in every PROGRESS_OPS instructions, and stops when this says to
+    let deadline: Arc<Mutex<Option<Instant>>> = Arc::default();
+    let interrupt = deadline.clone();
+    conn.progress_handler(
+        PROGRESS_OPS,
+        Some(move || {
+            interrupt
+                .lock()
+                .is_some_and(|deadline| Instant::now() > deadline)
+        }),
+    );
+
+    let mut queues = Queues::default();
+    let mut closing = None;
+    loop {
+        // wait when there is nothing to do, then take everything that has arrived
+        if queues.is_empty() && closing.is_none() {
+            match receiver.blocking_recv() {
+                Some(message) => queue(&mut queues, &mut closing, message),
+                None => break,
+            }
+        }
+        while let Ok(message) = receiver.try_recv() {
+            queue(&mut queues, &mut closing, message);
+        }
+
+        if let Some(call) = queues.pop() {
+            *deadline.lock() = timeout.lock().map(|timeout| Instant::now() + timeout);
+            call(&mut conn);
+            *deadline.lock() = None;
+            continue;
+        }
+
+        // close once the calls made before it have run
+        if let Some(s) = closing.take() {
+            match conn.close() {
+                Ok(v) => {
+                    s.send(Ok(v)).expect(BUG_TEXT);
+                    break;
+                }
+                Err((c, e)) => {
+                    conn = c;
+                    s.send(Err(e)).expect(BUG_TEXT);
                 }
             }
         }
     }
 }
 
+type CloseSender = oneshot::Sender<std::result::Result<(), rusqlite::Error>>;
+
+fn queue(queues: &mut Queues, closing: &mut Option<CloseSender>, message: Message) {
+    match message {
+        Message::Execute(handle, call) => queues.push(handle, call),
+        Message::Close(s) => *closing = Some(s),
+    }
+}
+
+fn user_error(err: LuaError) -> rusqlite::Error {
+    rusqlite::Error::UserFunctionError(Box::new(err))
+}
+
 impl LuaUserData for Database {
-    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {}
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        // database.stats.queue_depth and the rest, see database/stats.rs
+        fields.add_field_method_get("stats", |lua, this| this.stats.snapshot(lua));
+
+        // database.schema = [[ CREATE TABLE IF NOT EXISTS ... ]] runs the sql before any
+        // later call from this handle, like schema.sql does at startup, so it should be
+        // safe to run more than once. it doesn't wait for the sql to run, see
+        // create_function, so an error is logged instead of raised.
+        fields.add_field_method_set("schema", |_, this, sql: String| {
+            this.send(move |conn| {
+                if let Err(err) = conn.execute_batch(&sql) {
+                    tracing::error!("database.schema: {err}");
+                }
+                Ok(())
+            })
+            .into_lua_err()?;
+            Ok(())
+        });
+    }
 
-    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {}
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // database:create_function("slugify", 1, function(text) ... end) makes slugify(text)
+        // usable in sql. it is called on the database thread, so it must not yield, and
+        // must not use the database itself. -1 for n_arg takes any number of arguments.
+        // calling it locks lua, so nothing that runs with lua locked may block waiting on
+        // the database, or a query using the function and that code wait on each other.
+        methods.add_async_method(
+            "create_function",
+            |lua, this, (name, n_arg, function): (String, i32, LuaFunction)| async move {
+                this.call(move |conn| {
+                    conn.create_scalar_function(
+                        name.as_str(),
+                        n_arg,
+                        FunctionFlags::SQLITE_UTF8,
+                        move |ctx| {
+                            let args = (0..ctx.len())
+                                .map(|i| from_sql_value(&lua, ctx.get(i)?).map_err(user_error))
+                                .collect::<rusqlite::Result<LuaMultiValue>>()?;
+                            function
+                                .call::<LuaValue>(args)
+                                .and_then(to_sql_value)
+                                .map_err(user_error)
+                        },
+                    )?;
+                    Ok(())
+                })
+                .await
+                .into_lua_err()
+            },
+        );
+
+        // database:batch(function(b) b:execute(sql, ...) end), see database/batch.rs
+        methods.add_async_method("batch", |lua, this, function: LuaFunction| async move {
+            let batch = lua.create_userdata(batch::Batch::default())?;
+            function.call_async::<()>(batch.clone()).await?;
+            let batch = batch.take::<batch::Batch>()?;
+            this.call(move |conn| batch.run(conn)).await.into_lua_err()
+        });
+    }
 
     fn register(registry: &mut LuaUserDataRegistry<Self>) {
         Self::add_fields(registry);
         Self::add_methods(registry);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions_and_lua_dont_wait_on_each_other() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        let finished = runtime.block_on(async {
+            let database = Database::open_in_memory().expect("database");
+            database
+                .call(|conn| Ok(conn.execute_batch(include_str!("schema.sql"))?))
+                .await
+                .expect("schema");
+            let lua = Lua::new();
+            lua.globals()
+                .set("database", database.clone())
+                .expect("database");
+            lua.globals()
+                .set("global", global::Global::new(&database))
+                .expect("global");
+            lua.load(r#"database:create_function("twice", 1, function(n) return n * 2 end)"#)
+                .exec_async()
+                .await
+                .expect("create_function");
+
+            // the query calls into lua for every row while the script uses the database
+            let query = tokio::spawn(async move {
+                database
+                    .call(|conn| {
+                        Ok(conn.query_row(
+                            "WITH RECURSIVE n(x) AS (
+                                 SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 100000
+                             )
+                             SELECT sum(twice(x)) FROM n",
+                            [],
+                            |row| row.get::<_, i64>(0),
+                        )?)
+                    })
+                    .await
+            });
+            let script = tokio::spawn(async move {
+                lua.load(
+                    r#"
+                        for i = 1, 100 do
+                            database.schema = "CREATE TABLE IF NOT EXISTS notes (body TEXT)"
+                            global["table" .. i][i] = i
+                        end
+                    "#,
+                )
+                .exec_async()
+                .await
+            });
+            tokio::time::timeout(Duration::from_secs(30), async {
+                script.await.expect("script").expect("script");
+                query.await.expect("query").expect("query")
+            })
+            .await
+        });
+        // a stuck database thread would keep the runtime from shutting down
+        runtime.shutdown_background();
+        assert_eq!(
+            finished.expect("the query and the script waited on each other"),
+            100000 * 100001
+        );
+    }
+}
@@ -0,0 +1,110 @@
+// storage for activitypub; the lua side and the server are in runtime/activitypub.rs.
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+
+use super::{Error, Result};
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::Other(Box::new(err))
+}
+
+/// the pkcs8 pem of the key actor signs with
+pub fn private_key(conn: &Connection, actor: &str) -> Result<Option<String>> {
+    Ok(conn
+        .prepare_cached("SELECT private_key FROM lg_activitypub_keys WHERE actor = ?")?
+        .query_row([actor], |row| row.get(0))
+        .optional()?)
+}
+
+/// save a new key for actor, unless another request saved one first
+pub fn save_private_key(conn: &Connection, actor: &str, pem: &str) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR IGNORE INTO lg_activitypub_keys (actor, private_key) VALUES (?, ?)",
+    )?
+    .execute([actor, pem])?;
+    Ok(())
+}
+
+pub fn add_follower(conn: &Connection, actor: &str, follower: &str, inbox: &str) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_activitypub_followers (actor, follower, inbox) VALUES (?, ?, ?)",
+    )?
+    .execute([actor, follower, inbox])?;
+    Ok(())
+}
+
+pub fn remove_follower(conn: &Connection, actor: &str, follower: &str) -> Result<()> {
+    conn.prepare_cached("DELETE FROM lg_activitypub_followers WHERE actor = ? AND follower = ?")?
+        .execute([actor, follower])?;
+    Ok(())
+}
+
+/// actor's followers and their inboxes, oldest first
+pub fn followers(conn: &Connection, actor: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT follower, inbox FROM lg_activitypub_followers WHERE actor = ? ORDER BY created",
+    )?;
+    let followers = stmt
+        .query_map([actor], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(followers)
+}
+
+pub fn follower_count(conn: &Connection, actor: &str) -> Result<i64> {
+    Ok(conn
+        .prepare_cached("SELECT count(*) FROM lg_activitypub_followers WHERE actor = ?")?
+        .query_row([actor], |row| row.get(0))?)
+}
+
+pub fn add_to_outbox(conn: &Connection, actor: &str, activity: &Value) -> Result<()> {
+    let id = activity["id"].as_str().unwrap_or_default();
+    let activity = serde_json::to_string(activity).map_err(json_error)?;
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_activitypub_outbox (id, actor, activity) VALUES (?, ?, jsonb(?))",
+    )?
+    .execute([id, actor, &activity])?;
+    Ok(())
+}
+
+/// how many activities actor has published, and the latest of them
+pub fn outbox(conn: &Connection, actor: &str, limit: usize) -> Result<(i64, Vec<Value>)> {
+    let total = conn
+        .prepare_cached("SELECT count(*) FROM lg_activitypub_outbox WHERE actor = ?")?
+        .query_row([actor], |row| row.get(0))?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT json(activity) FROM lg_activitypub_outbox WHERE actor = ?
+         ORDER BY created DESC, rowid DESC LIMIT ?",
+    )?;
+    let rows = stmt
+        .query_map(params![actor, limit], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let activities = rows
+        .iter()
+        .map(|row| serde_json::from_str(row).map_err(json_error))
+        .collect::<Result<_>>()?;
+    Ok((total, activities))
+}
+
+/// a remote actor's document, if it was fetched less than max_age seconds ago
+pub fn remote_actor(conn: &Connection, id: &str, max_age: i64) -> Result<Option<Value>> {
+    let document: Option<String> = conn
+        .prepare_cached(
+            "SELECT json(document) FROM lg_activitypub_remote
+             WHERE id = ? AND fetched > unixepoch() - ?",
+        )?
+        .query_row(params![id, max_age], |row| row.get(0))
+        .optional()?;
+    document
+        .map(|document| serde_json::from_str(&document).map_err(json_error))
+        .transpose()
+}
+
+pub fn save_remote_actor(conn: &Connection, id: &str, document: &Value) -> Result<()> {
+    let document = serde_json::to_string(document).map_err(json_error)?;
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_activitypub_remote (id, document, fetched)
+         VALUES (?, jsonb(?), unixepoch())",
+    )?
+    .execute([id, &document])?;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+// statements collected in lua and run together, in one transaction, with one trip to the
+// database thread.
+//
+//   database:batch(function(b)
+//     for _, row in ipairs(rows) do
+//       b:execute("INSERT INTO items (name, price) VALUES (?, ?)", row.name, row.price)
+//     end
+//   end)
+//
+// nothing runs until the function returns, so results of one statement can't be read by
+// the next from lua. if the function errors, nothing runs; if a statement fails, none of
+// them are kept.
+use mlua::prelude::*;
+use rusqlite::{params_from_iter, types::Value, Connection};
+
+use super::Result;
+use crate::runtime::paginate::to_sql_value;
+
+#[derive(Debug, Default)]
+pub struct Batch {
+    statements: Vec<(String, Vec<Value>)>,
+}
+
+impl Batch {
+    /// run the statements in a transaction, returning how many rows they changed
+    pub fn run(self, conn: &mut Connection) -> Result<usize> {
+        let tx = conn.transaction()?;
+        let mut changed = 0;
+        for (sql, params) in self.statements {
+            changed += tx.prepare_cached(&sql)?.execute(params_from_iter(params))?;
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+}
+
+impl LuaUserData for Batch {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // b:execute(sql, ...)
+        methods.add_method_mut(
+            "execute",
+            |_, this, (sql, params): (String, LuaMultiValue)| {
+                let params = params
+                    .into_iter()
+                    .map(to_sql_value)
+                    .collect::<LuaResult<_>>()?;
+                this.statements.push((sql, params));
+                Ok(())
+            },
+        );
+    }
+}
@@ -0,0 +1,121 @@
+// yrs documents stored in lg_crdt; the lua side is runtime/crdt.rs.
+//
+// every operation loads the document, changes it and saves it again in one call on the
+// database thread, so operations from different requests never see a stale copy.
+use rusqlite::{params, Connection, OptionalExtension};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    Doc, ReadTxn, StateVector, Transact, Update,
+};
+
+use super::{sync::node_id, Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    LwwMap,
+    Text,
+    /// a document whose shape is up to its clients, like a yjs editor's
+    Doc,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::LwwMap => "lww_map",
+            Kind::Text => "text",
+            Kind::Doc => "doc",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CrdtError {
+    #[error("{name} is a {kind}, not a {expected}")]
+    Kind {
+        name: String,
+        kind: String,
+        expected: &'static str,
+    },
+
+    #[error("invalid crdt encoding: {0}")]
+    Decode(#[from] yrs::encoding::read::Error),
+
+    #[error("could not apply crdt update: {0}")]
+    Update(#[from] yrs::error::UpdateError),
+}
+
+impl From<CrdtError> for Error {
+    fn from(err: CrdtError) -> Self {
+        Error::Other(Box::new(err))
+    }
+}
+
+/// yrs client ids are 32 bits in yjs; using one derived from the node id keeps the
+/// document's state vector from growing with every operation
+fn client_id(conn: &Connection) -> Result<u64> {
+    let node = node_id(conn)?;
+    Ok(u64::from_str_radix(&node, 16).unwrap_or_default() & 0xffff_ffff)
+}
+
+/// load the named document, run f on it and save it if it changed
+pub fn with_doc<R>(
+    conn: &Connection,
+    name: &str,
+    kind: Kind,
+    f: impl FnOnce(&Doc) -> std::result::Result<R, CrdtError>,
+) -> Result<R> {
+    let saved: Option<(String, Vec<u8>)> = conn
+        .prepare_cached("SELECT kind, state FROM lg_crdt WHERE name = ?")?
+        .query_row([name], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    let doc = Doc::with_client_id(client_id(conn)?);
+    if let Some((saved_kind, state)) = saved {
+        if saved_kind != kind.as_str() {
+            return Err(CrdtError::Kind {
+                name: name.to_string(),
+                kind: saved_kind,
+                expected: kind.as_str(),
+            }
+            .into());
+        }
+        let update = Update::decode_v1(&state).map_err(CrdtError::from)?;
+        doc.transact_mut()
+            .apply_update(update)
+            .map_err(CrdtError::from)?;
+    }
+
+    let before = doc.transact().state_vector();
+    let result = f(&doc)?;
+    let txn = doc.transact();
+    if txn.state_vector() != before {
+        let state = txn.encode_state_as_update_v1(&StateVector::default());
+        conn.prepare_cached(
+            "INSERT OR REPLACE INTO lg_crdt (name, kind, state, updated)
+             VALUES (?, ?, ?, unixepoch())",
+        )?
+        .execute(params![name, kind.as_str(), state])?;
+    }
+    Ok(result)
+}
+
+/// the changes since a state vector, or the whole document without one
+pub fn encode_update(doc: &Doc, since: Option<&[u8]>) -> std::result::Result<Vec<u8>, CrdtError> {
+    let since = match since {
+        Some(since) => StateVector::decode_v1(since)?,
+        None => StateVector::default(),
+    };
+    Ok(doc.transact().encode_state_as_update_v1(&since))
+}
+
+pub fn apply_update(doc: &Doc, update: &[u8]) -> std::result::Result<(), CrdtError> {
+    let update = Update::decode_v1(update)?;
+    doc.transact_mut().apply_update(update)?;
+    Ok(())
+}
+
+pub fn state_vector(doc: &Doc) -> Vec<u8> {
+    doc.transact().state_vector().encode_v1()
+}
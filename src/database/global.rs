@@ -1,11 +1,27 @@
-use super::Database;
+use super::{
+    sync,
+    transfer::{self, Columns, Encoder, Format},
+    Database,
+};
 use mlua::prelude::*;
-use rusqlite::{params, OptionalExtension, Row, ToSql};
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::{
-    sync::mpsc::{self, Receiver},
-    task::block_in_place,
+use parking_lot::Mutex;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, Value, ValueRef},
+    Connection, OptionalExtension, Row, ToSql,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::runtime::file::resolve;
 
 #[derive(Debug, thiserror::Error)]
 pub enum GlobalTableError {
@@ -20,19 +36,44 @@ pub enum GlobalTableError {
 
     #[error("invalid key")]
     InvalidKey,
+
+    #[error("{0}")]
+    Transfer(#[from] transfer::Error),
+
+    #[error("{0}")]
+    Pairs(#[from] GlobalTablePairsError),
+
+    #[error("global table {0} is not versioned")]
+    NotVersioned(String),
+
+    #[error("no version {0} for that key")]
+    NoSuchVersion(i64),
+
+    /// an error from one of the other kinds, with the table and what was being done to it
+    #[error("global.{table} {operation}: {source}")]
+    Table {
+        table: String,
+        operation: &'static str,
+        source: Box<GlobalTableError>,
+    },
 }
 
 /// Handle to reference a global table in the database.
 /// This is table in the lua sense.
 /// Each one maps to a sqlite table, but the schema is always the same.
 /// The contents are (id, optional key, value).
-#[derive(Debug)]
+/// The sqlite table is made by the first write, so reading a table that was never
+/// written to finds nothing instead of creating it.
+#[derive(Debug, Clone)]
 pub struct GlobalTable {
     pub name: String,
     pub database: Database,
+
+    /// true once the sqlite table is known to exist, shared by handles to the same table
+    created: Arc<AtomicBool>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GlobalTableKey {
     Int(i64),
     Str(String),
@@ -59,6 +100,25 @@ impl Serialize for GlobalTableKey {
     }
 }
 
+impl<'de> Deserialize<'de> for GlobalTableKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Key {
+            Int(i64),
+            Str(String),
+        }
+
+        Ok(match Key::deserialize(deserializer)? {
+            Key::Int(key) => Self::Int(key),
+            Key::Str(key) => Self::Str(key),
+        })
+    }
+}
+
 impl From<i64> for GlobalTableKey {
     fn from(key: i64) -> Self {
         Self::Int(key)
@@ -102,41 +162,239 @@ impl ToSql for GlobalTableKey {
     }
 }
 
-pub struct GlobalTablePairs<V: DeserializeOwned>(
-    pub Receiver<Result<(GlobalTableKey, V), GlobalTablePairsError>>,
-);
+impl FromSql for GlobalTableKey {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Integer(key) => Ok(Self::Int(key)),
+            ValueRef::Text(key) => Ok(Self::Str(
+                std::str::from_utf8(key)
+                    .map_err(|err| FromSqlError::Other(Box::new(err)))?
+                    .to_string(),
+            )),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// the quoted sqlite name of the global table called name
+pub(super) fn sql_name(name: &str) -> String {
+    format!("\"lg_global_{}\"", name.replace("\"", "\"\""))
+}
+
+/// the name of the global table stored in the sqlite table called table, as given to
+/// Database::changes
+pub fn global_name(table: &str) -> Option<&str> {
+    table.strip_prefix("lg_global_")
+}
+
+/// create the sqlite table for a global table, if it doesn't exist
+pub(super) fn create_table(conn: &Connection, sql_name: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            r"
+                CREATE TABLE IF NOT EXISTS {sql_name} (
+                    key_int INTEGER UNIQUE,
+                    key_str TEXT UNIQUE,
+                    value JSONB NOT NULL,
+                    PRIMARY KEY (key_int, key_str),
+                    CHECK ((key_int IS NULL) != (key_str IS NULL))
+                )
+            "
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+/// sql that sets a key's value. an existing row is updated in place, so the key keeps
+/// its rowid and its place in insertion order.
+pub(super) fn upsert(sql_name: &str, column: &str) -> String {
+    format!(
+        "INSERT INTO {sql_name} ({column}, value) VALUES (?, jsonb(?))
+         ON CONFLICT ({column}) DO UPDATE SET value = excluded.value"
+    )
+}
+
+/// a sqlite json path for a dotted path, "profile.name" is $."profile"."name" and
+/// "tags.0" is $."tags"[0]
+fn json_path(path: &str) -> String {
+    let mut json_path = String::from("$");
+    for part in path.split('.') {
+        if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) {
+            json_path.push_str(&format!("[{part}]"));
+        } else {
+            json_path.push_str(&format!(".\"{}\"", part.replace('"', "\\\"")));
+        }
+    }
+    json_path
+}
+
+/// true when err is from a global table whose sqlite table hasn't been made yet
+fn is_missing_table(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(_, Some(message)) if message.starts_with("no such table")
+    )
+}
+
+/// reading a table that was never written to finds nothing
+fn or_empty<T: Default>(result: rusqlite::Result<T>) -> rusqlite::Result<T> {
+    match result {
+        Err(err) if is_missing_table(&err) => Ok(T::default()),
+        result => result,
+    }
+}
+
+/// one entry in the history of a key in a versioned table.
+/// value is None when the key was deleted.
+#[derive(Debug)]
+pub struct GlobalTableVersion {
+    pub version: i64,
+    pub created: i64,
+    pub value: Option<serde_json::Value>,
+}
+
+/// how many rows pairs() reads from the database at a time
+const PAIRS_PAGE: usize = 256;
+
+/// how much of an export is held before it is written out
+const EXPORT_BUFFER: usize = 64 * 1024;
+
+/// the order pairs() visits keys in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairsOrder {
+    /// the order keys were first set in. setting a key again keeps its place, deleting
+    /// it and setting it again moves it to the end.
+    #[default]
+    Insertion,
+
+    /// integer keys in order, then string keys in order
+    Key,
+}
+
+impl PairsOrder {
+    /// the column rows are sorted by, which is also where each page starts from
+    fn column(self) -> &'static str {
+        match self {
+            PairsOrder::Insertion => "rowid",
+            PairsOrder::Key => "coalesce(key_int, key_str)",
+        }
+    }
+}
+
+/// a cursor over the rows of a table. rows are read a page at a time as they are used,
+/// so stopping early leaves nothing running, and rows changed while it is used are seen
+/// or not depending on which page they are in.
+pub struct GlobalTablePairs<V: DeserializeOwned> {
+    table: GlobalTable,
+    order: PairsOrder,
+    rows: VecDeque<(GlobalTableKey, V)>,
+
+    /// the sort column of the last row read
+    after: Value,
+    done: bool,
+}
+
+impl<V> GlobalTablePairs<V>
+where
+    V: DeserializeOwned + Send + 'static,
+{
+    pub async fn next(&mut self) -> Result<Option<(GlobalTableKey, V)>, GlobalTablePairsError> {
+        if self.rows.is_empty() && !self.done {
+            self.read_page().await?;
+        }
+        Ok(self.rows.pop_front())
+    }
+
+    async fn read_page(&mut self) -> Result<(), GlobalTablePairsError> {
+        let sql_name = self.table.sql_name();
+        let column = self.order.column();
+        let after = self.after.clone();
+        let rows = self
+            .table
+            .database
+            .call(move |conn| {
+                let sql = format!(
+                    "SELECT key_int, key_str, jsonb(value), {column} FROM {sql_name}
+                     WHERE ?1 IS NULL OR {column} > ?1 ORDER BY {column} LIMIT ?2"
+                );
+                let mut rows = vec![];
+                let mut stmt = match conn.prepare_cached(&sql) {
+                    Err(err) if is_missing_table(&err) => return Ok(rows),
+                    stmt => stmt?,
+                };
+                let mut query = stmt.query(params![after, PAIRS_PAGE])?;
+                while let Some(row) = query.next()? {
+                    rows.push((do_pairs(row), row.get::<_, Value>(3)?));
+                }
+
+                Ok(rows)
+            })
+            .await?;
+
+        self.done = rows.len() < PAIRS_PAGE;
+        for (pair, after) in rows {
+            self.rows.push_back(pair?);
+            self.after = after;
+        }
+        Ok(())
+    }
+}
 
 impl GlobalTable {
     fn new(name: String, database: Database) -> Self {
-        Self { name, database }
+        Self {
+            name,
+            database,
+            created: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// a handle to the named table. the sqlite table is made by the first write, so this
+    /// never waits on the database.
+    pub fn open(name: String, database: Database) -> Self {
+        Self::new(name, database)
     }
 
     fn sql_name(&self) -> String {
-        format!("\"lg_global_{}\"", self.name.replace("\"", "\"\""))
+        sql_name(&self.name)
     }
 
-    pub fn create(&self) -> Result<(), super::Error> {
-        let sql_name = self.sql_name();
-        self.database.blocking_call(move |conn| {
-            conn.execute(
-                &format!(
-                    r"
-                            CREATE TABLE IF NOT EXISTS {sql_name} (
-                                key_int INTEGER UNIQUE,
-                                key_str TEXT UNIQUE,
-                                value JSONB NOT NULL,
-                                PRIMARY KEY (key_int, key_str),
-                                CHECK ((key_int IS NULL) != (key_str IS NULL))
-                            )
-                        "
-                ),
-                [],
-            )?;
+    /// err as a lua error that says which table it came from and what was being done
+    fn lua_error(&self, operation: &'static str, err: impl Into<GlobalTableError>) -> LuaError {
+        LuaError::external(GlobalTableError::Table {
+            table: self.name.clone(),
+            operation,
+            source: Box::new(err.into()),
+        })
+    }
 
+    /// the table that keeps every version of a versioned table's values
+    fn history_table(&self) -> String {
+        format!("lg_history_{}", self.name)
+    }
+
+    /// a quoted name for the history table, or for one of its indexes and triggers
+    fn history_name(&self, suffix: &str) -> String {
+        format!("\"{}{suffix}\"", self.history_table().replace("\"", "\"\""))
+    }
+
+    /// create the sqlite table in a write, unless it is already known to exist.
+    /// call created() once the write commits.
+    fn create_for_write(&self) -> impl Fn(&Connection) -> rusqlite::Result<()> + Send {
+        let sql_name = self.sql_name();
+        let created = self.created.load(Ordering::Relaxed);
+        move |conn| {
+            if !created {
+                create_table(conn, &sql_name)?;
+            }
             Ok(())
-        })?;
+        }
+    }
 
-        Ok(())
+    fn created(&self) {
+        self.created.store(true, Ordering::Relaxed);
     }
 
     pub async fn get<K, V>(&self, key: K) -> Result<Option<V>, GlobalTableError>
@@ -154,7 +412,7 @@ impl GlobalTable {
                     key_column = key.column(),
                 );
                 let value: Option<Vec<u8>> =
-                    conn.query_row(&sql, [key], |row| row.get(0)).optional()?;
+                    or_empty(conn.query_row(&sql, [key], |row| row.get(0)).optional())?;
 
                 Ok(value)
             })
@@ -172,20 +430,24 @@ impl GlobalTable {
         K: TryInto<GlobalTableKey>,
         V: Serialize,
     {
+        let name = self.name.clone();
         let sql_name = self.sql_name();
         let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
         let column = key.column();
         let value = serde_sqlite_jsonb::to_vec(&value)?;
+        let create = self.create_for_write();
 
         self.database
             .call(move |conn| {
-                let sql = format!(
-                    "INSERT OR REPLACE INTO {sql_name} ({column}, value) VALUES (?, jsonb(?))",
-                );
-                conn.execute(&sql, params![key, value])?;
+                let tx = conn.transaction()?;
+                create(&tx)?;
+                tx.execute(&upsert(&sql_name, column), params![key, value])?;
+                sync::record(&tx, &name, &key, Some(&value))?;
+                tx.commit()?;
                 Ok(())
             })
             .await?;
+        self.created();
 
         Ok(())
     }
@@ -194,16 +456,22 @@ impl GlobalTable {
     where
         K: TryInto<GlobalTableKey>,
     {
+        let name = self.name.clone();
         let sql_name = self.sql_name();
         let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
         let column = key.column();
 
         self.database
             .call(move |conn| {
-                conn.execute(
+                let tx = conn.transaction()?;
+                let deleted = or_empty(tx.execute(
                     &format!("DELETE FROM {sql_name} WHERE {column} = ?",),
-                    [key],
-                )?;
+                    [&key],
+                ))?;
+                if deleted > 0 {
+                    sync::record(&tx, &name, &key, None)?;
+                }
+                tx.commit()?;
 
                 Ok(())
             })
@@ -212,7 +480,93 @@ impl GlobalTable {
         Ok(())
     }
 
-    // TODO: pairs, ipairs, get numeric keys, set numeric keys, table.insert, len
+    /// the part of key's value at path, like "profile.name" or "tags.0"
+    pub async fn get_path<K>(
+        &self,
+        key: K,
+        path: &str,
+    ) -> Result<Option<serde_json::Value>, GlobalTableError>
+    where
+        K: TryInto<GlobalTableKey>,
+    {
+        let sql_name = self.sql_name();
+        let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
+        let path = json_path(path);
+        let value = self
+            .database
+            .call(move |conn| {
+                let sql = format!(
+                    "SELECT value -> ? FROM {sql_name} WHERE {key_column} = ?",
+                    key_column = key.column(),
+                );
+                let value: Option<Option<String>> = or_empty(
+                    conn.query_row(&sql, params![path, key], |row| row.get(0))
+                        .optional(),
+                )?;
+
+                Ok(value.flatten())
+            })
+            .await?;
+
+        Ok(value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()?)
+    }
+
+    /// set parts of key's value in place, each path like "profile.name" to its value.
+    /// as with sqlite's json_set, a path whose parent doesn't exist is skipped.
+    /// returns false if key isn't set.
+    pub async fn patch<K, V>(
+        &self,
+        key: K,
+        changes: Vec<(String, V)>,
+    ) -> Result<bool, GlobalTableError>
+    where
+        K: TryInto<GlobalTableKey>,
+        V: Serialize,
+    {
+        let name = self.name.clone();
+        let sql_name = self.sql_name();
+        let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
+        let column = key.column();
+        if changes.is_empty() {
+            return Ok(self.get::<_, serde_json::Value>(key).await?.is_some());
+        }
+        let mut params: Vec<String> = Vec::with_capacity(changes.len() * 2);
+        for (path, value) in &changes {
+            params.push(json_path(path));
+            params.push(serde_json::to_string(value)?);
+        }
+
+        let patched = self
+            .database
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let set = vec!["?, json(?)"; changes.len()].join(", ");
+                let sql = format!(
+                    "UPDATE {sql_name} SET value = jsonb_set(value, {set}) WHERE {column} = ?
+                     RETURNING value"
+                );
+                let mut values: Vec<&dyn ToSql> =
+                    params.iter().map(|param| param as &dyn ToSql).collect();
+                values.push(&key);
+                let value: Option<Vec<u8>> = or_empty(
+                    tx.query_row(&sql, values.as_slice(), |row| row.get(0))
+                        .optional(),
+                )?;
+                if let Some(value) = &value {
+                    sync::record(&tx, &name, &key, Some(value))?;
+                }
+                tx.commit()?;
+
+                Ok(value.is_some())
+            })
+            .await?;
+
+        Ok(patched)
+    }
+
+    // TODO: ipairs, get numeric keys, set numeric keys, table.insert, len
 
     /// len - like in lua, returns the number of elements in the table with a key that is null
     pub async fn len(&self) -> Result<usize, GlobalTableError> {
@@ -220,11 +574,11 @@ impl GlobalTable {
         let len: usize = self
             .database
             .call(move |conn| {
-                let len = conn.query_row(
+                let len = or_empty(conn.query_row(
                     &format!("SELECT max(key_int) FROM {sql_name}",),
                     [],
                     |row| row.get(0),
-                )?;
+                ))?;
 
                 Ok(len)
             })
@@ -233,43 +587,430 @@ impl GlobalTable {
         Ok(len)
     }
 
-    /// this returns a channel that will return the key and value pairs
-    pub async fn pairs<V>(&self) -> GlobalTablePairs<V>
+    /// a cursor over the key and value pairs, see GlobalTablePairs
+    pub fn pairs<V>(&self, order: PairsOrder) -> GlobalTablePairs<V>
     where
         V: DeserializeOwned + Send + 'static,
     {
+        GlobalTablePairs {
+            table: self.clone(),
+            order,
+            rows: VecDeque::new(),
+            after: Value::Null,
+            done: false,
+        }
+    }
+
+    /// count - the number of rows in the table, unlike len this includes string keys
+    pub async fn count(&self) -> Result<usize, GlobalTableError> {
         let sql_name = self.sql_name();
-        let conn = self.database.clone();
-        let (tx, rx) = mpsc::channel(1);
+        let count: usize = self
+            .database
+            .call(move |conn| {
+                let count = or_empty(conn.query_row(
+                    &format!("SELECT count(*) FROM {sql_name}"),
+                    [],
+                    |row| row.get(0),
+                ))?;
 
-        tokio::spawn(async move {
-            let sql = format!("SELECT key_int, key_str, jsonb(value) FROM {sql_name}");
-            conn.call(move |conn| {
-                let mut stmt = conn.prepare(&sql)?;
-                let mut query = stmt.query([])?;
+                Ok(count)
+            })
+            .await?;
+
+        Ok(count)
+    }
+
+    /// returns up to limit key and value pairs, skipping the first offset rows
+    pub async fn slice<V>(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(GlobalTableKey, V)>, GlobalTablePairsError>
+    where
+        V: DeserializeOwned + Send + 'static,
+    {
+        let sql_name = self.sql_name();
+        let rows = self
+            .database
+            .call(move |conn| {
+                let sql = format!(
+                    "SELECT key_int, key_str, jsonb(value) FROM {sql_name} ORDER BY rowid LIMIT ? OFFSET ?"
+                );
+                let mut rows = vec![];
+                let mut stmt = match conn.prepare(&sql) {
+                    Err(err) if is_missing_table(&err) => return Ok(rows),
+                    stmt => stmt?,
+                };
+                let mut query = stmt.query(params![limit, offset])?;
+                while let Some(row) = query.next()? {
+                    rows.push(do_pairs(row));
+                }
+
+                Ok(rows)
+            })
+            .await?;
+
+        rows.into_iter().collect()
+    }
+
+    /// every key and value pair, in insertion order
+    pub async fn entries(
+        &self,
+    ) -> Result<Vec<(GlobalTableKey, serde_json::Value)>, GlobalTablePairsError> {
+        self.slice(0, i64::MAX as usize).await
+    }
 
+    /// write the table to out in the given format, a page of rows at a time
+    pub async fn export<W>(&self, format: Format, out: &mut W) -> Result<(), GlobalTableError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut encoder = match format {
+            Format::Json => Encoder::json(vec![]),
+            Format::Csv => {
+                let mut columns = Columns::default();
+                let mut rows = self.pairs::<serde_json::Value>(PairsOrder::Insertion);
+                while let Some((_, value)) = rows.next().await? {
+                    columns.add(&value);
+                }
+                Encoder::csv(vec![], columns)?
+            }
+        };
+        let mut rows = self.pairs::<serde_json::Value>(PairsOrder::Insertion);
+        while let Some((key, value)) = rows.next().await? {
+            encoder.write(&key, &value)?;
+            let buffer = encoder.buffer()?;
+            if buffer.len() >= EXPORT_BUFFER {
+                out.write_all(buffer).await.map_err(transfer::Error::from)?;
+                buffer.clear();
+            }
+        }
+        let rest = encoder.finish()?;
+        out.write_all(&rest).await.map_err(transfer::Error::from)?;
+        out.flush().await.map_err(transfer::Error::from)?;
+        Ok(())
+    }
+
+    /// add the rows in text to the table, replacing existing keys. returns the number of rows.
+    pub async fn import(&self, text: &str, format: Format) -> Result<usize, GlobalTableError> {
+        let rows = transfer::decode(text, format)?;
+        self.set_many(rows).await
+    }
+
+    /// the values of the keys that are in the table, in one query
+    pub async fn get_many<V>(
+        &self,
+        keys: Vec<GlobalTableKey>,
+    ) -> Result<Vec<(GlobalTableKey, V)>, GlobalTablePairsError>
+    where
+        V: DeserializeOwned + Send + 'static,
+    {
+        let sql_name = self.sql_name();
+        let (ints, strs): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .partition(|key| matches!(key, GlobalTableKey::Int(_)));
+        let ints = serde_json::to_string(&ints).map_err(|err| super::Error::Other(err.into()))?;
+        let strs = serde_json::to_string(&strs).map_err(|err| super::Error::Other(err.into()))?;
+        let rows = self
+            .database
+            .call(move |conn| {
+                let sql = format!(
+                    "SELECT key_int, key_str, jsonb(value) FROM {sql_name}
+                     WHERE key_int IN (SELECT value FROM json_each(?))
+                     OR key_str IN (SELECT value FROM json_each(?))"
+                );
+                let mut rows = vec![];
+                let mut stmt = match conn.prepare(&sql) {
+                    Err(err) if is_missing_table(&err) => return Ok(rows),
+                    stmt => stmt?,
+                };
+                let mut query = stmt.query([ints, strs])?;
                 while let Some(row) = query.next()? {
-                    tx.blocking_send(do_pairs(row)).unwrap();
+                    rows.push(do_pairs(row));
+                }
+
+                Ok(rows)
+            })
+            .await?;
+
+        rows.into_iter().collect()
+    }
+
+    /// set every key and value in one transaction, replacing existing keys.
+    /// returns the number of rows.
+    pub async fn set_many<V>(
+        &self,
+        rows: Vec<(GlobalTableKey, V)>,
+    ) -> Result<usize, GlobalTableError>
+    where
+        V: Serialize,
+    {
+        self.write_rows(rows, false).await
+    }
+
+    /// make rows the whole contents of the table, in one transaction.
+    /// returns the number of rows.
+    pub async fn replace<V>(
+        &self,
+        rows: Vec<(GlobalTableKey, V)>,
+    ) -> Result<usize, GlobalTableError>
+    where
+        V: Serialize,
+    {
+        self.write_rows(rows, true).await
+    }
+
+    /// set rows, first deleting the keys that aren't in them when replacing
+    async fn write_rows<V>(
+        &self,
+        rows: Vec<(GlobalTableKey, V)>,
+        replace: bool,
+    ) -> Result<usize, GlobalTableError>
+    where
+        V: Serialize,
+    {
+        let name = self.name.clone();
+        let sql_name = self.sql_name();
+        let rows = rows
+            .into_iter()
+            .map(|(key, value)| Ok((key, serde_sqlite_jsonb::to_vec(&value)?)))
+            .collect::<Result<Vec<_>, GlobalTableError>>()?;
+        let count = rows.len();
+        let create = self.create_for_write();
+
+        self.database
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                create(&tx)?;
+                let synced = sync::is_synced(&tx, &name)?;
+                if replace {
+                    let keys: Vec<_> = rows.iter().map(|(key, _)| key).collect();
+                    let keys = serde_json::to_string(&keys)
+                        .map_err(|err| super::Error::Other(err.into()))?;
+                    let mut stmt = tx.prepare(&format!(
+                        "DELETE FROM {sql_name}
+                         WHERE coalesce(key_int, key_str) NOT IN (SELECT value FROM json_each(?))
+                         RETURNING coalesce(key_int, key_str)"
+                    ))?;
+                    let deleted = stmt
+                        .query_map([keys], |row| row.get::<_, GlobalTableKey>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    drop(stmt);
+                    if synced {
+                        for key in deleted {
+                            sync::record(&tx, &name, &key, None)?;
+                        }
+                    }
+                }
+                for (key, value) in rows {
+                    let column = key.column();
+                    tx.prepare_cached(&upsert(&sql_name, column))?
+                        .execute(params![key, value])?;
+                    if synced {
+                        sync::record(&tx, &name, &key, Some(&value))?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        self.created();
+
+        Ok(count)
+    }
+
+    /// keep every value that is set or deleted, so keys can be restored later.
+    /// triggers copy each change into the history table, so this lasts for the life
+    /// of the table, and values set by import() are kept too.
+    pub async fn versioned(&self) -> Result<(), GlobalTableError> {
+        let sql_name = self.sql_name();
+        let history_table = self.history_table();
+        let history_name = self.history_name("");
+        let index_int = self.history_name("_key_int");
+        let index_str = self.history_name("_key_str");
+        let triggers = [
+            (self.history_name("_insert"), "INSERT", "NEW.value", "NEW"),
+            (self.history_name("_update"), "UPDATE", "NEW.value", "NEW"),
+            (self.history_name("_delete"), "DELETE", "NULL", "OLD"),
+        ];
+        let create = self.create_for_write();
+
+        self.database
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                create(&tx)?;
+                let exists: bool = tx.query_row(
+                    "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+                    [history_table],
+                    |row| row.get(0),
+                )?;
+                if exists {
+                    tx.commit()?;
+                    return Ok(());
                 }
 
+                tx.execute_batch(&format!(
+                    r"
+                        CREATE TABLE {history_name} (
+                            version INTEGER PRIMARY KEY,
+                            key_int INTEGER,
+                            key_str TEXT,
+                            value JSONB,
+                            created INTEGER NOT NULL DEFAULT (unixepoch())
+                        );
+                        CREATE INDEX {index_int} ON {history_name} (key_int);
+                        CREATE INDEX {index_str} ON {history_name} (key_str);
+                        INSERT INTO {history_name} (key_int, key_str, value)
+                            SELECT key_int, key_str, value FROM {sql_name} ORDER BY rowid;
+                    "
+                ))?;
+                for (trigger, event, value, row) in triggers {
+                    tx.execute_batch(&format!(
+                        r"
+                            CREATE TRIGGER {trigger} AFTER {event} ON {sql_name} BEGIN
+                                INSERT INTO {history_name} (key_int, key_str, value)
+                                VALUES ({row}.key_int, {row}.key_str, {value});
+                            END;
+                        "
+                    ))?;
+                }
+                tx.commit()?;
+
                 Ok(())
             })
-            .await
-            .unwrap();
-        });
+            .await?;
+        self.created();
+
+        Ok(())
+    }
+
+    async fn is_versioned(&self) -> Result<bool, GlobalTableError> {
+        let history_table = self.history_table();
+        let exists = self
+            .database
+            .call(move |conn| {
+                let exists = conn.query_row(
+                    "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+                    [history_table],
+                    |row| row.get(0),
+                )?;
+                Ok(exists)
+            })
+            .await?;
 
-        GlobalTablePairs(rx)
+        Ok(exists)
+    }
+
+    /// every version of the value of key, newest first
+    pub async fn history<K>(&self, key: K) -> Result<Vec<GlobalTableVersion>, GlobalTableError>
+    where
+        K: TryInto<GlobalTableKey>,
+    {
+        if !self.is_versioned().await? {
+            return Err(GlobalTableError::NotVersioned(self.name.clone()));
+        }
+        let history_name = self.history_name("");
+        let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
+        let column = key.column();
+
+        let rows = self
+            .database
+            .call(move |conn| {
+                let sql = format!(
+                    "SELECT version, created, jsonb(value) FROM {history_name}
+                     WHERE {column} = ? ORDER BY version DESC"
+                );
+                let mut stmt = conn.prepare(&sql)?;
+                let rows = stmt
+                    .query_map([key], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get::<_, Option<Vec<u8>>>(2)?))
+                    })?
+                    .collect::<Result<Vec<(i64, i64, _)>, _>>()?;
+
+                Ok(rows)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|(version, created, value)| {
+                Ok(GlobalTableVersion {
+                    version,
+                    created,
+                    value: value
+                        .map(|value| serde_sqlite_jsonb::from_slice(&value[..]))
+                        .transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// set key back to the value it had at version. restoring is itself a change, so it
+    /// adds a new version and can be undone in turn.
+    pub async fn restore<K>(&self, key: K, version: i64) -> Result<(), GlobalTableError>
+    where
+        K: TryInto<GlobalTableKey>,
+    {
+        let key = key.try_into().map_err(|_| GlobalTableError::InvalidKey)?;
+        let found = self
+            .history(key.clone())
+            .await?
+            .into_iter()
+            .find(|entry| entry.version == version)
+            .ok_or(GlobalTableError::NoSuchVersion(version))?;
+
+        match found.value {
+            Some(value) => self.set(key, value).await,
+            None => self.del(key).await,
+        }
+    }
+
+    /// forget versions created before the unix timestamp. the current value of each key
+    /// is kept, however old; keys that were deleted before then are gone for good.
+    /// returns the number of versions removed.
+    pub async fn purge(&self, before: i64) -> Result<usize, GlobalTableError> {
+        if !self.is_versioned().await? {
+            return Err(GlobalTableError::NotVersioned(self.name.clone()));
+        }
+        let history_name = self.history_name("");
+
+        let count = self
+            .database
+            .call(move |conn| {
+                let count = conn.execute(
+                    &format!(
+                        r"
+                            DELETE FROM {history_name} AS h
+                            WHERE created < ?
+                            AND NOT (
+                                value IS NOT NULL
+                                AND version = (
+                                    SELECT max(version) FROM {history_name}
+                                    WHERE key_int IS h.key_int AND key_str IS h.key_str
+                                )
+                            )
+                        "
+                    ),
+                    [before],
+                )?;
+                Ok(count)
+            })
+            .await?;
+
+        Ok(count)
     }
 
     pub async fn destroy(&self) -> Result<(), super::Error> {
         let sql_name = self.sql_name();
+        let history_name = self.history_name("");
         self.database
             .call(move |conn| {
                 conn.execute(&format!("DROP TABLE IF EXISTS {sql_name}",), [])?;
+                conn.execute(&format!("DROP TABLE IF EXISTS {history_name}",), [])?;
 
                 Ok(())
             })
             .await?;
+        self.created.store(false, Ordering::Relaxed);
 
         Ok(())
     }
@@ -308,10 +1049,13 @@ where
 }
 
 impl LuaUserData for GlobalTablePairs<serde_json::Value> {
-    // implement call which is an async function that calls recv
+    // calling it returns the next key and value, or nil at the end
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_async_meta_method_mut(LuaMetaMethod::Call, |lua, mut this, ()| async move {
-            let value = this.0.recv().await.transpose().into_lua_err()?;
+            let value = this
+                .next()
+                .await
+                .map_err(|err| this.table.lua_error("pairs", err))?;
             let mut mv = LuaMultiValue::new();
 
             match value {
@@ -330,32 +1074,48 @@ impl LuaUserData for GlobalTablePairs<serde_json::Value> {
 #[derive(Debug)]
 pub struct Global {
     database: Database,
+
+    /// the handle for each table that has been used, so global.name is the same each time
+    tables: Mutex<HashMap<String, LuaAnyUserData>>,
 }
 
 impl Global {
     pub fn new(database: &Database) -> Self {
         Self {
             database: database.clone(),
+            tables: Mutex::default(),
+        }
+    }
+
+    fn table(&self, lua: &Lua, name: String) -> LuaResult<LuaAnyUserData> {
+        let mut tables = self.tables.lock();
+        if let Some(table) = tables.get(&name) {
+            return Ok(table.clone());
         }
+        let table = lua.create_userdata(GlobalTable::new(name.clone(), self.database.clone()))?;
+        tables.insert(name, table.clone());
+        Ok(table)
     }
 }
 
-// global.name creates a new GlobalTable
+// global.name is a GlobalTable, the same one each time
 impl LuaUserData for Global {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        methods.add_meta_method(LuaMetaMethod::Index, |_lua, this, key: String| {
-            let table = GlobalTable::new(key, this.database.clone());
-            block_in_place(|| table.create().into_lua_err())?;
-            Ok(table)
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: String| {
+            this.table(lua, key)
         });
 
         // global.name = nil deletes the table, no other values are allowed
         methods.add_async_meta_method(
             LuaMetaMethod::NewIndex,
-            |_, this, (key, value): (String, LuaValue)| async move {
+            |lua, this, (key, value): (String, LuaValue)| async move {
                 if value.is_nil() {
-                    let table = GlobalTable::new(key, this.database.clone());
-                    table.destroy().await.into_lua_err()?;
+                    let table =
+                        GlobalTable::clone(&*this.table(&lua, key)?.borrow::<GlobalTable>()?);
+                    table
+                        .destroy()
+                        .await
+                        .map_err(|err| table.lua_error("delete", err))?;
                     return Ok(());
                 }
                 Err(LuaError::external("cannot set value on global"))
@@ -369,7 +1129,10 @@ impl LuaUserData for GlobalTable {
         methods.add_async_meta_method(
             LuaMetaMethod::Index,
             |lua, this, key: LuaValue| async move {
-                let value: Option<serde_json::Value> = this.get(key).await.into_lua_err()?;
+                let value: Option<serde_json::Value> = this
+                    .get(key)
+                    .await
+                    .map_err(|err| this.lua_error("get", err))?;
                 if let Some(ref value) = value {
                     Ok(lua.to_value(value)?)
                 } else {
@@ -386,17 +1149,297 @@ impl LuaUserData for GlobalTable {
                     key => GlobalTableKey::from(key.to_string()?),
                 };
                 if value.is_nil() {
-                    this.del(key).await.into_lua_err()?;
+                    this.del(key)
+                        .await
+                        .map_err(|err| this.lua_error("delete", err))?;
                     return Ok(());
                 }
-                this.set(key, value).await.into_lua_err()?;
+                this.set(key, value)
+                    .await
+                    .map_err(|err| this.lua_error("set", err))?;
                 Ok(())
             },
         );
 
+        // for key, value in pairs(global.users) do ... end visits keys in insertion order,
+        // for key, value in globaltable.pairs(global.users, { order = "key" }) do sorts them
+        methods.add_meta_method(LuaMetaMethod::Pairs, |_, this, ()| {
+            Ok(this.pairs::<serde_json::Value>(PairsOrder::Insertion))
+        });
+        // luau's pairs() ignores __pairs, so there it is for key, value in global.users do,
+        // and luau only iterates with functions
+        #[cfg(feature = "luau")]
+        methods.add_meta_method(LuaMetaMethod::Iter, |lua, this, ()| {
+            let pairs =
+                lua.create_userdata(this.pairs::<serde_json::Value>(PairsOrder::Insertion))?;
+            lua.create_async_function(move |_, ()| {
+                let pairs = pairs.clone();
+                async move { pairs.call_async::<LuaMultiValue>(()).await }
+            })
+        });
+
         methods.add_async_meta_method(LuaMetaMethod::Len, |_, this, ()| async move {
-            let len = this.len().await.into_lua_err()?;
+            let len = this.len().await.map_err(|err| this.lua_error("len", err))?;
             Ok(len as i64)
         });
     }
 }
+
+/// globaltable.export(global.tasks, "tasks.csv") and the rest work on a whole global
+/// table. they aren't methods, so every key of a table is data: global.tasks.export is
+/// whatever was stored under "export".
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let globaltable = lua.create_table()?;
+    globaltable.set("export", lua.create_async_function(table_export)?)?;
+    globaltable.set("import", lua.create_async_function(table_import)?)?;
+    globaltable.set("pairs", lua.create_function(table_pairs)?)?;
+    globaltable.set("to_table", lua.create_async_function(table_to_table)?)?;
+    globaltable.set("from_table", lua.create_async_function(table_from_table)?)?;
+    globaltable.set("patch", lua.create_async_function(table_patch)?)?;
+    globaltable.set("get_path", lua.create_async_function(table_get_path)?)?;
+    globaltable.set("get_many", lua.create_async_function(table_get_many)?)?;
+    globaltable.set("set_many", lua.create_async_function(table_set_many)?)?;
+    globaltable.set("versioned", lua.create_async_function(table_versioned)?)?;
+    globaltable.set("history", lua.create_async_function(table_history)?)?;
+    globaltable.set("restore", lua.create_async_function(table_restore)?)?;
+    globaltable.set("purge", lua.create_async_function(table_purge)?)?;
+    lua.globals().set("globaltable", globaltable)?;
+
+    Ok(())
+}
+
+/// globaltable.export(global.tasks, "tasks.csv"), the format comes from the extension or
+/// { format = "json" }
+async fn table_export(
+    lua: Lua,
+    (table, path, options): (LuaUserDataRef<GlobalTable>, String, Option<LuaTable>),
+) -> LuaResult<()> {
+    let path = resolve(&lua, path);
+    let format = transfer_format(&path, options)?;
+    let mut file = tokio::fs::File::create(path).await.into_lua_err()?;
+    table
+        .export(format, &mut file)
+        .await
+        .map_err(|err| table.lua_error("export", err))
+}
+
+async fn table_import(
+    lua: Lua,
+    (table, path, options): (LuaUserDataRef<GlobalTable>, String, Option<LuaTable>),
+) -> LuaResult<usize> {
+    let path = resolve(&lua, path);
+    let format = transfer_format(&path, options)?;
+    let text = tokio::fs::read_to_string(path).await.into_lua_err()?;
+    table
+        .import(&text, format)
+        .await
+        .map_err(|err| table.lua_error("import", err))
+}
+
+/// for key, value in globaltable.pairs(global.users, { order = "key" }) do ... end
+fn table_pairs(
+    lua: &Lua,
+    (table, options): (LuaUserDataRef<GlobalTable>, Option<LuaTable>),
+) -> LuaResult<GlobalTablePairs<serde_json::Value>> {
+    let order = options
+        .map(|options| options.get::<LuaValue>("order"))
+        .transpose()?
+        .map(|order| lua.from_value::<Option<PairsOrder>>(order))
+        .transpose()?
+        .flatten()
+        .unwrap_or_default();
+    Ok(table.pairs::<serde_json::Value>(order))
+}
+
+/// local settings = globaltable.to_table(global.settings), every key and value at once,
+/// for tables that are read far more than they change
+async fn table_to_table(lua: Lua, table: LuaUserDataRef<GlobalTable>) -> LuaResult<LuaTable> {
+    let rows = table
+        .entries()
+        .await
+        .map_err(|err| table.lua_error("to_table", err))?;
+    let values = lua.create_table_with_capacity(0, rows.len())?;
+    for (key, value) in rows {
+        values.set(lua.to_value(&key)?, lua.to_value(&value)?)?;
+    }
+    Ok(values)
+}
+
+/// globaltable.from_table(global.settings, settings) makes the table hold exactly settings
+async fn table_from_table(
+    _lua: Lua,
+    (table, rows): (LuaUserDataRef<GlobalTable>, LuaTable),
+) -> LuaResult<usize> {
+    table
+        .replace(table_rows(rows)?)
+        .await
+        .map_err(|err| table.lua_error("from_table", err))
+}
+
+/// globaltable.patch(global.users, key, { ["profile.name"] = "X" }) changes parts of a
+/// value without reading it
+async fn table_patch(
+    _lua: Lua,
+    (table, key, changes): (LuaUserDataRef<GlobalTable>, LuaValue, LuaTable),
+) -> LuaResult<bool> {
+    let changes = changes
+        .pairs::<String, LuaValue>()
+        .collect::<LuaResult<Vec<_>>>()?;
+    table
+        .patch(key, changes)
+        .await
+        .map_err(|err| table.lua_error("patch", err))
+}
+
+/// globaltable.get_path(global.users, key, "profile.name") reads one part of a value
+async fn table_get_path(
+    lua: Lua,
+    (table, key, path): (LuaUserDataRef<GlobalTable>, LuaValue, String),
+) -> LuaResult<LuaValue> {
+    match table
+        .get_path(key, &path)
+        .await
+        .map_err(|err| table.lua_error("get_path", err))?
+    {
+        Some(value) => lua.to_value(&value),
+        None => Ok(LuaNil),
+    }
+}
+
+/// local users = globaltable.get_many(global.users, { "alice", "bob" }), keys that
+/// aren't set are left out
+async fn table_get_many(
+    lua: Lua,
+    (table, keys): (LuaUserDataRef<GlobalTable>, Vec<LuaValue>),
+) -> LuaResult<LuaTable> {
+    let keys = keys
+        .into_iter()
+        .map(GlobalTableKey::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .into_lua_err()?;
+    let rows = table
+        .get_many::<serde_json::Value>(keys)
+        .await
+        .map_err(|err| table.lua_error("get_many", err))?;
+    let found = lua.create_table()?;
+    for (key, value) in rows {
+        found.set(lua.to_value(&key)?, lua.to_value(&value)?)?;
+    }
+    Ok(found)
+}
+
+/// globaltable.set_many(global.users, { alice = { ... }, bob = { ... } })
+async fn table_set_many(
+    _lua: Lua,
+    (table, rows): (LuaUserDataRef<GlobalTable>, LuaTable),
+) -> LuaResult<usize> {
+    table
+        .set_many(table_rows(rows)?)
+        .await
+        .map_err(|err| table.lua_error("set_many", err))
+}
+
+/// globaltable.versioned(global.docs), then globaltable.history(global.docs, key),
+/// globaltable.restore(global.docs, key, version) and globaltable.purge(global.docs, before)
+async fn table_versioned(_lua: Lua, table: LuaUserDataRef<GlobalTable>) -> LuaResult<()> {
+    table
+        .versioned()
+        .await
+        .map_err(|err| table.lua_error("versioned", err))
+}
+
+async fn table_history(
+    lua: Lua,
+    (table, key): (LuaUserDataRef<GlobalTable>, LuaValue),
+) -> LuaResult<LuaTable> {
+    let history = table
+        .history(key)
+        .await
+        .map_err(|err| table.lua_error("history", err))?;
+    let versions = lua.create_table()?;
+    for entry in history {
+        let version = lua.create_table()?;
+        version.set("version", entry.version)?;
+        version.set("created", entry.created)?;
+        version.set("deleted", entry.value.is_none())?;
+        if let Some(ref value) = entry.value {
+            version.set("value", lua.to_value(value)?)?;
+        }
+        versions.push(version)?;
+    }
+    Ok(versions)
+}
+
+async fn table_restore(
+    _lua: Lua,
+    (table, key, version): (LuaUserDataRef<GlobalTable>, LuaValue, i64),
+) -> LuaResult<()> {
+    table
+        .restore(key, version)
+        .await
+        .map_err(|err| table.lua_error("restore", err))
+}
+
+async fn table_purge(
+    _lua: Lua,
+    (table, before): (LuaUserDataRef<GlobalTable>, i64),
+) -> LuaResult<usize> {
+    table
+        .purge(before)
+        .await
+        .map_err(|err| table.lua_error("purge", err))
+}
+
+/// the keys and values of a lua table, as rows of a global table
+fn table_rows(table: LuaTable) -> LuaResult<Vec<(GlobalTableKey, LuaValue)>> {
+    table
+        .pairs::<LuaValue, LuaValue>()
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((GlobalTableKey::try_from(key).into_lua_err()?, value))
+        })
+        .collect()
+}
+
+fn transfer_format(path: &Path, options: Option<LuaTable>) -> LuaResult<Format> {
+    let format = options
+        .map(|options| options.get::<Option<String>>("format"))
+        .transpose()?
+        .flatten();
+    match format {
+        Some(format) => format.parse().into_lua_err(),
+        None => Format::from_path(path)
+            .ok_or_else(|| LuaError::runtime("cannot tell the format from the file name")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_keep_their_place_in_pairs() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+        runtime.block_on(async {
+            let database = Database::open_in_memory().expect("database");
+            database
+                .call(|conn| Ok(conn.execute_batch(include_str!("../schema.sql"))?))
+                .await
+                .expect("schema");
+            let table = GlobalTable::new("items".to_string(), database);
+            let count = PAIRS_PAGE as i64 * 2 + 1;
+            for key in 1..=count {
+                table.set(key, key).await.expect("set");
+            }
+
+            // every row is updated as it is visited, and none come around again
+            let mut pairs = table.pairs::<i64>(PairsOrder::Insertion);
+            let mut seen = vec![];
+            while let Some((key, value)) = pairs.next().await.expect("next") {
+                table.set(key, value + 1).await.expect("update");
+                seen.push(value);
+            }
+            assert_eq!(seen, (1..=count).collect::<Vec<_>>());
+        });
+    }
+}
@@ -0,0 +1,48 @@
+// storage for payment webhook events; the lua side is in runtime/payments.rs.
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+
+use super::{Error, Result};
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::Other(Box::new(err))
+}
+
+/// save an event unless it was saved before. returns true if it has been handled already.
+pub fn save_event(conn: &Connection, event: &Value) -> Result<bool> {
+    let id = event["id"].as_str().unwrap_or_default();
+    let kind = event["type"].as_str().unwrap_or_default();
+    let created = event["created"].as_i64().unwrap_or_default();
+    let json = serde_json::to_string(event).map_err(json_error)?;
+    conn.prepare_cached(
+        "INSERT OR IGNORE INTO lg_payment_events (id, type, event, created)
+         VALUES (?, ?, jsonb(?), ?)",
+    )?
+    .execute(params![id, kind, json, created])?;
+    let handled: Option<i64> = conn
+        .prepare_cached("SELECT handled FROM lg_payment_events WHERE id = ?")?
+        .query_row([id], |row| row.get(0))
+        .optional()?
+        .flatten();
+    Ok(handled.is_some())
+}
+
+pub fn mark_handled(conn: &Connection, id: &str) -> Result<()> {
+    conn.prepare_cached("UPDATE lg_payment_events SET handled = unixepoch() WHERE id = ?")?
+        .execute([id])?;
+    Ok(())
+}
+
+/// the latest events, of one type or of every type
+pub fn events(conn: &Connection, kind: Option<&str>, limit: usize) -> Result<Vec<Value>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT json(event) FROM lg_payment_events WHERE ?1 IS NULL OR type = ?1
+         ORDER BY created DESC, rowid DESC LIMIT ?2",
+    )?;
+    let rows = stmt
+        .query_map(params![kind, limit], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.iter()
+        .map(|row| serde_json::from_str(row).map_err(json_error))
+        .collect()
+}
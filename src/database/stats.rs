@@ -0,0 +1,113 @@
+// how busy the database thread is, to tell whether a slow app is waiting on sqlite or on
+// lua. every call is counted and timed, calls slower than the threshold are logged, and
+// with log_sql the statements in them are logged too.
+use mlua::prelude::*;
+use rusqlite::trace::{TraceEvent, TraceEventCodes};
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// statements slower than this many microseconds are logged, when log_sql is on
+static SLOW_STATEMENT_MICROS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+#[derive(Debug)]
+pub struct Stats {
+    /// calls sent and not yet started
+    queued: AtomicUsize,
+    calls: AtomicU64,
+    waited_micros: AtomicU64,
+    ran_micros: AtomicU64,
+    slow_calls: AtomicU64,
+    slow_micros: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            queued: AtomicUsize::new(0),
+            calls: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+            ran_micros: AtomicU64::new(0),
+            slow_calls: AtomicU64::new(0),
+            slow_micros: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+impl Stats {
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn set_slow(&self, slow: Option<Duration>) {
+        self.slow_micros
+            .store(slow.map_or(u64::MAX, micros), Ordering::Relaxed);
+    }
+
+    /// a call was sent to the database thread
+    pub fn queued(&self) -> Instant {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    /// a call sent at queued is starting
+    pub fn started(&self, queued: Instant) -> Instant {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        let started = Instant::now();
+        self.waited_micros
+            .fetch_add(micros(started - queued), Ordering::Relaxed);
+        started
+    }
+
+    /// a call that started at started is done
+    pub fn finished(&self, queued: Instant, started: Instant) {
+        let ran = started.elapsed();
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.ran_micros.fetch_add(micros(ran), Ordering::Relaxed);
+        if micros(ran) > self.slow_micros.load(Ordering::Relaxed) {
+            self.slow_calls.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                ran = ?ran,
+                waited = ?(started - queued),
+                queue_depth = self.queue_depth(),
+                "slow database call"
+            );
+        }
+    }
+
+    /// database.stats is { queue_depth, calls, waited, ran, slow_calls }, times in seconds
+    pub fn snapshot(&self, lua: &Lua) -> LuaResult<LuaTable> {
+        let seconds = |micros: &AtomicU64| micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let stats = lua.create_table()?;
+        stats.set("queue_depth", self.queue_depth())?;
+        stats.set("calls", self.calls.load(Ordering::Relaxed))?;
+        stats.set("waited", seconds(&self.waited_micros))?;
+        stats.set("ran", seconds(&self.ran_micros))?;
+        stats.set("slow_calls", self.slow_calls.load(Ordering::Relaxed))?;
+        Ok(stats)
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// log each statement slower than slow with its sql, see rusqlite::Connection::trace_v2
+pub fn log_sql(conn: &rusqlite::Connection, slow: Option<Duration>) {
+    match slow {
+        Some(slow) => {
+            SLOW_STATEMENT_MICROS.store(micros(slow), Ordering::Relaxed);
+            conn.trace_v2(TraceEventCodes::SQLITE_TRACE_PROFILE, Some(log_statement));
+        }
+        None => conn.trace_v2(TraceEventCodes::empty(), None),
+    }
+}
+
+fn log_statement(event: TraceEvent<'_>) {
+    if let TraceEvent::Profile(stmt, ran) = event {
+        if micros(ran) > SLOW_STATEMENT_MICROS.load(Ordering::Relaxed) {
+            tracing::warn!(ran = ?ran, sql = %stmt.sql(), "slow database statement");
+        }
+    }
+}
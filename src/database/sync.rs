@@ -0,0 +1,343 @@
+// change tracking for sync between lilguy instances; the lua side is runtime/sync.rs.
+//
+// each write to a synced global table is recorded in lg_sync, one row per key, with a
+// vector clock: a counter for each node that has changed the key. comparing two clocks
+// tells whether one change came after the other, or whether they were made without
+// knowledge of each other and conflict. seq orders rows by when they last changed here,
+// so a peer can ask for everything since the last seq it saw.
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    global::{create_table, sql_name, upsert, GlobalTableKey},
+    Error, Result,
+};
+
+pub type Clock = BTreeMap<String, u64>;
+
+/// the latest change to a key, as exchanged with peers. value is None for deletes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: i64,
+    pub table: String,
+    pub key: GlobalTableKey,
+    pub value: Option<serde_json::Value>,
+    pub clock: Clock,
+    /// the node that made the change
+    pub node: String,
+    /// when the change was made, in milliseconds since the epoch
+    pub updated: i64,
+}
+
+/// how a change from a peer relates to the one we have for the same key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// the peer's change follows ours, so it replaces it
+    Apply,
+    /// we already have the peer's change, or one that follows it
+    Ignore,
+    /// neither change knew about the other
+    Conflict,
+}
+
+/// compare clocks. None means they are concurrent.
+pub fn compare(a: &Clock, b: &Clock) -> Option<Ordering> {
+    let mut order = Ordering::Equal;
+    for node in a.keys().chain(b.keys()) {
+        let a = a.get(node).copied().unwrap_or_default();
+        let b = b.get(node).copied().unwrap_or_default();
+        match (order, a.cmp(&b)) {
+            (_, Ordering::Equal) => {}
+            (Ordering::Equal, next) => order = next,
+            (order, next) if order != next => return None,
+            _ => {}
+        }
+    }
+    Some(order)
+}
+
+/// the clock that follows both a and b
+pub fn merge_clocks(a: &Clock, b: &Clock) -> Clock {
+    let mut clock = a.clone();
+    for (node, count) in b {
+        let entry = clock.entry(node.clone()).or_default();
+        *entry = (*entry).max(*count);
+    }
+    clock
+}
+
+pub fn resolve(local: Option<&Change>, remote: &Change) -> Resolution {
+    let Some(local) = local else {
+        return Resolution::Apply;
+    };
+    match compare(&remote.clock, &local.clock) {
+        Some(Ordering::Greater) => Resolution::Apply,
+        Some(_) => Resolution::Ignore,
+        None => Resolution::Conflict,
+    }
+}
+
+/// last writer wins: the change made later, with the node id breaking ties so every
+/// instance picks the same one. the result has a clock that follows both.
+pub fn last_writer_wins(local: &Change, remote: &Change) -> Change {
+    let clock = merge_clocks(&local.clock, &remote.clock);
+    let winner = if (remote.updated, &remote.node) > (local.updated, &local.node) {
+        remote
+    } else {
+        local
+    };
+    Change {
+        clock,
+        ..winner.clone()
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::Other(Box::new(err))
+}
+
+/// this instance's id, created the first time it is needed
+pub fn node_id(conn: &Connection) -> Result<String> {
+    let id = conn
+        .prepare_cached("SELECT value FROM lg_internal WHERE name = 'node_id'")?
+        .query_row([], |row| row.get(0))
+        .optional()?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    let id = format!("{:016x}", rand::random::<u64>());
+    conn.execute(
+        "INSERT INTO lg_internal (name, value) VALUES ('node_id', ?)",
+        [&id],
+    )?;
+    Ok(id)
+}
+
+pub fn is_synced(conn: &Connection, table: &str) -> Result<bool> {
+    let synced = conn
+        .prepare_cached("SELECT 1 FROM lg_sync_tables WHERE name = ?")?
+        .query_row([table], |_| Ok(()))
+        .optional()?;
+    Ok(synced.is_some())
+}
+
+/// start tracking changes to a table. rows it already has are recorded as changes by
+/// this node, so peers receive them on the next sync.
+pub fn enable(conn: &mut Connection, table: &str) -> Result<()> {
+    if is_synced(conn, table)? {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    let sql_name = sql_name(table);
+    create_table(&tx, &sql_name)?;
+    tx.execute("INSERT INTO lg_sync_tables (name) VALUES (?)", [table])?;
+    let rows = tx
+        .prepare(&format!(
+            "SELECT coalesce(key_int, key_str), jsonb(value) FROM {sql_name} ORDER BY rowid"
+        ))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(GlobalTableKey, Vec<u8>)>>>()?;
+    for (key, value) in rows {
+        record(&tx, table, &key, Some(&value))?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// record a local change to a key, if its table is synced. value is jsonb.
+pub fn record(
+    conn: &Connection,
+    table: &str,
+    key: &GlobalTableKey,
+    value: Option<&[u8]>,
+) -> Result<()> {
+    if !is_synced(conn, table)? {
+        return Ok(());
+    }
+    let node = node_id(conn)?;
+    let mut clock = current(conn, table, key)?
+        .map(|change| change.clock)
+        .unwrap_or_default();
+    *clock.entry(node.clone()).or_default() += 1;
+    store(conn, table, key, value, &clock, &node, now())
+}
+
+fn store(
+    conn: &Connection,
+    table: &str,
+    key: &GlobalTableKey,
+    value: Option<&[u8]>,
+    clock: &Clock,
+    node: &str,
+    updated: i64,
+) -> Result<()> {
+    let clock = serde_json::to_string(clock).map_err(json_error)?;
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_sync (seq, tbl, key, value, clock, node, updated)
+         VALUES ((SELECT coalesce(max(seq), 0) + 1 FROM lg_sync), ?, ?, jsonb(?), ?, ?, ?)",
+    )?
+    .execute(params![table, key, value, clock, node, updated])?;
+    Ok(())
+}
+
+const CHANGE_COLUMNS: &str = "seq, tbl, key, json(value), clock, node, updated";
+
+fn read_change(row: &rusqlite::Row<'_>) -> rusqlite::Result<(Change, Option<String>, String)> {
+    let change = Change {
+        seq: row.get(0)?,
+        table: row.get(1)?,
+        key: row.get(2)?,
+        value: None,
+        clock: Clock::new(),
+        node: row.get(5)?,
+        updated: row.get(6)?,
+    };
+    Ok((change, row.get(3)?, row.get(4)?))
+}
+
+fn parse_change((mut change, value, clock): (Change, Option<String>, String)) -> Result<Change> {
+    change.value = value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .map_err(json_error)?;
+    change.clock = serde_json::from_str(&clock).map_err(json_error)?;
+    Ok(change)
+}
+
+/// the latest change recorded for a key
+pub fn current(conn: &Connection, table: &str, key: &GlobalTableKey) -> Result<Option<Change>> {
+    let row = conn
+        .prepare_cached(&format!(
+            "SELECT {CHANGE_COLUMNS} FROM lg_sync WHERE tbl = ? AND key = ?"
+        ))?
+        .query_row(params![table, key], read_change)
+        .optional()?;
+    row.map(parse_change).transpose()
+}
+
+/// up to limit changes made or received after seq, oldest first
+pub fn changes_since(conn: &Connection, seq: i64, limit: usize) -> Result<Vec<Change>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {CHANGE_COLUMNS} FROM lg_sync WHERE seq > ? ORDER BY seq LIMIT ?"
+    ))?;
+    let rows = stmt
+        .query_map(params![seq, limit], read_change)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.into_iter().map(parse_change).collect()
+}
+
+/// write a change from a peer, or the result of resolving a conflict, to its table.
+/// expected is the change read before deciding what to write; if the key has changed
+/// since then nothing is written and false is returned, so the caller can decide again.
+pub fn apply(conn: &mut Connection, change: &Change, expected: Option<&Change>) -> Result<bool> {
+    let tx = conn.transaction()?;
+    if !is_synced(&tx, &change.table)? {
+        return Ok(true);
+    }
+    let seen = current(&tx, &change.table, &change.key)?;
+    if seen.as_ref().map(|change| &change.clock) != expected.map(|change| &change.clock) {
+        return Ok(false);
+    }
+
+    let sql_name = sql_name(&change.table);
+    let column = change.key.column();
+    let value = change
+        .value
+        .as_ref()
+        .map(serde_sqlite_jsonb::to_vec)
+        .transpose()
+        .map_err(|err| Error::Other(Box::new(err)))?;
+    match &value {
+        Some(value) => tx.execute(&upsert(&sql_name, column), params![change.key, value])?,
+        None => tx.execute(
+            &format!("DELETE FROM {sql_name} WHERE {column} = ?"),
+            [&change.key],
+        )?,
+    };
+    store(
+        &tx,
+        &change.table,
+        &change.key,
+        value.as_deref(),
+        &change.clock,
+        &change.node,
+        change.updated,
+    )?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// how far we have pulled from and pushed to a peer: (their seq, our seq)
+pub fn peer_cursor(conn: &Connection, peer: &str) -> Result<(i64, i64)> {
+    let cursor = conn
+        .prepare_cached("SELECT pulled, pushed FROM lg_sync_peers WHERE peer = ?")?
+        .query_row([peer], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    Ok(cursor.unwrap_or_default())
+}
+
+pub fn set_peer_cursor(conn: &Connection, peer: &str, pulled: i64, pushed: i64) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_sync_peers (peer, pulled, pushed) VALUES (?, ?, ?)",
+    )?
+    .execute(params![peer, pulled, pushed])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn change(node: &str, updated: i64, clock: &[(&str, u64)]) -> Change {
+        Change {
+            seq: 0,
+            table: "docs".to_string(),
+            key: GlobalTableKey::from("readme"),
+            value: Some(json!(node)),
+            clock: clock
+                .iter()
+                .map(|(node, count)| (node.to_string(), *count))
+                .collect(),
+            node: node.to_string(),
+            updated,
+        }
+    }
+
+    #[test]
+    fn later_changes_apply() {
+        let local = change("a", 1, &[("a", 1)]);
+        let remote = change("b", 2, &[("a", 1), ("b", 1)]);
+        assert_eq!(resolve(Some(&local), &remote), Resolution::Apply);
+        assert_eq!(resolve(Some(&remote), &local), Resolution::Ignore);
+        assert_eq!(resolve(Some(&remote), &remote), Resolution::Ignore);
+        assert_eq!(resolve(None, &local), Resolution::Apply);
+    }
+
+    #[test]
+    fn concurrent_changes_conflict_and_converge() {
+        let a = change("a", 5, &[("a", 2), ("b", 1)]);
+        let b = change("b", 7, &[("a", 1), ("b", 2)]);
+        assert_eq!(resolve(Some(&a), &b), Resolution::Conflict);
+
+        // both sides pick the same winner, with a clock that follows both changes
+        let on_a = last_writer_wins(&a, &b);
+        let on_b = last_writer_wins(&b, &a);
+        assert_eq!(on_a, on_b);
+        assert_eq!(on_a.value, Some(json!("b")));
+        assert_eq!(resolve(Some(&a), &on_a), Resolution::Apply);
+        assert_eq!(resolve(Some(&b), &on_b), Resolution::Apply);
+    }
+}
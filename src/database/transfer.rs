@@ -0,0 +1,351 @@
+// import and export of global tables, for backups, seeding and moving app state between
+// machines.
+//
+// json is an array of {"key": ..., "value": ...} objects, one per line. csv has a key
+// column and a column for each field of the values when they are all objects, or a single
+// value column when they aren't. a cell that is valid json (numbers, true, false, null,
+// objects) is read as json and any other cell as a string, so strings that would read as
+// json ("123", "true", "") are written as json strings; a table exported to either format
+// imports back the same, int and string keys included. an empty cell is a missing field.
+//
+// rows are written as they are read, so an export never holds the whole table. csv reads
+// the table twice, once to find its columns.
+use indexmap::IndexSet;
+use serde_json::{Map, Value};
+use std::{io::Write, path::Path};
+
+use super::global::GlobalTableKey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("csv error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("the first csv column must be key")]
+    NoKeyColumn,
+
+    #[error("the table changed while it was exported")]
+    Changed,
+
+    #[error("invalid key: {0}")]
+    InvalidKey(Value),
+
+    #[error("unknown format: {0}")]
+    UnknownFormat(String),
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(Error::UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+impl Format {
+    /// the format for a file name, by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+/// the csv columns for a table, from each of its values in turn
+#[derive(Debug)]
+pub struct Columns {
+    fields: IndexSet<String>,
+    objects: bool,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self {
+            fields: IndexSet::new(),
+            objects: true,
+        }
+    }
+}
+
+impl Columns {
+    pub fn add(&mut self, value: &Value) {
+        match value {
+            Value::Object(fields) if self.objects => {
+                self.fields.extend(fields.keys().cloned());
+            }
+            _ => self.objects = false,
+        }
+    }
+
+    /// the field columns, or None for a single value column. objects whose only field is
+    /// named value are written whole, so they aren't read back as that field's value.
+    fn fields(self) -> Option<IndexSet<String>> {
+        let single = self.fields.len() == 1 && self.fields.contains(VALUE_COLUMN);
+        (self.objects && !single).then_some(self.fields)
+    }
+}
+
+/// values that aren't objects are written to a single value column
+const VALUE_COLUMN: &str = "value";
+
+/// writes rows one at a time
+pub enum Encoder<W: Write> {
+    Json {
+        out: W,
+        rows: usize,
+    },
+    Csv {
+        out: csv::Writer<W>,
+        fields: Option<IndexSet<String>>,
+    },
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn json(out: W) -> Self {
+        Self::Json { out, rows: 0 }
+    }
+
+    /// a csv encoder for a table with columns, which writes the header
+    pub fn csv(out: W, columns: Columns) -> Result<Self, Error> {
+        let mut out = csv::Writer::from_writer(out);
+        let fields = columns.fields();
+        let header: Vec<&str> = match &fields {
+            Some(fields) => fields.iter().map(String::as_str).collect(),
+            None => vec![VALUE_COLUMN],
+        };
+        out.write_record(std::iter::once("key").chain(header))?;
+        Ok(Self::Csv { out, fields })
+    }
+
+    pub fn write(&mut self, key: &GlobalTableKey, value: &Value) -> Result<(), Error> {
+        match self {
+            Self::Json { out, rows } => {
+                out.write_all(if *rows == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(
+                    &mut *out,
+                    &serde_json::json!({ "key": key, "value": value }),
+                )?;
+                *rows += 1;
+            }
+            Self::Csv { out, fields } => {
+                let key = match key {
+                    GlobalTableKey::Int(key) => key.to_string(),
+                    GlobalTableKey::Str(key) => string_cell(key),
+                };
+                let cells = match (fields, value) {
+                    (Some(fields), Value::Object(values)) => {
+                        if values.keys().any(|field| !fields.contains(field)) {
+                            return Err(Error::Changed);
+                        }
+                        fields
+                            .iter()
+                            .map(|field| values.get(field).map(cell).unwrap_or_default())
+                            .collect()
+                    }
+                    (Some(_), _) => return Err(Error::Changed),
+                    (None, value) => vec![cell(value)],
+                };
+                out.write_record(std::iter::once(key).chain(cells))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// what has been written so far and not yet taken out
+    pub fn buffer(&mut self) -> Result<&mut W, Error> {
+        match self {
+            Self::Json { out, .. } => Ok(out),
+            Self::Csv { out, .. } => {
+                out.flush()?;
+                Ok(out.get_mut())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<W, Error> {
+        match self {
+            Self::Json { mut out, rows } => {
+                out.write_all(if rows == 0 { b"[]\n" } else { b"\n]\n" })?;
+                Ok(out)
+            }
+            Self::Csv { out, .. } => out.into_inner().map_err(|err| err.into_error().into()),
+        }
+    }
+}
+
+/// encode rows that are already in memory
+pub fn encode(rows: &[(GlobalTableKey, Value)], format: Format) -> Result<String, Error> {
+    let mut encoder = match format {
+        Format::Json => Encoder::json(vec![]),
+        Format::Csv => {
+            let mut columns = Columns::default();
+            for (_, value) in rows {
+                columns.add(value);
+            }
+            Encoder::csv(vec![], columns)?
+        }
+    };
+    for (key, value) in rows {
+        encoder.write(key, value)?;
+    }
+    let text = encoder.finish()?;
+    Ok(String::from_utf8(text).expect("json and csv of strings are utf-8"))
+}
+
+pub fn decode(text: &str, format: Format) -> Result<Vec<(GlobalTableKey, Value)>, Error> {
+    match format {
+        Format::Json => {
+            let rows: Vec<Map<String, Value>> = serde_json::from_str(text)?;
+            rows.into_iter()
+                .map(|mut row| {
+                    let key = parse_key(row.remove("key").unwrap_or_default())?;
+                    Ok((key, row.remove("value").unwrap_or_default()))
+                })
+                .collect()
+        }
+        Format::Csv => decode_csv(text),
+    }
+}
+
+fn parse_key(key: Value) -> Result<GlobalTableKey, Error> {
+    match key {
+        Value::Number(ref number) => number
+            .as_i64()
+            .map(GlobalTableKey::from)
+            .ok_or(Error::InvalidKey(key)),
+        Value::String(key) => Ok(GlobalTableKey::from(key)),
+        key => Err(Error::InvalidKey(key)),
+    }
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(value) => string_cell(value),
+        value => value.to_string(),
+    }
+}
+
+/// a string as it is, unless it would be read back as something else
+fn string_cell(value: &str) -> String {
+    if value.is_empty() || serde_json::from_str::<Value>(value).is_ok() {
+        Value::from(value).to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn read_cell(cell: &str) -> Value {
+    serde_json::from_str(cell).unwrap_or_else(|_| Value::from(cell))
+}
+
+fn decode_csv(text: &str) -> Result<Vec<(GlobalTableKey, Value)>, Error> {
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes())
+        .into_records();
+    let Some(header) = records.next().transpose()? else {
+        return Ok(vec![]);
+    };
+    if header.get(0) != Some("key") {
+        return Err(Error::NoKeyColumn);
+    }
+    let single_value = header.len() == 2 && &header[1] == VALUE_COLUMN;
+
+    records
+        .map(|record| {
+            let record = record?;
+            let mut cells = record.iter();
+            let key = cells.next().unwrap_or_default();
+            let key = match read_cell(key) {
+                Value::Number(number) if number.is_i64() => {
+                    GlobalTableKey::from(number.as_i64().unwrap_or_default())
+                }
+                Value::String(key) => GlobalTableKey::from(key),
+                _ => GlobalTableKey::from(key.to_string()),
+            };
+            if single_value {
+                let value = cells.next().filter(|cell| !cell.is_empty());
+                return Ok((key, value.map(read_cell).unwrap_or_default()));
+            }
+            let fields = header
+                .iter()
+                .skip(1)
+                .zip(cells)
+                .filter(|(_, cell)| !cell.is_empty())
+                .map(|(column, cell)| (column.to_string(), read_cell(cell)))
+                .collect();
+            Ok((key, Value::Object(fields)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn csv_round_trip() {
+        let rows = vec![
+            (
+                GlobalTableKey::from(1),
+                json!({ "title": "write, \"docs\"", "done": false, "tags": ["a"] }),
+            ),
+            (
+                GlobalTableKey::from("next"),
+                json!({ "title": "line\nbreak" }),
+            ),
+        ];
+        let csv = encode(&rows, Format::Csv).expect("encode");
+        let decoded = decode(&csv, Format::Csv).expect("decode");
+        assert_eq!(decoded.len(), 2);
+        assert!(matches!(decoded[0].0, GlobalTableKey::Int(1)));
+        assert_eq!(decoded[0].1, rows[0].1);
+        assert!(matches!(&decoded[1].0, GlobalTableKey::Str(key) if key == "next"));
+        assert_eq!(decoded[1].1, rows[1].1);
+    }
+
+    #[test]
+    fn csv_keeps_types() {
+        let rows = vec![
+            (GlobalTableKey::from("123"), json!("123")),
+            (GlobalTableKey::from(123), json!(true)),
+            (GlobalTableKey::from("true"), json!("true")),
+            (GlobalTableKey::from(""), json!("")),
+            (GlobalTableKey::from("plain"), json!(null)),
+            (GlobalTableKey::from("object"), json!({ "value": 1 })),
+        ];
+        let csv = encode(&rows, Format::Csv).expect("encode");
+        assert_eq!(decode(&csv, Format::Csv).expect("decode"), rows);
+
+        let rows = vec![(
+            GlobalTableKey::from(1),
+            json!({ "id": "007", "count": 7, "note": null }),
+        )];
+        let csv = encode(&rows, Format::Csv).expect("encode");
+        assert_eq!(decode(&csv, Format::Csv).expect("decode"), rows);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let rows = vec![(GlobalTableKey::from("a"), json!([1, 2, 3]))];
+        let text = encode(&rows, Format::Json).expect("encode");
+        let decoded = decode(&text, Format::Json).expect("decode");
+        assert_eq!(decoded[0].1, rows[0].1);
+    }
+}
@@ -0,0 +1,87 @@
+//! lilguy's runtime, for embedding in other programs and for integration tests.
+//!
+//! a [`Runtime`] runs one app: [`Runtime::start_services`] opens its [`Database`] and
+//! templates, and [`Runtime::start`] loads the app and starts the watcher. the lua state
+//! it builds has every global the `lilguy` binary provides, from the `register` functions
+//! in [`runtime`], and then from any functions given to [`Runtime::register`]. requests are
+//! matched with the app's [`Routes`].
+#[cfg(all(feature = "luajit", feature = "luau"))]
+compile_error!(
+    "the luajit and luau features can't both be enabled; \
+     build luau with --no-default-features --features luau"
+);
+#[cfg(not(any(feature = "luajit", feature = "luau")))]
+compile_error!("enable the luajit or luau feature");
+
+pub mod command;
+pub mod database;
+pub mod platform;
+pub mod repl;
+pub mod routes;
+pub mod runtime;
+pub mod template;
+pub mod watch;
+pub mod workspace;
+
+use parking_lot::Mutex;
+use reedline::ExternalPrinter;
+use std::sync::Arc;
+use tracing_subscriber::fmt::MakeWriter;
+
+pub use database::Database;
+pub use routes::Routes;
+pub use runtime::Runtime;
+
+/// where log lines go: a writer, or the shell's printer while the shell is running
+#[derive(Clone)]
+pub struct Output {
+    writer: Arc<Mutex<Box<dyn std::io::Write + Send + Sync>>>,
+    printer: Arc<Mutex<Option<ExternalPrinter<String>>>>,
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output").finish()
+    }
+}
+
+impl Output {
+    pub fn new(writer: impl std::io::Write + Send + Sync + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            printer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_printer(&self, printer: ExternalPrinter<String>) {
+        *self.printer.lock() = Some(printer);
+    }
+}
+
+impl std::io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(printer) = self.printer.lock().as_ref() {
+            printer
+                .print(String::from_utf8_lossy(buf).to_string())
+                .map_err(|_| std::io::Error::other("failed to write to external printer"))?;
+            Ok(buf.len())
+        } else {
+            self.writer.lock().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.printer.lock().is_none() {
+            self.writer.lock().flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl MakeWriter<'_> for Output {
+    type Writer = Self;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -1,23 +1,11 @@
-mod command;
-mod database;
-mod repl;
-mod routes;
-mod runtime;
-mod template;
-mod watch;
-
+use clap::Parser;
 use eyre::Result;
 use mimalloc::MiMalloc;
-use parking_lot::Mutex;
-use reedline::ExternalPrinter;
-use std::{io::IsTerminal, sync::Arc, time::Duration};
+use std::{io::IsTerminal, time::Duration};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
-use tracing_subscriber::{
-    fmt::{format::FmtSpan, MakeWriter},
-    EnvFilter,
-};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-use command::Args;
+use lilguy::{command::Args, Output};
 
 #[cfg(target_os = "windows")]
 use enable_ansi_support::enable_ansi_support;
@@ -25,56 +13,6 @@ use enable_ansi_support::enable_ansi_support;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-#[derive(Clone)]
-pub struct Output {
-    writer: Arc<Mutex<Box<dyn std::io::Write + Send + Sync>>>,
-    printer: Arc<Mutex<Option<ExternalPrinter<String>>>>,
-}
-
-impl std::fmt::Debug for Output {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Output").finish()
-    }
-}
-
-impl Output {
-    pub fn set_printer(&self, printer: ExternalPrinter<String>) {
-        *self.printer.lock() = Some(printer);
-    }
-}
-
-impl std::io::Write for Output {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if let Some(printer) = self.printer.lock().as_ref() {
-            printer
-                .print(String::from_utf8_lossy(buf).to_string())
-                .map_err(|_| {
-                    std::io::Error::other(
-                        "failed to write to external printer",
-                    )
-                })?;
-            Ok(buf.len())
-        } else {
-            self.writer.lock().write(buf)
-        }
-    }
-
-    fn flush(&mut self) -> std::io::Result<()> {
-        if self.printer.lock().is_none() {
-            self.writer.lock().flush()?;
-        }
-        Ok(())
-    }
-}
-
-impl MakeWriter<'_> for Output {
-    type Writer = Self;
-
-    fn make_writer(&self) -> Self::Writer {
-        self.clone()
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     #[cfg(target_os = "windows")]
@@ -82,13 +20,10 @@ async fn main() -> Result<()> {
 
     color_eyre::install()?;
 
-    let output = Output {
-        writer: Arc::new(Mutex::new(Box::new(std::io::stderr()))),
-        printer: Arc::new(Mutex::new(None)),
-    };
+    let output = Output::new(std::io::stderr());
     init_tracing_subscriber(output.clone());
 
-    let args = Args::new();
+    let args = Args::parse();
     let token = CancellationToken::new();
     let tracker = TaskTracker::new();
 
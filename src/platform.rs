@@ -0,0 +1,130 @@
+// path handling that differs between platforms.
+//
+// on windows, canonicalize returns verbatim paths (\\?\C:\app) that don't compare equal
+// to the paths file watchers report, paths longer than 260 characters need the verbatim
+// prefix to be opened at all, and file extensions are case-insensitive.
+use std::path::{Path, PathBuf};
+
+/// the longest path windows accepts without the \\?\ prefix
+#[cfg_attr(not(windows), allow(dead_code))]
+const MAX_PATH: usize = 260;
+
+/// remove the \\?\ prefix from a path when it isn't needed
+pub fn simplify(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let text = path.to_string_lossy();
+        if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+            return PathBuf::from(format!(r"\\{rest}"));
+        }
+        if let Some(rest) = text.strip_prefix(r"\\?\") {
+            let is_disk = rest.as_bytes().get(1) == Some(&b':');
+            if is_disk && rest.len() < MAX_PATH {
+                return PathBuf::from(rest);
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
+/// add the \\?\ prefix to absolute paths that are too long for windows without it
+pub fn long_path(path: PathBuf) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let text = path.to_string_lossy();
+        if path.is_absolute() && text.len() >= MAX_PATH && !text.starts_with(r"\\?\") {
+            // verbatim paths are not normalized, so they must use backslashes
+            let text = text.replace('/', r"\");
+            return match text.strip_prefix(r"\\") {
+                Some(unc) => PathBuf::from(format!(r"\\?\UNC\{unc}")),
+                None => PathBuf::from(format!(r"\\?\{text}")),
+            };
+        }
+    }
+    path
+}
+
+/// true if path has the extension, ignoring ascii case (Page.LUA is a lua file)
+pub fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension.trim_start_matches('.')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_ignores_case() {
+        assert!(has_extension(Path::new("app.lua"), "lua"));
+        assert!(has_extension(Path::new("APP.LUA"), "lua"));
+        assert!(has_extension(Path::new("notes.Md"), ".md"));
+        assert!(!has_extension(Path::new("app.luac"), "lua"));
+        assert!(!has_extension(Path::new("lua"), "lua"));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn paths_are_unchanged_elsewhere() {
+        let path = PathBuf::from(format!("/{}", "a".repeat(300)));
+        assert_eq!(long_path(path.clone()), path);
+        assert_eq!(simplify(&path), path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn simplify_strips_verbatim_prefix() {
+        assert_eq!(simplify(Path::new(r"\\?\C:\app")), PathBuf::from(r"C:\app"));
+        assert_eq!(
+            simplify(Path::new(r"\\?\UNC\server\share\app")),
+            PathBuf::from(r"\\server\share\app")
+        );
+        assert_eq!(simplify(Path::new(r"C:\app")), PathBuf::from(r"C:\app"));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn long_paths_get_verbatim_prefix() {
+        let long = format!(r"C:\{}", "a".repeat(300));
+        assert_eq!(
+            long_path(PathBuf::from(&long)),
+            PathBuf::from(format!(r"\\?\{long}"))
+        );
+        assert_eq!(
+            long_path(PathBuf::from(r"C:\app")),
+            PathBuf::from(r"C:\app")
+        );
+        let unc = format!(r"\\server\share\{}", "a".repeat(300));
+        assert_eq!(
+            long_path(PathBuf::from(&unc)),
+            PathBuf::from(format!(r"\\?\UNC\server\share\{}", "a".repeat(300)))
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn walkdir_follows_junctions() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let target = dir.path().join("target");
+        std::fs::create_dir(&target).expect("create target");
+        std::fs::write(target.join("page.LUA"), "").expect("write file");
+        let junction = dir.path().join("junction");
+        let status = std::process::Command::new("cmd")
+            .args(["/C", "mklink", "/J"])
+            .arg(&junction)
+            .arg(&target)
+            .status()
+            .expect("mklink");
+        assert!(status.success());
+
+        let found: Vec<PathBuf> = walkdir::WalkDir::new(long_path(dir.path().to_path_buf()))
+            .follow_links(true)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| has_extension(path, "lua"))
+            .collect();
+        assert_eq!(found.len(), 2, "found {found:?}");
+    }
+}
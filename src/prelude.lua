@@ -22,26 +22,229 @@ function Request:private_cookie(name)
     return self.cookie_jar:get_private(name)
 end
 
+-- what htmx says about a request, or nil if htmx didn't send it
+--
+--   local htmx = req:htmx()
+--   if htmx and htmx.target == "results" then ... end
+hx = {}
+
+function hx.request(req)
+    local headers = req.headers
+    if headers == nil or headers["HX-Request"] ~= "true" then
+        return nil
+    end
+    local function header(name)
+        local value = headers[name]
+        if value ~= "" then
+            return value
+        end
+    end
+    return {
+        boosted = headers["HX-Boosted"] == "true",
+        history_restore = headers["HX-History-Restore-Request"] == "true",
+        current_url = header("HX-Current-URL"),
+        prompt = header("HX-Prompt"),
+        target = header("HX-Target"),
+        trigger = header("HX-Trigger"),
+        trigger_name = header("HX-Trigger-Name"),
+    }
+end
+
+function Request:htmx()
+    return hx.request(self)
+end
+
+function Request:is_htmx()
+    return hx.request(self) ~= nil
+end
+
+Context = {}
+
+-- run fn after the response has been sent
+function Context:defer(fn)
+    table.insert(getmetatable(self).__deferred, fn)
+end
+
 Response = {}
 
-function Response:render(name, context)
-    local body = template:render(name, context)
+-- the request data templates can use as `request`, see session.rs for flash and
+-- csrf_token
+local function template_request(req)
+    if req == nil then
+        return nil
+    end
+    return {
+        path = req.path,
+        query = req.query,
+        flash = req.flash,
+        session = req.session,
+        csrf_token = req:csrf_token(),
+        htmx = hx.request(req),
+    }
+end
+
+local function template_context(res, context)
+    local ctx = {}
+    for k, v in pairs(context or {}) do
+        ctx[k] = v
+    end
+    if ctx.request == nil then
+        ctx.request = template_request(res.request)
+    end
+    return ctx
+end
+
+-- islands from res:island go before </body>, or at the end of a page without one
+local function insert_islands(body, islands)
+    local at
+    for pos in body:gmatch("()</body>") do
+        at = pos
+    end
+    if at == nil then
+        return body .. islands
+    end
+    return body:sub(1, at - 1) .. islands .. body:sub(at)
+end
+
+-- res:render(name, context, { layout = "other.html" })
+-- the layout defaults to template.layout, pass layout = false to render without one
+function Response:render(name, context, options)
+    local layout = template.layout
+    if options and options.layout ~= nil then
+        layout = options.layout
+    end
+    local body = template:render(name, template_context(self, context), layout or nil)
     if body then
-        if self.headers["Content-Type"] == nil then
+        if self.headers["Content-Type"] == "" then
             self.headers["Content-Type"] = "text/html"
         end
         self.body = body
+        self:add_islands()
     else
         self.status = 500
         self.body = "Error rendering template"
     end
 end
 
+function Response:render_block(name, block, context)
+    local body = template:render_block(name, block, template_context(self, context))
+    if self.headers["Content-Type"] == "" then
+        self.headers["Content-Type"] = "text/html"
+    end
+    self.body = body
+    self:add_islands()
+end
+
+-- res:island("cart", cart) puts cart in the rendered page as json; see runtime/island.rs
+function Response:island(name, data)
+    self.islands = (self.islands or "") .. json.island(name, data)
+    if type(self.body) == "string" and self.body:find("</body>", 1, true) then
+        self:add_islands()
+    end
+end
+
+function Response:add_islands()
+    if self.islands then
+        self.body = insert_islands(self.body, self.islands)
+        self.islands = nil
+    end
+end
+
 function Response:redirect(url)
     self.status = 302
     self.headers["Location"] = url
 end
 
+-- res:early_hints({ "/app.css", { href = "/app.js", as = "script" } })
+-- adds Link headers so browsers start loading the assets before they read the page.
+-- the server can't send a 103 Early Hints response ahead of this one, but proxies and
+-- CDNs that send early hints make them from these headers.
+function Response:early_hints(links)
+    for _, link in ipairs(links) do
+        if type(link) == "string" then
+            link = { href = link }
+        end
+        local as = link.as
+        if as == nil then
+            local ext = link.href:match("%.(%w+)$")
+            as = ({ css = "style", js = "script", mjs = "script", woff2 = "font", woff = "font" })[ext]
+        end
+        local value = "<" .. link.href .. ">; rel=" .. (link.rel or "preload")
+        if as then
+            value = value .. "; as=" .. as
+        end
+        if as == "font" or link.crossorigin then
+            value = value .. "; crossorigin"
+        end
+        self.headers["Link"] = value
+    end
+end
+
+local function replace_header(res, name, value)
+    res.headers[name] = nil
+    res.headers[name] = value
+end
+
+local hx_trigger_headers = {
+    receive = "HX-Trigger",
+    settle = "HX-Trigger-After-Settle",
+    swap = "HX-Trigger-After-Swap",
+}
+
+-- res:hx_trigger("itemAdded", { id = 3 }) fires an event in the browser when htmx
+-- receives the response, or after it swaps or settles with when = "swap" or "settle".
+-- each call adds an event.
+function Response:hx_trigger(event, payload, when)
+    local header = hx_trigger_headers[when or "receive"]
+    if header == nil then
+        error("hx_trigger: when must be receive, swap or settle", 2)
+    end
+    self.hx_events = self.hx_events or {}
+    local events = self.hx_events[header] or {}
+    self.hx_events[header] = events
+    events[event] = payload == nil and {} or payload
+    replace_header(self, header, json.encode(events))
+end
+
+-- send the browser to url with a full page load
+function Response:hx_redirect(url)
+    replace_header(self, "HX-Redirect", url)
+end
+
+-- load url, or { path = url, target = "#main" }, as if it were a boosted link
+function Response:hx_location(location)
+    if type(location) == "table" then
+        location = json.encode(location)
+    end
+    replace_header(self, "HX-Location", location)
+end
+
+-- the url for the browser's history, or false to leave it alone
+function Response:hx_push_url(url)
+    replace_header(self, "HX-Push-Url", tostring(url))
+end
+
+function Response:hx_replace_url(url)
+    replace_header(self, "HX-Replace-Url", tostring(url))
+end
+
+function Response:hx_refresh()
+    replace_header(self, "HX-Refresh", "true")
+end
+
+-- swap the response into another element, or in another way, than the request asked for
+function Response:hx_retarget(selector)
+    replace_header(self, "HX-Retarget", selector)
+end
+
+function Response:hx_reswap(swap)
+    replace_header(self, "HX-Reswap", swap)
+end
+
+function Response:hx_reselect(selector)
+    replace_header(self, "HX-Reselect", selector)
+end
+
 function Response:json(data)
     self.headers["Content-Type"] = "application/json"
     self.body = json.encode(data)
@@ -1,47 +1,682 @@
+pub mod analytics;
+pub mod assets;
+pub mod bots;
+pub mod firewall;
+mod listing;
+pub mod live;
+pub mod middleware;
+pub mod mounts;
+mod openapi;
+pub mod rooms;
+pub mod rpc;
+pub mod socket_limits;
+pub mod socketio;
+pub mod spa;
+pub mod yjs;
+
+use indexmap::IndexMap;
 use mlua::prelude::*;
+use parking_lot::RwLock;
 use path_tree::PathTree;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use crate::runtime::{cache::CachePolicy, slug};
 
+use firewall::Rules;
+use middleware::Middleware;
+use openapi::ApiRoute;
+use yjs::YjsRoute;
+
+/// the handlers for a single route pattern. a handler registered for a specific
+/// method wins over one registered with routes[pattern] = handler.
 #[derive(Debug)]
-pub struct Routes {
-    tree: PathTree<LuaFunction>,
+struct Route {
+    pattern: Arc<str>,
+    handler: Option<LuaFunction>,
+    methods: IndexMap<String, LuaFunction>,
+    /// set by routes:raw(), so requests are passed on without being parsed
+    raw: bool,
+    /// the param holding the slug, for routes:slug()
+    slug: Option<String>,
+}
+
+impl Route {
+    fn handler(&self, method: &str) -> Option<&LuaFunction> {
+        self.methods
+            .get(method)
+            .or_else(|| {
+                (method == "HEAD")
+                    .then(|| self.methods.get("GET"))
+                    .flatten()
+            })
+            .or(self.handler.as_ref())
+    }
+
+    /// the methods there are handlers for, for the allow header of a 405
+    fn allowed(&self) -> Vec<String> {
+        let mut allowed: Vec<String> = self.methods.keys().cloned().collect();
+        if self.methods.contains_key("GET") && !self.methods.contains_key("HEAD") {
+            allowed.push("HEAD".to_string());
+        }
+        allowed
+    }
+}
+
+/// routes.get, routes.post and the rest, for handlers of one method:
+/// routes.get["/users/:id"] = function(req, res) ... end
+const METHODS: [(&str, &str); 7] = [
+    ("get", "GET"),
+    ("head", "HEAD"),
+    ("post", "POST"),
+    ("put", "PUT"),
+    ("patch", "PATCH"),
+    ("delete", "DELETE"),
+    ("options", "OPTIONS"),
+];
+
+#[derive(Debug, Clone)]
+struct MethodRoutes {
+    routes: Routes,
+    method: &'static str,
+}
+
+/// true if a pattern like "/admin/*", "/search" or "*" covers path, for the routes
+/// methods that apply to a path and what is under it rather than a single route
+pub fn covers(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => spa::strip_prefix(prefix, path).is_some(),
+        None => pattern.trim_end_matches('/') == path.trim_end_matches('/'),
+    }
+}
+
+/// what a request matched, with what handle_lua_request needs to know about the route
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    pub handler: LuaFunction,
+    /// the pattern that matched, or None if the handler is routes.not_found
+    pub pattern: Option<Arc<str>>,
+    pub params: Vec<(String, String)>,
+    pub raw: bool,
+    pub cache_policy: Option<CachePolicy>,
+    /// the slug param, if the route was added with routes:slug()
+    pub slug: Option<String>,
+    /// when the path matched but the method didn't, the methods it has handlers for
+    pub allowed: Vec<String>,
+}
+
+/// the routes lua registers. the runtime keeps a handle to the same table, so requests
+/// are matched without going through the lua state.
+#[derive(Debug, Clone)]
+pub struct Routes(Arc<RwLock<RouteTable>>);
+
+#[derive(Debug)]
+struct RouteTable {
+    tree: PathTree<usize>,
+    routes: Vec<Route>,
+    patterns: HashMap<String, usize>,
     not_found: LuaFunction,
+    cache: HashMap<String, CachePolicy>,
+    api: Vec<ApiRoute>,
+    rpc: HashMap<String, LuaFunction>,
+    yjs_tree: PathTree<usize>,
+    yjs: Vec<(String, Option<LuaFunction>)>,
+    socketio: Option<(String, LuaFunction)>,
+    /// routes:spa() directories by prefix
+    spa: Vec<(String, PathBuf)>,
+    /// static[prefix] files and directories
+    mounts: Vec<(String, PathBuf)>,
+    meta: IndexMap<String, LuaTable>,
+    middleware: Vec<Middleware>,
+    /// routes:block_bots() patterns
+    block_bots: Vec<String>,
+    /// routes:firewall() rules by pattern
+    firewall: Vec<(String, Arc<Rules>)>,
 }
 
 impl Routes {
     pub fn new(not_found: LuaFunction) -> Self {
-        Self {
+        Self(Arc::new(RwLock::new(RouteTable {
             tree: PathTree::new(),
+            routes: Vec::new(),
+            patterns: HashMap::new(),
             not_found,
+            cache: HashMap::new(),
+            api: Vec::new(),
+            rpc: HashMap::new(),
+            yjs_tree: PathTree::new(),
+            yjs: Vec::new(),
+            socketio: None,
+            spa: Vec::new(),
+            mounts: Vec::new(),
+            meta: IndexMap::new(),
+            middleware: Vec::new(),
+            block_bots: Vec::new(),
+            firewall: Vec::new(),
+        })))
+    }
+
+    /// the handler for a request, or routes.not_found
+    pub fn find(&self, method: &str, path: &str) -> RouteMatch {
+        let table = self.0.read();
+        let Some((route, found)) = table
+            .tree
+            .find(path)
+            .and_then(|(index, found)| Some((table.routes.get(*index)?, found)))
+        else {
+            return table.not_found(Vec::new());
+        };
+        let Some(handler) = route.handler(method) else {
+            return table.not_found(route.allowed());
+        };
+        let params = found
+            .params_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        RouteMatch {
+            handler: handler.clone(),
+            pattern: Some(route.pattern.clone()),
+            params,
+            raw: route.raw,
+            cache_policy: table.cache.get(&*route.pattern).cloned(),
+            slug: route.slug.clone(),
+            allowed: Vec::new(),
+        }
+    }
+
+    /// how many patterns have handlers
+    pub fn count(&self) -> usize {
+        self.0.read().routes.len()
+    }
+
+    /// the openapi document and swagger ui, served when there are api routes
+    /// and nothing else is registered at /openapi.json or /openapi
+    pub fn openapi(&self, path: &str) -> Option<(&'static str, String)> {
+        let table = self.0.read();
+        if table.api.is_empty() {
+            return None;
+        }
+        match path {
+            "/openapi.json" => {
+                let document = openapi::document("API", env!("CARGO_PKG_VERSION"), &table.api);
+                Some(("application/json", document.to_string()))
+            }
+            "/openapi" => Some(("text/html", openapi::SWAGGER_UI.to_string())),
+            _ => None,
+        }
+    }
+
+    /// the handler for an rpc procedure, path is /package.Service/Method
+    pub fn rpc(&self, path: &str) -> Option<LuaFunction> {
+        self.0.read().rpc.get(path.trim_start_matches('/')).cloned()
+    }
+
+    /// the patterns marked with routes:meta(pattern, { sitemap = ... }), and their sitemap option
+    pub fn sitemap(&self) -> Vec<(String, LuaValue)> {
+        self.0
+            .read()
+            .meta
+            .iter()
+            .filter_map(|(pattern, meta)| {
+                let sitemap = meta.get::<LuaValue>("sitemap").ok()?;
+                let marked = !matches!(sitemap, LuaValue::Nil | LuaValue::Boolean(false));
+                marked.then(|| (pattern.clone(), sitemap))
+            })
+            .collect()
+    }
+
+    /// the yjs endpoint for a websocket request's path
+    pub fn yjs(&self, path: &str) -> Option<YjsRoute> {
+        let table = self.0.read();
+        let (index, found) = table.yjs_tree.find(path)?;
+        let (pattern, authorize) = table.yjs.get(*index)?;
+        let params: Vec<(String, String)> = found
+            .params_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let doc = params
+            .iter()
+            .find(|(name, _)| name == "doc")
+            .map_or_else(|| path.to_string(), |(_, doc)| doc.clone());
+        Some(YjsRoute {
+            pattern: pattern.clone(),
+            params,
+            doc,
+            authorize: authorize.clone(),
+        })
+    }
+
+    /// the routes:socketio() handler, if it is served at path
+    pub fn socketio(&self, path: &str) -> Option<LuaFunction> {
+        let table = self.0.read();
+        let (pattern, handler) = table.socketio.as_ref()?;
+        (pattern.trim_end_matches('/') == path.trim_end_matches('/')).then(|| handler.clone())
+    }
+
+    /// the routes:use() functions for a path, in the order they were added
+    pub fn middleware(&self, path: &str) -> Vec<LuaFunction> {
+        self.0
+            .read()
+            .middleware
+            .iter()
+            .filter(|middleware| middleware.applies(path))
+            .map(|middleware| middleware.function.clone())
+            .collect()
+    }
+
+    /// true if path is under a routes:block_bots() pattern
+    pub fn blocks_bots(&self, path: &str) -> bool {
+        self.0
+            .read()
+            .block_bots
+            .iter()
+            .any(|pattern| covers(pattern, path))
+    }
+
+    /// the routes:block_bots() patterns, for robots.txt
+    pub fn blocked_bots(&self) -> Vec<String> {
+        self.0.read().block_bots.clone()
+    }
+
+    /// serve a single page app from dir for GETs under prefix that nothing else matches
+    pub fn add_spa(&self, prefix: &str, dir: PathBuf) {
+        let mut table = self.0.write();
+        table.spa.retain(|(existing, _)| existing != prefix);
+        table.spa.push((prefix.to_string(), dir));
+    }
+
+    /// the routes:firewall() rules for path, from the most specific pattern that covers it
+    pub fn firewall(&self, path: &str) -> Option<Arc<Rules>> {
+        self.0
+            .read()
+            .firewall
+            .iter()
+            .filter(|(pattern, _)| covers(pattern, path))
+            .max_by_key(|(pattern, _)| pattern.trim_end_matches(['*', '/']).len())
+            .map(|(_, rules)| rules.clone())
+    }
+
+    /// serve dir, or a file, at prefix; see routes/mounts.rs
+    pub fn add_mount(&self, prefix: &str, path: PathBuf) {
+        let mut table = self.0.write();
+        table.mounts.retain(|(existing, _)| existing != prefix);
+        table.mounts.push((prefix.to_string(), path));
+    }
+
+    pub fn remove_mount(&self, prefix: &str) {
+        self.0
+            .write()
+            .mounts
+            .retain(|(existing, _)| existing != prefix);
+    }
+
+    /// what static[prefix] serves
+    pub fn mount(&self, prefix: &str) -> Option<PathBuf> {
+        let table = self.0.read();
+        let (_, path) = table
+            .mounts
+            .iter()
+            .find(|(existing, _)| existing == prefix)?;
+        Some(path.clone())
+    }
+
+    /// the static mount for a path, by the longest prefix it is under
+    pub fn mount_for(&self, path: &str) -> Option<(String, PathBuf)> {
+        self.0
+            .read()
+            .mounts
+            .iter()
+            .filter(|(prefix, _)| spa::strip_prefix(prefix, path).is_some())
+            .max_by_key(|(prefix, _)| prefix.trim_end_matches('/').len())
+            .cloned()
+    }
+
+    /// the single page app for a path, by the longest prefix it is under
+    pub fn spa(&self, path: &str) -> Option<(String, PathBuf)> {
+        self.0
+            .read()
+            .spa
+            .iter()
+            .filter(|(prefix, _)| spa::strip_prefix(prefix, path).is_some())
+            .max_by_key(|(prefix, _)| prefix.trim_end_matches('/').len())
+            .cloned()
+    }
+}
+
+impl RouteTable {
+    fn not_found(&self, allowed: Vec<String>) -> RouteMatch {
+        RouteMatch {
+            handler: self.not_found.clone(),
+            pattern: None,
+            params: Vec::new(),
+            raw: false,
+            cache_policy: None,
+            slug: None,
+            allowed,
         }
     }
 
-    pub fn find<'a, 'b>(&'a self, path: &'b str) -> (LuaFunction, Option<path_tree::Path<'a, 'b>>) {
-        match self.tree.find(path) {
-            Some((handler, route)) => (handler.clone(), Some(route)),
-            None => (self.not_found.clone(), None),
+    fn route_mut(&mut self, pattern: &str) -> LuaResult<&mut Route> {
+        if !pattern.starts_with("/") {
+            return Err(LuaError::runtime("routes must start with /"));
         }
+        let index = match self.patterns.get(pattern) {
+            Some(index) => *index,
+            None => {
+                let index = self.routes.len();
+                // each pattern's tree id is its index, unless it matches the same paths as
+                // one already there, like /users/:id and /users/:name. insert replaces that
+                // one, so it is put back.
+                let id = self.tree.insert(pattern, index);
+                if id != index {
+                    let existing = Arc::clone(&self.routes[id].pattern);
+                    self.tree.insert(&existing, id);
+                    return Err(LuaError::runtime(format!(
+                        "route {pattern} conflicts with {existing}"
+                    )));
+                }
+                self.routes.push(Route {
+                    pattern: pattern.into(),
+                    handler: None,
+                    methods: IndexMap::new(),
+                    raw: false,
+                    slug: None,
+                });
+                self.patterns.insert(pattern.to_string(), index);
+                index
+            }
+        };
+        Ok(&mut self.routes[index])
     }
 }
 
 impl LuaUserData for Routes {
     fn add_fields<'lua, F: LuaUserDataFields<Self>>(fields: &mut F) {
         fields.add_field_method_set("not_found", |_, this, function: LuaFunction| {
-            this.not_found = function;
+            this.0.write().not_found = function;
             Ok(())
         });
+        for (name, method) in METHODS {
+            fields.add_field_method_get(name, move |_, this| {
+                Ok(MethodRoutes {
+                    routes: this.clone(),
+                    method,
+                })
+            });
+        }
     }
 
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        methods.add_meta_method_mut(
+        // routes:cache(pattern, { ttl = 300, vary = { "accept-encoding" }, credentials = false })
+        methods.add_method(
+            "cache",
+            |_, this, (pattern, options): (String, Option<LuaTable>)| {
+                if !pattern.starts_with("/") {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                let policy = CachePolicy::from_lua_options(options)?;
+                this.0.write().cache.insert(pattern, policy);
+                Ok(())
+            },
+        );
+
+        // routes:api("GET /users/:id", { summary = "...", params = { id = "integer" } }, handler)
+        methods.add_method(
+            "api",
+            |lua, this, (route, spec, handler): (String, LuaTable, LuaFunction)| {
+                let Some((method, pattern)) = route.trim().split_once(' ') else {
+                    return Err(LuaError::runtime(
+                        "api routes must look like \"METHOD /path\"",
+                    ));
+                };
+                let method = method.to_uppercase();
+                let pattern = pattern.trim().to_string();
+                let spec: serde_json::Value = lua.from_value(LuaValue::Table(spec))?;
+
+                let mut table = this.0.write();
+                table
+                    .route_mut(&pattern)?
+                    .methods
+                    .insert(method.clone(), handler);
+                table.api.push(ApiRoute {
+                    method,
+                    pattern,
+                    spec,
+                });
+                Ok(())
+            },
+        );
+
+        // routes:rpc("greet.v1.GreetService/Greet", function(input, headers) ... end)
+        methods.add_method(
+            "rpc",
+            |_, this, (procedure, handler): (String, LuaFunction)| {
+                let procedure = procedure.trim_start_matches('/');
+                match procedure.split_once('/') {
+                    Some((service, method)) if !service.is_empty() && !method.is_empty() => {}
+                    _ => {
+                        return Err(LuaError::runtime(
+                            "rpc procedures must look like \"package.Service/Method\"",
+                        ))
+                    }
+                }
+                this.0.write().rpc.insert(procedure.to_string(), handler);
+                Ok(())
+            },
+        );
+
+        // routes:raw("/webhook", function(req, res) ... end)
+        // the request's query, cookies and body are not parsed; see create_raw_request
+        methods.add_method(
+            "raw",
+            |_, this, (pattern, handler): (String, LuaFunction)| {
+                let mut table = this.0.write();
+                let route = table.route_mut(&pattern)?;
+                route.handler = Some(handler);
+                route.raw = true;
+                Ok(())
+            },
+        );
+
+        // routes:slug("/blog/:slug", function(req, res) ... end), see runtime/slug.rs
+        methods.add_method(
+            "slug",
+            |_, this, (pattern, handler): (String, LuaFunction)| {
+                let Some(param) = slug::param(&pattern) else {
+                    return Err(LuaError::runtime(
+                        "slug routes need a param, like /blog/:slug",
+                    ));
+                };
+                let mut table = this.0.write();
+                let route = table.route_mut(&pattern)?;
+                route.handler = Some(handler);
+                route.slug = Some(param);
+                Ok(())
+            },
+        );
+
+        // routes:use(function(req, res, next) ... end), or routes:use("/admin", function ...)
+        // see routes/middleware.rs
+        methods.add_method(
+            "use",
+            |_, this, (first, second): (LuaValue, Option<LuaFunction>)| {
+                let middleware = match (first, second) {
+                    (LuaValue::Function(function), None) => Middleware {
+                        prefix: None,
+                        function,
+                    },
+                    (LuaValue::String(prefix), Some(function)) => {
+                        let prefix = prefix.to_str()?.to_string();
+                        if !prefix.starts_with("/") {
+                            return Err(LuaError::runtime("routes must start with /"));
+                        }
+                        Middleware {
+                            prefix: Some(prefix),
+                            function,
+                        }
+                    }
+                    _ => {
+                        return Err(LuaError::runtime(
+                            "routes:use takes a function, or a prefix and a function",
+                        ))
+                    }
+                };
+                this.0.write().middleware.push(middleware);
+                Ok(())
+            },
+        );
+
+        // routes:block_bots("/admin/*"), see routes/bots.rs
+        methods.add_method("block_bots", |_, this, pattern: String| {
+            if !pattern.starts_with("/") && pattern != "*" {
+                return Err(LuaError::runtime("routes must start with /"));
+            }
+            let mut table = this.0.write();
+            if !table.block_bots.contains(&pattern) {
+                table.block_bots.push(pattern);
+            }
+            Ok(())
+        });
+
+        // routes:firewall("/admin/*", { allow = { "10.8.0.0/16" } }), see routes/firewall.rs
+        methods.add_method(
+            "firewall",
+            |_, this, (pattern, rules): (String, LuaTable)| {
+                if !pattern.starts_with("/") && pattern != "*" {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                let allow: Vec<String> = rules.get::<Option<_>>("allow")?.unwrap_or_default();
+                let deny: Vec<String> = rules.get::<Option<_>>("deny")?.unwrap_or_default();
+                let rules = Rules::parse(&allow, &deny).map_err(LuaError::external)?;
+                let mut table = this.0.write();
+                table.firewall.retain(|(existing, _)| *existing != pattern);
+                table.firewall.push((pattern, Arc::new(rules)));
+                Ok(())
+            },
+        );
+
+        // routes:meta("/about", { sitemap = true }), see runtime/seo.rs
+        methods.add_method("meta", |_, this, (pattern, meta): (String, LuaTable)| {
+            if !pattern.starts_with("/") {
+                return Err(LuaError::runtime("routes must start with /"));
+            }
+            this.0.write().meta.insert(pattern, meta);
+            Ok(())
+        });
+
+        // routes:yjs("/collab/:doc", function(req) return true end)
+        methods.add_method(
+            "yjs",
+            |_, this, (pattern, authorize): (String, Option<LuaFunction>)| {
+                if !pattern.starts_with("/") {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                let mut table = this.0.write();
+                let index = table.yjs.len();
+                let _ = table.yjs_tree.insert(&pattern, index);
+                table.yjs.push((pattern, authorize));
+                Ok(())
+            },
+        );
+
+        // routes:socketio(function(socket, req) ... end, { path = "/socket.io/" })
+        methods.add_method(
+            "socketio",
+            |_, this, (handler, options): (LuaFunction, Option<LuaTable>)| {
+                let path = match options {
+                    Some(options) => options.get::<Option<String>>("path")?,
+                    None => None,
+                };
+                let path = path.unwrap_or_else(|| socketio::DEFAULT_PATH.to_string());
+                if !path.starts_with("/") {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                this.0.write().socketio = Some((path, handler));
+                Ok(())
+            },
+        );
+
+        // routes:spa("dist", { prefix = "/app" }), see routes/spa.rs
+        methods.add_method(
+            "spa",
+            |lua, this, (dir, options): (String, Option<LuaTable>)| {
+                let prefix = match options {
+                    Some(options) => options.get::<Option<String>>("prefix")?,
+                    None => None,
+                };
+                let prefix = prefix.unwrap_or_else(|| "/".to_string());
+                if !prefix.starts_with("/") {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                let root: String = lua.globals().get::<LuaTable>("lilguy")?.get("root")?;
+                this.add_spa(&prefix, PathBuf::from(root).join(dir));
+                Ok(())
+            },
+        );
+
+        // routes["/x"] = handler, or routes["/x"] = { GET = handler, POST = handler }
+        methods.add_meta_method(
             LuaMetaMethod::NewIndex,
-            |_, this, (key, value): (LuaString, LuaFunction)| {
+            |_, this, (key, value): (LuaString, LuaValue)| {
                 let key = key.to_str()?;
-                if !key.starts_with("/") {
-                    return Err(LuaError::runtime("routes must start with /"));
+                match value {
+                    LuaValue::Function(handler) => {
+                        this.0.write().route_mut(&key)?.handler = Some(handler);
+                    }
+                    LuaValue::Table(handlers) => {
+                        let handlers = handlers
+                            .pairs::<String, LuaFunction>()
+                            .collect::<LuaResult<Vec<_>>>()?;
+                        let mut table = this.0.write();
+                        let route = table.route_mut(&key)?;
+                        for (method, handler) in handlers {
+                            route.methods.insert(method.to_uppercase(), handler);
+                        }
+                    }
+                    _ => {
+                        return Err(LuaError::runtime(
+                            "routes are functions, or tables of functions by method",
+                        ))
+                    }
                 }
-                let size = this.tree.insert(&key, value);
-                Ok(size)
+                Ok(())
             },
         );
     }
 }
+
+impl LuaUserData for MethodRoutes {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(
+            LuaMetaMethod::NewIndex,
+            |_, this, (pattern, handler): (String, LuaFunction)| {
+                this.routes
+                    .0
+                    .write()
+                    .route_mut(&pattern)?
+                    .methods
+                    .insert(this.method.to_string(), handler);
+                Ok(())
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicting_patterns_are_errors() {
+        let lua = Lua::new();
+        let handler = lua.create_function(|_, ()| Ok(())).unwrap();
+        let routes = Routes::new(handler.clone());
+        let mut table = routes.0.write();
+        table.route_mut("/users/:id").unwrap().handler = Some(handler.clone());
+        assert!(table.route_mut("/users/:id").is_ok());
+        let err = table.route_mut("/users/:name").unwrap_err();
+        assert!(err.to_string().contains("conflicts with /users/:id"));
+        drop(table);
+        let found = routes.find("GET", "/users/1");
+        assert_eq!(found.pattern.as_deref(), Some("/users/:id"));
+    }
+}
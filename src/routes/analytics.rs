@@ -0,0 +1,519 @@
+// page views, counted by the server instead of a third-party script.
+//
+//   [analytics]
+//   enabled = true
+//   password = "hunter2"          # for /_admin/analytics, with any user name
+//   geoip = "geoip/country.csv"   # relative to app.lua
+//
+// a GET answered with an html page is a view. each records its path, the host it was
+// linked from, the visitor's country and a hash of their address and user agent, salted
+// with a secret that changes every day, so visitors are counted within a day without
+// being followed from one day to the next. addresses are never stored, and there are no
+// cookies.
+//
+// the country is looked up in the geoip csv, which is a list of ip ranges and their
+// countries like the IP2Location LITE or DB-IP lite country databases, or else taken
+// from the header a cdn in front of the app adds, like cf-ipcountry.
+//
+// /_admin/analytics?days=30 shows views and visitors by day, and the top pages, referrers
+// and countries. without a password it is only shown to requests made from this machine
+// directly: a request from localhost that a proxy forwarded, which says so in a forwarded,
+// x-forwarded-for or x-real-ip header, could be from anyone, so it is refused too. an app
+// behind a proxy that strips those headers has to set a password.
+use axum::{
+    extract::{Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, HOST, REFERER, USER_AGENT, WWW_AUTHENTICATE},
+        HeaderMap, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use parking_lot::Mutex;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{database::Database, runtime::http::client::ClientIp};
+
+pub const DASHBOARD_PATH: &str = "/_admin/analytics";
+const DEFAULT_DAYS: i64 = 30;
+/// how many pages, referrers and countries the dashboard lists
+const TOP: i64 = 20;
+/// headers cdns tell the app the visitor's country with
+const COUNTRY_HEADERS: [&str; 4] = [
+    "cf-ipcountry",
+    "cloudfront-viewer-country",
+    "x-vercel-ip-country",
+    "x-country-code",
+];
+
+const STYLE: &str = "body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 60em; \
+    padding: 0 1em; color: #222; } h1 { font-size: 1.4em; font-weight: 500; } \
+    h2 { font-size: 1.1em; font-weight: 500; margin-top: 2em; } \
+    .totals { display: flex; gap: 3em; } .totals b { display: block; font-size: 2em; font-weight: 500; } \
+    table { border-collapse: collapse; width: 100%; } \
+    th, td { text-align: left; padding: 0.35em 0.75em; border-bottom: 1px solid #eee; } \
+    th { color: #666; font-weight: 500; } td.n { text-align: right; width: 6em; } \
+    .bar { background: #dbe9fb; height: 1em; min-width: 1px; }";
+
+/// the [analytics] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AnalyticsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// the dashboard's password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// a csv of ip ranges and their countries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<PathBuf>,
+}
+
+impl AnalyticsConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+}
+
+/// ip ranges and their countries, sorted by where each starts. ipv4 addresses are kept as
+/// ipv4-mapped ipv6 ones.
+#[derive(Debug, Default)]
+struct GeoIp(Vec<(u128, u128, [u8; 2])>);
+
+/// an address in a geoip csv, written out or as a number
+fn range_bound(field: &str) -> Option<u128> {
+    let field = field.trim().trim_matches('"');
+    if let Ok(ip) = field.parse::<IpAddr>() {
+        return Some(ip_number(ip));
+    }
+    let number = field.parse::<u128>().ok()?;
+    match u32::try_from(number) {
+        Ok(v4) => Some(ip_number(IpAddr::from(v4.to_be_bytes()))),
+        Err(_) => Some(number),
+    }
+}
+
+fn ip_number(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl GeoIp {
+    fn parse(csv: &str) -> Self {
+        let mut ranges: Vec<(u128, u128, [u8; 2])> = csv
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(',');
+                let start = range_bound(fields.next()?)?;
+                let end = range_bound(fields.next()?)?;
+                let country = fields.next()?.trim().trim_matches('"').as_bytes();
+                let country: [u8; 2] = country.try_into().ok()?;
+                country
+                    .iter()
+                    .all(u8::is_ascii_alphabetic)
+                    .then(|| (start, end, country.map(|c| c.to_ascii_uppercase())))
+            })
+            .collect();
+        ranges.sort_unstable();
+        Self(ranges)
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let number = ip_number(ip);
+        let after = self.0.partition_point(|(start, _, _)| *start <= number);
+        let (_, end, country) = self.0.get(after.checked_sub(1)?)?;
+        (number <= *end).then(|| String::from_utf8_lossy(country).into_owned())
+    }
+}
+
+#[derive(Debug)]
+pub struct Analytics {
+    database: Database,
+    geoip: GeoIp,
+    password: Option<String>,
+    /// the day and the salt visitors are hashed with on it
+    salt: Mutex<(u64, [u8; 16])>,
+}
+
+impl Analytics {
+    /// the analytics for an app, with its geoip csv relative to root
+    pub fn new(config: &AnalyticsConfig, root: &Path, database: Database) -> Arc<Self> {
+        let geoip = match &config.geoip {
+            Some(file) => match std::fs::read_to_string(root.join(file)) {
+                Ok(csv) => GeoIp::parse(&csv),
+                Err(err) => {
+                    tracing::warn!(%err, file = %file.display(), "error reading geoip csv");
+                    GeoIp::default()
+                }
+            },
+            None => GeoIp::default(),
+        };
+        Arc::new(Self {
+            database,
+            geoip,
+            password: config.password.clone(),
+            salt: Mutex::new((0, [0; 16])),
+        })
+    }
+
+    fn visitor(&self, ip: IpAddr, user_agent: &str, now: u64) -> String {
+        let salt = {
+            let mut salt = self.salt.lock();
+            let day = now / 86400;
+            if salt.0 != day {
+                *salt = (day, rand::random());
+            }
+            salt.1
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(ip.to_string());
+        hasher.update(user_agent);
+        let digest = hasher.finalize();
+        digest[..8].iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+    }
+
+    fn country(&self, ip: IpAddr, headers: &HeaderMap) -> Option<String> {
+        self.geoip.country(ip).or_else(|| {
+            COUNTRY_HEADERS
+                .iter()
+                .filter_map(|name| headers.get(*name)?.to_str().ok())
+                .map(|country| country.trim().to_ascii_uppercase())
+                .find(|country| country.len() == 2 && country != "XX")
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// a page view, before it is known whether the response was a page
+#[derive(Debug)]
+struct View {
+    created: u64,
+    path: String,
+    referrer: Option<String>,
+    country: Option<String>,
+    visitor: String,
+}
+
+/// the host part of a referer header, unless it is the site itself
+fn referrer_host(headers: &HeaderMap) -> Option<String> {
+    let referer = headers.get(REFERER)?.to_str().ok()?;
+    let (_, rest) = referer.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+    let host = host.rsplit('@').next()?.to_ascii_lowercase();
+    let own = headers.get(HOST).and_then(|host| host.to_str().ok());
+    (!host.is_empty() && own.is_none_or(|own| !own.eq_ignore_ascii_case(&host))).then_some(host)
+}
+
+fn is_view(request: &Request) -> bool {
+    let headers = request.headers();
+    request.method() == Method::GET
+        && !request.uri().path().starts_with("/_")
+        // htmx swaps and prefetches aren't someone looking at a page
+        && !headers.contains_key("hx-request")
+        && headers
+            .get("sec-purpose")
+            .or_else(|| headers.get("purpose"))
+            .is_none_or(|purpose| !purpose.as_bytes().starts_with(b"prefetch"))
+}
+
+/// record the views of pages
+pub async fn record(
+    State(analytics): State<Arc<Analytics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let view = is_view(&request).then(|| {
+        let ClientIp(ip) = ClientIp::from_extensions(request.extensions());
+        let headers = request.headers();
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        let created = now();
+        View {
+            created,
+            path: request.uri().path().to_string(),
+            referrer: referrer_host(headers),
+            country: analytics.country(ip, headers),
+            visitor: analytics.visitor(ip, user_agent, created),
+        }
+    });
+    let response = next.run(request).await;
+    let is_page = response.status().is_success()
+        && response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/html"));
+    if let Some(view) = view.filter(|_| is_page) {
+        let database = analytics.database.clone();
+        tokio::spawn(async move {
+            let result = database
+                .call(move |conn| {
+                    conn.execute(
+                        "INSERT INTO lg_analytics (created, path, referrer, country, visitor) \
+                         VALUES (?, ?, ?, ?, ?)",
+                        params![
+                            view.created,
+                            view.path,
+                            view.referrer,
+                            view.country,
+                            view.visitor
+                        ],
+                    )?;
+                    Ok(())
+                })
+                .await;
+            if let Err(err) = result {
+                tracing::warn!(%err, "error recording a page view");
+            }
+        });
+    }
+    response
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DashboardQuery {
+    days: Option<i64>,
+}
+
+/// what the dashboard shows
+#[derive(Debug, Default)]
+struct Report {
+    days: i64,
+    views: i64,
+    visitors: i64,
+    by_day: Vec<(String, i64, i64)>,
+    pages: Vec<(String, i64)>,
+    referrers: Vec<(String, i64)>,
+    countries: Vec<(String, i64)>,
+}
+
+/// the password in a basic authorization header
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// headers a proxy adds to the requests it forwards
+const FORWARDING_HEADERS: [&str; 3] = ["forwarded", "x-forwarded-for", "x-real-ip"];
+
+/// true for a request from this machine that no proxy passed on
+fn local(ip: IpAddr, headers: &HeaderMap) -> bool {
+    ip.is_loopback()
+        && !FORWARDING_HEADERS
+            .iter()
+            .any(|header| headers.contains_key(*header))
+}
+
+/// /_admin/analytics
+pub async fn dashboard(
+    State(analytics): State<Arc<Analytics>>,
+    ClientIp(ip): ClientIp,
+    Query(query): Query<DashboardQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match &analytics.password {
+        Some(password) => {
+            let given = basic_password(&headers).unwrap_or_default();
+            let matches = Sha256::digest(given.as_bytes()) == Sha256::digest(password.as_bytes());
+            if !matches {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(WWW_AUTHENTICATE, "Basic realm=\"analytics\"")],
+                )
+                    .into_response();
+            }
+        }
+        None if !local(ip, &headers) => {
+            return (
+                StatusCode::FORBIDDEN,
+                "set a password in [analytics] to see this from elsewhere or through a proxy\n",
+            )
+                .into_response();
+        }
+        None => {}
+    }
+
+    let days = query.days.unwrap_or(DEFAULT_DAYS).clamp(1, 3650);
+    let since = now() as i64 - days * 86400;
+    let report = analytics
+        .database
+        .call(move |conn| {
+            let mut report = Report {
+                days,
+                ..Report::default()
+            };
+            (report.views, report.visitors) = conn.query_row(
+                "SELECT count(*), count(DISTINCT visitor) FROM lg_analytics WHERE created >= ?",
+                [since],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let mut statement = conn.prepare(
+                "SELECT date(created, 'unixepoch') AS day, count(*), count(DISTINCT visitor) \
+                 FROM lg_analytics WHERE created >= ? GROUP BY day ORDER BY day",
+            )?;
+            report.by_day = statement
+                .query_map([since], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            let top = |column: &str| -> rusqlite::Result<Vec<(String, i64)>> {
+                let mut statement = conn.prepare(&format!(
+                    "SELECT {column}, count(*) AS n FROM lg_analytics \
+                     WHERE created >= ? AND {column} IS NOT NULL \
+                     GROUP BY {column} ORDER BY n DESC, {column} LIMIT ?"
+                ))?;
+                let rows = statement
+                    .query_map(params![since, TOP], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect();
+                rows
+            };
+            report.pages = top("path")?;
+            report.referrers = top("referrer")?;
+            report.countries = top("country")?;
+            Ok(report)
+        })
+        .await;
+    match report {
+        Ok(report) => Html(dashboard_html(&report)).into_response(),
+        Err(err) => {
+            tracing::error!(%err, "error reading analytics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// a table of counts, each with a bar as wide as its share of the largest
+fn counts_table(html: &mut String, title: &str, heading: &str, rows: &[(String, i64)]) {
+    let _ = writeln!(html, "<h2>{title}</h2>");
+    if rows.is_empty() {
+        html.push_str("<p>none yet</p>\n");
+        return;
+    }
+    let most = rows.iter().map(|(_, n)| *n).max().unwrap_or(1).max(1);
+    let _ = writeln!(
+        html,
+        "<table>\n<tr><th>{heading}</th><th></th><th class=\"n\">Views</th></tr>"
+    );
+    for (name, n) in rows {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td><div class=\"bar\" style=\"width: {}%\"></div></td>\
+             <td class=\"n\">{n}</td></tr>",
+            escape(name),
+            n * 100 / most,
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+fn dashboard_html(report: &Report) -> String {
+    let mut html = format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>Analytics</title>\n<style>{STYLE}</style>\n</head>\n<body>\n\
+         <h1>Analytics, the last {} days</h1>\n\
+         <div class=\"totals\"><div><b>{}</b>views</div><div><b>{}</b>visitors</div></div>\n",
+        report.days, report.views, report.visitors
+    );
+    let by_day: Vec<(String, i64)> = report
+        .by_day
+        .iter()
+        .map(|(day, views, visitors)| (format!("{day} ({visitors} visitors)"), *views))
+        .collect();
+    counts_table(&mut html, "By day", "Day", &by_day);
+    counts_table(&mut html, "Pages", "Path", &report.pages);
+    counts_table(&mut html, "Referrers", "Site", &report.referrers);
+    counts_table(&mut html, "Countries", "Country", &report.countries);
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_direct_local_requests_skip_the_password() {
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let mut headers = HeaderMap::new();
+        assert!(local(localhost, &headers));
+        assert!(!local(IpAddr::from([192, 168, 1, 2]), &headers));
+        headers.insert("x-forwarded-for", "203.0.113.9".parse().unwrap());
+        assert!(!local(localhost, &headers));
+    }
+
+    #[test]
+    fn finds_countries() {
+        let geoip = GeoIp::parse(
+            "\"16777216\",\"16777471\",\"AU\",\"Australia\"\n\
+             2001:db8::,2001:db8::ffff,nz\n\
+             8.8.8.0,8.8.8.255,US\n\
+             9.9.9.0,9.9.9.255,-\n",
+        );
+        assert_eq!(
+            geoip.country("1.0.0.7".parse().unwrap()).as_deref(),
+            Some("AU")
+        );
+        assert_eq!(
+            geoip.country("8.8.8.8".parse().unwrap()).as_deref(),
+            Some("US")
+        );
+        assert_eq!(
+            geoip.country("2001:db8::1".parse().unwrap()).as_deref(),
+            Some("NZ")
+        );
+        assert_eq!(geoip.country("9.9.9.9".parse().unwrap()), None);
+        assert_eq!(geoip.country("1.0.1.0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn keeps_only_referrer_hosts() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "example.com".parse().unwrap());
+        headers.insert(
+            REFERER,
+            "https://news.ycombinator.com/item?id=1".parse().unwrap(),
+        );
+        assert_eq!(
+            referrer_host(&headers).as_deref(),
+            Some("news.ycombinator.com")
+        );
+        headers.insert(REFERER, "https://example.com/about".parse().unwrap());
+        assert_eq!(referrer_host(&headers), None);
+    }
+}
@@ -0,0 +1,374 @@
+// the files in an app's assets directory, and their cache-control.
+//
+//   [assets]
+//   cache_control = "public, max-age=300"  # the default
+//   immutable = true                       # the default, see below
+//   index = ["index.html", "index.md"]     # served for a directory, index.html by default
+//   autoindex = true                       # list directories without an index file
+//   dotfiles = false                       # the default, .env and .git are 404
+//   markdown_template = "markdown.html"    # the default
+//
+//   [assets.extensions]
+//   css = "public, max-age=3600"
+//   woff2 = "public, max-age=31536000, immutable"
+//
+// an index.md is rendered with the markdown template, which gets the page as `content`,
+// its first heading as `title` and its `path`; without that template it is plain html.
+// .well-known is served even when dotfiles aren't.
+//
+// a fingerprinted file, one with a hash in its name like app.3f9a2c1e.css or
+// index-B7xk2d9q.js, never changes at that name, so with immutable it is cached for a
+// year and never checked again. an extension's own policy wins over both. while the app
+// reloads on change, as in development, every asset is "no-cache", so edits show up at
+// once; browsers still only fetch a file again if its last-modified date changed.
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION},
+        HeaderValue, StatusCode, Uri,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tower_http::services::ServeDir;
+
+use crate::{routes::listing, template::Template};
+
+const DEFAULT_CACHE_CONTROL: &str = "public, max-age=300";
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const NO_CACHE: &str = "no-cache";
+const DEFAULT_INDEX: &str = "index.html";
+const DEFAULT_MARKDOWN_TEMPLATE: &str = "markdown.html";
+
+/// the [assets] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AssetsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub immutable: Option<bool>,
+
+    /// cache-control by file extension, without the dot
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extensions: HashMap<String, String>,
+
+    #[serde(flatten)]
+    pub mount: MountOptions,
+}
+
+/// how a directory of static files is served
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MountOptions {
+    /// the files served for a directory, the first that exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<Vec<String>>,
+
+    /// list a directory that has no index file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autoindex: Option<bool>,
+
+    /// serve files and directories whose names start with a dot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dotfiles: Option<bool>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub markdown_template: Option<String>,
+}
+
+impl MountOptions {
+    fn index(&self) -> Vec<&str> {
+        match &self.index {
+            Some(index) => index.iter().map(String::as_str).collect(),
+            None => vec![DEFAULT_INDEX],
+        }
+    }
+
+    fn dotfiles(&self) -> bool {
+        self.dotfiles.unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+struct Policy {
+    config: AssetsConfig,
+    dev: bool,
+}
+
+impl Policy {
+    /// the cache-control for an asset
+    fn cache_control(&self, path: &str) -> &str {
+        if self.dev {
+            return NO_CACHE;
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if let Some((_, extension)) = name.rsplit_once('.') {
+            let found = self.config.extensions.iter().find(|(configured, _)| {
+                configured
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(extension)
+            });
+            if let Some((_, cache_control)) = found {
+                return cache_control;
+            }
+        }
+        if self.config.immutable.unwrap_or(true) && is_fingerprinted(name) {
+            return IMMUTABLE;
+        }
+        self.config
+            .cache_control
+            .as_deref()
+            .unwrap_or(DEFAULT_CACHE_CONTROL)
+    }
+}
+
+/// true if a file name ends in a content hash, like app.3f9a2c1e.css or index-B7xk2d9q.js
+pub fn is_fingerprinted(name: &str) -> bool {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let Some((_, hash)) = stem.rsplit_once(['.', '-']) else {
+        return false;
+    };
+    (8..=64).contains(&hash.len())
+        && hash.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && hash.bytes().any(|b| b.is_ascii_digit())
+        && hash.bytes().any(|b| b.is_ascii_alphabetic())
+}
+
+/// percent-decode a request path, and split it into segments; None if it has .. in it
+pub fn segments(path: &str) -> Option<Vec<String>> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut raw = path.bytes();
+    while let Some(byte) = raw.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [raw.next()?, raw.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .map(String::from)
+        .collect();
+    if segments
+        .iter()
+        .any(|segment| segment == ".." || segment.contains('\\'))
+    {
+        return None;
+    }
+    Some(segments)
+}
+
+/// a static mount: its directory, and how it is served
+#[derive(Debug)]
+struct Mount {
+    dir: PathBuf,
+    options: MountOptions,
+    template: Template,
+}
+
+/// the files in dir, with the cache-control config says
+pub fn service(dir: PathBuf, config: &AssetsConfig, template: Template, dev: bool) -> Router {
+    let policy = Arc::new(Policy {
+        config: config.clone(),
+        dev,
+    });
+    let mount = Arc::new(Mount {
+        dir: dir.clone(),
+        options: config.mount.clone(),
+        template,
+    });
+    Router::new()
+        .fallback_service(ServeDir::new(dir).append_index_html_on_directories(false))
+        .layer(middleware::from_fn_with_state(mount, serve_mount))
+        .layer(middleware::from_fn_with_state(policy, set_cache_control))
+}
+
+fn not_found() -> Response {
+    StatusCode::NOT_FOUND.into_response()
+}
+
+/// keep dotfiles hidden, and serve directories by their index file or a listing
+async fn serve_mount(
+    State(mount): State<Arc<Mount>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let Some(segments) = segments(&path) else {
+        return not_found();
+    };
+    let dotfiles = mount.options.dotfiles();
+    let hidden = |segment: &String| segment.starts_with('.') && segment != ".well-known";
+    if !dotfiles && segments.iter().any(hidden) {
+        return not_found();
+    }
+    let full: PathBuf = segments
+        .iter()
+        .fold(mount.dir.clone(), |full, s| full.join(s));
+    if !tokio::fs::metadata(&full)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return next.run(request).await;
+    }
+    if !path.ends_with('/') {
+        // relative to the directory's own name, which works under any prefix
+        let name = segments
+            .last()
+            .map_or(String::new(), |name| format!("{name}/"));
+        let location = match request.uri().query() {
+            Some(query) => format!("./{name}?{query}"),
+            None => format!("./{name}"),
+        };
+        let Ok(location) = HeaderValue::from_str(&location) else {
+            return not_found();
+        };
+        return (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response();
+    }
+
+    for index in mount.options.index() {
+        if !tokio::fs::metadata(full.join(index))
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            continue;
+        }
+        if index.ends_with(".md") {
+            return markdown_page(&mount, &full.join(index), &path).await;
+        }
+        let Ok(uri) = format!("{path}{index}").parse::<Uri>() else {
+            return not_found();
+        };
+        *request.uri_mut() = uri;
+        return next.run(request).await;
+    }
+    if !mount.options.autoindex.unwrap_or(false) {
+        return not_found();
+    }
+    match listing::html(&full, &path, dotfiles).await {
+        Ok(page) => html(page),
+        Err(err) => {
+            tracing::error!(%err, dir = %full.display(), "error listing directory");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn html(page: String) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8"),
+            (CACHE_CONTROL, NO_CACHE),
+        ],
+        page,
+    )
+        .into_response()
+}
+
+async fn markdown_page(mount: &Mount, file: &Path, path: &str) -> Response {
+    let markdown = match tokio::fs::read_to_string(file).await {
+        Ok(markdown) => markdown,
+        Err(err) => {
+            tracing::error!(%err, file = %file.display(), "error reading markdown");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let template = mount
+        .options
+        .markdown_template
+        .clone()
+        .unwrap_or_else(|| DEFAULT_MARKDOWN_TEMPLATE.to_string());
+    match mount
+        .template
+        .render_markdown(template, path.to_string(), markdown)
+        .await
+    {
+        Ok(page) => html(page),
+        Err(err) => {
+            tracing::error!(%err, file = %file.display(), "error rendering markdown");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn set_cache_control(
+    State(policy): State<Arc<Policy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if !(status.is_success() || status == StatusCode::NOT_MODIFIED)
+        || response.headers().contains_key(CACHE_CONTROL)
+    {
+        return response;
+    }
+    let cache_control = policy.cache_control(&path);
+    match HeaderValue::from_str(cache_control) {
+        Ok(value) => {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+        Err(_) => tracing::warn!(cache_control, "invalid cache-control in [assets]"),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_fingerprints() {
+        assert!(is_fingerprinted("app.3f9a2c1e.css"));
+        assert!(is_fingerprinted("index-B7xk2d9q.js"));
+        assert!(is_fingerprinted("vendor-react-B7xk2d9q.js"));
+        assert!(!is_fingerprinted("app.css"));
+        assert!(!is_fingerprinted("app.min.js"));
+        assert!(!is_fingerprinted("bootstrap-reboot.css"));
+        assert!(!is_fingerprinted("report-20240101.pdf"));
+    }
+
+    #[test]
+    fn splits_paths() {
+        assert_eq!(
+            segments("/docs/a%20b/./"),
+            Some(vec!["docs".to_string(), "a b".to_string()])
+        );
+        assert_eq!(segments("/"), Some(Vec::new()));
+        assert_eq!(segments("/docs/../secret"), None);
+        assert_eq!(segments("/docs/%2e%2e/secret"), None);
+        assert_eq!(segments("/bad%zz"), None);
+    }
+
+    #[test]
+    fn picks_a_policy() {
+        let mut config = AssetsConfig::default();
+        config
+            .extensions
+            .insert("woff2".to_string(), "public, max-age=86400".to_string());
+        let policy = Policy { config, dev: false };
+        assert_eq!(policy.cache_control("/app.css"), DEFAULT_CACHE_CONTROL);
+        assert_eq!(policy.cache_control("/js/app.3f9a2c1e.js"), IMMUTABLE);
+        assert_eq!(
+            policy.cache_control("/fonts/inter.WOFF2"),
+            "public, max-age=86400"
+        );
+
+        let dev = Policy {
+            config: AssetsConfig::default(),
+            dev: true,
+        };
+        assert_eq!(dev.cache_control("/js/app.3f9a2c1e.js"), NO_CACHE);
+    }
+}
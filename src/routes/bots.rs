@@ -0,0 +1,196 @@
+// keeping crawlers and scrapers off the routes that aren't for them.
+//
+//   routes:block_bots("/admin/*")   -- /admin and everything under it
+//   routes:block_bots("/search")    -- just /search
+//
+//   [bots]
+//   allow = ["Googlebot", "bingbot"]  # crawlers let through anyway
+//   max_per_minute = 120              # the default, 0 for no limit
+//
+// a request to a blocked route from a user agent that says it is a crawler or a script,
+// like curl, or that has no user agent at all, gets a 403. an allowed crawler that makes
+// more than max_per_minute requests to blocked routes from one address gets a 429 until
+// the minute is up. both are logged, once a minute for each address. requests from
+// browsers aren't counted. the most recent addresses are kept, and the least recent is
+// forgotten to make room for a new one. blocked routes are also disallowed in the
+// robots.txt lilguy generates.
+use axum::{
+    http::{
+        header::{RETRY_AFTER, USER_AGENT},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::runtime::useragent;
+
+const DEFAULT_MAX_PER_MINUTE: u32 = 120;
+const WINDOW: Duration = Duration::from_secs(60);
+/// how many addresses are tracked
+const MAX_CLIENTS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// the [bots] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BotsConfig {
+    /// crawlers blocked routes let through, by name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_minute: Option<u32>,
+}
+
+/// the robots.txt disallow line for a pattern
+pub fn disallow(pattern: &str) -> &str {
+    pattern.trim_end_matches('*')
+}
+
+/// requests from bots to blocked routes, by address, shared by every app a server runs
+#[derive(Debug, Clone)]
+pub struct Bots {
+    allow: Arc<[String]>,
+    max_per_minute: u32,
+    clients: Arc<Mutex<LruCache<IpAddr, Window>>>,
+}
+
+impl Default for Bots {
+    fn default() -> Self {
+        Self::new(&BotsConfig::default())
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    requests: u32,
+    logged: bool,
+}
+
+impl Bots {
+    pub fn new(config: &BotsConfig) -> Self {
+        Self {
+            allow: config.allow.iter().cloned().collect(),
+            max_per_minute: config.max_per_minute.unwrap_or(DEFAULT_MAX_PER_MINUTE),
+            clients: Arc::new(Mutex::new(LruCache::new(MAX_CLIENTS))),
+        }
+    }
+
+    /// the response for a request to a blocked route, or None to let it through
+    pub fn check(&self, client: IpAddr, headers: &HeaderMap, path: &str) -> Option<Response> {
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        let bot = useragent::parse(user_agent).bot?;
+        let allowed = self
+            .allow
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&bot));
+
+        let mut clients = self.clients.lock();
+        let window = clients.get_or_insert_mut(client, || Window {
+            started: Instant::now(),
+            requests: 0,
+            logged: false,
+        });
+        if window.started.elapsed() >= WINDOW {
+            *window = Window {
+                started: Instant::now(),
+                requests: 0,
+                logged: false,
+            };
+        }
+        window.requests = window.requests.saturating_add(1);
+        let limited = self.max_per_minute > 0 && window.requests > self.max_per_minute;
+        if allowed && !limited {
+            return None;
+        }
+        if !window.logged {
+            window.logged = true;
+            tracing::warn!(
+                %client,
+                user_agent,
+                path,
+                requests = window.requests,
+                "blocked a bot"
+            );
+        }
+        if !allowed {
+            let body = format!("{bot} isn't allowed here\n");
+            return Some((StatusCode::FORBIDDEN, body).into_response());
+        }
+        let retry_after = WINDOW
+            .saturating_sub(window.started.elapsed())
+            .as_secs()
+            .max(1);
+        Some(
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                "too many requests\n",
+            )
+                .into_response(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::covers;
+
+    #[test]
+    fn covers_paths() {
+        assert!(covers("/admin/*", "/admin"));
+        assert!(covers("/admin/*", "/admin/users/1"));
+        assert!(!covers("/admin/*", "/administrator"));
+        assert!(covers("/search", "/search/"));
+        assert!(!covers("/search", "/search/more"));
+        assert!(covers("*", "/anything"));
+        assert_eq!(disallow("/admin/*"), "/admin/");
+    }
+
+    #[test]
+    fn blocks_bots_and_storms() {
+        let bots = Bots::new(&BotsConfig {
+            allow: vec!["Googlebot".to_string()],
+            max_per_minute: Some(2),
+        });
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, "curl/8.4.0".parse().unwrap());
+        let blocked = bots.check(client, &headers, "/admin").unwrap();
+        assert_eq!(blocked.status(), StatusCode::FORBIDDEN);
+
+        headers.insert(
+            USER_AGENT,
+            "Mozilla/5.0 (compatible; Googlebot/2.1)".parse().unwrap(),
+        );
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(bots.check(other, &headers, "/admin").is_none());
+        assert!(bots.check(other, &headers, "/admin").is_none());
+        let limited = bots.check(other, &headers, "/admin").unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        headers.insert(
+            USER_AGENT,
+            "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0"
+                .parse()
+                .unwrap(),
+        );
+        let browser: IpAddr = "192.0.2.3".parse().unwrap();
+        for _ in 0..10 {
+            assert!(bots.check(browser, &headers, "/admin").is_none());
+        }
+        assert!(bots.clients.lock().peek(&browser).is_none());
+    }
+}
@@ -0,0 +1,160 @@
+// which addresses can reach an app, like locking an internal tool to a vpn.
+//
+//   [firewall]
+//   allow = ["10.8.0.0/16", "192.168.1.0/24"]  # only these, when there are any
+//   deny = ["203.0.113.7"]                      # never these, even when allowed
+//
+//   routes:firewall("/admin/*", { allow = { "10.8.0.0/16" } })
+//   routes:firewall("/health", {})  -- open to everyone, whatever the config says
+//
+// the rules are checked before anything else handles a request, including assets and
+// websockets, and a request they don't let through gets a 403. the rules for a path
+// passed to routes:firewall() take the place of the config's, the most specific pattern
+// winning, so a route can be opened up as well as locked down. a pattern ending in /*
+// is a prefix and everything under it, and "*" is every path. patterns are matched
+// against the decoded path, the way assets and mounts find files, so /assets/%73ecret is
+// /assets/secret; a path with .. in it is turned away.
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::{Result, WrapErr};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc};
+
+use super::assets::segments;
+use crate::runtime::{http::client::ClientIp, Runtime};
+
+/// the [firewall] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FirewallConfig {
+    /// networks or addresses that can connect, everyone when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+
+    /// networks or addresses that can't
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+/// allow and deny lists of networks
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+/// a network like 10.0.0.0/8, or a single address
+fn network(text: &str) -> Result<IpNet> {
+    let text = text.trim();
+    if let Ok(network) = text.parse::<IpNet>() {
+        return Ok(network.trunc());
+    }
+    let address: IpAddr = text
+        .parse()
+        .wrap_err_with(|| format!("{text:?} is not a network or an ip address"))?;
+    Ok(address.into())
+}
+
+impl Rules {
+    pub fn parse<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<Self> {
+        let networks = |list: &[S]| -> Result<Vec<IpNet>> {
+            list.iter().map(|text| network(text.as_ref())).collect()
+        };
+        Ok(Self {
+            allow: networks(allow)?,
+            deny: networks(deny)?,
+        })
+    }
+
+    /// true if ip can connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // an ipv4 client on a dual-stack listener shows up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|network| network.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(&ip))
+    }
+}
+
+/// the rules from config.toml, shared by every app a server runs
+#[derive(Debug, Clone, Default)]
+pub struct Firewall(Arc<Rules>);
+
+impl Firewall {
+    pub fn new(config: &FirewallConfig) -> Result<Self> {
+        let rules = Rules::parse(&config.allow, &config.deny).wrap_err("in [firewall]")?;
+        Ok(Self(Arc::new(rules)))
+    }
+}
+
+/// a request path decoded, without empty or . segments, for matching patterns
+fn normalize(path: &str) -> Option<String> {
+    let mut normalized = format!("/{}", segments(path)?.join("/"));
+    if path.ends_with('/') && normalized.len() > 1 {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// turn away requests the rules for their path don't permit
+pub async fn guard(State(runtime): State<Runtime>, request: Request, next: Next) -> Response {
+    let ClientIp(client) = ClientIp::from_extensions(request.extensions());
+    let Some(path) = normalize(request.uri().path()) else {
+        return (StatusCode::BAD_REQUEST, "bad path\n").into_response();
+    };
+    // before the app has loaded there are only the config's rules
+    let route_rules = runtime
+        .lua_with_routes()
+        .ok()
+        .and_then(|(_, routes)| routes.firewall(&path));
+    let permitted = match route_rules {
+        Some(rules) => rules.permits(client),
+        None => runtime.firewall().0.permits(client),
+    };
+    if !permitted {
+        tracing::info!(%client, %path, "firewall turned away a request");
+        return (StatusCode::FORBIDDEN, "forbidden\n").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_networks() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        let rules = Rules::parse(&["10.8.0.0/16", "2001:db8::/32"], &["10.8.0.13"]).unwrap();
+        assert!(rules.permits(ip("10.8.4.2")));
+        assert!(rules.permits(ip("::ffff:10.8.4.2")));
+        assert!(rules.permits(ip("2001:db8::1")));
+        assert!(!rules.permits(ip("10.8.0.13")));
+        assert!(!rules.permits(ip("192.0.2.1")));
+
+        let open = Rules::parse::<&str>(&[], &["192.0.2.0/24"]).unwrap();
+        assert!(open.permits(ip("198.51.100.1")));
+        assert!(!open.permits(ip("192.0.2.200")));
+        assert!(Rules::parse(&["10.0.0.0/33"], &[]).is_err());
+        assert!(Rules::parse(&["vpn"], &[]).is_err());
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(
+            normalize("/assets/%73ecret/x").as_deref(),
+            Some("/assets/secret/x")
+        );
+        assert_eq!(
+            normalize("//admin/./users/").as_deref(),
+            Some("/admin/users/")
+        );
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/a/..%2F..%2Fadmin"), None);
+    }
+}
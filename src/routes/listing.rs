@@ -0,0 +1,120 @@
+// the page listing a directory, for static mounts with autoindex = true; see
+// routes/assets.rs.
+use std::{fmt::Write, path::Path, time::SystemTime};
+
+const STYLE: &str = "body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 60em; \
+    padding: 0 1em; color: #222; } h1 { font-size: 1.4em; font-weight: 500; } \
+    table { border-collapse: collapse; width: 100%; } \
+    th, td { text-align: left; padding: 0.35em 0.75em; border-bottom: 1px solid #eee; } \
+    th { color: #666; font-weight: 500; } td.size, td.modified { color: #666; white-space: nowrap; } \
+    a { color: #0550ae; text-decoration: none; } a:hover { text-decoration: underline; }";
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// a file name as a relative link, percent-encoded
+fn href(name: &str) -> String {
+    let mut href = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$()*+,;=:@".contains(&byte) {
+            href.push(byte as char);
+        } else {
+            let _ = write!(href, "%{byte:02X}");
+        }
+    }
+    href
+}
+
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// the listing of dir, which is at path in the mount. dotfiles are left out unless they
+/// can be served.
+pub async fn html(dir: &Path, path: &str, dotfiles: bool) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !dotfiles {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = format!("Index of {}", escape(path));
+    let mut page = format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n"
+    );
+    if path != "/" {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let (slash, bytes) = if entry.is_dir {
+            ("/", String::new())
+        } else {
+            ("", size(entry.size))
+        };
+        let modified = entry
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td class=\"size\">{bytes}</td>\
+             <td class=\"modified\">{modified}</td></tr>",
+            href(&entry.name),
+            escape(&entry.name),
+        );
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_entries() {
+        assert_eq!(href("a file#1.txt"), "a%20file%231.txt");
+        assert_eq!(href("naïve"), "na%C3%AFve");
+        assert_eq!(size(512), "512 B");
+        assert_eq!(size(1536), "1.5 KB");
+        assert_eq!(size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
@@ -0,0 +1,127 @@
+// fragments of a page that re-render when a global table changes.
+//
+//   {% filter live("tasks") %}
+//     <ul>{% for task in tasks %}<li>{{ task.title }}</li>{% endfor %}</ul>
+//   {% endfilter %}
+//
+// the fragment is wrapped in an element naming the tables it shows, and the page gets a
+// small script that listens at /_lilguy/live for changes to them. when one changes, the
+// script fetches the page again and swaps in the new copy of each fragment that uses
+// it, so the route's handler stays the only place the page is put together. those
+// requests carry an x-lilguy-live header. fragments are matched by their order on the
+// page, so pages should render the same fragments each time.
+use axum::extract::ws::{Message, WebSocket};
+use minijinja::{value::Rest, State, Value};
+use std::{collections::HashSet, fmt::Write, time::Duration};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+
+use crate::database::{global::global_name, Database};
+
+/// where pages listen for changes, relative to the app
+pub const LIVE_PATH: &str = "/_lilguy/live";
+
+/// changes that come close together are sent at once, so a loop of writes is one render
+const SETTLE: Duration = Duration::from_millis(50);
+
+/// counts the fragments on the page being rendered
+const FRAGMENTS: &str = "lilguy_live_fragments";
+
+const SCRIPT: &str = r#"<script>
+(() => {
+  const fragments = () => document.querySelectorAll("[data-lilguy-live]");
+  const uses = (el, changed) => el.dataset.lilguyLive.split(" ").some((t) => changed.has(t));
+  const refresh = async (changed) => {
+    const res = await fetch(location.href, { headers: { "x-lilguy-live": "1" } });
+    if (!res.ok) return;
+    const page = new DOMParser().parseFromString(await res.text(), "text/html");
+    for (const el of fragments()) {
+      const fresh = page.getElementById(el.id);
+      if (fresh && uses(el, changed)) el.replaceWith(fresh);
+    }
+  };
+  const connect = () => {
+    const tables = new Set();
+    fragments().forEach((el) => el.dataset.lilguyLive.split(" ").forEach((t) => tables.add(t)));
+    const url = new URL("/_lilguy/live", location.href);
+    url.protocol = location.protocol === "https:" ? "wss:" : "ws:";
+    url.searchParams.set("tables", [...tables].join(","));
+    const ws = new WebSocket(url);
+    ws.onmessage = (event) => refresh(new Set(JSON.parse(event.data).changed));
+    ws.onclose = () => setTimeout(connect, 2000);
+  };
+  if (document.readyState === "loading") {
+    document.addEventListener("DOMContentLoaded", connect);
+  } else {
+    connect();
+  }
+})();
+</script>
+"#;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// the live filter: wraps a fragment in an element that names its tables, and adds the
+/// script after the first fragment on a page
+pub fn filter(state: &State, fragment: Value, tables: Rest<String>) -> Value {
+    let count = state
+        .get_temp(FRAGMENTS)
+        .and_then(|count| u64::try_from(count).ok())
+        .unwrap_or(0);
+    state.set_temp(FRAGMENTS, Value::from(count + 1));
+
+    let fragment = if fragment.is_safe() {
+        fragment.to_string()
+    } else {
+        escape(&fragment.to_string())
+    };
+    let mut html = format!(
+        "<div id=\"lilguy-live-{count}\" data-lilguy-live=\"{}\">{fragment}</div>\n",
+        escape(&tables.join(" "))
+    );
+    if count == 0 {
+        let _ = write!(html, "{SCRIPT}");
+    }
+    Value::from_safe_string(html)
+}
+
+/// tell the socket which of tables change until it closes
+pub async fn serve(mut socket: WebSocket, database: Database, tables: HashSet<String>) {
+    let mut changes = database.changes();
+    let mut changed = HashSet::new();
+    // when the changes seen so far are sent
+    let mut settled = None;
+    loop {
+        tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            change = changes.recv() => match change {
+                Ok(table) => {
+                    if let Some(name) = global_name(&table).filter(|name| tables.contains(*name)) {
+                        changed.insert(name.to_string());
+                        settled.get_or_insert_with(|| Instant::now() + SETTLE);
+                    }
+                }
+                // too many changes to say which, so say all of them
+                Err(RecvError::Lagged(_)) => {
+                    changed.extend(tables.iter().cloned());
+                    settled.get_or_insert_with(|| Instant::now() + SETTLE);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = tokio::time::sleep_until(settled.unwrap_or_else(Instant::now)), if settled.is_some() => {
+                settled = None;
+                let message = serde_json::json!({ "changed": changed.drain().collect::<Vec<_>>() });
+                if socket.send(Message::Text(message.to_string().into())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// lua functions that wrap every request's handler.
+//
+//   routes:use(function(req, res, next)
+//       local started = os.clock()
+//       next()                                 -- the rest of the chain, then the handler
+//       res.headers["server-timing"] = "app;dur=" .. (os.clock() - started) * 1000
+//   end)
+//
+//   routes:use("/admin", function(req, res, next)
+//       if not req.session.user then
+//           return res:redirect("/login")      -- the handler never runs
+//       end
+//       req.ctx.user = req.session.user
+//       next()
+//   end)
+//
+// middleware runs in the order it was added, around route handlers and routes.not_found
+// alike; with a prefix, only for paths under it.
+use mlua::prelude::*;
+
+use super::spa::strip_prefix;
+
+/// one routes:use() function, and the prefix it is limited to
+#[derive(Debug, Clone)]
+pub struct Middleware {
+    pub prefix: Option<String>,
+    pub function: LuaFunction,
+}
+
+impl Middleware {
+    pub fn applies(&self, path: &str) -> bool {
+        self.prefix
+            .as_deref()
+            .is_none_or(|prefix| strip_prefix(prefix, path).is_some())
+    }
+}
+
+/// call handler with req and res, inside each of middleware, the first outermost
+pub async fn call(
+    lua: &Lua,
+    middleware: Vec<LuaFunction>,
+    handler: LuaFunction,
+    req: LuaTable,
+    res: LuaTable,
+) -> LuaResult<()> {
+    if middleware.is_empty() {
+        return handler.call_async::<()>((req, res)).await;
+    }
+    let mut next = lua.create_async_function({
+        let req = req.clone();
+        let res = res.clone();
+        move |_, ()| {
+            let handler = handler.clone();
+            let (req, res) = (req.clone(), res.clone());
+            async move { handler.call_async::<()>((req, res)).await }
+        }
+    })?;
+    for function in middleware.into_iter().rev() {
+        let inner = next;
+        next = lua.create_async_function({
+            let req = req.clone();
+            let res = res.clone();
+            move |_, ()| {
+                let function = function.clone();
+                let (req, res, inner) = (req.clone(), res.clone(), inner.clone());
+                async move { function.call_async::<()>((req, res, inner)).await }
+            }
+        })?;
+    }
+    next.call_async::<()>(()).await
+}
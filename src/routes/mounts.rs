@@ -0,0 +1,108 @@
+// static files the app mounts itself, beyond its assets directory.
+//
+//   static["/blog"] = "public/blog"              -- a directory, relative to app.lua
+//   static["/robots.txt"] = "public/robots.txt"  -- or a single file, at the root or anywhere
+//   static["/blog"] = nil                        -- mounted no more
+//   print(static["/blog"])                       -- the full path it serves
+//
+// mounts are served like /assets, with the [assets] config for their cache-control, index
+// files, listings and dotfiles. like routes, they are set up each time app.lua loads, so
+// they change when it reloads. routes, api routes and rpc procedures match first, and a
+// mount only answers for files and directories that exist, so a path it doesn't have is
+// still left for content pages or the single page app. a mounted /robots.txt or
+// /sitemap.xml wins over the ones generated for routes:meta().
+use axum::{
+    extract::Request,
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use mlua::prelude::*;
+use std::path::{Path, PathBuf};
+use tower::ServiceExt;
+
+use super::{assets, spa::strip_prefix, Routes};
+use crate::{command::Config, template::Template};
+
+/// the static global
+#[derive(Debug, Clone)]
+pub struct LuaStatic(pub Routes);
+
+impl LuaUserData for LuaStatic {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::Index, |_, this, prefix: String| {
+            Ok(this
+                .0
+                .mount(&prefix)
+                .map(|path| path.to_string_lossy().into_owned()))
+        });
+        methods.add_meta_method(
+            LuaMetaMethod::NewIndex,
+            |lua, this, (prefix, path): (String, Option<String>)| {
+                if !prefix.starts_with("/") {
+                    return Err(LuaError::runtime("routes must start with /"));
+                }
+                let Some(path) = path else {
+                    this.0.remove_mount(&prefix);
+                    return Ok(());
+                };
+                let root: String = lua.globals().get::<LuaTable>("lilguy")?.get("root")?;
+                this.0.add_mount(&prefix, PathBuf::from(root).join(path));
+                Ok(())
+            },
+        );
+    }
+}
+
+/// true if the mount at prefix has the file or directory path names
+pub async fn exists(prefix: &str, mounted: &Path, path: &str) -> bool {
+    let Some(rest) = strip_prefix(prefix, path) else {
+        return false;
+    };
+    // decoded whole, like /assets does, so an encoded slash can't hide a ..
+    let Some(segments) = assets::segments(rest) else {
+        return false;
+    };
+    let mut target = mounted.to_path_buf();
+    target.extend(segments);
+    tokio::fs::metadata(&target).await.is_ok()
+}
+
+/// serve request from the mount at prefix
+pub async fn serve(
+    mut request: Request,
+    prefix: &str,
+    mounted: &Path,
+    config: &Config,
+    template: Template,
+    dev: bool,
+) -> Response {
+    let file = tokio::fs::metadata(mounted)
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    // a file is served from its directory, to get the same headers as one would there
+    let (dir, path) = match (file, mounted.parent(), mounted.file_name()) {
+        (true, Some(dir), Some(name)) => {
+            (dir.to_path_buf(), format!("/{}", name.to_string_lossy()))
+        }
+        _ => {
+            let rest = strip_prefix(prefix, request.uri().path()).unwrap_or_default();
+            (
+                mounted.to_path_buf(),
+                format!("/{}", rest.trim_start_matches('/')),
+            )
+        }
+    };
+    let uri = match request.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+    let Ok(uri) = uri.parse::<Uri>() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    *request.uri_mut() = uri;
+    let service = assets::service(dir, &config.assets, template, dev);
+    match service.oneshot(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
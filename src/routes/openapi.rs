@@ -0,0 +1,109 @@
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::sync::LazyLock;
+
+static PATH_PARAM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[:*+]([A-Za-z0-9_]+)\??").expect("valid regex"));
+
+pub const SWAGGER_UI: &str = r##"<!doctype html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// a route declared with routes:api("GET /users/:id", { ... }, handler)
+#[derive(Debug, Clone)]
+pub struct ApiRoute {
+    pub method: String,
+    pub pattern: String,
+    pub spec: Value,
+}
+
+/// the openapi 3 document for all api routes, in the order they were declared.
+///
+/// the spec table understands summary, description, tags, params, query, body,
+/// response and responses. params, query, body and response take a type name such as
+/// "integer" or a json schema table.
+pub fn document(title: &str, version: &str, routes: &[ApiRoute]) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let path = PATH_PARAM.replace_all(&route.pattern, "{$1}").to_string();
+        let operation = operation(&route.spec);
+        paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .expect("path item is an object")
+            .insert(route.method.to_lowercase(), operation);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": title, "version": version },
+        "paths": paths,
+    })
+}
+
+fn operation(spec: &Value) -> Value {
+    let mut operation = Map::new();
+    for key in ["summary", "description", "tags", "operationId"] {
+        if let Some(value) = spec.get(key) {
+            operation.insert(key.to_string(), value.clone());
+        }
+    }
+
+    let mut parameters = vec![];
+    for (location, key) in [("path", "params"), ("query", "query")] {
+        let Some(Value::Object(params)) = spec.get(key) else {
+            continue;
+        };
+        for (name, schema) in params {
+            parameters.push(json!({
+                "name": name,
+                "in": location,
+                "required": location == "path",
+                "schema": schema_for(schema),
+            }));
+        }
+    }
+    if !parameters.is_empty() {
+        operation.insert("parameters".to_string(), Value::Array(parameters));
+    }
+
+    if let Some(body) = spec.get("body") {
+        operation.insert(
+            "requestBody".to_string(),
+            json!({ "content": { "application/json": { "schema": schema_for(body) } } }),
+        );
+    }
+
+    let responses = match (spec.get("responses"), spec.get("response")) {
+        (Some(responses), _) => responses.clone(),
+        (None, Some(response)) => json!({
+            "200": {
+                "description": "OK",
+                "content": { "application/json": { "schema": schema_for(response) } },
+            }
+        }),
+        (None, None) => json!({ "200": { "description": "OK" } }),
+    };
+    operation.insert("responses".to_string(), responses);
+
+    Value::Object(operation)
+}
+
+fn schema_for(value: &Value) -> Value {
+    match value {
+        Value::String(name) => json!({ "type": name }),
+        other => other.clone(),
+    }
+}
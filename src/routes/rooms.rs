@@ -0,0 +1,249 @@
+// named rooms that lua publishes to and pages listen to, over a websocket or, on networks
+// that block websockets, by long-polling.
+//
+//   channel.publish("chat", { from = "ann", text = "hi" })
+//   channel.authorize(function(req, room) return req.cookies.session ~= nil end)
+//
+//   local sub = channel.subscribe("chat")  -- to relay a room from lua, as in on_ws_connect
+//   local message = sub:recv()
+//
+// and in the page:
+//
+//   <script src="/_lilguy/rooms.js"></script>
+//   lilguy.listen("chat", (message) => show(message))
+//
+// the script listens at /_lilguy/rooms/chat, and if the websocket can't connect it polls
+// /_lilguy/poll/chat?after=N instead. a poll answers {"cursor": N, "messages": [...]} as
+// soon as there are messages after N, or with none after POLL_TIMEOUT; without after, it
+// answers at once with the cursor to start from. each room keeps its last BACKLOG
+// messages, so a poller misses nothing sent between its requests unless it falls further
+// behind than that. rooms are kept across reloads, and anyone may listen to one unless
+// channel.authorize says otherwise.
+use axum::extract::ws::{Message, WebSocket};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// where pages listen to a room with a websocket, relative to the app
+pub const ROOMS_PATH: &str = "/_lilguy/rooms";
+
+/// where pages poll a room when websockets are blocked
+pub const POLL_PATH: &str = "/_lilguy/poll";
+
+/// the script with lilguy.listen
+pub const SCRIPT_PATH: &str = "/_lilguy/rooms.js";
+
+/// how long a poll waits for a message
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// how many messages a room keeps for pollers
+const BACKLOG: usize = 100;
+
+pub const SCRIPT: &str = r#"(() => {
+  const lilguy = (window.lilguy = window.lilguy || {});
+  const poll = async (room, onmessage, after) => {
+    const url = new URL(`/_lilguy/poll/${encodeURIComponent(room)}`, location.href);
+    for (;;) {
+      if (after !== undefined) url.searchParams.set("after", after);
+      try {
+        const res = await fetch(url, { cache: "no-store" });
+        if (!res.ok) throw new Error(res.statusText);
+        const body = await res.json();
+        after = body.cursor;
+        body.messages.forEach(onmessage);
+      } catch {
+        await new Promise((resolve) => setTimeout(resolve, 2000));
+      }
+    }
+  };
+  lilguy.listen = (room, onmessage) => {
+    const url = new URL(`/_lilguy/rooms/${encodeURIComponent(room)}`, location.href);
+    url.protocol = location.protocol === "https:" ? "wss:" : "ws:";
+    let opened = false;
+    const connect = () => {
+      const ws = new WebSocket(url);
+      ws.onopen = () => (opened = true);
+      ws.onmessage = (event) => onmessage(JSON.parse(event.data));
+      ws.onclose = () => (opened ? setTimeout(connect, 2000) : poll(room, onmessage));
+    };
+    connect();
+  };
+})();
+"#;
+
+/// every room an app's pages listen to
+#[derive(Debug, Clone, Default)]
+pub struct Rooms(Arc<Mutex<HashMap<String, Arc<Room>>>>);
+
+#[derive(Debug)]
+struct Room {
+    relay: broadcast::Sender<Arc<str>>,
+    backlog: Mutex<Backlog>,
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    /// the cursor of the latest message
+    cursor: u64,
+    messages: VecDeque<(u64, Arc<str>)>,
+}
+
+/// the answer to a poll
+#[derive(Debug)]
+pub struct Poll {
+    pub cursor: u64,
+    /// each one json
+    pub messages: Vec<Arc<str>>,
+}
+
+impl Poll {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"cursor\":{},\"messages\":[{}]}}",
+            self.cursor,
+            self.messages.join(",")
+        )
+    }
+}
+
+impl Backlog {
+    /// the messages after a cursor, or None to wait for more
+    fn after(&self, after: Option<u64>) -> Option<Poll> {
+        let poll = |messages| Poll {
+            cursor: self.cursor,
+            messages,
+        };
+        match after {
+            // a cursor from before a restart, or a first poll, starts from now
+            Some(after) if after <= self.cursor => {
+                let messages: Vec<_> = self
+                    .messages
+                    .iter()
+                    .filter(|(cursor, _)| *cursor > after)
+                    .map(|(_, message)| message.clone())
+                    .collect();
+                (!messages.is_empty()).then(|| poll(messages))
+            }
+            _ => Some(poll(Vec::new())),
+        }
+    }
+}
+
+impl Rooms {
+    fn room(&self, name: &str) -> Arc<Room> {
+        self.0
+            .lock()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(Room {
+                    relay: broadcast::channel(BACKLOG).0,
+                    backlog: Mutex::default(),
+                })
+            })
+            .clone()
+    }
+
+    /// send a json message to everyone listening to a room
+    pub fn publish(&self, name: &str, message: &serde_json::Value) {
+        let room = self.room(name);
+        let message: Arc<str> = message.to_string().into();
+        let mut backlog = room.backlog.lock();
+        backlog.cursor += 1;
+        let cursor = backlog.cursor;
+        backlog.messages.push_back((cursor, message.clone()));
+        if backlog.messages.len() > BACKLOG {
+            backlog.messages.pop_front();
+        }
+        // sent while the backlog is locked, so listeners see messages in cursor order
+        let _ = room.relay.send(message);
+    }
+
+    /// listen to a room's messages as they are published
+    pub fn subscribe(&self, name: &str) -> broadcast::Receiver<Arc<str>> {
+        self.room(name).relay.subscribe()
+    }
+
+    /// the messages after a cursor, waiting up to POLL_TIMEOUT for one if there are none
+    pub async fn poll(&self, name: &str, after: Option<u64>) -> Poll {
+        let room = self.room(name);
+        // subscribed before looking, so nothing is published in between unseen
+        let mut relay = room.relay.subscribe();
+        if let Some(poll) = room.backlog.lock().after(after) {
+            return poll;
+        }
+        let _ = tokio::time::timeout(POLL_TIMEOUT, relay.recv()).await;
+        let backlog = room.backlog.lock();
+        backlog.after(after).unwrap_or(Poll {
+            cursor: backlog.cursor,
+            messages: Vec::new(),
+        })
+    }
+}
+
+/// send a room's messages to the socket until it closes
+pub async fn serve(mut socket: WebSocket, rooms: Rooms, name: String) {
+    let mut relay = rooms.subscribe(&name);
+    loop {
+        tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            message = relay.recv() => match message {
+                Ok(message) => {
+                    if socket.send(Message::Text(message.as_ref().into())).await.is_err() {
+                        break;
+                    }
+                }
+                // a client this far behind has missed messages either way
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn polls_from_a_cursor() {
+        let rooms = Rooms::default();
+        let room = rooms.room("chat");
+        let first = room.backlog.lock().after(None).expect("poll");
+        assert_eq!(first.to_json(), r#"{"cursor":0,"messages":[]}"#);
+        assert!(room.backlog.lock().after(Some(0)).is_none());
+
+        rooms.publish("chat", &json!("a"));
+        rooms.publish("chat", &json!({ "b": 1 }));
+        let poll = room.backlog.lock().after(Some(1)).expect("poll");
+        assert_eq!(poll.to_json(), r#"{"cursor":2,"messages":[{"b":1}]}"#);
+        assert!(room.backlog.lock().after(Some(2)).is_none());
+
+        // a cursor the room never reached, as after a restart, starts over
+        let poll = room.backlog.lock().after(Some(10)).expect("poll");
+        assert_eq!(poll.to_json(), r#"{"cursor":2,"messages":[]}"#);
+    }
+
+    #[test]
+    fn keeps_a_backlog() {
+        let rooms = Rooms::default();
+        for n in 0..BACKLOG + 10 {
+            rooms.publish("count", &json!(n));
+        }
+        let poll = rooms
+            .room("count")
+            .backlog
+            .lock()
+            .after(Some(0))
+            .expect("poll");
+        assert_eq!(poll.messages.len(), BACKLOG);
+        assert_eq!(&*poll.messages[0], "10");
+    }
+}
@@ -0,0 +1,237 @@
+// unary rpc handlers served over the connect protocol and grpc-web, using the json codec.
+//
+//   routes:rpc("greet.v1.GreetService/Greet", function(input, headers)
+//       if not input.name then
+//           return nil, { code = "invalid_argument", message = "name is required" }
+//       end
+//       return { greeting = "hello " .. input.name }
+//   end)
+//
+// the procedure is served at POST /greet.v1.GreetService/Greet. there is no protobuf
+// support, so clients must use the json codec (application/json for connect,
+// application/grpc-web+json for grpc-web).
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::CONTENT_TYPE, HeaderValue, Request, Response, StatusCode},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use mlua::prelude::*;
+use serde_json::{json, Value};
+
+use crate::runtime::http::LuaHeaders;
+
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 4;
+const TRAILER_FLAG: u8 = 0x80;
+
+/// the canonical error codes, in grpc order: the grpc status is the index plus one.
+/// the second field is the http status connect uses for the code.
+const CODES: [(&str, u16); 16] = [
+    ("canceled", 499),
+    ("unknown", 500),
+    ("invalid_argument", 400),
+    ("deadline_exceeded", 504),
+    ("not_found", 404),
+    ("already_exists", 409),
+    ("permission_denied", 403),
+    ("resource_exhausted", 429),
+    ("failed_precondition", 400),
+    ("aborted", 409),
+    ("out_of_range", 400),
+    ("unimplemented", 501),
+    ("internal", 500),
+    ("unavailable", 503),
+    ("data_loss", 500),
+    ("unauthenticated", 401),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Connect,
+    GrpcWeb,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: &'static str,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: &str, message: impl Into<String>) -> Self {
+        let code = CODES
+            .iter()
+            .map(|(name, _)| *name)
+            .find(|name| *name == code)
+            .unwrap_or("unknown");
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn grpc_status(&self) -> usize {
+        CODES
+            .iter()
+            .position(|(name, _)| *name == self.code)
+            .map_or(2, |index| index + 1)
+    }
+
+    fn http_status(&self) -> StatusCode {
+        CODES
+            .iter()
+            .find(|(name, _)| *name == self.code)
+            .and_then(|(_, status)| StatusCode::from_u16(*status).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+pub async fn serve(lua: &Lua, handler: LuaFunction, request: Request<Body>) -> Response<Body> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let protocol = match content_type.as_str() {
+        "application/json" => Protocol::Connect,
+        "application/grpc-web+json" => Protocol::GrpcWeb,
+        _ => {
+            return Response::builder()
+                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                .body(Body::from("only the json codec is supported for rpc"))
+                .expect("could not create response")
+        }
+    };
+
+    let result = call(lua, handler, protocol, request).await;
+    match protocol {
+        Protocol::Connect => connect_response(result),
+        Protocol::GrpcWeb => grpc_web_response(result),
+    }
+}
+
+async fn call(
+    lua: &Lua,
+    handler: LuaFunction,
+    protocol: Protocol,
+    request: Request<Body>,
+) -> Result<Value, RpcError> {
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_MESSAGE_SIZE)
+        .await
+        .map_err(|err| RpcError::new("resource_exhausted", err.to_string()))?;
+    let message = match protocol {
+        Protocol::Connect => body,
+        Protocol::GrpcWeb => read_frame(body)?,
+    };
+    let input: Value = if message.is_empty() {
+        json!({})
+    } else {
+        serde_json::from_slice(&message)
+            .map_err(|err| RpcError::new("invalid_argument", err.to_string()))?
+    };
+
+    let internal = |err: LuaError| RpcError::new("internal", err.to_string());
+    let input = lua.to_value(&input).map_err(internal)?;
+    let headers = lua
+        .create_ser_userdata(LuaHeaders::from(parts.headers))
+        .map_err(internal)?;
+    let (output, error) = handler
+        .call_async::<(LuaValue, LuaValue)>((input, headers))
+        .await
+        .map_err(|err| {
+            tracing::error!(?err, "error in rpc handler");
+            internal(err)
+        })?;
+
+    match error {
+        LuaValue::Nil => {}
+        LuaValue::Table(error) => {
+            let code = error
+                .get::<Option<String>>("code")
+                .map_err(internal)?
+                .unwrap_or_else(|| "unknown".to_string());
+            let message = error
+                .get::<Option<String>>("message")
+                .map_err(internal)?
+                .unwrap_or_default();
+            return Err(RpcError::new(&code, message));
+        }
+        error => {
+            return Err(RpcError::new(
+                "unknown",
+                error.to_string().map_err(internal)?,
+            ))
+        }
+    }
+
+    match output {
+        LuaValue::Nil => Ok(json!({})),
+        output => lua.from_value(output).map_err(internal),
+    }
+}
+
+/// grpc-web messages are framed with a flag byte and a big endian u32 length
+fn read_frame(body: Bytes) -> Result<Bytes, RpcError> {
+    if body.len() < 5 {
+        return Err(RpcError::new("invalid_argument", "missing message frame"));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if body[0] & TRAILER_FLAG != 0 || body.len() < 5 + len {
+        return Err(RpcError::new("invalid_argument", "invalid message frame"));
+    }
+    Ok(body.slice(5..5 + len))
+}
+
+fn write_frame(buf: &mut BytesMut, flag: u8, data: &[u8]) {
+    buf.put_u8(flag);
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+}
+
+fn connect_response(result: Result<Value, RpcError>) -> Response<Body> {
+    let (status, body) = match result {
+        Ok(output) => (StatusCode::OK, output),
+        Err(err) => (
+            err.http_status(),
+            json!({ "code": err.code, "message": err.message }),
+        ),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Body::from(body.to_string()))
+        .expect("could not create response")
+}
+
+/// grpc-web always responds with 200; the status travels in a trailer frame
+fn grpc_web_response(result: Result<Value, RpcError>) -> Response<Body> {
+    let mut buf = BytesMut::new();
+    let trailers = match result {
+        Ok(output) => {
+            write_frame(&mut buf, 0, output.to_string().as_bytes());
+            "grpc-status: 0\r\n".to_string()
+        }
+        Err(err) => {
+            let message = err.message.replace(['\r', '\n'], " ");
+            format!(
+                "grpc-status: {}\r\ngrpc-message: {message}\r\n",
+                err.grpc_status()
+            )
+        }
+    };
+    write_frame(&mut buf, TRAILER_FLAG, trailers.as_bytes());
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc-web+json"),
+        )
+        .body(Body::from(buf.freeze()))
+        .expect("could not create response")
+}
@@ -0,0 +1,162 @@
+// limits on open websockets, so one client can't use up the server's tasks.
+//
+//   [websocket]
+//   max_connections = 10000  # from every client together, no limit by default
+//   max_per_client = 64      # from one ip address, 0 for no limit
+//   reject = "status"        # or "close"
+//
+// a websocket over a limit is refused with 429 Too Many Requests. with reject = "close"
+// it is upgraded and then closed with code 1013, try again later, which a page can see
+// where a failed handshake only shows as an error. the limits count /ws, live fragment,
+// yjs and socket.io sockets together, across every app the server runs.
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, future::Future, net::IpAddr, sync::Arc};
+
+const DEFAULT_MAX_PER_CLIENT: usize = 64;
+
+/// the close code for "try again later"
+const TRY_AGAIN_LATER: u16 = 1013;
+
+/// the [websocket] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebSocketConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_client: Option<usize>,
+
+    #[serde(default)]
+    pub reject: Reject,
+}
+
+/// how a websocket over a limit is turned away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reject {
+    #[default]
+    Status,
+    Close,
+}
+
+/// the websockets open now, shared by every app a server runs
+#[derive(Debug, Clone, Default)]
+pub struct SocketLimits {
+    open: Arc<Mutex<Open>>,
+    max_connections: Option<usize>,
+    max_per_client: Option<usize>,
+    reject: Reject,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    clients: HashMap<IpAddr, usize>,
+}
+
+/// held while a websocket is open
+#[derive(Debug)]
+pub struct SocketPermit {
+    open: Arc<Mutex<Open>>,
+    client: IpAddr,
+}
+
+impl Drop for SocketPermit {
+    fn drop(&mut self) {
+        let mut open = self.open.lock();
+        open.total -= 1;
+        if let Some(count) = open.clients.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.clients.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl SocketLimits {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        let per_client = config.max_per_client.unwrap_or(DEFAULT_MAX_PER_CLIENT);
+        Self {
+            open: Arc::default(),
+            max_connections: config.max_connections,
+            max_per_client: (per_client > 0).then_some(per_client),
+            reject: config.reject,
+        }
+    }
+
+    /// a permit for another websocket from client, if it's under the limits
+    pub fn acquire(&self, client: IpAddr) -> Option<SocketPermit> {
+        let mut open = self.open.lock();
+        if self.max_connections.is_some_and(|max| open.total >= max) {
+            return None;
+        }
+        let count = open.clients.get(&client).copied().unwrap_or(0);
+        if self.max_per_client.is_some_and(|max| count >= max) {
+            return None;
+        }
+        open.total += 1;
+        open.clients.insert(client, count + 1);
+        Some(SocketPermit {
+            open: self.open.clone(),
+            client,
+        })
+    }
+
+    /// upgrade to a websocket that serve handles, unless client is over a limit
+    pub fn upgrade<F, Fut>(&self, client: IpAddr, ws: WebSocketUpgrade, serve: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if let Some(permit) = self.acquire(client) {
+            return ws.on_upgrade(move |socket| async move {
+                serve(socket).await;
+                drop(permit);
+            });
+        }
+        tracing::warn!(%client, "too many websockets, refusing another");
+        match self.reject {
+            Reject::Status => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            Reject::Close => ws.on_upgrade(|mut socket| async move {
+                let frame = CloseFrame {
+                    code: TRY_AGAIN_LATER,
+                    reason: "too many connections".into(),
+                };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn permits_are_counted_per_client_and_in_total() {
+        let limits = SocketLimits::new(&WebSocketConfig {
+            max_connections: Some(3),
+            max_per_client: Some(2),
+            ..Default::default()
+        });
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let first = limits.acquire(a).expect("first");
+        let _second = limits.acquire(a).expect("second");
+        assert!(limits.acquire(a).is_none(), "a is at its limit");
+        let _third = limits.acquire(b).expect("b has its own limit");
+        assert!(limits.acquire(b).is_none(), "the total is at its limit");
+
+        drop(first);
+        assert!(limits.acquire(a).is_some(), "closing one frees a permit");
+    }
+}
@@ -0,0 +1,543 @@
+// a socket.io endpoint, so frontends written for socket.io-client can talk to an app.
+//
+//   local sockets = {}
+//   routes:socketio(function(socket, req)
+//       if not req.cookies.session then return false end  -- refuse the connection
+//       sockets[socket.id] = socket
+//       socket:on("chat", function(message)
+//           for _, other in pairs(sockets) do other:emit("chat", message) end
+//           return "delivered"  -- what is returned is the ack, if the client asked for one
+//       end)
+//       socket:on("disconnect", function(reason) sockets[socket.id] = nil end)
+//   end)
+//
+// and in the browser:
+//
+//   const socket = io({ transports: ["websocket"] })
+//
+// it is served at /socket.io/, or routes:socketio(handler, { path = "/chat/" }). only the
+// websocket transport of engine.io v4 is spoken, so clients must skip long-polling as
+// above; a polling request is answered with engine.io's "Transport unknown" error. the
+// default namespace is the only one, and binary attachments are not supported.
+//
+// socket.auth is the auth the client connected with. events from a client are handled
+// one at a time, in order. emits are queued like ws:send, and a client too slow to keep
+// up is disconnected.
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{collections::HashMap, fmt::Write, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// where socket.io-client connects by default
+pub const DEFAULT_PATH: &str = "/socket.io/";
+
+const PING_INTERVAL: Duration = Duration::from_secs(25);
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// how long a client has to join the namespace after the handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(45);
+const MAX_PAYLOAD: usize = 1_000_000;
+
+/// packets a socket holds for a client before it is too slow
+const QUEUE_SIZE: usize = 256;
+
+/// the close code for a policy violation, which falling behind is
+const OVERFLOW_CODE: u16 = 1008;
+
+// engine.io packet types
+const OPEN: char = '0';
+const CLOSE: char = '1';
+const PING: char = '2';
+const MESSAGE: char = '4';
+
+// socket.io packet types
+const CONNECT: u8 = 0;
+const DISCONNECT: u8 = 1;
+const EVENT: u8 = 2;
+const ACK: u8 = 3;
+const CONNECT_ERROR: u8 = 4;
+
+/// a socket.io packet from a client
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Connect {
+        namespace: String,
+        auth: Option<Value>,
+    },
+    Disconnect {
+        namespace: String,
+    },
+    Event {
+        namespace: String,
+        id: Option<u64>,
+        data: Vec<Value>,
+    },
+}
+
+/// parse a socket.io packet: <type>[<namespace>,][<ack id>][<json>]
+fn parse(packet: &str) -> Option<Packet> {
+    let kind = packet
+        .bytes()
+        .next()?
+        .checked_sub(b'0')
+        .filter(|kind| *kind < 10)?;
+    let rest = &packet[1..];
+    let (namespace, rest) = match rest.strip_prefix('/') {
+        Some(_) => rest.split_once(',').unwrap_or((rest, "")),
+        None => ("/", rest),
+    };
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let id = rest[..digits].parse().ok();
+    let json = match &rest[digits..] {
+        "" => None,
+        json => Some(serde_json::from_str::<Value>(json).ok()?),
+    };
+    let namespace = namespace.to_string();
+    match (kind, json) {
+        (CONNECT, auth) => Some(Packet::Connect { namespace, auth }),
+        (DISCONNECT, _) => Some(Packet::Disconnect { namespace }),
+        (EVENT, Some(Value::Array(data))) if data.first().is_some_and(Value::is_string) => {
+            Some(Packet::Event {
+                namespace,
+                id,
+                data,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// a socket.io packet, wrapped in an engine.io message
+fn encode(kind: u8, namespace: &str, id: Option<u64>, data: Option<&Value>) -> String {
+    let mut packet = format!("{MESSAGE}{kind}");
+    if namespace != "/" {
+        let _ = write!(packet, "{namespace},");
+    }
+    if let Some(id) = id {
+        let _ = write!(packet, "{id}");
+    }
+    if let Some(data) = data {
+        let _ = write!(packet, "{data}");
+    }
+    packet
+}
+
+/// the engine.io error for a request that can't become a socket, if it is one
+pub fn handshake_error(query: Option<&str>, websocket: bool) -> Option<Response> {
+    let param = |name: &str| {
+        query?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    };
+    let (code, message) = if param("EIO") != Some("4") {
+        (5, "Unsupported protocol version")
+    } else if !websocket || param("transport") != Some("websocket") {
+        (0, "Transport unknown")
+    } else if param("sid").is_some() {
+        // an upgrade from long-polling, which never started
+        (1, "Session ID unknown")
+    } else {
+        return None;
+    };
+    let body = json!({ "code": code, "message": message });
+    Some((StatusCode::BAD_REQUEST, axum::Json(body)).into_response())
+}
+
+/// why a socket closed
+#[derive(Debug, Clone, Copy)]
+enum Closing {
+    /// socket:disconnect()
+    Server,
+    /// the client fell too far behind
+    Overflow,
+    Client(&'static str),
+}
+
+impl Closing {
+    /// the reason socket.io gives disconnect handlers
+    fn reason(self) -> &'static str {
+        match self {
+            Closing::Server => "server namespace disconnect",
+            Closing::Overflow => "transport error",
+            Closing::Client(reason) => reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SocketIo {
+    id: String,
+    auth: Option<Value>,
+    queue: mpsc::Sender<String>,
+    events: Arc<Mutex<HashMap<String, LuaFunction>>>,
+    closing: Arc<Mutex<Option<Closing>>>,
+    /// cancelled once the socket is closing, for any reason
+    closed: CancellationToken,
+}
+
+impl SocketIo {
+    fn close(&self, closing: Closing) {
+        self.closing.lock().get_or_insert(closing);
+        self.closed.cancel();
+    }
+
+    fn send(&self, packet: String) -> LuaResult<()> {
+        if self.closed.is_cancelled() {
+            return Err(LuaError::runtime("socket.io connection is closed"));
+        }
+        match self.queue.try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.close(Closing::Overflow);
+                Err(LuaError::runtime(
+                    "socket.io send queue is full, the client is too slow and was disconnected",
+                ))
+            }
+            Err(TrySendError::Closed(_)) => {
+                Err(LuaError::runtime("socket.io connection is closed"))
+            }
+        }
+    }
+
+    fn emit(&self, lua: &Lua, event: String, args: LuaMultiValue) -> LuaResult<()> {
+        let mut data = vec![Value::String(event)];
+        for arg in args {
+            data.push(lua.from_value(arg)?);
+        }
+        self.send(encode(EVENT, "/", None, Some(&Value::Array(data))))
+    }
+}
+
+impl LuaUserData for SocketIo {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_, this| Ok(this.id.clone()));
+        fields.add_field_method_get("auth", |lua, this| match &this.auth {
+            Some(auth) => lua.to_value(auth),
+            None => Ok(LuaNil),
+        });
+        fields.add_field_method_get("connected", |_, this| Ok(!this.closed.is_cancelled()));
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // socket:on(event, function(...) end), or nil to stop handling it
+        methods.add_method(
+            "on",
+            |_, this, (event, handler): (String, Option<LuaFunction>)| {
+                let mut events = this.events.lock();
+                match handler {
+                    Some(handler) => events.insert(event, handler),
+                    None => events.remove(&event),
+                };
+                Ok(())
+            },
+        );
+        methods.add_method(
+            "emit",
+            |lua, this, (event, args): (String, LuaMultiValue)| this.emit(lua, event, args),
+        );
+        methods.add_method("disconnect", |_, this, ()| {
+            this.close(Closing::Server);
+            Ok(())
+        });
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!("socket.io {}", this.id))
+        });
+    }
+}
+
+/// serve one client until it disconnects
+pub async fn serve(socket: WebSocket, lua: Lua, handler: LuaFunction, req: LuaTable) {
+    let (mut sink, mut stream) = socket.split();
+    let id = format!("{:016x}", rand::random::<u64>());
+    let open = json!({
+        "sid": id,
+        "upgrades": [],
+        "pingInterval": PING_INTERVAL.as_millis() as u64,
+        "pingTimeout": PING_TIMEOUT.as_millis() as u64,
+        "maxPayload": MAX_PAYLOAD,
+    });
+    if send(&mut sink, format!("{OPEN}{open}")).await.is_err() {
+        return;
+    }
+    let Ok(Some(auth)) =
+        tokio::time::timeout(CONNECT_TIMEOUT, connect(&mut sink, &mut stream)).await
+    else {
+        return;
+    };
+
+    let (queue, packets) = mpsc::channel(QUEUE_SIZE);
+    let socket = SocketIo {
+        id: id.clone(),
+        auth,
+        queue,
+        events: Arc::default(),
+        closing: Arc::default(),
+        closed: CancellationToken::new(),
+    };
+    let refused = match handler.call_async::<LuaValue>((socket.clone(), req)).await {
+        Ok(LuaValue::Boolean(false)) => Some("not authorized"),
+        Ok(_) => None,
+        Err(err) => {
+            tracing::error!(%err, "error connecting socket.io client");
+            Some("server error")
+        }
+    };
+    if let Some(message) = refused {
+        socket.close(Closing::Server);
+        let error = json!({ "message": message });
+        let _ = send(&mut sink, encode(CONNECT_ERROR, "/", None, Some(&error))).await;
+        return;
+    }
+    let connected = json!({ "sid": id });
+    if send(&mut sink, encode(CONNECT, "/", None, Some(&connected)))
+        .await
+        .is_err()
+    {
+        socket.close(Closing::Client("transport close"));
+        return;
+    }
+    tokio::spawn(write_queue(sink, packets, socket.clone()));
+
+    let reason = read(&lua, &socket, &mut stream).await;
+    socket.close(Closing::Client(reason));
+    let closing = *socket.closing.lock();
+    let reason = closing.map_or(reason, Closing::reason);
+    let on_disconnect = socket.events.lock().get("disconnect").cloned();
+    if let Some(on_disconnect) = on_disconnect {
+        if let Err(err) = on_disconnect.call_async::<()>(reason).await {
+            tracing::error!(%err, "error handling socket.io disconnect");
+        }
+    }
+}
+
+async fn send(sink: &mut SplitSink<WebSocket, Message>, packet: String) -> Result<(), axum::Error> {
+    sink.send(Message::Text(packet.into())).await
+}
+
+/// wait for the client to join the default namespace, and return its auth
+async fn connect(
+    sink: &mut SplitSink<WebSocket, Message>,
+    stream: &mut SplitStream<WebSocket>,
+) -> Option<Option<Value>> {
+    loop {
+        let Message::Text(text) = stream.next().await?.ok()? else {
+            continue;
+        };
+        let Some(packet) = text.as_str().strip_prefix(MESSAGE) else {
+            if text.as_str().starts_with(CLOSE) {
+                return None;
+            }
+            continue;
+        };
+        match parse(packet)? {
+            Packet::Connect { namespace, auth } if namespace == "/" => return Some(auth),
+            Packet::Connect { namespace, .. } => {
+                let error = json!({ "message": "Invalid namespace" });
+                let packet = encode(CONNECT_ERROR, &namespace, None, Some(&error));
+                send(sink, packet).await.ok()?;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// handle the client's packets until it goes, and return why it did
+async fn read(lua: &Lua, socket: &SocketIo, stream: &mut SplitStream<WebSocket>) -> &'static str {
+    let mut deadline = Instant::now() + PING_INTERVAL + PING_TIMEOUT;
+    loop {
+        let message = tokio::select! {
+            biased;
+            message = stream.next() => message,
+            _ = socket.closed.cancelled() => return "transport close",
+            _ = tokio::time::sleep_until(deadline) => return "ping timeout",
+        };
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return "transport close",
+            Some(Ok(_)) => continue,
+        };
+        if text.len() > MAX_PAYLOAD {
+            return "transport error";
+        }
+        // anything from the client shows it is still there
+        deadline = Instant::now() + PING_INTERVAL + PING_TIMEOUT;
+        let Some(packet) = text.as_str().strip_prefix(MESSAGE) else {
+            // pongs only matter for the deadline
+            if text.as_str().starts_with(CLOSE) {
+                return "transport close";
+            }
+            continue;
+        };
+        match parse(packet) {
+            Some(Packet::Event {
+                namespace,
+                id,
+                data,
+            }) if namespace == "/" => dispatch(lua, socket, id, data).await,
+            Some(Packet::Disconnect { namespace }) if namespace == "/" => {
+                return "client namespace disconnect"
+            }
+            // acks are never asked for, and other namespaces were refused
+            _ => continue,
+        }
+    }
+}
+
+/// call the handler for an event, and send what it returns if the client wants an ack
+async fn dispatch(lua: &Lua, socket: &SocketIo, id: Option<u64>, data: Vec<Value>) {
+    let mut data = data.into_iter();
+    let Some(Value::String(event)) = data.next() else {
+        return;
+    };
+    let Some(handler) = socket.events.lock().get(&event).cloned() else {
+        return;
+    };
+    let acked = async {
+        let args = data
+            .map(|arg| lua.to_value(&arg))
+            .collect::<LuaResult<LuaMultiValue>>()?;
+        let returned = handler.call_async::<LuaMultiValue>(args).await?;
+        let Some(id) = id else {
+            return Ok(());
+        };
+        let ack = returned
+            .into_iter()
+            .map(|value| lua.from_value(value))
+            .collect::<LuaResult<Vec<Value>>>()?;
+        socket.send(encode(ACK, "/", Some(id), Some(&Value::Array(ack))))
+    };
+    if let Err(err) = acked.await {
+        tracing::error!(%event, %err, "error handling socket.io event");
+    }
+}
+
+/// write queued packets and pings to the client until the socket closes
+async fn write_queue(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut packets: mpsc::Receiver<String>,
+    socket: SocketIo,
+) {
+    let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    loop {
+        let packet = tokio::select! {
+            _ = socket.closed.cancelled() => break,
+            _ = ping.tick() => PING.to_string(),
+            packet = packets.recv() => match packet {
+                Some(packet) => packet,
+                None => break,
+            },
+        };
+        if send(&mut sink, packet).await.is_err() {
+            socket.close(Closing::Client("transport close"));
+            return;
+        }
+    }
+    packets.close();
+    let closing = *socket.closing.lock();
+    let goodbye = match closing {
+        Some(Closing::Server) => Message::Text(encode(DISCONNECT, "/", None, None).into()),
+        Some(Closing::Overflow) => Message::Close(Some(CloseFrame {
+            code: OVERFLOW_CODE,
+            reason: "too slow".into(),
+        })),
+        Some(Closing::Client(_)) | None => return,
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        sink.send(goodbye).await?;
+        sink.close().await
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_packets() {
+        assert_eq!(
+            parse("0"),
+            Some(Packet::Connect {
+                namespace: "/".into(),
+                auth: None
+            })
+        );
+        assert_eq!(
+            parse(r#"0/admin,{"token":"x"}"#),
+            Some(Packet::Connect {
+                namespace: "/admin".into(),
+                auth: Some(json!({ "token": "x" }))
+            })
+        );
+        assert_eq!(
+            parse(r#"212["chat","hi"]"#),
+            Some(Packet::Event {
+                namespace: "/".into(),
+                id: Some(12),
+                data: vec![json!("chat"), json!("hi")]
+            })
+        );
+        assert_eq!(
+            parse(r#"2["chat"]"#),
+            Some(Packet::Event {
+                namespace: "/".into(),
+                id: None,
+                data: vec![json!("chat")]
+            })
+        );
+        assert_eq!(
+            parse("1"),
+            Some(Packet::Disconnect {
+                namespace: "/".into()
+            })
+        );
+        // events need a name, and acks an id
+        assert_eq!(parse("2[1]"), None);
+        assert_eq!(parse("3[1]"), None);
+        assert_eq!(parse("2[bad json"), None);
+    }
+
+    #[test]
+    fn encodes_packets() {
+        assert_eq!(
+            encode(CONNECT, "/", None, Some(&json!({ "sid": "a" }))),
+            r#"40{"sid":"a"}"#
+        );
+        assert_eq!(
+            encode(ACK, "/", Some(7), Some(&json!(["ok"]))),
+            r#"437["ok"]"#
+        );
+        assert_eq!(
+            encode(
+                CONNECT_ERROR,
+                "/admin",
+                None,
+                Some(&json!({ "message": "no" }))
+            ),
+            r#"44/admin,{"message":"no"}"#
+        );
+        assert_eq!(encode(DISCONNECT, "/", None, None), "41");
+    }
+
+    #[test]
+    fn handshake_needs_websocket_v4() {
+        assert!(handshake_error(Some("EIO=4&transport=websocket"), true).is_none());
+        assert!(handshake_error(Some("EIO=4&transport=polling&t=abc"), false).is_some());
+        assert!(handshake_error(Some("EIO=3&transport=websocket"), true).is_some());
+        assert!(handshake_error(Some("EIO=4&transport=websocket&sid=abc"), true).is_some());
+        assert!(handshake_error(None, true).is_some());
+    }
+}
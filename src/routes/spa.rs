@@ -0,0 +1,78 @@
+// single page apps, like a vite or react build, served alongside an app's routes.
+//
+//   lilguy serve --spa dist/
+//   routes:spa("dist", { prefix = "/app" })  -- relative to app.lua, prefix defaults to /
+//
+// a GET under the prefix that no route matches is served from the directory, and when
+// there is no such file, index.html is served instead so the page's own router can
+// handle the path. routes, api routes and rpc procedures all match first. a request that
+// doesn't accept html, like a script that isn't there, still gets a 404.
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header::ACCEPT, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::path::Path;
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// the part of path under prefix, if it is under it
+pub fn strip_prefix<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+fn accepts_html(request: &Request) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// a file from dir, or its index.html
+pub async fn serve(mut request: Request, prefix: &str, dir: &Path) -> Response {
+    let path = strip_prefix(prefix, request.uri().path()).unwrap_or_default();
+    let uri = match request.uri().query() {
+        Some(query) => format!("/{}?{query}", path.trim_start_matches('/')),
+        None => format!("/{}", path.trim_start_matches('/')),
+    };
+    let Ok(uri) = uri.parse::<Uri>() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    *request.uri_mut() = uri;
+
+    let files = ServeDir::new(dir);
+    let served = if accepts_html(&request) {
+        files
+            .fallback(ServeFile::new(dir.join("index.html")))
+            .oneshot(request)
+            .await
+    } else {
+        files.oneshot(request).await
+    };
+    match served {
+        Ok(response) => response.map(Body::new),
+        Err(err) => match err {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_prefixes() {
+        assert_eq!(strip_prefix("/", "/about"), Some("/about"));
+        assert_eq!(strip_prefix("/app", "/app"), Some(""));
+        assert_eq!(strip_prefix("/app/", "/app/settings"), Some("/settings"));
+        assert_eq!(strip_prefix("/app", "/application"), None);
+        assert_eq!(strip_prefix("/app", "/api/users"), None);
+    }
+}
@@ -0,0 +1,385 @@
+// yjs documents served over the y-websocket protocol, for collaborative editors like
+// tiptap and blocknote.
+//
+//   routes:yjs("/collab/:doc")
+//   routes:yjs("/notes/:doc", function(req) return can_edit(req, req.params.doc) end)
+//
+// and in the browser:
+//
+//   new WebsocketProvider("ws://localhost:8000/collab", "my-doc", ydoc)
+//
+// the document is named by the :doc param, or the whole path without one, and stored
+// in lg_crdt like crdt.doc(name), so lua can read and merge it too. updates from one
+// client are applied to the stored document and relayed to everyone else editing it.
+// awareness (cursors, who is here) is only relayed, and forgotten when a client leaves.
+use axum::extract::ws::{Message, WebSocket};
+use bytes::Bytes;
+use eyre::{eyre, Result};
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::database::{
+    crdt::{apply_update, encode_update, state_vector, with_doc, CrdtError, Kind},
+    Database,
+};
+
+const MESSAGE_SYNC: u64 = 0;
+const MESSAGE_AWARENESS: u64 = 1;
+const MESSAGE_QUERY_AWARENESS: u64 = 3;
+
+const SYNC_STEP_1: u64 = 0;
+const SYNC_STEP_2: u64 = 1;
+const SYNC_UPDATE: u64 = 2;
+
+/// how many relayed messages a slow client can fall behind before it is sent the
+/// whole document again
+const ROOM_CAPACITY: usize = 256;
+
+/// a yjs endpoint matched by a request
+#[derive(Debug, Clone)]
+pub struct YjsRoute {
+    pub pattern: String,
+    pub params: Vec<(String, String)>,
+    pub doc: String,
+    pub authorize: Option<LuaFunction>,
+}
+
+/// the clients editing each document, shared by every connection to an app
+#[derive(Debug, Clone, Default)]
+pub struct YjsRooms(Arc<Mutex<HashMap<String, Arc<Room>>>>);
+
+#[derive(Debug)]
+struct Room {
+    relay: broadcast::Sender<Relayed>,
+    /// the latest awareness state of each yjs client: (clock, json)
+    awareness: Mutex<HashMap<u64, (u64, String)>>,
+}
+
+#[derive(Debug, Clone)]
+struct Relayed {
+    from: u64,
+    message: Bytes,
+}
+
+impl YjsRooms {
+    fn join(&self, doc: &str) -> Arc<Room> {
+        self.0
+            .lock()
+            .entry(doc.to_string())
+            .or_insert_with(|| {
+                Arc::new(Room {
+                    relay: broadcast::channel(ROOM_CAPACITY).0,
+                    awareness: Mutex::default(),
+                })
+            })
+            .clone()
+    }
+
+    /// forget the room once its last client has left
+    fn leave(&self, doc: &str) {
+        let mut rooms = self.0.lock();
+        if rooms
+            .get(doc)
+            .is_some_and(|room| room.relay.receiver_count() == 0)
+        {
+            rooms.remove(doc);
+        }
+    }
+}
+
+/// serve one client until it disconnects
+pub async fn serve(mut socket: WebSocket, database: Database, rooms: YjsRooms, doc: String) {
+    let room = rooms.join(&doc);
+    let mut relayed = room.relay.subscribe();
+    let mut connection = Connection {
+        id: rand::random(),
+        doc,
+        database,
+        room,
+        clients: HashSet::new(),
+    };
+    if let Err(err) = connection.run(&mut socket, &mut relayed).await {
+        tracing::debug!(%err, doc = %connection.doc, "yjs connection closed");
+    }
+    connection.forget_clients();
+    drop(relayed);
+    rooms.leave(&connection.doc);
+}
+
+struct Connection {
+    id: u64,
+    doc: String,
+    database: Database,
+    room: Arc<Room>,
+    /// the yjs clients whose awareness this connection has sent
+    clients: HashSet<u64>,
+}
+
+impl Connection {
+    async fn run(
+        &mut self,
+        socket: &mut WebSocket,
+        relayed: &mut broadcast::Receiver<Relayed>,
+    ) -> Result<()> {
+        let vector = self.call(|doc| Ok(state_vector(doc))).await?;
+        send(socket, sync_message(SYNC_STEP_1, &vector)).await?;
+        if let Some(message) = self.awareness_states() {
+            send(socket, message).await?;
+        }
+
+        loop {
+            tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Binary(data))) => {
+                        if let Some(reply) = self.receive(&data).await? {
+                            send(socket, reply).await?;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err.into()),
+                },
+                message = relayed.recv() => match message {
+                    Ok(Relayed { from, message }) if from != self.id => {
+                        socket.send(Message::Binary(message)).await?;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        let update = self.call(|doc| encode_update(doc, None)).await?;
+                        send(socket, sync_message(SYNC_UPDATE, &update)).await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// handle a message from the client, returning the reply if there is one
+    async fn receive(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut reader = Reader::new(data);
+        match reader.var_uint()? {
+            MESSAGE_SYNC => {
+                let step = reader.var_uint()?;
+                let payload = reader.var_bytes()?.to_vec();
+                match step {
+                    SYNC_STEP_1 => {
+                        let update = self
+                            .call(move |doc| encode_update(doc, Some(&payload)))
+                            .await?;
+                        Ok(Some(sync_message(SYNC_STEP_2, &update)))
+                    }
+                    SYNC_STEP_2 | SYNC_UPDATE => {
+                        let relay = sync_message(SYNC_UPDATE, &payload);
+                        self.call(move |doc| apply_update(doc, &payload)).await?;
+                        self.relay(relay);
+                        Ok(None)
+                    }
+                    step => Err(eyre!("unknown yjs sync step {step}")),
+                }
+            }
+            MESSAGE_AWARENESS => {
+                let update = reader.var_bytes()?;
+                self.update_awareness(update)?;
+                self.relay(data.to_vec());
+                Ok(None)
+            }
+            MESSAGE_QUERY_AWARENESS => Ok(self.awareness_states()),
+            // auth and custom messages are not part of what the server does
+            _ => Ok(None),
+        }
+    }
+
+    /// run f on the stored document; changes it makes are saved
+    async fn call<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&yrs::Doc) -> Result<R, CrdtError> + Send + 'static,
+    {
+        let doc = self.doc.clone();
+        Ok(self
+            .database
+            .call(move |conn| with_doc(conn, &doc, Kind::Doc, f))
+            .await?)
+    }
+
+    fn relay(&self, message: Vec<u8>) {
+        // no receivers is fine, it means everyone else has left
+        let _ = self.room.relay.send(Relayed {
+            from: self.id,
+            message: message.into(),
+        });
+    }
+
+    fn update_awareness(&mut self, update: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(update);
+        let mut states = self.room.awareness.lock();
+        for _ in 0..reader.var_uint()? {
+            let client = reader.var_uint()?;
+            let clock = reader.var_uint()?;
+            let json = reader.var_string()?;
+            self.clients.insert(client);
+            if json == "null" {
+                states.remove(&client);
+            } else {
+                states.insert(client, (clock, json));
+            }
+        }
+        Ok(())
+    }
+
+    /// everyone's awareness, for a client that just joined or asked for it
+    fn awareness_states(&self) -> Option<Vec<u8>> {
+        let states = self.room.awareness.lock();
+        if states.is_empty() {
+            return None;
+        }
+        let states = states
+            .iter()
+            .map(|(client, (clock, json))| (*client, *clock, json.as_str()));
+        Some(awareness_message(states))
+    }
+
+    /// tell everyone else this connection's clients are gone, the way a client does
+    /// when it closes cleanly
+    fn forget_clients(&mut self) {
+        let removed: Vec<(u64, u64)> = {
+            let mut states = self.room.awareness.lock();
+            self.clients
+                .drain()
+                .filter_map(|client| Some((client, states.remove(&client)?.0 + 1)))
+                .collect()
+        };
+        if !removed.is_empty() {
+            let removed = removed
+                .into_iter()
+                .map(|(client, clock)| (client, clock, "null"));
+            self.relay(awareness_message(removed));
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: Vec<u8>) -> Result<()> {
+    socket.send(Message::Binary(message.into())).await?;
+    Ok(())
+}
+
+fn sync_message(step: u64, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(payload.len() + 8);
+    write_var_uint(&mut message, MESSAGE_SYNC);
+    write_var_uint(&mut message, step);
+    write_var_bytes(&mut message, payload);
+    message
+}
+
+fn awareness_message<'a>(states: impl ExactSizeIterator<Item = (u64, u64, &'a str)>) -> Vec<u8> {
+    let mut update = Vec::new();
+    write_var_uint(&mut update, states.len() as u64);
+    for (client, clock, json) in states {
+        write_var_uint(&mut update, client);
+        write_var_uint(&mut update, clock);
+        write_var_bytes(&mut update, json.as_bytes());
+    }
+    let mut message = Vec::with_capacity(update.len() + 8);
+    write_var_uint(&mut message, MESSAGE_AWARENESS);
+    write_var_bytes(&mut message, &update);
+    message
+}
+
+// lib0 encoding: unsigned integers are 7 bits per byte, least significant first, with
+// the high bit set on every byte but the last. byte strings are prefixed by their length.
+
+fn write_var_uint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_var_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_var_uint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn var_uint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .data
+                .split_first()
+                .ok_or_else(|| eyre!("truncated yjs message"))?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(eyre!("yjs integer is too long"))
+    }
+
+    fn var_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.var_uint()?)?;
+        if len > self.data.len() {
+            return Err(eyre!("truncated yjs message"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn var_string(&mut self) -> Result<String> {
+        Ok(std::str::from_utf8(self.var_bytes()?)?.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_uint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_var_uint(&mut buf, value);
+            assert_eq!(Reader::new(&buf).var_uint().expect("decode"), value);
+        }
+        let mut buf = Vec::new();
+        write_var_uint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn awareness_messages() {
+        let message = awareness_message([(7, 2, "{\"user\":\"a\"}")].into_iter());
+        let mut reader = Reader::new(&message);
+        assert_eq!(reader.var_uint().expect("type"), MESSAGE_AWARENESS);
+        let mut update = Reader::new(reader.var_bytes().expect("update"));
+        assert_eq!(update.var_uint().expect("count"), 1);
+        assert_eq!(update.var_uint().expect("client"), 7);
+        assert_eq!(update.var_uint().expect("clock"), 2);
+        assert_eq!(update.var_string().expect("json"), "{\"user\":\"a\"}");
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let message = sync_message(SYNC_UPDATE, b"update");
+        let mut reader = Reader::new(&message[..message.len() - 1]);
+        reader.var_uint().expect("type");
+        reader.var_uint().expect("step");
+        assert!(reader.var_bytes().is_err());
+    }
+}
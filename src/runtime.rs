@@ -1,29 +1,70 @@
+pub mod activitypub;
+pub mod audit;
+pub mod blob;
+pub mod bytecode;
+pub mod cache;
 pub mod channel;
+pub mod compat;
+pub mod content;
+pub mod coroutine;
+pub mod crdt;
+pub mod debugger;
 pub mod dump;
 pub mod file;
+pub mod form;
+pub mod highlight;
 pub mod http;
+pub mod island;
 pub mod mdns;
+pub mod net;
+pub mod og;
 pub mod os;
+pub mod paginate;
+pub mod payments;
+pub mod profiler;
 pub mod regex;
-
+pub mod seo;
+pub mod sidecar;
+pub mod slug;
+pub mod sri;
+pub mod ssh;
+pub mod stdlib;
+pub mod sync;
+pub mod useragent;
+pub mod validate;
+#[cfg(feature = "passkeys")]
+pub mod webauthn;
+
+use debugger::Debugger;
 use eyre::{eyre, Result};
 use http::not_found;
 pub use mlua::prelude::*;
 use mlua::IntoLua;
 use parking_lot::Mutex;
+use profiler::Profiler;
+use rusqlite::OptionalExtension;
 use serde::Serialize;
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
+use tokio::time::timeout;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    database::{global::Global, Database},
-    routes::Routes,
+    command::Config,
+    database::{
+        global::{self, Global},
+        Database,
+    },
+    routes::{
+        bots::Bots, mounts::LuaStatic, rooms::Rooms, socket_limits::SocketLimits, yjs::YjsRooms,
+        Routes,
+    },
     template::Template,
     watch::{watch, Match},
 };
@@ -31,11 +72,65 @@ use crate::{
 const LUA_PRELUDE: &str = include_str!("prelude.lua");
 const SQL_SCHEMA: &str = include_str!("schema.sql");
 
+/// how long to wait for in-flight requests to finish during shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how long on_shutdown is allowed to run before we give up on it
+const ON_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// which lua lilguy was built with, chosen by the luajit or luau cargo feature
+#[cfg(not(feature = "luau"))]
+pub const LUA_FLAVOR: &str = "luajit";
+#[cfg(feature = "luau")]
+pub const LUA_FLAVOR: &str = "luau";
+
+/// the parts of the lua standard library apps get. os and io are replaced by async versions,
+/// and coroutine is wrapped to work with them; see runtime/coroutine.rs.
+#[cfg(not(feature = "luau"))]
+pub fn std_libs() -> LuaStdLib {
+    LuaStdLib::COROUTINE
+        | LuaStdLib::TABLE
+        | LuaStdLib::STRING
+        | LuaStdLib::MATH
+        | LuaStdLib::PACKAGE
+        | LuaStdLib::BIT
+}
+
+/// luau has no package library; require is added in Runtime::new_lua
+#[cfg(feature = "luau")]
+pub fn std_libs() -> LuaStdLib {
+    LuaStdLib::COROUTINE | LuaStdLib::TABLE | LuaStdLib::STRING | LuaStdLib::MATH | LuaStdLib::BIT32
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Runtime {
-    lua: Arc<Mutex<Option<Lua>>>,
+    /// the lua state, and the routes it registered so requests can be matched without it
+    lua: Arc<Mutex<Option<(Lua, Routes)>>>,
     services: Arc<Mutex<Option<Services>>>,
     started: Arc<AtomicBool>,
+    dev: Arc<AtomicBool>,
+    requests: TaskTracker,
+    config: Arc<Config>,
+    debugger: Option<Debugger>,
+    profiler: Option<Profiler>,
+    offline: bool,
+    yjs_rooms: YjsRooms,
+    rooms: Rooms,
+    modules: Vec<Module>,
+    socket_limits: SocketLimits,
+    bots: Bots,
+    firewall: Firewall,
+    spa: Option<PathBuf>,
+}
+
+/// a function that adds globals to each new lua state, from Runtime::register
+#[derive(Clone)]
+struct Module(Arc<dyn Fn(&Lua) -> LuaResult<()> + Send + Sync>);
+
+impl std::fmt::Debug for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Module").finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -45,8 +140,79 @@ struct Services {
 }
 
 impl Runtime {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// step through handlers with a debug adapter client; see runtime/debugger.rs
+    pub fn with_debugger(mut self, debugger: Debugger) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    /// sample lua stacks per route; see runtime/profiler.rs
+    pub fn with_profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    /// fail fetches that no fetch.mock() answers; see runtime/http/fetch/mock.rs
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// the websocket limits to share with other apps the server runs; see routes/socket_limits.rs
+    pub fn with_socket_limits(mut self, socket_limits: SocketLimits) -> Self {
+        self.socket_limits = socket_limits;
+        self
+    }
+
+    /// the bot counts to share with other apps the server runs; see routes/bots.rs
+    pub fn with_bots(mut self, bots: Bots) -> Self {
+        self.bots = bots;
+        self
+    }
+
+    /// the config's address rules, shared with other apps the server runs; see routes/firewall.rs
+    pub fn with_firewall(mut self, firewall: Firewall) -> Self {
+        self.firewall = firewall;
+        self
+    }
+
+    /// serve a single page app for requests no route matches; see routes/spa.rs
+    pub fn with_spa(mut self, dir: Option<PathBuf>) -> Self {
+        self.spa = dir;
+        self
+    }
+
+    pub fn socket_limits(&self) -> &SocketLimits {
+        &self.socket_limits
+    }
+
+    pub fn bots(&self) -> &Bots {
+        &self.bots
+    }
+
+    pub fn firewall(&self) -> &Firewall {
+        &self.firewall
+    }
+
+    /// add globals of your own to every lua state, after the built-in ones and before the
+    /// app is loaded. the function runs again each time the app reloads.
+    pub fn register(
+        mut self,
+        module: impl Fn(&Lua) -> LuaResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.modules.push(Module(Arc::new(module)));
+        self
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
     }
 
     /// load the main lua file and set up the environment
@@ -66,6 +232,11 @@ impl Runtime {
     }
 
     pub fn lua(&self) -> Result<Lua> {
+        Ok(self.lua_with_routes()?.0)
+    }
+
+    /// the lua state and its routes, which are always from the same load of the app
+    pub fn lua_with_routes(&self) -> Result<(Lua, Routes)> {
         let lua = self
             .lua
             .lock()
@@ -76,8 +247,10 @@ impl Runtime {
     }
 
     #[tracing::instrument(level = "debug", skip(self))]
-    fn set_lua(&self, lua: Lua) {
-        self.lua.lock().replace(lua);
+    fn set_lua(&self, lua: Lua) -> Result<()> {
+        let routes = Routes::clone(&*lua.globals().get::<LuaUserDataRef<Routes>>("routes")?);
+        self.lua.lock().replace((lua, routes));
+        Ok(())
     }
 
     #[tracing::instrument(level = "debug", skip(self, app))]
@@ -87,7 +260,11 @@ impl Runtime {
             let mut services = self.services.lock();
             if services.is_none() {
                 let database = Database::open(app.with_extension("db"))?;
-                let template = Template::new(app.with_file_name("templates"));
+                database.set_statement_timeout(self.config.database.statement_timeout()?);
+                let template = Template::new(
+                    app.with_file_name("templates"),
+                    self.config.template.workers(),
+                );
                 db = database.clone();
                 services.replace(Services { database, template });
             } else {
@@ -95,15 +272,103 @@ impl Runtime {
             }
         }
 
+        let config = &self.config.database;
+        db.set_slow_call(config.slow_call()?, config.log_sql)
+            .await?;
         db.call(|conn| {
-            conn.execute_batch(SQL_SCHEMA)?;
+            let tx = conn.transaction()?;
+            let created = tx
+                .query_row(
+                    "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'lg_internal'",
+                    [],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_none();
+            tx.execute_batch(SQL_SCHEMA)?;
+            // only a database made just now is seeded, see seed()
+            if created {
+                tx.execute(
+                    "INSERT INTO lg_internal (name, value) VALUES ('unseeded', unixepoch())",
+                    [],
+                )?;
+            }
+            tx.commit()?;
             Ok(())
         })
         .await?;
 
+        // the app's own tables, from schema.sql next to app.lua. it runs on every start,
+        // so it should use CREATE TABLE IF NOT EXISTS and the like.
+        let schema = app.with_file_name("schema.sql");
+        match tokio::fs::read_to_string(&schema).await {
+            Ok(sql) => db
+                .call(move |conn| {
+                    conn.execute_batch(&sql)?;
+                    Ok(())
+                })
+                .await
+                .map_err(|err| eyre!("{}: {err}", schema.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(())
+    }
+
+    /// services backed by an empty database in memory, for a lua state that is only looked
+    /// at, like the one `lilguy defs` describes. the app's database is neither opened nor
+    /// made, and nothing from the app runs.
+    pub async fn start_scratch_services(&self, app: &Path) -> Result<()> {
+        let database = Database::open_in_memory()?;
+        database
+            .call(|conn| {
+                conn.execute_batch(SQL_SCHEMA)?;
+                Ok(())
+            })
+            .await?;
+        let template = Template::new(
+            app.with_file_name("templates"),
+            self.config.template.workers(),
+        );
+        self.services
+            .lock()
+            .replace(Services { database, template });
         Ok(())
     }
 
+    /// true when the app is reloaded on changes, which is how it is run during development
+    pub fn is_dev(&self) -> bool {
+        self.dev.load(Ordering::Relaxed)
+    }
+
+    /// in-flight requests, drained before on_shutdown is called
+    pub fn requests(&self) -> &TaskTracker {
+        &self.requests
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn database(&self) -> Result<Database> {
+        Ok(self.services()?.database)
+    }
+
+    pub fn template(&self) -> Result<Template> {
+        Ok(self.services()?.template)
+    }
+
+    /// the clients editing each routes:yjs() document, kept across reloads
+    pub fn yjs_rooms(&self) -> &YjsRooms {
+        &self.yjs_rooms
+    }
+
+    /// the rooms channel.publish sends to, kept across reloads
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
     fn services(&self) -> Result<Services> {
         self.services
             .lock()
@@ -127,7 +392,7 @@ impl Runtime {
             directory,
             vec![
                 ("runtime", Match::Extension("lua".to_string())),
-                ("templates", Match::StartsWith(directory.join("templates"))),
+                ("templates", Match::StartsWith(PathBuf::from("templates"))),
             ],
         )
         .await?;
@@ -146,7 +411,7 @@ impl Runtime {
                     "templates" => {
                         tracing::info!("reloading templates");
                         if let Err(err) = template
-                            .call(|env| {
+                            .call_each(|env| {
                                 env.clear_templates();
                                 Ok(())
                             })
@@ -171,21 +436,126 @@ impl Runtime {
         token: &CancellationToken,
     ) -> Result<()> {
         let lua = self.new_lua(app).await?;
-        self.set_lua(lua);
+        self.seed(&lua, app).await?;
+        self.set_lua(lua)?;
 
         let runtime = self.clone();
         let token = token.clone();
         tracker.spawn(async move {
             token.cancelled().await;
-            if let Err(err) = runtime.shutdown().await {
-                tracing::error!(?err, "error calling on_shutdown");
-            }
+            runtime.shutdown().await;
         });
         Ok(())
     }
 
+    /// shut down in order: stop net servers, drain in-flight requests, call on_shutdown,
+    /// then close the database and template actors. each phase is logged and bounded by a timeout.
     #[tracing::instrument(level = "debug", skip(self))]
-    pub async fn shutdown(&self) -> Result<()> {
+    pub async fn shutdown(&self) {
+        if let Ok(lua) = self.lua() {
+            tracing::info!("shutdown: closing net servers");
+            if let Err(err) = net::stop(&lua).await {
+                tracing::error!(?err, "error closing net servers");
+            }
+            tracing::info!("shutdown: stopping sync");
+            if let Err(err) = sync::stop(&lua).await {
+                tracing::error!(?err, "error stopping sync");
+            }
+        }
+
+        tracing::info!(
+            "shutdown: draining {} in-flight requests",
+            self.requests.len()
+        );
+        self.requests.close();
+        if timeout(DRAIN_TIMEOUT, self.requests.wait()).await.is_err() {
+            tracing::warn!(
+                pending = self.requests.len(),
+                "shutdown: timed out draining requests"
+            );
+        }
+
+        tracing::info!("shutdown: calling on_shutdown");
+        match timeout(ON_SHUTDOWN_TIMEOUT, self.on_shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!(?err, "error calling on_shutdown"),
+            Err(_) => tracing::warn!(
+                "shutdown: on_shutdown did not finish within {}s",
+                ON_SHUTDOWN_TIMEOUT.as_secs()
+            ),
+        }
+
+        if let Ok(services) = self.services() {
+            tracing::info!("shutdown: closing templates");
+            services.template.close();
+
+            tracing::info!("shutdown: closing database");
+            if let Err(err) = services.database.close().await {
+                tracing::error!(?err, "error closing database");
+            }
+        }
+
+        tracing::info!("shutdown: complete");
+    }
+
+    /// the first time the app starts with a new database, run seed.lua next to the app,
+    /// or else the app's seed function. a database is new when start_services made it,
+    /// which leaves an 'unseeded' row in lg_internal; seeding swaps it for a 'seeded' row, so
+    /// it happens once, and insert an 'unseeded' row to seed again. a database from before
+    /// there was seeding has neither, and isn't seeded. a failed seed is retried on the next
+    /// start.
+    #[tracing::instrument(level = "debug", skip(self, lua, app))]
+    async fn seed(&self, lua: &Lua, app: &Path) -> Result<()> {
+        let database = self.services()?.database;
+        let unseeded = database
+            .call(|conn| {
+                let unseeded = conn
+                    .query_row(
+                        "SELECT 1 FROM lg_internal WHERE name = 'unseeded'",
+                        [],
+                        |_| Ok(()),
+                    )
+                    .optional()?;
+                Ok(unseeded.is_some())
+            })
+            .await?;
+        if !unseeded {
+            return Ok(());
+        }
+
+        let seed_file = app.with_file_name("seed.lua");
+        if seed_file.exists() {
+            tracing::info!("seeding the database with {}", seed_file.display());
+            let source = tokio::fs::read_to_string(&seed_file).await?;
+            lua.load(source)
+                .set_name(format!("@{}", seed_file.display()))
+                .exec_async()
+                .await?;
+        } else if let Some(seed) = lua.globals().get::<Option<LuaFunction>>("seed")? {
+            tracing::info!("seeding the database with seed()");
+            seed.call_async::<()>(()).await?;
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        database
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM lg_internal WHERE name = 'unseeded'", [])?;
+                tx.execute(
+                    "INSERT INTO lg_internal (name, value) VALUES ('seeded', ?)",
+                    [now.to_string()],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn on_shutdown(&self) -> Result<()> {
         let lua = self.lua()?;
         let globals = lua.globals();
         if let Some(on_shutdown) = globals.get::<Option<LuaFunction>>("on_shutdown")? {
@@ -197,8 +567,13 @@ impl Runtime {
 
     #[tracing::instrument(level = "debug", skip(self))]
     async fn restart_lua(&self, app: &Path) -> Result<()> {
+        // the new app will most likely want the same addresses
+        if let Ok(lua) = self.lua() {
+            net::stop(&lua).await?;
+            sync::stop(&lua).await?;
+        }
         let lua = self.new_lua(app).await?;
-        self.set_lua(lua);
+        self.set_lua(lua)?;
         Ok(())
     }
 
@@ -214,10 +589,28 @@ impl Runtime {
             return Ok(());
         }
         self.start_services(app).await?;
+        self.dev.store(reload, Ordering::Relaxed);
+        // keep template source around for the error page
+        self.services()?
+            .template
+            .call_each(move |env| {
+                env.set_debug(reload);
+                Ok(())
+            })
+            .await?;
         if reload {
             self.start_watcher(app, tracker, token).await?;
         }
         self.start_lua(app, tracker, token).await?;
+        if !reload && !self.config.seo.ping.is_empty() {
+            let content = file::app_root(app)?.join("content");
+            tracker.spawn({
+                let runtime = self.clone();
+                let config = self.config.seo.clone();
+                let token = token.clone();
+                async move { seo::ping_on_change(runtime, config, &content, token).await }
+            });
+        }
         self.started.store(true, Ordering::Relaxed);
         Ok(())
     }
@@ -225,21 +618,58 @@ impl Runtime {
     #[allow(dependency_on_unit_never_type_fallback)]
     #[tracing::instrument(level = "debug", skip(self, app))]
     async fn new_lua(&self, app: &Path) -> Result<Lua> {
+        let lua = self.new_env(app).await?;
+        let require = lua.globals().get::<LuaFunction>("require")?;
+        require.call_async::<()>("app").await?;
+        Ok(lua)
+    }
+
+    /// a lua state with every global the runtime provides, before the app is loaded
+    #[tracing::instrument(level = "debug", skip(self, app))]
+    pub async fn new_env(&self, app: &Path) -> Result<Lua> {
         let services = self.services()?;
-        let lua = Lua::new_with(
-            LuaStdLib::TABLE
-                | LuaStdLib::STRING
-                | LuaStdLib::MATH
-                | LuaStdLib::PACKAGE
-                | LuaStdLib::BIT,
-            LuaOptions::default(),
-        )?;
+        // a lua state has one hook, so the debugger and profiler can't both be attached
+        let lua = match (&self.debugger, &self.profiler) {
+            (Some(_), Some(_)) => {
+                return Err(eyre!("the debugger and profiler can't run together"))
+            }
+            (Some(debugger), None) => {
+                let lua = debugger::new_lua()?;
+                debugger.attach(&lua)?;
+                lua
+            }
+            (None, Some(profiler)) => {
+                let lua = debugger::new_lua()?;
+                profiler.attach(&lua)?;
+                lua
+            }
+            (None, None) => Lua::new_with(std_libs(), LuaOptions::default())?,
+        };
 
         let globals = lua.globals();
-        let package = globals.get::<LuaTable>("package")?;
-        if let Some(parent) = app.parent() {
-            package.set("path", parent.join("?.lua").to_string_lossy())?;
+        let root = file::app_root(app)?;
+        #[cfg(not(feature = "luau"))]
+        {
+            let package = globals.get::<LuaTable>("package")?;
+            package.set("path", root.join("?.lua").to_string_lossy())?;
+            bytecode::register(&lua, &root)?;
         }
+        // luau requires modules relative to the file doing the requiring, and compiles
+        // hot functions to native code
+        #[cfg(feature = "luau")]
+        {
+            globals.set(
+                "require",
+                lua.create_require_function(mlua::TextRequirer::new())?,
+            )?;
+            lua.enable_jit(true);
+        }
+
+        let lilguy = lua.create_table()?;
+        lilguy.set("root", root.to_string_lossy())?;
+        lilguy.set("flavor", LUA_FLAVOR)?;
+        lilguy.set("api_version", compat::API_VERSION)?;
+        globals.set("lilguy", lilguy)?;
 
         globals.set("warn", lua.create_function(builtin_warn)?)?;
         globals.set("debug", lua.create_function(builtin_debug)?)?;
@@ -254,7 +684,13 @@ impl Runtime {
         globals.set("json", json)?;
 
         globals.set("global", Global::new(&services.database))?;
-        globals.set("routes", Routes::new(lua.create_function(not_found)?))?;
+        global::register(&lua)?;
+        let routes = Routes::new(lua.create_function(not_found)?);
+        if let Some(dir) = &self.spa {
+            routes.add_spa("/", dir.clone());
+        }
+        globals.set("static", LuaStatic(routes.clone()))?;
+        globals.set("routes", routes)?;
         globals.set("database", services.database.clone())?;
         globals.set("template", services.template.clone())?;
         globals.set("null", lua.null())?;
@@ -262,18 +698,53 @@ impl Runtime {
 
         lua.load(LUA_PRELUDE).exec_async().await?;
 
-        channel::register(&lua)?;
-        file::register(&lua)?;
-        http::register(&lua)?;
+        activitypub::register(&lua, &services.database)?;
+        audit::register(&lua, &services.database)?;
+        blob::register(&lua, &services.database)?;
+        cache::register(&lua)?;
+        channel::register(&lua, &self.rooms)?;
+        content::register(&lua, &root, &services.template)?;
+        coroutine::register(&lua)?;
+        crdt::register(&lua, &services.database)?;
+        file::register(&lua, &root)?;
+        form::register(&lua)?;
+        highlight::register(&lua)?;
+        http::register(
+            &lua,
+            &self.config.fetch,
+            &self.config.uploads,
+            &services.database,
+        )?;
+        if self.offline {
+            globals.get::<LuaTable>("fetch")?.set("offline", true)?;
+        }
+        island::register(&lua)?;
+        og::register(&lua)?;
         os::register(&lua)?;
+        paginate::register(&lua, &services.database)?;
+        payments::register(&lua, &services.database)?;
         regex::register(&lua)?;
+        seo::register(&lua, &self.config.seo)?;
+        sidecar::register(&lua)?;
+        slug::register(&lua, &services.database)?;
+        ssh::register(&lua)?;
+        stdlib::register(&lua, &self.config.stdlib)?;
+        sync::register(&lua, &services.database)?;
+        useragent::register(&lua)?;
+        validate::register(&lua)?;
+        #[cfg(feature = "passkeys")]
+        webauthn::register(&lua, &services.database)?;
         mdns::register(&lua)?;
+        net::register(&lua, CancellationToken::new(), &self.requests)?;
+        // old names point at the functions registered above
+        compat::register(&lua)?;
+        for Module(module) in &self.modules {
+            module(&lua)?;
+        }
 
         let db = &services.database;
         http::set_cookie_key(&lua, db).await?;
 
-        let require = globals.get::<LuaFunction>("require")?;
-        require.call_async::<()>("app").await?;
         Ok(lua)
     }
 }
@@ -342,6 +813,14 @@ fn builtin_info(_lua: &Lua, args: LuaMultiValue) -> LuaResult<()> {
     Ok(())
 }
 
+/// seconds from lua or the config as a duration, with negatives as zero and an
+/// error for the ones a duration can't hold, like inf
+fn seconds(name: &str, seconds: f64) -> LuaResult<Duration> {
+    Duration::try_from_secs_f64(seconds.max(0.0)).map_err(|_| {
+        LuaError::runtime(format!("{name} must be a number of seconds, not {seconds}"))
+    })
+}
+
 trait ToLuaArray {
     fn to_lua_array(self, lua: &Lua) -> LuaResult<LuaTable>;
 }
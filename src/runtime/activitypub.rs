@@ -0,0 +1,747 @@
+// a minimal activitypub server, so apps can federate with mastodon and the rest of the
+// fediverse.
+//
+//   activitypub.base_url = "https://blog.example.com"  -- defaults to the request's host
+//   activitypub.actor("alice", { name = "Alice", summary = "notes on rust", icon = "https://blog.example.com/alice.png" })
+//   activitypub.on("Follow", function(activity, actor) return not blocked[activity.actor] end)
+//   activitypub.on("Create", function(activity, actor) save_reply(activity.object) end)
+//
+//   activitypub.publish("alice", { type = "Note", content = "<p>hello, fediverse</p>" })
+//   activitypub.send("alice", "https://mastodon.social/users/bob", { type = "Like", object = url })
+//   activitypub.followers("alice")
+//
+// alice is then @alice@blog.example.com. webfinger is served at /.well-known/webfinger,
+// and her actor, inbox, outbox and followers under /_lilguy/ap/users/alice. follows are
+// accepted and recorded unless the Follow handler returns false, and undoing a follow
+// removes it. everything posted to an inbox must carry an http signature from the actor
+// that sent it (see activitypub/signature.rs), covering its path, host, date and digest,
+// from a key served over https from a public address. everything sent is signed with the
+// local actor's key, which is made the first time it is needed.
+mod signature;
+
+use axum::http::{
+    header::{ACCEPT, CONTENT_TYPE, DATE, HOST},
+    HeaderMap, StatusCode,
+};
+use futures_util::future::join_all;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use signature::{digest, signing_string, KeyPair, SignatureHeader};
+
+use crate::database::{activitypub as store, Database};
+
+const ACTIVITYPUB: &str = "activitypub";
+const ACTIVITYPUB_HANDLERS: &str = "activitypub_handlers";
+
+/// where local actors live, relative to the app
+pub const AP_PATH: &str = "/_lilguy/ap";
+pub const WEBFINGER_PATH: &str = "/.well-known/webfinger";
+
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY: &str = "https://w3id.org/security/v1";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// types that are sent as they are; anything else is wrapped in a Create
+const ACTIVITY_TYPES: [&str; 11] = [
+    "Create", "Update", "Delete", "Follow", "Accept", "Reject", "Add", "Remove", "Like",
+    "Announce", "Undo",
+];
+
+/// what an inbox delivery has to sign, so it can't be replayed to another inbox or later
+const SIGNED_HEADERS: [&str; 4] = ["(request-target)", "host", "date", "digest"];
+/// how far a signed request's Date may be from ours
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(12 * 60 * 60);
+/// how long a remote actor's document is used before it is fetched again, in seconds
+const REMOTE_ACTOR_TTL: i64 = 24 * 60 * 60;
+/// how many activities the outbox shows
+const OUTBOX_SIZE: usize = 20;
+
+#[derive(Debug, Clone)]
+struct ActivityPubState {
+    database: Database,
+    client: reqwest::Client,
+    /// the local actors and their profiles
+    actors: Arc<Mutex<HashMap<String, Value>>>,
+    keys: Arc<Mutex<HashMap<String, Arc<KeyPair>>>>,
+}
+
+impl LuaUserData for ActivityPubState {}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(format!("lilguy/{}", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .into_lua_err()?;
+    lua.set_named_registry_value(
+        ACTIVITYPUB,
+        ActivityPubState {
+            database: database.clone(),
+            client,
+            actors: Arc::default(),
+            keys: Arc::default(),
+        },
+    )?;
+    lua.set_named_registry_value(ACTIVITYPUB_HANDLERS, lua.create_table()?)?;
+
+    let activitypub = lua.create_table()?;
+    activitypub.set("actor", lua.create_function(ap_actor)?)?;
+    activitypub.set("on", lua.create_function(ap_on)?)?;
+    activitypub.set("publish", lua.create_async_function(ap_publish)?)?;
+    activitypub.set("send", lua.create_async_function(ap_send)?)?;
+    activitypub.set("followers", lua.create_async_function(ap_followers)?)?;
+    lua.globals().set("activitypub", activitypub)?;
+    Ok(())
+}
+
+fn state(lua: &Lua) -> LuaResult<ActivityPubState> {
+    let state = lua.named_registry_value::<LuaUserDataRef<ActivityPubState>>(ACTIVITYPUB)?;
+    Ok(ActivityPubState::clone(&state))
+}
+
+/// activitypub.actor(name, { name, summary, icon, url, type = "Person" })
+///
+/// any other fields are added to the actor document as they are
+fn ap_actor(lua: &Lua, (name, profile): (String, Option<LuaTable>)) -> LuaResult<()> {
+    let profile = match profile {
+        Some(profile) => lua.from_value(LuaValue::Table(profile))?,
+        None => json!({}),
+    };
+    if !profile.is_object() {
+        return Err(LuaError::runtime("an actor's profile must be a table"));
+    }
+    state(lua)?.actors.lock().insert(name, profile);
+    Ok(())
+}
+
+/// activitypub.on(type, function(activity, actor) end)
+fn ap_on(lua: &Lua, (kind, handler): (String, Option<LuaFunction>)) -> LuaResult<()> {
+    let handlers = lua.named_registry_value::<LuaTable>(ACTIVITYPUB_HANDLERS)?;
+    handlers.set(kind, handler)
+}
+
+/// activitypub.base_url, or the scheme and host of the request being handled
+fn base_url(lua: &Lua, headers: Option<&HeaderMap>) -> LuaResult<String> {
+    let activitypub = lua.globals().get::<LuaTable>("activitypub")?;
+    if let Some(base_url) = activitypub.get::<Option<String>>("base_url")? {
+        return Ok(base_url.trim_end_matches('/').to_string());
+    }
+    let headers =
+        headers.ok_or_else(|| LuaError::runtime("activitypub.base_url must be set to send"))?;
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("x-forwarded-proto").unwrap_or("https");
+    let host = header(HOST.as_str()).unwrap_or("localhost");
+    Ok(format!("{scheme}://{host}"))
+}
+
+fn actor_id(base_url: &str, name: &str) -> String {
+    format!("{base_url}{AP_PATH}/users/{name}")
+}
+
+fn new_id(actor_id: &str, kind: &str) -> String {
+    format!("{actor_id}/{kind}/{:016x}", rand::random::<u64>())
+}
+
+/// the id of an object, which may be given as the object or as its id
+fn id_of(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+/// a local actor, ready to sign
+struct Signer {
+    id: String,
+    key_id: String,
+    keys: Arc<KeyPair>,
+}
+
+impl ActivityPubState {
+    fn profile(&self, name: &str) -> Option<Value> {
+        self.actors.lock().get(name).cloned()
+    }
+
+    async fn signer(&self, base_url: &str, name: &str) -> LuaResult<Signer> {
+        let id = actor_id(base_url, name);
+        Ok(Signer {
+            key_id: format!("{id}#main-key"),
+            id,
+            keys: self.keys(name).await?,
+        })
+    }
+
+    /// the actor's key pair, made and saved the first time
+    async fn keys(&self, name: &str) -> LuaResult<Arc<KeyPair>> {
+        if let Some(keys) = self.keys.lock().get(name) {
+            return Ok(keys.clone());
+        }
+        let actor = name.to_string();
+        let mut pem = self
+            .database
+            .call(move |conn| store::private_key(conn, &actor))
+            .await
+            .into_lua_err()?;
+        if pem.is_none() {
+            let generated = tokio::task::spawn_blocking(KeyPair::generate)
+                .await
+                .into_lua_err()?
+                .into_lua_err()?;
+            let generated = generated.private_pem().into_lua_err()?;
+            let actor = name.to_string();
+            pem = self
+                .database
+                .call(move |conn| {
+                    store::save_private_key(conn, &actor, &generated)?;
+                    store::private_key(conn, &actor)
+                })
+                .await
+                .into_lua_err()?;
+        }
+        let pem = pem.ok_or_else(|| LuaError::runtime("could not save actor key"))?;
+        let keys = Arc::new(KeyPair::from_pem(&pem).into_lua_err()?);
+        self.keys.lock().insert(name.to_string(), keys.clone());
+        Ok(keys)
+    }
+
+    /// a request to url signed by signer, with the headers that are signed already set
+    fn signed(
+        &self,
+        signer: &Signer,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> LuaResult<reqwest::RequestBuilder> {
+        let parsed = Url::parse(url).into_lua_err()?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(LuaError::runtime(format!("{url} has no host"))),
+        };
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{query}", parsed.path()),
+            None => parsed.path().to_string(),
+        };
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let digest = body.map(digest);
+
+        let mut headers = vec![("host", host.as_str()), ("date", date.as_str())];
+        if let Some(ref digest) = digest {
+            headers.push(("digest", digest.as_str()));
+        }
+        let signature = signer
+            .keys
+            .sign(&signer.key_id, method.as_str(), &path, &headers);
+
+        let mut request = self
+            .client
+            .request(method, parsed)
+            .header(DATE, &date)
+            .header("signature", signature);
+        if let (Some(body), Some(digest)) = (body, digest) {
+            request = request
+                .header("digest", digest)
+                .header(CONTENT_TYPE, ACTIVITY_JSON)
+                .body(body.to_vec());
+        }
+        Ok(request)
+    }
+
+    async fn post(&self, signer: &Signer, inbox: &str, activity: &Value) -> LuaResult<()> {
+        let body = serde_json::to_vec(activity).into_lua_err()?;
+        self.signed(signer, reqwest::Method::POST, inbox, Some(&body))?
+            .send()
+            .await
+            .into_lua_err()?
+            .error_for_status()
+            .into_lua_err()?;
+        Ok(())
+    }
+
+    /// a remote actor's document, from the database unless it is old or refresh is set.
+    /// the request is signed, for servers that only show actors to other servers.
+    async fn remote_actor(&self, signer: &Signer, id: &str, refresh: bool) -> LuaResult<Value> {
+        if !refresh {
+            let cached = id.to_string();
+            let cached = self
+                .database
+                .call(move |conn| store::remote_actor(conn, &cached, REMOTE_ACTOR_TTL))
+                .await
+                .into_lua_err()?;
+            if let Some(document) = cached {
+                return Ok(document);
+            }
+        }
+        let document: Value = self
+            .signed(signer, reqwest::Method::GET, id, None)?
+            .header(ACCEPT, ACTIVITY_JSON)
+            .send()
+            .await
+            .into_lua_err()?
+            .error_for_status()
+            .into_lua_err()?
+            .json()
+            .await
+            .into_lua_err()?;
+        if id_of(&document) != Some(id) {
+            return Err(LuaError::runtime(format!("{id} is not an actor")));
+        }
+        let saved = (id.to_string(), document.clone());
+        self.database
+            .call(move |conn| store::save_remote_actor(conn, &saved.0, &saved.1))
+            .await
+            .into_lua_err()?;
+        Ok(document)
+    }
+}
+
+/// where to deliver to a remote actor, preferring their server's shared inbox
+fn inbox_of(actor: &Value) -> Option<&str> {
+    actor["endpoints"]["sharedInbox"]
+        .as_str()
+        .or_else(|| actor["inbox"].as_str())
+}
+
+/// an activity from an object or activity given to publish or send
+fn to_activity(actor: &str, mut object: Value) -> LuaResult<Value> {
+    let Some(fields) = object.as_object_mut() else {
+        return Err(LuaError::runtime("activities must be tables"));
+    };
+    let kind = fields
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| LuaError::runtime("activities must have a type"))?
+        .to_string();
+    let followers = format!("{actor}/followers");
+    fields.entry("to").or_insert_with(|| json!([PUBLIC]));
+    fields.entry("cc").or_insert_with(|| json!([followers]));
+
+    if ACTIVITY_TYPES.contains(&kind.as_str()) {
+        fields
+            .entry("id")
+            .or_insert_with(|| json!(new_id(actor, "activities")));
+        fields.insert("actor".to_string(), json!(actor));
+        fields.insert("@context".to_string(), json!(ACTIVITY_STREAMS));
+        return Ok(object);
+    }
+
+    fields
+        .entry("id")
+        .or_insert_with(|| json!(new_id(actor, "objects")));
+    fields.insert("attributedTo".to_string(), json!(actor));
+    let id = format!("{}/activity", id_of(&object).unwrap_or_default());
+    Ok(json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": id,
+        "type": "Create",
+        "actor": actor,
+        "to": object["to"].clone(),
+        "cc": object["cc"].clone(),
+        "object": object,
+    }))
+}
+
+/// activitypub.publish(actor, object) sends an object (wrapped in a Create) or an
+/// activity to every follower, and adds it to the outbox. returns the activity's id and
+/// how many inboxes it was delivered to.
+async fn ap_publish(lua: Lua, (name, object): (String, LuaValue)) -> LuaResult<(String, usize)> {
+    let state = state(&lua)?;
+    if state.profile(&name).is_none() {
+        return Err(LuaError::runtime(format!(
+            "no activitypub actor named {name}"
+        )));
+    }
+    let base_url = base_url(&lua, None)?;
+    let signer = state.signer(&base_url, &name).await?;
+    let activity = to_activity(&signer.id, lua.from_value(object)?)?;
+
+    let (actor, saved) = (name.clone(), activity.clone());
+    let followers = state
+        .database
+        .call(move |conn| {
+            store::add_to_outbox(conn, &actor, &saved)?;
+            store::followers(conn, &actor)
+        })
+        .await
+        .into_lua_err()?;
+
+    let inboxes: HashSet<String> = followers.into_iter().map(|(_, inbox)| inbox).collect();
+    let deliveries = inboxes.iter().map(|inbox| async {
+        let result = state.post(&signer, inbox, &activity).await;
+        if let Err(ref err) = result {
+            tracing::warn!(%inbox, %err, "could not deliver activity");
+        }
+        result.is_ok()
+    });
+    let delivered = join_all(deliveries)
+        .await
+        .into_iter()
+        .filter(|ok| *ok)
+        .count();
+    let id = id_of(&activity).unwrap_or_default().to_string();
+    Ok((id, delivered))
+}
+
+/// activitypub.send(actor, to, activity) sends to one remote actor or inbox url,
+/// returning the activity's id
+async fn ap_send(lua: Lua, (name, to, object): (String, String, LuaValue)) -> LuaResult<String> {
+    let state = state(&lua)?;
+    if state.profile(&name).is_none() {
+        return Err(LuaError::runtime(format!(
+            "no activitypub actor named {name}"
+        )));
+    }
+    let base_url = base_url(&lua, None)?;
+    let signer = state.signer(&base_url, &name).await?;
+    let activity = to_activity(&signer.id, lua.from_value(object)?)?;
+
+    let inbox = match state.remote_actor(&signer, &to, false).await {
+        Ok(actor) => inbox_of(&actor)
+            .ok_or_else(|| LuaError::runtime(format!("{to} has no inbox")))?
+            .to_string(),
+        // not an actor, so it should be an inbox
+        Err(_) => to,
+    };
+    state.post(&signer, &inbox, &activity).await?;
+    Ok(id_of(&activity).unwrap_or_default().to_string())
+}
+
+/// activitypub.followers(actor) returns the ids of the actor's followers
+async fn ap_followers(lua: Lua, name: String) -> LuaResult<Vec<String>> {
+    let followers = state(&lua)?
+        .database
+        .call(move |conn| store::followers(conn, &name))
+        .await
+        .into_lua_err()?;
+    Ok(followers
+        .into_iter()
+        .map(|(follower, _)| follower)
+        .collect())
+}
+
+/// GET /.well-known/webfinger?resource=acct:alice@example.com
+pub fn webfinger(lua: &Lua, headers: &HeaderMap, resource: &str) -> LuaResult<Option<Value>> {
+    let state = state(lua)?;
+    let account = resource.strip_prefix("acct:").unwrap_or(resource);
+    let name = account.split('@').next().unwrap_or_default();
+    if state.profile(name).is_none() {
+        return Ok(None);
+    }
+    let id = actor_id(&base_url(lua, Some(headers))?, name);
+    Ok(Some(json!({
+        "subject": format!("acct:{account}"),
+        "aliases": [id],
+        "links": [{ "rel": "self", "type": ACTIVITY_JSON, "href": id }],
+    })))
+}
+
+/// the actor document for GET /_lilguy/ap/users/name, or with part, its outbox or followers
+pub async fn document(
+    lua: &Lua,
+    headers: &HeaderMap,
+    name: &str,
+    part: Option<&str>,
+) -> LuaResult<Option<Value>> {
+    let state = state(lua)?;
+    let Some(profile) = state.profile(name) else {
+        return Ok(None);
+    };
+    let id = actor_id(&base_url(lua, Some(headers))?, name);
+    let actor = name.to_string();
+    match part {
+        None => {
+            let keys = state.keys(name).await?;
+            let mut document = profile;
+            if let Some(icon) = document["icon"].as_str().map(String::from) {
+                document["icon"] = json!({ "type": "Image", "url": icon });
+            }
+            if document["type"].is_null() {
+                document["type"] = json!("Person");
+            }
+            let fields = [
+                ("@context", json!([ACTIVITY_STREAMS, SECURITY])),
+                ("id", json!(id)),
+                ("preferredUsername", json!(name)),
+                ("inbox", json!(format!("{id}/inbox"))),
+                ("outbox", json!(format!("{id}/outbox"))),
+                ("followers", json!(format!("{id}/followers"))),
+                (
+                    "publicKey",
+                    json!({
+                        "id": format!("{id}#main-key"),
+                        "owner": id,
+                        "publicKeyPem": keys.public_pem(),
+                    }),
+                ),
+            ];
+            for (field, value) in fields {
+                document[field] = value;
+            }
+            Ok(Some(document))
+        }
+        Some("outbox") => {
+            let (total, items) = state
+                .database
+                .call(move |conn| store::outbox(conn, &actor, OUTBOX_SIZE))
+                .await
+                .into_lua_err()?;
+            Ok(Some(json!({
+                "@context": ACTIVITY_STREAMS,
+                "id": format!("{id}/outbox"),
+                "type": "OrderedCollection",
+                "totalItems": total,
+                "orderedItems": items,
+            })))
+        }
+        Some("followers") => {
+            let total = state
+                .database
+                .call(move |conn| store::follower_count(conn, &actor))
+                .await
+                .into_lua_err()?;
+            Ok(Some(json!({
+                "@context": ACTIVITY_STREAMS,
+                "id": format!("{id}/followers"),
+                "type": "OrderedCollection",
+                "totalItems": total,
+            })))
+        }
+        Some(_) => Ok(None),
+    }
+}
+
+/// true if the request's Date is close enough to now
+fn fresh(headers: &HeaderMap) -> bool {
+    let date = headers
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    let Some(date) = date else {
+        return false;
+    };
+    let now = SystemTime::now();
+    let skew = now
+        .duration_since(date)
+        .or_else(|_| date.duration_since(now))
+        .unwrap_or_default();
+    skew <= MAX_CLOCK_SKEW
+}
+
+/// true if ip is on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is carrier-grade nat
+            let shared = a == 100 && (64..128).contains(&b);
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // fc00::/7 is unique local and fe80::/10 link local
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// check that a signer's key can be fetched from url: over https, from a public address
+async fn check_key_url(url: &str) -> LuaResult<()> {
+    let url = Url::parse(url).into_lua_err()?;
+    if url.scheme() != "https" {
+        return Err(LuaError::runtime(format!("{url} is not https")));
+    }
+    let port = url.port().unwrap_or(443);
+    let host = url.host_str().unwrap_or_default();
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<IpAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .into_lua_err()?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    if addresses.is_empty() || !addresses.into_iter().all(is_public) {
+        return Err(LuaError::runtime(format!("{url} is not a public address")));
+    }
+    Ok(())
+}
+
+/// POST /_lilguy/ap/users/name/inbox. path is the request's path as the sender saw it.
+pub async fn inbox(
+    lua: &Lua,
+    headers: &HeaderMap,
+    name: &str,
+    path: &str,
+    body: &[u8],
+) -> LuaResult<StatusCode> {
+    let state = state(lua)?;
+    if state.profile(name).is_none() {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    let signature = headers
+        .get("signature")
+        .and_then(|signature| signature.to_str().ok())
+        .and_then(SignatureHeader::parse);
+    let Some(signature) = signature else {
+        return Ok(StatusCode::UNAUTHORIZED);
+    };
+    let digest_matches = headers
+        .get("digest")
+        .is_some_and(|header| header.as_bytes() == digest(body).as_bytes());
+    let covered = SIGNED_HEADERS.iter().all(|name| signature.signs(name));
+    if !covered || !digest_matches || !fresh(headers) {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+    let names: Vec<&str> = signature.headers.iter().map(String::as_str).collect();
+    let Some(signed) = signing_string("post", path, &names, |name| {
+        let value = headers.get(name)?.to_str().ok()?;
+        Some(value.to_string())
+    }) else {
+        return Ok(StatusCode::UNAUTHORIZED);
+    };
+
+    let owner = signature.owner().to_string();
+    let Ok(activity) = serde_json::from_slice::<Value>(body) else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+    if id_of(&activity["actor"]) != Some(owner.as_str()) {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+    // the key's url comes from the sender, so don't let it point anywhere inside
+    if let Err(err) = check_key_url(&owner).await {
+        tracing::debug!(%owner, %err, "refused to fetch the signer of an activity");
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+
+    let signer = state.signer(&base_url(lua, Some(headers))?, name).await?;
+    let mut verified = None;
+    // the key may have changed since the actor was saved, so try again with a fresh copy
+    for refresh in [false, true] {
+        let actor = match state.remote_actor(&signer, &owner, refresh).await {
+            Ok(actor) => actor,
+            Err(err) => {
+                tracing::debug!(%owner, %err, "could not fetch the signer of an activity");
+                break;
+            }
+        };
+        let key = actor["publicKey"]["publicKeyPem"]
+            .as_str()
+            .unwrap_or_default();
+        if signature.verify(key, &signed).is_ok() {
+            verified = Some(actor);
+            break;
+        }
+    }
+    let Some(remote) = verified else {
+        return Ok(StatusCode::UNAUTHORIZED);
+    };
+    receive(lua, &state, &signer, name, &remote, activity).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// the handler's result for an activity, or nil without one
+async fn call_handler(lua: &Lua, kind: &str, activity: &Value, name: &str) -> LuaResult<LuaValue> {
+    let handlers = lua.named_registry_value::<LuaTable>(ACTIVITYPUB_HANDLERS)?;
+    match handlers.get::<Option<LuaFunction>>(kind)? {
+        Some(handler) => handler.call_async((lua.to_value(activity)?, name)).await,
+        None => Ok(LuaValue::Nil),
+    }
+}
+
+async fn receive(
+    lua: &Lua,
+    state: &ActivityPubState,
+    signer: &Signer,
+    name: &str,
+    remote: &Value,
+    activity: Value,
+) -> LuaResult<()> {
+    let kind = activity["type"].as_str().unwrap_or_default().to_string();
+    let follower = id_of(remote).unwrap_or_default().to_string();
+    let (actor, follower_id) = (name.to_string(), follower.clone());
+
+    match kind.as_str() {
+        "Follow" if id_of(&activity["object"]) == Some(signer.id.as_str()) => {
+            let Some(inbox) = inbox_of(remote).map(String::from) else {
+                return Ok(());
+            };
+            let accepted = !matches!(
+                call_handler(lua, &kind, &activity, name).await?,
+                LuaValue::Boolean(false)
+            );
+            if accepted {
+                let inbox = inbox.clone();
+                state
+                    .database
+                    .call(move |conn| store::add_follower(conn, &actor, &follower_id, &inbox))
+                    .await
+                    .into_lua_err()?;
+            }
+            let reply = json!({
+                "@context": ACTIVITY_STREAMS,
+                "id": new_id(&signer.id, "activities"),
+                "type": if accepted { "Accept" } else { "Reject" },
+                "actor": signer.id,
+                "object": activity,
+            });
+            if let Err(err) = state.post(signer, &inbox, &reply).await {
+                tracing::warn!(%follower, %err, "could not answer a follow");
+            }
+        }
+        "Undo" if activity["object"]["type"] == "Follow" => {
+            state
+                .database
+                .call(move |conn| store::remove_follower(conn, &actor, &follower_id))
+                .await
+                .into_lua_err()?;
+            call_handler(lua, &kind, &activity, name).await?;
+        }
+        _ => {
+            call_handler(lua, &kind, &activity, name).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:4700::1111"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+        for ip in ["::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+}
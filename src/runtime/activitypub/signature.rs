@@ -0,0 +1,217 @@
+// http signatures, the cavage draft that mastodon and most of the fediverse use.
+//
+// the signer lists the headers it signed, and signs a string made of them, one
+// "name: value" line each; (request-target) is "post /users/alice/inbox". the body is
+// covered by signing a Digest header with its sha-256. keys are rsa, signed with
+// rsassa-pkcs1-v1_5 over sha-256.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    signature::{SignatureEncoding, Signer, Verifier},
+    RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+
+const KEY_BITS: usize = 2048;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("rsa error: {0}")]
+    Rsa(#[from] rsa::Error),
+
+    #[error("invalid private key: {0}")]
+    PrivateKey(#[from] rsa::pkcs8::Error),
+
+    #[error("invalid public key: {0}")]
+    PublicKey(#[from] rsa::pkcs8::spki::Error),
+
+    #[error("signature does not match")]
+    Mismatch,
+}
+
+pub struct KeyPair {
+    private: RsaPrivateKey,
+    public_pem: String,
+}
+
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair").finish_non_exhaustive()
+    }
+}
+
+impl KeyPair {
+    /// a new key; this takes a moment, so it belongs on a blocking thread
+    pub fn generate() -> Result<Self, SignatureError> {
+        Self::new(RsaPrivateKey::new(&mut rand_core::OsRng, KEY_BITS)?)
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, SignatureError> {
+        Self::new(RsaPrivateKey::from_pkcs8_pem(pem)?)
+    }
+
+    fn new(private: RsaPrivateKey) -> Result<Self, SignatureError> {
+        let public_pem = private.to_public_key().to_public_key_pem(LineEnding::LF)?;
+        Ok(Self {
+            private,
+            public_pem,
+        })
+    }
+
+    pub fn private_pem(&self) -> Result<String, SignatureError> {
+        Ok(self.private.to_pkcs8_pem(LineEnding::LF)?.to_string())
+    }
+
+    pub fn public_pem(&self) -> &str {
+        &self.public_pem
+    }
+
+    /// the Signature header for a request with these headers, which are all signed
+    pub fn sign(&self, key_id: &str, method: &str, path: &str, headers: &[(&str, &str)]) -> String {
+        let mut names = vec!["(request-target)"];
+        names.extend(headers.iter().map(|(name, _)| *name));
+        let string = signing_string(method, path, &names, |name| {
+            headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string())
+        })
+        .expect("every header is given");
+        let signature = SigningKey::<Sha256>::new(self.private.clone()).sign(string.as_bytes());
+        format!(
+            "keyId=\"{key_id}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+            names.join(" ").to_ascii_lowercase(),
+            STANDARD.encode(signature.to_bytes())
+        )
+    }
+}
+
+/// the Digest header for a body
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// the string that is signed, or None if a signed header is missing
+pub fn signing_string(
+    method: &str,
+    path: &str,
+    names: &[&str],
+    header: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let lines = names
+        .iter()
+        .map(|name| {
+            let name = name.to_ascii_lowercase();
+            if name == "(request-target)" {
+                return Some(format!("{name}: {} {path}", method.to_ascii_lowercase()));
+            }
+            Some(format!("{name}: {}", header(&name)?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(lines.join("\n"))
+}
+
+/// a parsed Signature header
+#[derive(Debug)]
+pub struct SignatureHeader {
+    pub key_id: String,
+    pub headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+impl SignatureHeader {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut key_id = None;
+        let mut headers = None;
+        let mut signature = None;
+        for param in value.split(',') {
+            let (name, value) = param.trim().split_once('=')?;
+            let value = value.trim_matches('"');
+            match name {
+                "keyId" => key_id = Some(value.to_string()),
+                "headers" => headers = Some(value.split_whitespace().map(String::from).collect()),
+                "signature" => signature = Some(STANDARD.decode(value).ok()?),
+                _ => {}
+            }
+        }
+        Some(Self {
+            key_id: key_id?,
+            // without a list, only the date is signed
+            headers: headers.unwrap_or_else(|| vec!["date".to_string()]),
+            signature: signature?,
+        })
+    }
+
+    /// the actor the key belongs to, which is its id without the fragment
+    pub fn owner(&self) -> &str {
+        self.key_id.split('#').next().unwrap_or_default()
+    }
+
+    pub fn signs(&self, header: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(header))
+    }
+
+    pub fn verify(&self, public_pem: &str, signing_string: &str) -> Result<(), SignatureError> {
+        let public = match RsaPublicKey::from_public_key_pem(public_pem) {
+            Ok(public) => public,
+            // some servers publish pkcs1 keys, "BEGIN RSA PUBLIC KEY"
+            Err(err) => RsaPublicKey::from_pkcs1_pem(public_pem).map_err(|_| err)?,
+        };
+        let signature =
+            Signature::try_from(self.signature.as_slice()).map_err(|_| SignatureError::Mismatch)?;
+        VerifyingKey::<Sha256>::new(public)
+            .verify(signing_string.as_bytes(), &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = KeyPair::new(RsaPrivateKey::new(&mut rand_core::OsRng, 1024).expect("key"))
+            .expect("key pair");
+        let headers = [
+            ("host", "example.com"),
+            ("date", "Tue, 07 Jun 2022 20:51:35 GMT"),
+            ("digest", "SHA-256=abc"),
+        ];
+        let header = key.sign(
+            "https://example.com/users/alice#main-key",
+            "POST",
+            "/inbox",
+            &headers,
+        );
+
+        let parsed = SignatureHeader::parse(&header).expect("parse");
+        assert_eq!(parsed.owner(), "https://example.com/users/alice");
+        assert!(parsed.signs("digest"));
+        let names: Vec<&str> = parsed.headers.iter().map(String::as_str).collect();
+        let lookup = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.to_string())
+        };
+        let string = signing_string("post", "/inbox", &names, lookup).expect("string");
+        assert!(string.starts_with("(request-target): post /inbox\nhost: example.com"));
+        parsed.verify(key.public_pem(), &string).expect("verify");
+
+        let tampered = string.replace("abc", "abd");
+        assert!(parsed.verify(key.public_pem(), &tampered).is_err());
+    }
+
+    #[test]
+    fn digest_is_base64_sha256() {
+        assert_eq!(
+            digest(b""),
+            "SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// an append-only record of who changed what.
+//
+//   audit.log(user.email, "update", "post:" .. post.id, { title = post.title })
+//   for _, entry in ipairs(audit.query({ subject = "post:42", limit = 20 })) do ... end
+//   audit.retention(90) -- days; older entries are removed as new ones are logged
+//
+// entries logged while handling a request record its req.id, so they can be matched
+// with logs and with each other.
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use rusqlite::{params_from_iter, types::Value};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::http::current_request_id;
+use crate::database::Database;
+
+const DEFAULT_LIMIT: i64 = 100;
+/// how often logging checks for expired entries
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default)]
+struct Retention {
+    days: Option<u64>,
+    last_pruned: Option<Instant>,
+}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let retention = Arc::new(Mutex::new(Retention::default()));
+    let audit = lua.create_table()?;
+
+    audit.set(
+        "log",
+        lua.create_async_function({
+            let database = database.clone();
+            let retention = retention.clone();
+            move |lua, args| audit_log(lua, database.clone(), retention.clone(), args)
+        })?,
+    )?;
+    audit.set(
+        "query",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, filter| audit_query(lua, database.clone(), filter)
+        })?,
+    )?;
+    audit.set(
+        "retention",
+        lua.create_function(move |_, days: Option<u64>| {
+            let mut retention = retention.lock();
+            retention.days = days;
+            retention.last_pruned = None;
+            Ok(())
+        })?,
+    )?;
+
+    lua.globals().set("audit", audit)?;
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// audit.log(actor, action, subject, details)
+async fn audit_log(
+    lua: Lua,
+    database: Database,
+    retention: Arc<Mutex<Retention>>,
+    (actor, action, subject, details): (Option<String>, String, Option<String>, LuaValue),
+) -> LuaResult<()> {
+    let details = match details {
+        LuaValue::Nil => None,
+        details => Some(
+            serde_json::to_string(&lua.from_value::<serde_json::Value>(details)?).into_lua_err()?,
+        ),
+    };
+    let request_id = current_request_id();
+    let created = now();
+
+    // prune at most once an interval, as part of logging
+    let expire_before = {
+        let mut retention = retention.lock();
+        let due = !matches!(retention.last_pruned, Some(at) if at.elapsed() < PRUNE_INTERVAL);
+        match retention.days {
+            Some(days) if due => {
+                retention.last_pruned = Some(Instant::now());
+                // a retention too long to count back from now keeps everything
+                let age = i64::try_from(days.saturating_mul(24 * 60 * 60)).unwrap_or(i64::MAX);
+                Some(created.saturating_sub(age))
+            }
+            _ => None,
+        }
+    };
+
+    database
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO lg_audit (created, actor, action, subject, details, request_id)
+                 VALUES (?, ?, ?, ?, jsonb(?), ?)",
+                rusqlite::params![created, actor, action, subject, details, request_id],
+            )?;
+            if let Some(before) = expire_before {
+                conn.execute("DELETE FROM lg_audit WHERE created < ?", [before])?;
+            }
+            Ok(())
+        })
+        .await
+        .into_lua_err()
+}
+
+/// audit.query({ actor, action, subject, request_id, since, before, limit })
+/// returns entries newest first. since and before are unix timestamps.
+async fn audit_query(
+    lua: Lua,
+    database: Database,
+    filter: Option<LuaTable>,
+) -> LuaResult<LuaTable> {
+    let mut conditions = vec![];
+    let mut values: Vec<Value> = vec![];
+    let mut limit = DEFAULT_LIMIT;
+    if let Some(filter) = filter {
+        for column in ["actor", "action", "subject", "request_id"] {
+            if let Some(value) = filter.get::<Option<String>>(column)? {
+                conditions.push(format!("{column} = ?"));
+                values.push(Value::Text(value));
+            }
+        }
+        if let Some(since) = filter.get::<Option<i64>>("since")? {
+            conditions.push("created >= ?".to_string());
+            values.push(Value::Integer(since));
+        }
+        if let Some(before) = filter.get::<Option<i64>>("before")? {
+            conditions.push("created < ?".to_string());
+            values.push(Value::Integer(before));
+        }
+        limit = filter.get::<Option<i64>>("limit")?.unwrap_or(DEFAULT_LIMIT);
+    }
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    values.push(Value::Integer(limit));
+
+    let rows = database
+        .call(move |conn| {
+            let sql = format!(
+                "SELECT id, created, actor, action, subject, json(details), request_id
+                 FROM lg_audit {filter} ORDER BY id DESC LIMIT ?"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt
+                .query_map(params_from_iter(values), |row| {
+                    Ok(AuditEntry {
+                        id: row.get(0)?,
+                        created: row.get(1)?,
+                        actor: row.get(2)?,
+                        action: row.get(3)?,
+                        subject: row.get(4)?,
+                        details: row.get(5)?,
+                        request_id: row.get(6)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+        .await
+        .into_lua_err()?;
+
+    let entries = lua.create_table()?;
+    for row in rows {
+        let entry = lua.create_table()?;
+        entry.set("id", row.id)?;
+        entry.set("created", row.created)?;
+        entry.set("actor", row.actor)?;
+        entry.set("action", row.action)?;
+        entry.set("subject", row.subject)?;
+        if let Some(details) = row.details {
+            let details: serde_json::Value = serde_json::from_str(&details).into_lua_err()?;
+            entry.set("details", lua.to_value(&details)?)?;
+        }
+        entry.set("request_id", row.request_id)?;
+        entries.push(entry)?;
+    }
+    Ok(entries)
+}
+
+struct AuditEntry {
+    id: i64,
+    created: i64,
+    actor: Option<String>,
+    action: String,
+    subject: Option<String>,
+    details: Option<String>,
+    request_id: Option<String>,
+}
@@ -0,0 +1,302 @@
+// blob is a key/value store for large binary values such as images and pdfs.
+// values are stored as-is in the lg_blob table instead of being JSONB-encoded like
+// global tables, and are written and read in chunks with sqlite's incremental blob i/o.
+//
+//   blob.put("avatar/42", req.raw_body, { content_type = "image/png" })
+//   blob.put("report.pdf", { path = "/tmp/report.pdf" })
+//   local data, content_type = blob.get("avatar/42")
+//   for chunk in blob.stream("report.pdf") do ... end
+//   res:send_blob("avatar/42")
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, HeaderValue},
+};
+use bytes::Bytes;
+use mlua::prelude::*;
+use rusqlite::{params, DatabaseName, OptionalExtension};
+use std::{
+    io::{self, Read},
+    ops::Range,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{file::resolve, http::LuaHeaders};
+use crate::database::Database;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let blob = lua.create_table()?;
+    blob.set("put", async_fn(lua, database, blob_put)?)?;
+    blob.set("get", async_fn(lua, database, blob_get)?)?;
+    blob.set("info", async_fn(lua, database, blob_info)?)?;
+    blob.set("delete", async_fn(lua, database, blob_delete)?)?;
+    blob.set("stream", async_fn(lua, database, blob_stream)?)?;
+    lua.globals().set("blob", blob)?;
+
+    // res:send_blob(key)
+    let response = lua.globals().get::<LuaTable>("Response")?;
+    response.set("send_blob", async_fn(lua, database, response_send_blob)?)?;
+
+    Ok(())
+}
+
+/// wrap an async function that needs the database
+fn async_fn<A, F, R>(
+    lua: &Lua,
+    database: &Database,
+    function: fn(Lua, Database, A) -> F,
+) -> LuaResult<LuaFunction>
+where
+    A: FromLuaMulti + Send + 'static,
+    F: std::future::Future<Output = LuaResult<R>> + Send + 'static,
+    R: IntoLuaMulti + 'static,
+{
+    let database = database.clone();
+    lua.create_async_function(move |lua, args: A| function(lua, database.clone(), args))
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub rowid: i64,
+    pub size: usize,
+    pub content_type: String,
+    pub updated: i64,
+}
+
+async fn info(database: &Database, key: String) -> LuaResult<Option<BlobInfo>> {
+    database
+        .call(move |conn| {
+            let info = conn
+                .query_row(
+                    "SELECT rowid, size, content_type, updated FROM lg_blob WHERE key = ?",
+                    params![key],
+                    |row| {
+                        Ok(BlobInfo {
+                            rowid: row.get(0)?,
+                            size: row.get(1)?,
+                            content_type: row.get(2)?,
+                            updated: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(info)
+        })
+        .await
+        .into_lua_err()
+}
+
+/// blob.put(key, bytes | { path = "file" }, { content_type = "image/png" })
+async fn blob_put(
+    lua: Lua,
+    database: Database,
+    (key, value, options): (String, LuaValue, Option<LuaTable>),
+) -> LuaResult<usize> {
+    let content_type = options
+        .map(|options| options.get::<Option<String>>("content_type"))
+        .transpose()?
+        .flatten()
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+    let source = match value {
+        LuaValue::String(bytes) => Source::Bytes(Bytes::from(bytes.as_bytes().to_vec())),
+        LuaValue::Table(table) => Source::File(resolve(&lua, table.get::<String>("path")?)),
+        _ => {
+            return Err(LuaError::runtime(
+                "blob.put expects a string or a table with a path",
+            ))
+        }
+    };
+    let updated = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    database
+        .call(move |conn| {
+            let (mut reader, size): (Box<dyn Read + Send>, usize) = match source {
+                Source::Bytes(bytes) => {
+                    let size = bytes.len();
+                    (Box::new(io::Cursor::new(bytes)), size)
+                }
+                Source::File(path) => {
+                    let file = std::fs::File::open(&path)
+                        .map_err(|err| crate::database::Error::Other(Box::new(err)))?;
+                    let size = file
+                        .metadata()
+                        .map_err(|err| crate::database::Error::Other(Box::new(err)))?
+                        .len() as usize;
+                    (Box::new(file), size)
+                }
+            };
+
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO lg_blob (key, content_type, size, updated, data)
+                 VALUES (?, ?, ?, ?, zeroblob(?))",
+                params![key, content_type, size, updated, size],
+            )?;
+            let rowid = tx.last_insert_rowid();
+            {
+                let mut blob = tx.blob_open(DatabaseName::Main, "lg_blob", "data", rowid, false)?;
+                io::copy(&mut reader.by_ref().take(size as u64), &mut blob)
+                    .map_err(|err| crate::database::Error::Other(Box::new(err)))?;
+            }
+            tx.commit()?;
+
+            Ok(size)
+        })
+        .await
+        .into_lua_err()
+}
+
+enum Source {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+
+/// blob.get(key) returns the bytes and content type, or nil
+async fn blob_get(
+    lua: Lua,
+    database: Database,
+    key: String,
+) -> LuaResult<(Option<LuaString>, Option<String>)> {
+    let Some(info) = info(&database, key).await? else {
+        return Ok((None, None));
+    };
+    let data = database
+        .call(move |conn| {
+            let blob = conn.blob_open(DatabaseName::Main, "lg_blob", "data", info.rowid, true)?;
+            let mut data = vec![0; blob.len()];
+            blob.read_at_exact(&mut data, 0)?;
+            Ok(data)
+        })
+        .await
+        .into_lua_err()?;
+
+    Ok((Some(lua.create_string(data)?), Some(info.content_type)))
+}
+
+/// blob.info(key) returns { size, content_type, updated }, or nil
+async fn blob_info(lua: Lua, database: Database, key: String) -> LuaResult<Option<LuaTable>> {
+    let Some(info) = info(&database, key).await? else {
+        return Ok(None);
+    };
+    let table = lua.create_table()?;
+    table.set("size", info.size)?;
+    table.set("content_type", info.content_type)?;
+    table.set("updated", info.updated)?;
+    Ok(Some(table))
+}
+
+async fn blob_delete(_lua: Lua, database: Database, key: String) -> LuaResult<bool> {
+    database
+        .call(move |conn| {
+            let deleted = conn.execute("DELETE FROM lg_blob WHERE key = ?", params![key])?;
+            Ok(deleted > 0)
+        })
+        .await
+        .into_lua_err()
+}
+
+/// for chunk in blob.stream(key) do ... end
+async fn blob_stream(_lua: Lua, database: Database, key: String) -> LuaResult<Option<BlobReader>> {
+    let Some(info) = info(&database, key).await? else {
+        return Ok(None);
+    };
+    Ok(Some(BlobReader::new(database, info)))
+}
+
+async fn response_send_blob(
+    _lua: Lua,
+    database: Database,
+    (res, key): (LuaTable, String),
+) -> LuaResult<()> {
+    let Some(info) = info(&database, key).await? else {
+        res.set("status", 404)?;
+        res.set("body", "Not Found")?;
+        return Ok(());
+    };
+    let content_type = HeaderValue::from_str(&info.content_type).into_lua_err()?;
+    res.get::<LuaAnyUserData>("headers")?
+        .borrow_mut::<LuaHeaders>()?
+        .insert(CONTENT_TYPE, content_type);
+    res.set("body", BlobReader::new(database, info))?;
+    Ok(())
+}
+
+/// reads a blob a chunk at a time. it is both the iterator returned by blob.stream
+/// and the body set by res:send_blob.
+pub struct BlobReader {
+    database: Database,
+    info: BlobInfo,
+    offset: usize,
+    end: usize,
+}
+
+impl BlobReader {
+    fn new(database: Database, info: BlobInfo) -> Self {
+        let end = info.size;
+        Self {
+            database,
+            info,
+            offset: 0,
+            end,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.info.size
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, crate::database::Error> {
+        if self.offset >= self.end {
+            return Ok(None);
+        }
+        let rowid = self.info.rowid;
+        let offset = self.offset;
+        let len = CHUNK_SIZE.min(self.end - offset);
+        let chunk = self
+            .database
+            .call(move |conn| {
+                let blob = conn.blob_open(DatabaseName::Main, "lg_blob", "data", rowid, true)?;
+                let mut chunk = vec![0; len];
+                blob.read_at_exact(&mut chunk, offset)?;
+                Ok(chunk)
+            })
+            .await?;
+        self.offset += len;
+        Ok(Some(Bytes::from(chunk)))
+    }
+
+    /// the response body for the given byte range, streamed from the database
+    pub fn into_body(mut self, range: Range<usize>) -> Body {
+        self.offset = range.start;
+        self.end = range.end.min(self.info.size);
+        let stream = futures_util::stream::unfold(self, |mut reader| async move {
+            match reader.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), reader)),
+                Ok(None) => None,
+                Err(err) => {
+                    // stop at the end of this item rather than erroring forever
+                    reader.offset = reader.end;
+                    Some((Err(err), reader))
+                }
+            }
+        });
+        Body::from_stream(stream)
+    }
+}
+
+impl LuaUserData for BlobReader {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_meta_method_mut(LuaMetaMethod::Call, |lua, mut this, ()| async move {
+            match this.next_chunk().await.into_lua_err()? {
+                Some(chunk) => Ok(Some(lua.create_string(chunk)?)),
+                None => Ok(None),
+            }
+        });
+    }
+}
@@ -0,0 +1,241 @@
+// app modules are loaded from bytecode when the same source has been compiled before.
+//
+// require finds a module next to the app as usual, then looks up its compiled chunk by
+// its path, and uses it if it was compiled from the same source, by sha-256: first in
+// memory, which covers reloads, then in the cache directory, which `lilguy compile` fills
+// ahead of time. a module that changed is compiled again and replaces the old chunk, in
+// memory and on disk, so neither grows as an app is edited. chunks keep their debug info,
+// so errors and the debugger still show files and lines.
+//
+// lua doesn't check binary chunks the way it checks source, so a damaged one could crash
+// it. each cache file carries the hash of its chunk and a chunk that doesn't match isn't
+// loaded, nor is one from a cache directory other users can write to. the cache is
+// otherwise as trusted as the app's own files: whoever can write to it can change those.
+// files from other builds of lilguy, and ones not written for 30 days, are removed the
+// first time the cache is written to.
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Once},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use super::LUA_FLAVOR;
+
+/// how long a cache file is kept without being written
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+type Hash = [u8; 32];
+
+/// compiled chunks by module path, with the hash of the source they were compiled from
+static COMPILED: LazyLock<Mutex<HashMap<PathBuf, (Hash, Arc<[u8]>)>>> =
+    LazyLock::new(Default::default);
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+/// add a searcher for modules in root that runs before lua's own
+#[cfg(not(feature = "luau"))]
+pub fn register(lua: &Lua, root: &Path) -> LuaResult<()> {
+    let package = lua.globals().get::<LuaTable>("package")?;
+    let searchers = match package.get::<Option<LuaTable>>("searchers")? {
+        Some(searchers) => searchers,
+        None => package.get::<LuaTable>("loaders")?,
+    };
+    let root = root.to_path_buf();
+    let searcher = lua.create_function(move |lua, name: String| {
+        let path = root.join(format!("{}.lua", name.replace('.', "/")));
+        let source = match std::fs::read(&path) {
+            Ok(source) => source,
+            Err(_) => return format!("\n\tno file '{}'", path.display()).into_lua_multi(lua),
+        };
+        (load(lua, &path, &source)?, path.to_string_lossy()).into_lua_multi(lua)
+    })?;
+    // after package.preload, before the searcher that reads package.path
+    searchers.raw_insert(2, searcher)?;
+    Ok(())
+}
+
+/// the compiled chunk for source, compiling it if it hasn't been seen
+pub fn load(lua: &Lua, path: &Path, source: &[u8]) -> LuaResult<LuaFunction> {
+    let name = format!("@{}", path.display());
+    let hash = sha256(source);
+    if let Some(bytecode) = cached(path, &hash) {
+        let chunk = lua
+            .load(&*bytecode)
+            .set_name(&name)
+            .set_mode(LuaChunkMode::Binary)
+            .into_function();
+        // a chunk lua won't load is compiled again
+        if let Ok(chunk) = chunk {
+            return Ok(chunk);
+        }
+    }
+    let chunk = lua.load(source).set_name(&name).into_function()?;
+    // luau functions can't be dumped, so luau builds compile every time
+    #[cfg(not(feature = "luau"))]
+    store(path, hash, chunk.dump(false));
+    Ok(chunk)
+}
+
+/// compile every lua file under root into the cache, returning how many there were
+pub fn compile_dir(root: &Path) -> eyre::Result<usize> {
+    let lua = Lua::new_with(super::std_libs(), LuaOptions::default())?;
+    let mut count = 0;
+    let files = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "lua")
+        });
+    for entry in files {
+        let source = std::fs::read(entry.path())?;
+        load(&lua, entry.path(), &source)
+            .map_err(|err| eyre::eyre!("{}: {err}", entry.path().display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// bytecode only loads in the lua that wrote it, so cache files name the build too
+fn build_prefix() -> String {
+    format!("{}-{LUA_FLAVOR}-", env!("CARGO_PKG_VERSION"))
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join("bytecode"))
+}
+
+/// the one cache file for the module at path
+fn cache_file(path: &Path) -> Option<PathBuf> {
+    let name = format!(
+        "{}{:x}",
+        build_prefix(),
+        Sha256::digest(path.as_os_str().as_encoded_bytes())
+    );
+    Some(cache_dir()?.join(name))
+}
+
+/// a cache file is the hash of the source, the hash of the chunk, then the chunk
+fn pack(source: &Hash, bytecode: &[u8]) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(64 + bytecode.len());
+    contents.extend_from_slice(source);
+    contents.extend_from_slice(&sha256(bytecode));
+    contents.extend_from_slice(bytecode);
+    contents
+}
+
+/// the chunk in a cache file, if it is for source and whole
+fn unpack<'a>(contents: &'a [u8], source: &Hash) -> Option<&'a [u8]> {
+    let (compiled_from, rest) = contents.split_at_checked(32)?;
+    let (checksum, bytecode) = rest.split_at_checked(32)?;
+    (compiled_from == source && checksum == sha256(bytecode)).then_some(bytecode)
+}
+
+fn cached(path: &Path, source: &Hash) -> Option<Arc<[u8]>> {
+    if let Some((compiled_from, bytecode)) = COMPILED.lock().get(path) {
+        if compiled_from == source {
+            return Some(bytecode.clone());
+        }
+    }
+    let file = cache_file(path)?;
+    if !private(&file) {
+        return None;
+    }
+    let contents = std::fs::read(&file).ok()?;
+    let bytecode: Arc<[u8]> = unpack(&contents, source)?.into();
+    COMPILED
+        .lock()
+        .insert(path.to_path_buf(), (*source, bytecode.clone()));
+    Some(bytecode)
+}
+
+/// true if no one else can write to file or its directory
+#[cfg(unix)]
+fn private(file: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let writable_by_others =
+        |path: &Path| std::fs::metadata(path).map_or(true, |metadata| metadata.mode() & 0o022 != 0);
+    file.parent()
+        .is_some_and(|dir| !writable_by_others(dir) && !writable_by_others(file))
+}
+
+#[cfg(not(unix))]
+fn private(_file: &Path) -> bool {
+    true
+}
+
+/// keep bytecode in memory and on disk; a cache that can't be written is only slower
+fn store(path: &Path, source: Hash, bytecode: Vec<u8>) {
+    if let Some(file) = cache_file(path) {
+        if let Err(err) = write(&file, &pack(&source, &bytecode)) {
+            tracing::debug!(?err, "could not write bytecode cache");
+        }
+    }
+    COMPILED
+        .lock()
+        .insert(path.to_path_buf(), (source, bytecode.into()));
+}
+
+/// replace file, which a reader never sees half written
+fn write(file: &Path, contents: &[u8]) -> io::Result<()> {
+    let Some(dir) = file.parent() else {
+        return Ok(());
+    };
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+    static PRUNED: Once = Once::new();
+    PRUNED.call_once(|| prune(dir));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(contents)?;
+    temp.persist(file).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// remove cache files from other builds, and ones that haven't been written in a while
+fn prune(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = build_prefix();
+    let now = SystemTime::now();
+    for entry in entries.filter_map(Result::ok) {
+        let current = entry.file_name().to_string_lossy().starts_with(&prefix);
+        let old = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > MAX_AGE);
+        if !current || old {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_cache_files() {
+        let source = sha256(b"return 1");
+        let contents = pack(&source, b"chunk");
+        assert_eq!(unpack(&contents, &source), Some(&b"chunk"[..]));
+        assert_eq!(unpack(&contents, &sha256(b"return 2")), None);
+        let mut damaged = contents.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(unpack(&damaged, &source), None);
+        assert_eq!(unpack(&contents[..40], &source), None);
+    }
+}
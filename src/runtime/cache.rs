@@ -0,0 +1,317 @@
+use axum::http::{
+    header::{AUTHORIZATION, COOKIE},
+    HeaderMap, HeaderName, Method, StatusCode,
+};
+use bytes::Bytes;
+use lru::LruCache;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::seconds;
+
+const RESPONSE_CACHE: &str = "response_cache";
+/// the largest response body that is cached; larger ones, and ones streamed without a
+/// known length, are sent without being kept
+pub const MAX_CACHED_BODY: u64 = 1024 * 1024;
+/// how many responses are cached before the ones closest to expiring make room
+const MAX_CACHED_RESPONSES: usize = 1000;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+    lua.set_named_registry_value(RESPONSE_CACHE, ResponseCache::default())?;
+
+    let cache = lua.create_table()?;
+    cache.set("new", lua.create_function(cache_new)?)?;
+    cache.set("purge", lua.create_function(cache_purge)?)?;
+    globals.set("cache", cache)?;
+
+    Ok(())
+}
+
+/// the response cache used by serve, shared with cache.purge()
+pub fn response_cache(lua: &Lua) -> LuaResult<ResponseCache> {
+    let cache = lua.named_registry_value::<LuaUserDataRef<ResponseCache>>(RESPONSE_CACHE)?;
+    Ok(ResponseCache::clone(&cache))
+}
+
+/// cache.purge([path])
+/// removes cached responses for path, or every cached response if path is nil
+fn cache_purge(lua: &Lua, path: Option<String>) -> LuaResult<()> {
+    let cache = response_cache(lua)?;
+    match path {
+        Some(path) => cache.purge(&path),
+        None => cache.clear(),
+    }
+    Ok(())
+}
+
+/// cache.new { max_items = 1000, ttl = 60 }
+/// ttl is in seconds and is optional; without it entries only leave by eviction or del
+fn cache_new(_lua: &Lua, options: Option<LuaTable>) -> LuaResult<LuaCache> {
+    let options = options.as_ref();
+    let max_items = options
+        .map(|options| options.get::<Option<usize>>("max_items"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(1000);
+    let ttl = options
+        .map(|options| options.get::<Option<f64>>("ttl"))
+        .transpose()?
+        .flatten()
+        .map(|ttl| seconds("ttl", ttl))
+        .transpose()?;
+
+    let Some(max_items) = NonZeroUsize::new(max_items) else {
+        return Err(LuaError::runtime("max_items must be greater than 0"));
+    };
+
+    Ok(LuaCache {
+        entries: LruCache::new(max_items),
+        ttl,
+    })
+}
+
+/// an LRU cache of lua values
+pub struct LuaCache {
+    entries: LruCache<String, (LuaValue, Option<Instant>)>,
+    ttl: Option<Duration>,
+}
+
+impl LuaCache {
+    fn get(&mut self, key: &str) -> Option<LuaValue> {
+        let (_, expires) = self.entries.peek(key)?;
+        if expires.is_some_and(|expires| expires <= Instant::now()) {
+            self.entries.pop(key);
+            return None;
+        }
+        self.entries.get(key).map(|(value, _)| value.clone())
+    }
+
+    fn set(&mut self, key: String, value: LuaValue) {
+        // a ttl too long to add to now never expires
+        let expires = self.ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.entries.put(key, (value, expires));
+    }
+
+    fn del(&mut self, key: &str) -> Option<LuaValue> {
+        self.entries.pop(key).map(|(value, _)| value)
+    }
+}
+
+impl LuaUserData for LuaCache {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("get", |_, this, key: String| Ok(this.get(&key)));
+
+        // cache:set(key, nil) is the same as cache:del(key)
+        methods.add_method_mut("set", |_, this, (key, value): (String, LuaValue)| {
+            if value.is_nil() {
+                this.del(&key);
+            } else {
+                this.set(key, value);
+            }
+            Ok(())
+        });
+
+        methods.add_method_mut("del", |_, this, key: String| Ok(this.del(&key)));
+
+        methods.add_method_mut("clear", |_, this, ()| {
+            this.entries.clear();
+            Ok(())
+        });
+
+        methods.add_meta_method(LuaMetaMethod::Len, |_, this, ()| Ok(this.entries.len()));
+    }
+}
+
+/// the longest ttl or stale window, which is as good as forever for a page
+const MAX_CACHE_AGE: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// set by routes:cache(pattern, { ttl = 300, stale = 3600, vary = { "accept-encoding" } })
+///
+/// for stale seconds after the ttl, the cached page is still served, and the first
+/// request to get it starts a new render in the background. pages stay fast under load,
+/// and are at most one render behind.
+///
+/// requests with a cookie or authorization header aren't cached, since their page could
+/// be someone's own, unless credentials = true; vary on the cookie to cache per visitor.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    pub ttl: Duration,
+    pub stale: Duration,
+    pub vary: Vec<HeaderName>,
+    pub credentials: bool,
+}
+
+impl CachePolicy {
+    pub fn from_lua_options(options: Option<LuaTable>) -> LuaResult<Self> {
+        let ttl = options
+            .as_ref()
+            .map(|options| options.get::<Option<f64>>("ttl"))
+            .transpose()?
+            .flatten()
+            .unwrap_or(60.0);
+        let stale = options
+            .as_ref()
+            .map(|options| options.get::<Option<f64>>("stale"))
+            .transpose()?
+            .flatten()
+            .unwrap_or(0.0);
+        let vary = options
+            .as_ref()
+            .map(|options| options.get::<Option<Vec<String>>>("vary"))
+            .transpose()?
+            .flatten()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).into_lua_err())
+            .collect::<LuaResult<Vec<_>>>()?;
+        let credentials = options
+            .as_ref()
+            .map(|options| options.get::<Option<bool>>("credentials"))
+            .transpose()?
+            .flatten()
+            .unwrap_or(false);
+
+        Ok(Self {
+            ttl: seconds("ttl", ttl)?.min(MAX_CACHE_AGE),
+            stale: seconds("stale", stale)?.min(MAX_CACHE_AGE),
+            vary,
+            credentials,
+        })
+    }
+
+    /// true if a request with these headers can be answered from the cache
+    pub fn cacheable(&self, headers: &HeaderMap) -> bool {
+        self.credentials || !(headers.contains_key(COOKIE) || headers.contains_key(AUTHORIZATION))
+    }
+
+    /// the cache key is the method, the request path and query, plus the value of each
+    /// vary header
+    pub fn key(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> String {
+        let mut key = format!("{method} {path}");
+        if let Some(query) = query {
+            key.push('?');
+            key.push_str(query);
+        }
+        for name in &self.vary {
+            key.push('\n');
+            key.push_str(name.as_str());
+            key.push(':');
+            for value in headers.get_all(name) {
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        key
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    path: String,
+    fresh_until: Instant,
+    expires: Instant,
+    /// a render to replace this response has started
+    revalidating: bool,
+}
+
+/// what the cache has for a request
+pub enum Cached {
+    Fresh(CachedResponse),
+    /// past its ttl but inside the stale window. revalidate is true for the one request
+    /// that should render the page again.
+    Stale {
+        response: CachedResponse,
+        revalidate: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCache(Arc<Mutex<HashMap<String, CachedResponse>>>);
+
+impl ResponseCache {
+    pub fn get(&self, key: &str) -> Option<Cached> {
+        let now = Instant::now();
+        let mut entries = self.0.lock();
+        let entry = entries.get_mut(key)?;
+        if entry.expires <= now {
+            entries.remove(key);
+            return None;
+        }
+        if entry.fresh_until > now {
+            return Some(Cached::Fresh(entry.clone()));
+        }
+        let revalidate = !entry.revalidating;
+        entry.revalidating = true;
+        Some(Cached::Stale {
+            response: entry.clone(),
+            revalidate,
+        })
+    }
+
+    pub fn insert(
+        &self,
+        key: String,
+        path: &str,
+        policy: &CachePolicy,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    ) {
+        let now = Instant::now();
+        let mut entries = self.0.lock();
+        entries.retain(|_, entry| entry.expires > now);
+        if entries.len() >= MAX_CACHED_RESPONSES && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                status,
+                headers,
+                body,
+                path: path.to_string(),
+                fresh_until: now + policy.ttl,
+                expires: now + policy.ttl + policy.stale,
+                revalidating: false,
+            },
+        );
+    }
+
+    /// after a background render; if it didn't replace the entry, a later request tries again
+    pub fn revalidated(&self, key: &str) {
+        if let Some(entry) = self.0.lock().get_mut(key) {
+            entry.revalidating = false;
+        }
+    }
+
+    pub fn purge(&self, path: &str) {
+        self.0.lock().retain(|_, entry| entry.path != path);
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().clear();
+    }
+}
+
+impl LuaUserData for ResponseCache {}
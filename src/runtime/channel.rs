@@ -1,5 +1,11 @@
 use mlua::prelude::*;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::routes::rooms::Rooms;
+
+const CHANNEL_ROOMS: &str = "channel_rooms";
+const CHANNEL_AUTHORIZE: &str = "channel_authorize";
 
 pub struct LuaBroadcastSender {
     tx: broadcast::Sender<LuaValue>,
@@ -9,10 +15,24 @@ pub struct LuaBroadcastReceiver {
     rx: broadcast::Receiver<LuaValue>,
 }
 
-pub fn register(lua: &Lua) -> LuaResult<()> {
+/// a room's messages, from channel.subscribe; see routes/rooms.rs
+pub struct LuaRoomReceiver {
+    rx: broadcast::Receiver<Arc<str>>,
+}
+
+struct LuaRooms(Rooms);
+
+impl LuaUserData for LuaRooms {}
+
+pub fn register(lua: &Lua, rooms: &Rooms) -> LuaResult<()> {
+    lua.set_named_registry_value(CHANNEL_ROOMS, LuaRooms(rooms.clone()))?;
+
     let globals = lua.globals();
     let channel = lua.create_table()?;
     channel.set("broadcast", lua.create_function(channel_broadast)?)?;
+    channel.set("publish", lua.create_function(channel_publish)?)?;
+    channel.set("subscribe", lua.create_function(channel_subscribe)?)?;
+    channel.set("authorize", lua.create_function(channel_authorize)?)?;
     globals.set("channel", channel)?;
     Ok(())
 }
@@ -25,6 +45,35 @@ fn channel_broadast(lua: &Lua, capacity: usize) -> LuaResult<(LuaAnyUserData, Lu
     Ok((tx, rx))
 }
 
+fn rooms(lua: &Lua) -> LuaResult<Rooms> {
+    let rooms = lua.named_registry_value::<LuaUserDataRef<LuaRooms>>(CHANNEL_ROOMS)?;
+    Ok(rooms.0.clone())
+}
+
+/// channel.publish(room, value), sent to pages as json
+fn channel_publish(lua: &Lua, (room, value): (String, LuaValue)) -> LuaResult<()> {
+    let message: serde_json::Value = lua.from_value(value)?;
+    rooms(lua)?.publish(&room, &message);
+    Ok(())
+}
+
+/// channel.subscribe(room)
+fn channel_subscribe(lua: &Lua, room: String) -> LuaResult<LuaRoomReceiver> {
+    Ok(LuaRoomReceiver {
+        rx: rooms(lua)?.subscribe(&room),
+    })
+}
+
+/// channel.authorize(function(req, room) return true end), or nil to let anyone listen
+fn channel_authorize(lua: &Lua, authorize: Option<LuaFunction>) -> LuaResult<()> {
+    lua.set_named_registry_value(CHANNEL_AUTHORIZE, authorize)
+}
+
+/// the function from channel.authorize, if there is one
+pub fn authorizer(lua: &Lua) -> LuaResult<Option<LuaFunction>> {
+    lua.named_registry_value(CHANNEL_AUTHORIZE)
+}
+
 impl LuaUserData for LuaBroadcastSender {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("send", |_, this, value: LuaValue| {
@@ -45,3 +94,22 @@ impl LuaUserData for LuaBroadcastReceiver {
         });
     }
 }
+
+impl LuaUserData for LuaRoomReceiver {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // the next message, skipping any missed by falling behind
+        methods.add_async_method_mut("recv", |lua, mut this, _: ()| async move {
+            loop {
+                match this.rx.recv().await {
+                    Ok(message) => {
+                        let message: serde_json::Value =
+                            serde_json::from_str(&message).into_lua_err()?;
+                        return lua.to_value(&message);
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Ok(LuaNil),
+                }
+            }
+        });
+    }
+}
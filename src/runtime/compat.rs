@@ -0,0 +1,152 @@
+// old names for runtime functions, kept working after they are renamed or change
+// signature so apps can move to a new lilguy before they are updated. the first call to
+// one logs a warning naming its replacement.
+//
+//   if lilguy.api_version < 2 then ... end
+//
+// lilguy.api_version goes up by one whenever a function is deprecated, and an entry is
+// added to DEPRECATED below. entries are removed a few versions later.
+use mlua::prelude::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+pub const API_VERSION: u32 = 1;
+
+struct Deprecated {
+    /// the old name, like "fetch_json" or "file.exists"
+    name: &'static str,
+    /// the function that replaced it
+    replacement: &'static str,
+    /// the api version it was deprecated in
+    since: u32,
+    /// lua that takes the replacement and returns a function with the old signature, when
+    /// more than the name changed
+    adapter: Option<&'static str>,
+}
+
+/// empty while nothing has been renamed since api version 1
+const DEPRECATED: &[Deprecated] = &[];
+
+/// install the old names; this runs after every other module has registered
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    install_all(lua, DEPRECATED)
+}
+
+fn install_all(lua: &Lua, entries: &[Deprecated]) -> LuaResult<()> {
+    for deprecated in entries {
+        let Some(replacement) = lookup(lua, deprecated.replacement)? else {
+            tracing::warn!(
+                name = deprecated.replacement,
+                "deprecated name has no replacement"
+            );
+            continue;
+        };
+        let replacement = match deprecated.adapter {
+            Some(adapter) => lua
+                .load(adapter)
+                .set_name(format!("=compat:{}", deprecated.name))
+                .call::<LuaFunction>(replacement)?,
+            None => replacement,
+        };
+        let warned = Arc::new(AtomicBool::new(false));
+        let (name, new_name, since) = (deprecated.name, deprecated.replacement, deprecated.since);
+        let old = lua.create_async_function(move |_, args: LuaMultiValue| {
+            if !warned.swap(true, Ordering::Relaxed) {
+                tracing::warn!("{name} is deprecated since api version {since}, use {new_name}");
+            }
+            let replacement = replacement.clone();
+            async move { replacement.call_async::<LuaMultiValue>(args).await }
+        })?;
+        install(lua, name, old)?;
+    }
+    Ok(())
+}
+
+/// a function by its dotted name, starting from the globals
+fn lookup(lua: &Lua, name: &str) -> LuaResult<Option<LuaFunction>> {
+    let mut value = LuaValue::Table(lua.globals());
+    for part in name.split('.') {
+        value = match value {
+            LuaValue::Table(table) => table.get(part)?,
+            _ => return Ok(None),
+        };
+    }
+    Ok(value.as_function().cloned())
+}
+
+/// set a function at a dotted name, making tables along the way
+fn install(lua: &Lua, name: &str, function: LuaFunction) -> LuaResult<()> {
+    let (path, last) = name.rsplit_once('.').unwrap_or(("", name));
+    let mut table = lua.globals();
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        table = match table.get::<Option<LuaTable>>(part)? {
+            Some(next) => next,
+            None => {
+                let next = lua.create_table()?;
+                table.set(part, &next)?;
+                next
+            }
+        };
+    }
+    table.set(last, function)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_names_call_their_replacements() {
+        let lua = Lua::new();
+        lua.load(
+            r#"
+                text = { upper = function(s) return s:upper() end }
+                function join(list, sep) return table.concat(list, sep) end
+            "#,
+        )
+        .exec()
+        .unwrap();
+        install_all(
+            &lua,
+            &[
+                Deprecated {
+                    name: "upper",
+                    replacement: "text.upper",
+                    since: 2,
+                    adapter: None,
+                },
+                Deprecated {
+                    name: "util.join",
+                    replacement: "join",
+                    since: 2,
+                    // the old one took the separator first
+                    adapter: Some(
+                        "local join = ... return function(sep, list) return join(list, sep) end",
+                    ),
+                },
+                Deprecated {
+                    name: "missing",
+                    replacement: "nothing.here",
+                    since: 2,
+                    adapter: None,
+                },
+            ],
+        )
+        .unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (upper, joined, missing) = runtime
+            .block_on(
+                lua.load(r#"return upper("abc"), util.join(",", { "a", "b" }), missing"#)
+                    .eval_async::<(String, String, Option<LuaFunction>)>(),
+            )
+            .unwrap();
+        assert_eq!(upper, "ABC");
+        assert_eq!(joined, "a,b");
+        assert!(missing.is_none());
+    }
+}
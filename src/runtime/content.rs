@@ -0,0 +1,477 @@
+// markdown pages in the app's content directory, served without a route.
+//
+//   content/index.md        -> /
+//   content/about.md        -> /about
+//   content/blog/index.md   -> /blog
+//   content/blog/hello.md   -> /blog/hello
+//
+// a page starts with front matter, yaml between --- lines or toml between +++ lines:
+//
+//   ---
+//   title: Hello
+//   date: 2024-05-01
+//   tags: [intro, news]
+//   ---
+//   # Hello
+//
+// only simple yaml is understood: one key: value a line, and lists as [a, b] or as
+// "- item" lines under the key. a GET that no route matches is answered with its page,
+// rendered with the template named by the page's `template`, or content.template, or
+// content.html. the template gets `page` (the front matter with `url` and `slug`),
+// `content` (the html) and `title`; with no such template the page is plain html. pages
+// with `draft: true` are only served while the app reloads on change.
+//
+//   content.template = "post.html"
+//   content.list("blog")      -- the pages under content/blog, newest date first
+//   content.list("blog", { drafts = true })
+//   content.get("/blog/hello") -- a page with its `html` and markdown `body`, or nil
+use minijinja::{context, Value as TemplateValue};
+use mlua::prelude::*;
+use serde_json::{Map, Value};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::template::Template;
+
+const CONTENT: &str = "content";
+const DEFAULT_TEMPLATE: &str = "content.html";
+
+#[derive(Debug, Clone)]
+struct ContentDir {
+    dir: PathBuf,
+    template: Template,
+}
+
+impl LuaUserData for ContentDir {}
+
+/// a parsed page
+#[derive(Debug)]
+struct Page {
+    meta: Map<String, Value>,
+    body: String,
+}
+
+pub fn register(lua: &Lua, root: &Path, template: &Template) -> LuaResult<()> {
+    lua.set_named_registry_value(
+        CONTENT,
+        ContentDir {
+            dir: root.join(CONTENT),
+            template: template.clone(),
+        },
+    )?;
+    let content = lua.create_table()?;
+    content.set("template", DEFAULT_TEMPLATE)?;
+    content.set("list", lua.create_async_function(content_list)?)?;
+    content.set("get", lua.create_async_function(content_get)?)?;
+    lua.globals().set("content", content)?;
+    Ok(())
+}
+
+fn content_dir(lua: &Lua) -> LuaResult<ContentDir> {
+    let dir = lua.named_registry_value::<LuaUserDataRef<ContentDir>>(CONTENT)?;
+    Ok(ContentDir::clone(&dir))
+}
+
+/// split a page into its front matter and markdown
+fn split_front_matter(text: &str) -> (Map<String, Value>, &str) {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    for (fence, parse) in [
+        ("---", parse_yaml as fn(&str) -> Map<String, Value>),
+        ("+++", parse_toml),
+    ] {
+        let Some(rest) = text.strip_prefix(fence).and_then(|rest| {
+            rest.strip_prefix('\n')
+                .or_else(|| rest.strip_prefix("\r\n"))
+        }) else {
+            continue;
+        };
+        let mut offset = 0;
+        for line in rest.split_inclusive('\n') {
+            if line.trim_end() == fence {
+                let meta = parse(&rest[..offset]);
+                return (meta, &rest[offset + line.len()..]);
+            }
+            offset += line.len();
+        }
+    }
+    (Map::new(), text)
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn parse_toml(text: &str) -> Map<String, Value> {
+    match text.parse::<toml::Table>() {
+        Ok(table) => match toml_to_json(toml::Value::Table(table)) {
+            Value::Object(meta) => meta,
+            _ => Map::new(),
+        },
+        Err(err) => {
+            tracing::warn!(%err, "invalid toml front matter");
+            Map::new()
+        }
+    }
+}
+
+/// a yaml scalar or [a, b] list
+fn yaml_value(text: &str) -> Value {
+    let text = text.trim();
+    if let Some(items) = text
+        .strip_prefix('[')
+        .and_then(|text| text.strip_suffix(']'))
+    {
+        return Value::Array(
+            items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(yaml_value)
+                .collect(),
+        );
+    }
+    for quote in ['"', '\''] {
+        if let Some(quoted) = text
+            .strip_prefix(quote)
+            .and_then(|text| text.strip_suffix(quote))
+        {
+            return Value::String(quoted.to_string());
+        }
+    }
+    match text {
+        "" | "~" | "null" => Value::Null,
+        "true" | "yes" => Value::Bool(true),
+        "false" | "no" => Value::Bool(false),
+        _ => text
+            .parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| text.parse::<f64>().map(Value::from))
+            .unwrap_or_else(|_| Value::String(text.to_string())),
+    }
+}
+
+fn parse_yaml(text: &str) -> Map<String, Value> {
+    let mut meta = Map::new();
+    let mut list: Option<String> = None;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(item) = trimmed.strip_prefix("- ") {
+            if let Some(Value::Array(items)) = list.as_ref().and_then(|key| meta.get_mut(key)) {
+                items.push(yaml_value(item));
+            }
+            continue;
+        }
+        let Some((key, value)) = trimmed.split_once(':') else {
+            continue;
+        };
+        end_list(&mut meta, list.take());
+        let key = key.trim().to_string();
+        if value.trim().is_empty() {
+            meta.insert(key.clone(), Value::Array(Vec::new()));
+            list = Some(key);
+        } else {
+            meta.insert(key, yaml_value(value));
+        }
+    }
+    end_list(&mut meta, list);
+    meta
+}
+
+/// a key with no items under it was empty, not a list
+fn end_list(meta: &mut Map<String, Value>, list: Option<String>) {
+    let Some(key) = list else {
+        return;
+    };
+    if let Some(value) = meta.get_mut(&key) {
+        if matches!(value, Value::Array(items) if items.is_empty()) {
+            *value = Value::Null;
+        }
+    }
+}
+
+/// the page file for a request path, if the path could name one
+fn page_file(dir: &Path, path: &str) -> Option<[PathBuf; 2]> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments
+        .iter()
+        .any(|segment| segment.starts_with('.') || segment.contains('\\'))
+    {
+        return None;
+    }
+    let base = segments
+        .iter()
+        .fold(dir.to_path_buf(), |base, s| base.join(s));
+    let index = base.join("index.md");
+    match segments.last() {
+        Some(last) => Some([base.with_file_name(format!("{last}.md")), index]),
+        None => Some([index.clone(), index]),
+    }
+}
+
+/// the url of a page file, relative to the content directory
+fn page_url(relative: &Path) -> String {
+    let mut segments: Vec<String> = relative
+        .iter()
+        .map(|segment| segment.to_string_lossy().into_owned())
+        .collect();
+    if let Some(last) = segments.pop() {
+        let stem = last.strip_suffix(".md").unwrap_or(&last);
+        if stem != "index" {
+            segments.push(stem.to_string());
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn read_page(file: &Path) -> io::Result<Page> {
+    let text = std::fs::read_to_string(file)?;
+    let (meta, body) = split_front_matter(&text);
+    Ok(Page {
+        meta,
+        body: body.to_string(),
+    })
+}
+
+fn is_draft(meta: &Map<String, Value>) -> bool {
+    meta.get("draft").and_then(Value::as_bool).unwrap_or(false)
+}
+
+/// the front matter, with the page's url and slug
+fn page_info(page: &Page, url: &str) -> Map<String, Value> {
+    let mut info = page.meta.clone();
+    let slug = url.rsplit('/').next().unwrap_or_default();
+    info.insert("url".to_string(), Value::String(url.to_string()));
+    info.entry("slug")
+        .or_insert_with(|| Value::String(slug.to_string()));
+    info
+}
+
+fn title(page: &Page, url: &str) -> String {
+    page.meta
+        .get("title")
+        .and_then(Value::as_str)
+        .map(String::from)
+        .or_else(|| {
+            page.body
+                .lines()
+                .find_map(|line| line.strip_prefix("# "))
+                .map(|title| title.trim().to_string())
+        })
+        .unwrap_or_else(|| url.to_string())
+}
+
+fn html(markdown: &str) -> String {
+    comrak::markdown_to_html(markdown, &comrak::ComrakOptions::default())
+}
+
+/// the page for a path, if there is one to serve
+fn find_page(dir: &Path, path: &str, drafts: bool) -> Option<(String, Page)> {
+    for file in page_file(dir, path)? {
+        if !file.is_file() {
+            continue;
+        }
+        let page = match read_page(&file) {
+            Ok(page) => page,
+            Err(err) => {
+                tracing::error!(%err, file = %file.display(), "error reading content");
+                return None;
+            }
+        };
+        if is_draft(&page.meta) && !drafts {
+            return None;
+        }
+        let url = page_url(file.strip_prefix(dir).ok()?);
+        return Some((url, page));
+    }
+    None
+}
+
+/// the rendered page for a GET that no route matched
+pub async fn serve(lua: &Lua, path: &str, dev: bool) -> LuaResult<Option<String>> {
+    let content = content_dir(lua)?;
+    let dir = content.dir.clone();
+    let path = path.to_string();
+    let found = tokio::task::spawn_blocking(move || find_page(&dir, &path, dev))
+        .await
+        .into_lua_err()?;
+    let Some((url, page)) = found else {
+        return Ok(None);
+    };
+    let template = match page.meta.get("template").and_then(Value::as_str) {
+        Some(template) => template.to_string(),
+        None => lua
+            .globals()
+            .get::<LuaTable>("content")?
+            .get::<Option<String>>("template")?
+            .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+    };
+    let title = title(&page, &url);
+    let content_html = html(&page.body);
+    let info = page_info(&page, &url);
+    let rendered = content
+        .template
+        .call(move |env| match env.get_template(&template) {
+            Ok(template) => Ok(template.render(context! {
+                page => TemplateValue::from_serialize(&info),
+                content => TemplateValue::from_safe_string(content_html),
+                title,
+            })?),
+            Err(err) if err.kind() == minijinja::ErrorKind::TemplateNotFound => Ok(format!(
+                "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                 <title>{}</title>\n</head>\n<body>\n{content_html}</body>\n</html>\n",
+                escape(&title)
+            )),
+            Err(err) => Err(err.into()),
+        })
+        .await
+        .into_lua_err()?;
+    Ok(Some(rendered))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// every page under dir, newest first by their date, then by url
+fn list_pages(root: &Path, dir: &str, drafts: bool) -> Vec<Map<String, Value>> {
+    let Some([_, own_index]) = page_file(root, dir) else {
+        return Vec::new();
+    };
+    let base = own_index
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let mut pages: Vec<_> = WalkDir::new(&base)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && entry.path() != own_index)
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "md"))
+        .filter_map(|entry| {
+            let page = read_page(entry.path()).ok()?;
+            if is_draft(&page.meta) && !drafts {
+                return None;
+            }
+            let url = page_url(entry.path().strip_prefix(root).ok()?);
+            Some(page_info(&page, &url))
+        })
+        .collect();
+    let date = |page: &Map<String, Value>| {
+        page.get("date")
+            .map(|date| date.as_str().map_or_else(|| date.to_string(), String::from))
+    };
+    pages.sort_by(|a, b| {
+        date(b)
+            .cmp(&date(a))
+            .then_with(|| a["url"].as_str().cmp(&b["url"].as_str()))
+    });
+    pages
+}
+
+/// content.list(dir, { drafts = false })
+async fn content_list(lua: Lua, (dir, options): (String, Option<LuaTable>)) -> LuaResult<LuaValue> {
+    let content = content_dir(&lua)?;
+    let drafts = match options {
+        Some(options) => options.get::<Option<bool>>("drafts")?.unwrap_or(false),
+        None => false,
+    };
+    let pages = tokio::task::spawn_blocking(move || list_pages(&content.dir, &dir, drafts))
+        .await
+        .into_lua_err()?;
+    lua.to_value(&pages)
+}
+
+/// content.get(url), with drafts
+async fn content_get(lua: Lua, url: String) -> LuaResult<LuaValue> {
+    let content = content_dir(&lua)?;
+    let found = tokio::task::spawn_blocking(move || find_page(&content.dir, &url, true))
+        .await
+        .into_lua_err()?;
+    let Some((url, page)) = found else {
+        return Ok(LuaNil);
+    };
+    let mut info = page_info(&page, &url);
+    info.insert("title".to_string(), Value::String(title(&page, &url)));
+    info.insert("html".to_string(), Value::String(html(&page.body)));
+    info.insert("body".to_string(), Value::String(page.body));
+    lua.to_value(&info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_front_matter() {
+        let (meta, body) = split_front_matter(
+            "---\ntitle: \"Hello: world\"\ndate: 2024-05-01\ndraft: false\ncount: 3\n\
+             tags: [intro, news]\nnone: []\nauthors:\n  - ann\n  - bo\nempty:\n---\n# Hi\n",
+        );
+        assert_eq!(
+            Value::Object(meta),
+            json!({
+                "title": "Hello: world",
+                "date": "2024-05-01",
+                "draft": false,
+                "count": 3,
+                "tags": ["intro", "news"],
+                "none": [],
+                "authors": ["ann", "bo"],
+                "empty": null,
+            })
+        );
+        assert_eq!(body, "# Hi\n");
+
+        let (meta, body) =
+            split_front_matter("+++\ntitle = \"Toml\"\ndate = 2024-05-01\n+++\nbody");
+        assert_eq!(
+            Value::Object(meta),
+            json!({ "title": "Toml", "date": "2024-05-01" })
+        );
+        assert_eq!(body, "body");
+
+        let (meta, body) = split_front_matter("no front matter\n---\n");
+        assert!(meta.is_empty());
+        assert_eq!(body, "no front matter\n---\n");
+    }
+
+    #[test]
+    fn maps_urls() {
+        assert_eq!(page_url(Path::new("index.md")), "/");
+        assert_eq!(page_url(Path::new("about.md")), "/about");
+        assert_eq!(page_url(Path::new("blog/index.md")), "/blog");
+        assert_eq!(page_url(Path::new("blog/hello.md")), "/blog/hello");
+
+        let dir = Path::new("/app/content");
+        let [file, index] = page_file(dir, "/blog/hello").expect("files");
+        assert_eq!(file, dir.join("blog/hello.md"));
+        assert_eq!(index, dir.join("blog/hello/index.md"));
+        assert!(page_file(dir, "/blog/../../secret").is_none());
+        assert!(page_file(dir, "/.git/config").is_none());
+    }
+}
@@ -0,0 +1,101 @@
+// the coroutine library, made safe to use around lilguy's async functions, and a small
+// async library for running lua functions side by side.
+//
+//   local get = async.wrap(function(url) return fetch(url).body end)
+//   local a, b = get("https://a.example.com"), get("https://b.example.com")
+//   print(async.await(a), async.await(b))
+//
+// async.wrap(fn) returns a function that starts fn in its own task and returns a promise;
+// async.spawn(fn, ...) does the same right away. async.await(promise) waits for it and
+// returns what fn returned, or raises its error. a promise can be awaited more than once.
+//
+// functions like fetch() wait by yielding to tokio from the coroutine they are called in.
+// in a coroutine made with coroutine.create, that yield would reach its resume instead,
+// so coroutine.resume and coroutine.wrap pass those yields on and resume the coroutine
+// when it is ready. coroutine-based lua libraries work as they expect, and can call
+// anything lilguy provides.
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use mlua::prelude::*;
+
+const COROUTINE_SHIM: &str = r#"
+local pending, create, resume, yield, status =
+    ...,
+    coroutine.create,
+    coroutine.resume,
+    coroutine.yield,
+    coroutine.status
+
+local function pass_pending(co, ok, first, ...)
+    if ok and first == pending and status(co) == "suspended" then
+        yield(pending)
+        return pass_pending(co, resume(co))
+    end
+    return ok, first, ...
+end
+
+function coroutine.resume(co, ...)
+    return pass_pending(co, resume(co, ...))
+end
+
+function coroutine.wrap(fn)
+    local co = create(fn)
+    return function(...)
+        local function results(ok, ...)
+            if not ok then
+                error((...), 0)
+            end
+            return ...
+        end
+        return results(coroutine.resume(co, ...))
+    end
+end
+"#;
+
+/// a lua function running in its own task
+#[derive(Clone)]
+pub struct Promise(Shared<BoxFuture<'static, LuaResult<LuaMultiValue>>>);
+
+impl Promise {
+    fn spawn(function: LuaFunction, args: LuaMultiValue) -> Self {
+        let task = tokio::spawn(async move { function.call_async::<LuaMultiValue>(args).await });
+        let result = async move { task.await.into_lua_err()? }.boxed();
+        Self(result.shared())
+    }
+}
+
+impl LuaUserData for Promise {}
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    lua.load(COROUTINE_SHIM)
+        .set_name("=coroutine")
+        .call::<()>(Lua::poll_pending())?;
+
+    let async_table = lua.create_table()?;
+    async_table.set(
+        "spawn",
+        lua.create_function(|_, (function, args): (LuaFunction, LuaMultiValue)| {
+            Ok(Promise::spawn(function, args))
+        })?,
+    )?;
+    async_table.set(
+        "wrap",
+        lua.create_function(|lua, function: LuaFunction| {
+            lua.create_function(move |_, args: LuaMultiValue| {
+                Ok(Promise::spawn(function.clone(), args))
+            })
+        })?,
+    )?;
+    async_table.set(
+        "await",
+        lua.create_async_function(|_, promise: LuaUserDataRef<Promise>| {
+            let result = promise.0.clone();
+            async move { result.await }
+        })?,
+    )?;
+    lua.globals().set("async", async_table)?;
+
+    Ok(())
+}
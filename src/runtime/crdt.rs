@@ -0,0 +1,280 @@
+// conflict-free replicated data types, stored in the database and merged without
+// conflicts, for shared counters, settings and collaborative notes.
+//
+//   local likes = crdt.counter("likes")
+//   likes:add(1)
+//   likes:value()                  -- 1
+//
+//   local prefs = crdt.lww_map("prefs")
+//   prefs:set("theme", "dark")     -- the last write to a key wins
+//   prefs:get("theme")             -- "dark"
+//   prefs:delete("theme")
+//   prefs:to_table()
+//
+//   local notes = crdt.text("notes")
+//   notes:insert(1, "hello world")  -- positions are 1-based byte offsets, like string.sub
+//   notes:delete(6, 6)             -- remove 6 bytes starting at position 6
+//   notes:to_string()              -- "hello"
+//
+// each one is a yrs (yjs) document. to merge with a copy elsewhere, exchange updates:
+//
+//   local sv = notes:state_vector()    -- what this copy has
+//   local update = other:update(sv)    -- what the other copy has that this one doesn't
+//   notes:merge(update)
+//
+// updates and state vectors are binary strings, and update() without a state vector
+// returns the whole document. crdt.doc(name) is a document shaped by its clients, like
+// the ones routes:yjs() serves, with only these methods.
+use mlua::prelude::*;
+use yrs::{types::ToJson, Any, GetString, Map, Out, ReadTxn, Text, Transact};
+
+use crate::database::{
+    crdt::{apply_update, encode_update, state_vector, with_doc, CrdtError, Kind},
+    Database,
+};
+
+/// the name of the root type that holds each crdt's data
+const ROOT: &str = "value";
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let crdt = lua.create_table()?;
+    for kind in [Kind::Counter, Kind::LwwMap, Kind::Text, Kind::Doc] {
+        let database = database.clone();
+        crdt.set(
+            kind.as_str(),
+            lua.create_function(move |_, name: String| {
+                Ok(LuaCrdt {
+                    name,
+                    kind,
+                    database: database.clone(),
+                })
+            })?,
+        )?;
+    }
+    lua.globals().set("crdt", crdt)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct LuaCrdt {
+    name: String,
+    kind: Kind,
+    database: Database,
+}
+
+impl LuaCrdt {
+    /// run f on the stored document
+    async fn call<R, F>(&self, f: F) -> LuaResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&yrs::Doc) -> Result<R, CrdtError> + Send + 'static,
+    {
+        let (name, kind) = (self.name.clone(), self.kind);
+        self.database
+            .call(move |conn| with_doc(conn, &name, kind, f))
+            .await
+            .into_lua_err()
+    }
+
+    fn expect(&self, kind: Kind, method: &str) -> LuaResult<()> {
+        if self.kind == kind {
+            Ok(())
+        } else {
+            Err(LuaError::runtime(format!(
+                "{method} is not a method of {}",
+                self.kind.as_str()
+            )))
+        }
+    }
+}
+
+fn to_any(lua: &Lua, value: LuaValue) -> LuaResult<Any> {
+    let value: serde_json::Value = lua.from_value(value)?;
+    serde_json::from_value(value).into_lua_err()
+}
+
+fn from_any(lua: &Lua, value: &Any) -> LuaResult<LuaValue> {
+    let value = serde_json::to_value(value).into_lua_err()?;
+    lua.to_value(&value)
+}
+
+/// the number in a counter's entry for one node
+fn count(value: &Out) -> f64 {
+    match value {
+        Out::Any(Any::Number(count)) => *count,
+        Out::Any(Any::BigInt(count)) => *count as f64,
+        _ => 0.0,
+    }
+}
+
+/// a 1-based position as a 0-based offset
+fn offset(position: i64) -> LuaResult<u32> {
+    if position < 1 {
+        return Err(LuaError::runtime("positions start at 1"));
+    }
+    u32::try_from(position - 1).into_lua_err()
+}
+
+impl LuaUserData for LuaCrdt {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // counter: each node adds to its own entry, and the value is their sum
+        methods.add_async_method("add", |_, this, amount: Option<f64>| async move {
+            this.expect(Kind::Counter, "add")?;
+            let amount = amount.unwrap_or(1.0);
+            this.call(move |doc| {
+                let map = doc.get_or_insert_map(ROOT);
+                let node = doc.client_id().to_string();
+                let mut txn = doc.transact_mut();
+                let current = map.get(&txn, &node).map(|value| count(&value));
+                map.insert(&mut txn, node, current.unwrap_or_default() + amount);
+                Ok(())
+            })
+            .await
+        });
+
+        methods.add_async_method("value", |_, this, ()| async move {
+            this.expect(Kind::Counter, "value")?;
+            this.call(|doc| {
+                let map = doc.get_or_insert_map(ROOT);
+                let txn = doc.transact();
+                Ok(map.iter(&txn).map(|(_, value)| count(&value)).sum::<f64>())
+            })
+            .await
+        });
+
+        // lww_map
+        methods.add_async_method("get", |lua, this, key: String| async move {
+            this.expect(Kind::LwwMap, "get")?;
+            let value = this
+                .call(move |doc| {
+                    let map = doc.get_or_insert_map(ROOT);
+                    let txn = doc.transact();
+                    Ok(map.get(&txn, &key).map(|value| value.to_json(&txn)))
+                })
+                .await?;
+            match value {
+                Some(value) => from_any(&lua, &value),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+
+        methods.add_async_method(
+            "set",
+            |lua, this, (key, value): (String, LuaValue)| async move {
+                this.expect(Kind::LwwMap, "set")?;
+                let value = (!value.is_nil()).then(|| to_any(&lua, value)).transpose()?;
+                this.call(move |doc| {
+                    let map = doc.get_or_insert_map(ROOT);
+                    let mut txn = doc.transact_mut();
+                    match value {
+                        Some(value) => {
+                            map.insert(&mut txn, key, value);
+                        }
+                        None => {
+                            map.remove(&mut txn, &key);
+                        }
+                    }
+                    Ok(())
+                })
+                .await
+            },
+        );
+
+        methods.add_async_method("to_table", |lua, this, ()| async move {
+            this.expect(Kind::LwwMap, "to_table")?;
+            let value = this
+                .call(|doc| {
+                    let map = doc.get_or_insert_map(ROOT);
+                    let txn = doc.transact();
+                    Ok(map.to_json(&txn))
+                })
+                .await?;
+            from_any(&lua, &value)
+        });
+
+        // text
+        methods.add_async_method(
+            "insert",
+            |_, this, (position, text): (i64, String)| async move {
+                this.expect(Kind::Text, "insert")?;
+                let offset = offset(position)?;
+                this.call(move |doc| {
+                    let root = doc.get_or_insert_text(ROOT);
+                    let mut txn = doc.transact_mut();
+                    let offset = offset.min(root.len(&txn));
+                    root.insert(&mut txn, offset, &text);
+                    Ok(())
+                })
+                .await
+            },
+        );
+
+        // prefs:delete(key) for maps, notes:delete(position, length) for text
+        methods.add_async_method(
+            "delete",
+            |_, this, (key, length): (LuaValue, Option<u32>)| async move {
+                match this.kind {
+                    Kind::LwwMap => {
+                        let key = key.to_string()?;
+                        this.call(move |doc| {
+                            let map = doc.get_or_insert_map(ROOT);
+                            map.remove(&mut doc.transact_mut(), &key);
+                            Ok(())
+                        })
+                        .await
+                    }
+                    Kind::Text => {
+                        let position = key
+                            .as_i64()
+                            .ok_or_else(|| LuaError::runtime("position must be an integer"))?;
+                        let offset = offset(position)?;
+                        let length = length.unwrap_or(1);
+                        this.call(move |doc| {
+                            let root = doc.get_or_insert_text(ROOT);
+                            let mut txn = doc.transact_mut();
+                            let len = root.len(&txn);
+                            if offset < len {
+                                root.remove_range(&mut txn, offset, length.min(len - offset));
+                            }
+                            Ok(())
+                        })
+                        .await
+                    }
+                    _ => this.expect(Kind::LwwMap, "delete"),
+                }
+            },
+        );
+
+        methods.add_async_method("to_string", |_, this, ()| async move {
+            this.expect(Kind::Text, "to_string")?;
+            this.call(|doc| {
+                let root = doc.get_or_insert_text(ROOT);
+                Ok(root.get_string(&doc.transact()))
+            })
+            .await
+        });
+
+        // every kind
+        methods.add_async_method("update", |lua, this, since: Option<LuaString>| async move {
+            let since = since.map(|since| since.as_bytes().to_vec());
+            let update = this
+                .call(move |doc| encode_update(doc, since.as_deref()))
+                .await?;
+            lua.create_string(update)
+        });
+
+        methods.add_async_method("state_vector", |lua, this, ()| async move {
+            let vector = this.call(|doc| Ok(state_vector(doc))).await?;
+            lua.create_string(vector)
+        });
+
+        methods.add_async_method("merge", |_, this, update: LuaString| async move {
+            let update = update.as_bytes().to_vec();
+            this.call(move |doc| apply_update(doc, &update)).await
+        });
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!("crdt.{}({:?})", this.kind.as_str(), this.name))
+        });
+    }
+}
@@ -0,0 +1,505 @@
+// a debug adapter protocol (DAP) server for stepping through lua handlers.
+//
+//   lilguy serve --debug-port 4711
+//
+// then attach an editor's DAP client to 127.0.0.1:4711. breakpoints, stepping, the stack
+// and locals (including table contents) are supported.
+//
+// when a breakpoint is hit the hook blocks the thread running lua, which holds the lua
+// lock, so every other request waits until the debugger continues. for that reason the
+// adapter runs on its own os threads rather than on the tokio runtime.
+use mlua::prelude::*;
+#[cfg(not(feature = "luau"))]
+use mlua::{HookTriggers, VmState};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path,
+    sync::{mpsc, Arc},
+};
+
+use super::std_libs;
+
+const DEBUG_LIB: &str = "lilguy.debug";
+const THREAD_ID: i64 = 1;
+
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: Mutex<State>,
+    /// messages to the connected client
+    client: Mutex<Option<mpsc::Sender<Value>>>,
+    /// while stopped, requests that need lua are handed to the stopped hook
+    stopped: Mutex<Option<mpsc::Sender<Value>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    breakpoints: HashMap<String, HashSet<usize>>,
+    step: Step,
+    /// chunk names (@./app.lua) to the absolute paths editors use
+    paths: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum Step {
+    #[default]
+    Run,
+    Pause,
+    In,
+    Over(usize),
+    Out(usize),
+}
+
+impl State {
+    fn path(&mut self, source: &str) -> String {
+        self.paths
+            .entry(source.to_string())
+            .or_insert_with(|| normalize(source.strip_prefix('@').unwrap_or(source)))
+            .clone()
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path::absolute(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+impl Debugger {
+    /// accept debug clients on addr, one at a time
+    pub fn listen(&self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        tracing::info!(%addr, "debug adapter listening");
+        let debugger = self.clone();
+        std::thread::Builder::new()
+            .name("lilguy-debugger".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(err) = debugger.session(stream) {
+                                tracing::warn!(?err, "debug session ended with an error");
+                            }
+                            debugger.detach();
+                        }
+                        Err(err) => tracing::error!(?err, "error accepting debug client"),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// install the line hook on a new lua state. the state must have the debug library
+    /// loaded; it is kept in the registry so apps can't reach it.
+    #[cfg(not(feature = "luau"))]
+    pub fn attach(&self, lua: &Lua) -> LuaResult<()> {
+        let debug = lua.globals().get::<LuaTable>("debug")?;
+        lua.set_named_registry_value(DEBUG_LIB, debug)?;
+        lua.globals().set("debug", LuaNil)?;
+
+        let debugger = self.clone();
+        lua.set_hook(HookTriggers::EVERY_LINE, move |lua, debug| {
+            let (Some(source), Some(line)) = (debug.source().source, debug.current_line()) else {
+                return Ok(VmState::Continue);
+            };
+            if let Err(err) = debugger.on_line(lua, &source, line) {
+                tracing::warn!(?err, "debugger error");
+            }
+            Ok(VmState::Continue)
+        })?;
+        Ok(())
+    }
+
+    /// luau has no hooks for the debugger to use
+    #[cfg(feature = "luau")]
+    pub fn attach(&self, _lua: &Lua) -> LuaResult<()> {
+        Err(LuaError::runtime(
+            "the debugger needs lilguy built with luajit",
+        ))
+    }
+
+    fn send(&self, message: Value) {
+        if let Some(client) = self.inner.client.lock().as_ref() {
+            let _ = client.send(message);
+        }
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&self, request: &Value, body: Value) {
+        self.send(response(request, true, body));
+    }
+
+    /// forget the client, its breakpoints, and let a stopped handler carry on
+    fn detach(&self) {
+        self.inner.client.lock().take();
+        {
+            let mut state = self.inner.state.lock();
+            state.breakpoints.clear();
+            state.step = Step::Run;
+        }
+        if let Some(stopped) = self.inner.stopped.lock().take() {
+            let _ = stopped.send(json!({ "command": "disconnect" }));
+        }
+    }
+
+    fn session(&self, stream: TcpStream) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel::<Value>();
+        let mut writer = stream.try_clone()?;
+        std::thread::spawn(move || {
+            for (seq, mut message) in rx.into_iter().zip(1..) {
+                message["seq"] = seq.into();
+                let body = message.to_string();
+                let frame = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+                if writer.write_all(frame.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+        self.inner.client.lock().replace(tx);
+
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_message(&mut reader)? {
+            let command = request["command"].as_str().unwrap_or_default();
+            match command {
+                "initialize" => {
+                    self.respond(
+                        &request,
+                        json!({
+                            "supportsConfigurationDoneRequest": true,
+                        }),
+                    );
+                    self.event("initialized", json!({}));
+                }
+                "launch" | "attach" | "configurationDone" => self.respond(&request, json!({})),
+                "setBreakpoints" => {
+                    let path = normalize(
+                        request["arguments"]["source"]["path"]
+                            .as_str()
+                            .unwrap_or_default(),
+                    );
+                    let lines: Vec<usize> = request["arguments"]["breakpoints"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                        .map(|line| line as usize)
+                        .collect();
+                    let breakpoints: Vec<Value> = lines
+                        .iter()
+                        .map(|line| json!({ "verified": true, "line": line }))
+                        .collect();
+                    self.inner
+                        .state
+                        .lock()
+                        .breakpoints
+                        .insert(path, lines.into_iter().collect());
+                    self.respond(&request, json!({ "breakpoints": breakpoints }));
+                }
+                "threads" => {
+                    self.respond(
+                        &request,
+                        json!({ "threads": [{ "id": THREAD_ID, "name": "lua" }] }),
+                    );
+                }
+                "pause" => {
+                    self.inner.state.lock().step = Step::Pause;
+                    self.respond(&request, json!({}));
+                }
+                "disconnect" => {
+                    self.respond(&request, json!({}));
+                    break;
+                }
+                _ => {
+                    // everything else needs the stopped lua state
+                    let stopped = self.inner.stopped.lock().clone();
+                    match stopped {
+                        Some(stopped) => {
+                            let _ = stopped.send(request);
+                        }
+                        None => self.send(response(&request, false, json!({}))),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_line(&self, lua: &Lua, source: &str, line: usize) -> LuaResult<()> {
+        if self.inner.client.lock().is_none() {
+            return Ok(());
+        }
+        let reason = {
+            let mut state = self.inner.state.lock();
+            if state.breakpoints.is_empty() && matches!(state.step, Step::Run) {
+                return Ok(());
+            }
+            let path = state.path(source);
+            let hit = state
+                .breakpoints
+                .get(&path)
+                .is_some_and(|lines| lines.contains(&line));
+            match state.step {
+                _ if hit => "breakpoint",
+                Step::Run => return Ok(()),
+                Step::Pause => "pause",
+                Step::In => "step",
+                Step::Over(depth) if stack_depth(lua)? <= depth => "step",
+                Step::Out(depth) if stack_depth(lua)? < depth => "step",
+                Step::Over(_) | Step::Out(_) => return Ok(()),
+            }
+        };
+        self.stop(lua, reason)
+    }
+
+    /// block until the client resumes, answering questions about the stack meanwhile
+    fn stop(&self, lua: &Lua, reason: &str) -> LuaResult<()> {
+        let (tx, rx) = mpsc::channel();
+        self.inner.stopped.lock().replace(tx);
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        );
+
+        let frames = stack(lua)?;
+        // variablesReference n refers to handles[n - 1]
+        let mut handles: Vec<Handle> = vec![];
+        let step = loop {
+            let Ok(request) = rx.recv() else {
+                break Step::Run;
+            };
+            let arguments = &request["arguments"];
+            match request["command"].as_str().unwrap_or_default() {
+                "stackTrace" => {
+                    let stack_frames: Vec<Value> = frames
+                        .iter()
+                        .enumerate()
+                        .map(|(id, frame)| {
+                            json!({
+                                "id": id,
+                                "name": frame.name,
+                                "line": frame.line,
+                                "column": 1,
+                                "source": { "path": frame.path },
+                            })
+                        })
+                        .collect();
+                    self.respond(
+                        &request,
+                        json!({ "stackFrames": stack_frames, "totalFrames": frames.len() }),
+                    );
+                }
+                "scopes" => {
+                    let frame = arguments["frameId"].as_u64().unwrap_or_default() as usize;
+                    let scopes = match frames.get(frame) {
+                        Some(frame) => {
+                            handles.push(Handle::Locals(frame.level));
+                            json!([{
+                                "name": "Locals",
+                                "variablesReference": handles.len(),
+                                "expensive": false,
+                            }])
+                        }
+                        None => json!([]),
+                    };
+                    self.respond(&request, json!({ "scopes": scopes }));
+                }
+                "variables" => {
+                    let reference = arguments["variablesReference"].as_u64().unwrap_or_default();
+                    let values = match handles.get((reference as usize).wrapping_sub(1)) {
+                        Some(Handle::Locals(level)) => locals(lua, *level)?,
+                        Some(Handle::Table(table)) => fields(table)?,
+                        None => vec![],
+                    };
+                    let variables: Vec<Value> = values
+                        .into_iter()
+                        .map(|(name, value)| variable(&mut handles, name, value))
+                        .collect();
+                    self.respond(&request, json!({ "variables": variables }));
+                }
+                "continue" => {
+                    self.respond(&request, json!({ "allThreadsContinued": true }));
+                    break Step::Run;
+                }
+                "next" => {
+                    self.respond(&request, json!({}));
+                    break Step::Over(stack_depth(lua)?);
+                }
+                "stepIn" => {
+                    self.respond(&request, json!({}));
+                    break Step::In;
+                }
+                "stepOut" => {
+                    self.respond(&request, json!({}));
+                    break Step::Out(stack_depth(lua)?);
+                }
+                "disconnect" => break Step::Run,
+                _ => self.send(response(&request, false, json!({}))),
+            }
+        };
+
+        self.inner.stopped.lock().take();
+        self.inner.state.lock().step = step;
+        self.event(
+            "continued",
+            json!({ "threadId": THREAD_ID, "allThreadsContinued": true }),
+        );
+        Ok(())
+    }
+}
+
+fn response(request: &Value, success: bool, body: Value) -> Value {
+    json!({
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+        "success": success,
+        "body": body,
+    })
+}
+
+/// read one Content-Length framed message, or None when the client hangs up
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::other("missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+enum Handle {
+    Locals(usize),
+    Table(LuaTable),
+}
+
+struct Frame {
+    level: usize,
+    name: String,
+    path: String,
+    line: i64,
+}
+
+fn debug_lib(lua: &Lua) -> LuaResult<LuaTable> {
+    lua.named_registry_value::<LuaTable>(DEBUG_LIB)
+}
+
+/// how many frames are on the stack of the running coroutine
+fn stack_depth(lua: &Lua) -> LuaResult<usize> {
+    let getinfo = debug_lib(lua)?.get::<LuaFunction>("getinfo")?;
+    let mut depth = 0;
+    while getinfo
+        .call::<Option<LuaTable>>((depth + 1, "l"))?
+        .is_some()
+    {
+        depth += 1;
+    }
+    Ok(depth)
+}
+
+/// the lua frames of the running coroutine, innermost first. levels are relative to
+/// a debug library function called from the hook.
+fn stack(lua: &Lua) -> LuaResult<Vec<Frame>> {
+    let getinfo = debug_lib(lua)?.get::<LuaFunction>("getinfo")?;
+    let mut frames = vec![];
+    let mut level = 1;
+    while let Some(info) = getinfo.call::<Option<LuaTable>>((level, "Sln"))? {
+        if info.get::<String>("what")? != "C" {
+            let source = info.get::<String>("source")?;
+            frames.push(Frame {
+                level,
+                name: info
+                    .get::<Option<String>>("name")?
+                    .unwrap_or_else(|| "?".to_string()),
+                path: normalize(source.strip_prefix('@').unwrap_or(&source)),
+                line: info.get("currentline")?,
+            });
+        }
+        level += 1;
+    }
+    Ok(frames)
+}
+
+fn locals(lua: &Lua, level: usize) -> LuaResult<Vec<(String, LuaValue)>> {
+    let getlocal = debug_lib(lua)?.get::<LuaFunction>("getlocal")?;
+    let mut locals = vec![];
+    for index in 1.. {
+        let (name, value) = getlocal.call::<(Option<String>, LuaValue)>((level, index))?;
+        let Some(name) = name else {
+            break;
+        };
+        // skip the vm's temporaries, such as (for index)
+        if !name.starts_with('(') {
+            locals.push((name, value));
+        }
+    }
+    Ok(locals)
+}
+
+fn fields(table: &LuaTable) -> LuaResult<Vec<(String, LuaValue)>> {
+    table
+        .pairs::<LuaValue, LuaValue>()
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((render(&key), value))
+        })
+        .collect()
+}
+
+fn variable(handles: &mut Vec<Handle>, name: String, value: LuaValue) -> Value {
+    let reference = match &value {
+        LuaValue::Table(table) => {
+            handles.push(Handle::Table(table.clone()));
+            handles.len()
+        }
+        _ => 0,
+    };
+    json!({
+        "name": name,
+        "value": render(&value),
+        "type": value.type_name(),
+        "variablesReference": reference,
+    })
+}
+
+fn render(value: &LuaValue) -> String {
+    match value {
+        LuaValue::Nil => "nil".to_string(),
+        LuaValue::Boolean(value) => value.to_string(),
+        LuaValue::Integer(value) => value.to_string(),
+        LuaValue::Number(value) => value.to_string(),
+        LuaValue::String(value) => format!("{:?}", value.to_string_lossy()),
+        value => format!("{}: {:?}", value.type_name(), value.to_pointer()),
+    }
+}
+
+/// the standard libraries plus debug, which the debugger and profiler hooks need
+pub fn new_lua() -> LuaResult<Lua> {
+    // SAFETY: the debug library is moved into the registry by attach before any app
+    // code runs, so lua code can't use it to break out of the sandbox.
+    Ok(unsafe { Lua::unsafe_new_with(std_libs() | LuaStdLib::DEBUG, LuaOptions::default()) })
+}
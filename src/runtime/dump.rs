@@ -33,8 +33,7 @@ pub fn stringify_value(indent: usize, value: LuaValue) -> String {
 
 fn stringify_userdata<'a>(ud: LuaAnyUserData) -> Cow<'a, str> {
     if ud.is::<Routes>() {
-        let routes = ud.borrow::<Routes>();
-        let n = routes.iter().count();
+        let n = ud.borrow::<Routes>().map_or(0, |routes| routes.count());
         return format!("Routes [[ {n} routes ]]").into();
     }
 
@@ -1,8 +1,15 @@
 // this is an async implementation of the `io` module
+//
+// relative paths are resolved against the app's directory (lilguy.root), not the
+// process's working directory, so apps behave the same when started by systemd.
 
 use mlua::prelude::*;
 use parking_lot::Mutex;
-use std::{io::SeekFrom, path::Path, sync::Arc};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tempfile::{NamedTempFile, TempPath};
 use tokio::{
     fs::File,
@@ -11,7 +18,13 @@ use tokio::{
 };
 use walkdir::{DirEntry, WalkDir};
 
-pub fn register(lua: &Lua) -> LuaResult<()> {
+use crate::platform::{has_extension, long_path, simplify};
+
+const APP_ROOT: &str = "lilguy.root";
+
+pub fn register(lua: &Lua, root: &Path) -> LuaResult<()> {
+    lua.set_named_registry_value(APP_ROOT, root.to_string_lossy())?;
+
     let file = lua.create_table()?;
     file.set("open", lua.create_async_function(file_open)?)?;
     file.set("type", lua.create_function(file_type)?)?;
@@ -28,6 +41,26 @@ pub fn register(lua: &Lua) -> LuaResult<()> {
     Ok(())
 }
 
+/// the absolute directory containing the app's lua file
+pub fn app_root(app: &Path) -> std::io::Result<PathBuf> {
+    match app.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::path::absolute(parent),
+        _ => std::env::current_dir(),
+    }
+}
+
+/// resolve a path from lua against the app's directory
+pub fn resolve(lua: &Lua, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        return long_path(path.to_path_buf());
+    }
+    match lua.named_registry_value::<String>(APP_ROOT) {
+        Ok(root) => long_path(Path::new(&root).join(path)),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
 enum Message {
     Write(Vec<u8>),
     ReadExact(usize),
@@ -217,7 +250,7 @@ async fn file_open(
     lua: Lua,
     (path, mode): (LuaValue, Option<String>),
 ) -> LuaResult<LuaAnyUserData> {
-    let path = path.to_string()?;
+    let path = resolve(&lua, path.to_string()?);
 
     let file = match mode.as_deref() {
         Some("r") | None => File::open(path).await?,
@@ -270,27 +303,30 @@ fn file_type(_lua: &Lua, value: LuaValue) -> LuaResult<String> {
 
 // read in an entire file
 async fn file_read(lua: Lua, filename: LuaValue) -> LuaResult<LuaString> {
-    let filename = filename.to_string()?;
+    let filename = resolve(&lua, filename.to_string()?);
     let data = tokio::fs::read(filename).await.into_lua_err()?;
 
     lua.create_string(&data)
 }
 
-async fn file_write(_lua: Lua, (filename, data): (LuaValue, LuaString)) -> LuaResult<()> {
-    let filename = filename.to_string()?;
+async fn file_write(lua: Lua, (filename, data): (LuaValue, LuaString)) -> LuaResult<()> {
+    let filename = resolve(&lua, filename.to_string()?);
 
     tokio::fs::write(filename, data.as_bytes())
         .await
         .into_lua_err()
 }
 
-async fn file_rename(_lua: Lua, (old, new): (LuaValue, LuaValue)) -> LuaResult<()> {
-    let (old, new) = (old.to_string()?, new.to_string()?);
+async fn file_rename(lua: Lua, (old, new): (LuaValue, LuaValue)) -> LuaResult<()> {
+    let (old, new) = (
+        resolve(&lua, old.to_string()?),
+        resolve(&lua, new.to_string()?),
+    );
     tokio::fs::rename(old, new).await.into_lua_err()
 }
 
-async fn file_exists(_lua: Lua, filename: LuaValue) -> LuaResult<bool> {
-    let filename = filename.to_string()?;
+async fn file_exists(lua: Lua, filename: LuaValue) -> LuaResult<bool> {
+    let filename = resolve(&lua, filename.to_string()?);
 
     tokio::fs::metadata(filename)
         .await
@@ -304,16 +340,22 @@ async fn file_exists(_lua: Lua, filename: LuaValue) -> LuaResult<bool> {
         })
 }
 
-async fn create_dir(_lua: Lua, path: String) -> LuaResult<()> {
-    tokio::fs::create_dir(path).await.into_lua_err()
+async fn create_dir(lua: Lua, path: String) -> LuaResult<()> {
+    tokio::fs::create_dir(resolve(&lua, path))
+        .await
+        .into_lua_err()
 }
 
-async fn create_dir_al(_lua: Lua, path: String) -> LuaResult<()> {
-    tokio::fs::create_dir_all(path).await.into_lua_err()
+async fn create_dir_al(lua: Lua, path: String) -> LuaResult<()> {
+    tokio::fs::create_dir_all(resolve(&lua, path))
+        .await
+        .into_lua_err()
 }
 
-async fn file_remove(_lua: Lua, filename: String) -> LuaResult<()> {
-    tokio::fs::remove_file(filename).await.into_lua_err()
+async fn file_remove(lua: Lua, filename: String) -> LuaResult<()> {
+    tokio::fs::remove_file(resolve(&lua, filename))
+        .await
+        .into_lua_err()
 }
 
 pub struct LuaTempFile {
@@ -367,6 +409,8 @@ fn file_temp(lua: &Lua, _args: LuaValue) -> LuaResult<LuaAnyUserData> {
 
 pub struct LuaWalkDir {
     iter: Box<dyn Iterator<Item = Result<DirEntry, walkdir::Error>> + Send>,
+    /// for relative paths, the app root that is removed from each entry's path
+    root: Option<PathBuf>,
 }
 
 fn file_walkdir(lua: &Lua, (path, opts): (String, Option<LuaTable>)) -> LuaResult<LuaAnyUserData> {
@@ -385,8 +429,11 @@ fn file_walkdir(lua: &Lua, (path, opts): (String, Option<LuaTable>)) -> LuaResul
     let same_file_system = opts
         .and_then(|opts| opts.get::<bool>("same_file_system").ok())
         .unwrap_or(false);
+    // only files with this extension, ignoring case
+    let extension = opts.and_then(|opts| opts.get::<String>("extension").ok());
 
-    let walker = WalkDir::new(path)
+    let root = Path::new(&path).is_relative().then(|| resolve(lua, ""));
+    let walker = WalkDir::new(resolve(lua, &path))
         .follow_links(follow_links)
         .follow_root_links(follow_root_links)
         .contents_first(contents_first)
@@ -404,7 +451,14 @@ fn file_walkdir(lua: &Lua, (path, opts): (String, Option<LuaTable>)) -> LuaResul
     };
 
     let ud = lua.create_userdata(LuaWalkDir {
-        iter: Box::new(walker.into_iter()),
+        iter: match extension {
+            Some(extension) => Box::new(walker.into_iter().filter(move |entry| match entry {
+                Ok(entry) => entry.file_type().is_file() && has_extension(entry.path(), &extension),
+                Err(_) => true,
+            })),
+            None => Box::new(walker.into_iter()),
+        },
+        root,
     })?;
     Ok(ud)
 }
@@ -417,7 +471,12 @@ impl LuaUserData for LuaWalkDir {
             let entry = this.iter.next().transpose().into_lua_err()?;
             let mut ret = LuaMultiValue::new();
             if let Some(entry) = entry {
-                let path = create_string_from_path(lua, entry.path())?;
+                let path = match &this.root {
+                    Some(root) => entry.path().strip_prefix(root).unwrap_or(entry.path()),
+                    None => entry.path(),
+                };
+                let path = simplify(path);
+                let path = create_string_from_path(lua, path)?;
                 ret.push_back(LuaValue::String(path));
                 let ft = entry.file_type();
                 if ft.is_dir() {
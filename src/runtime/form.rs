@@ -0,0 +1,98 @@
+// form.parse(req, schema) collects the query string and body of a request into a single
+// table, converts each field to the type its rule asks for and validates it.
+//
+//   local data, errors = form.parse(req, { name = "string:min=1", age = "integer?" })
+//   if next(errors) then
+//       return res:render("signup.html", { form = data, errors = errors })
+//   end
+//
+// rules are the same as validate(). values that fail are kept as they were sent so the
+// form can be shown again with what the user typed.
+use mlua::prelude::*;
+
+use super::validate::Rule;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let form = lua.create_table()?;
+    form.set("parse", lua.create_function(form_parse)?)?;
+    lua.globals().set("form", form)?;
+    Ok(())
+}
+
+fn form_parse(lua: &Lua, (req, schema): (LuaTable, LuaTable)) -> LuaResult<(LuaTable, LuaTable)> {
+    let input = lua.create_table()?;
+    if let Some(query) = req.get::<Option<LuaTable>>("query")? {
+        merge(&input, &query)?;
+    }
+    match req.get::<LuaValue>("body")? {
+        LuaValue::Table(body) => merge(&input, &body)?,
+        LuaValue::String(body) if body.as_bytes().trim_ascii_start().starts_with(b"{") => {
+            let body: serde_json::Value =
+                serde_json::from_slice(&body.as_bytes()).into_lua_err()?;
+            if let LuaValue::Table(body) = lua.to_value(&body)? {
+                merge(&input, &body)?;
+            }
+        }
+        _ => {}
+    }
+
+    let data = lua.create_table()?;
+    let errors = lua.create_table()?;
+    bind(lua, &input, &schema, "", &data, &errors)?;
+
+    Ok((data, errors))
+}
+
+fn merge(into: &LuaTable, from: &LuaTable) -> LuaResult<()> {
+    for pair in from.pairs::<LuaValue, LuaValue>() {
+        let (key, value) = pair?;
+        into.set(key, value)?;
+    }
+    Ok(())
+}
+
+fn bind(
+    lua: &Lua,
+    input: &LuaTable,
+    schema: &LuaTable,
+    prefix: &str,
+    data: &LuaTable,
+    errors: &LuaTable,
+) -> LuaResult<()> {
+    for pair in schema.pairs::<String, LuaValue>() {
+        let (field, rule) = pair?;
+        let name = if prefix.is_empty() {
+            field.clone()
+        } else {
+            format!("{prefix}.{field}")
+        };
+        let value: LuaValue = input.get(field.as_str())?;
+
+        match rule {
+            LuaValue::String(rule) => {
+                let rule = Rule::parse(&rule.to_str()?)?;
+                let value = rule.coerce(lua, value)?;
+                if let Err(message) = rule.check(&value) {
+                    errors.set(name, message)?;
+                }
+                data.set(field, value)?;
+            }
+            LuaValue::Table(nested) => match value {
+                LuaValue::Table(value) => {
+                    let nested_data = lua.create_table()?;
+                    bind(lua, &value, &nested, &name, &nested_data, errors)?;
+                    data.set(field, nested_data)?;
+                }
+                LuaValue::Nil => errors.set(name, "is required")?,
+                _ => errors.set(name, "must be a table")?,
+            },
+            _ => {
+                return Err(LuaError::runtime(format!(
+                    "rule for {name} must be a string or a table"
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
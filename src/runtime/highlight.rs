@@ -0,0 +1,172 @@
+// syntax highlighting on the server, with the tree-sitter grammars lilguy has.
+//
+//   highlight(code, "lua")  -- <pre class="highlight"><code class="language-lua">...
+//   highlight_css()         -- a stylesheet for the classes, to put in a <style>
+//
+// and in templates:
+//
+//   {{ highlight(snippet, "lua") }}
+//   {% filter highlight("lua") %}print("hi"){% endfilter %}
+//   <style>{{ highlight_css() }}</style>
+//
+// each kind of token is wrapped in a span with a class like hl-keyword or
+// hl-function-builtin, so a page can bring its own theme. lua is the only language for
+// now; code in any other is escaped and wrapped the same way, without the spans.
+use mlua::prelude::*;
+use std::{fmt::Write, sync::LazyLock};
+use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent, Highlighter};
+
+/// the highlights the grammars are asked for, and the classes they become
+const NAMES: &[&str] = &[
+    "attribute",
+    "boolean",
+    "comment",
+    "constant",
+    "constant.builtin",
+    "constructor",
+    "escape",
+    "field",
+    "function",
+    "function.builtin",
+    "function.call",
+    "keyword",
+    "label",
+    "method",
+    "number",
+    "operator",
+    "property",
+    "punctuation",
+    "punctuation.bracket",
+    "punctuation.delimiter",
+    "string",
+    "string.special",
+    "type",
+    "variable",
+    "variable.builtin",
+    "variable.parameter",
+];
+
+pub const THEME_CSS: &str = ".highlight { background: #f6f8fa; color: #24292f; padding: 1em; \
+    overflow-x: auto; border-radius: 6px; } \
+    .hl-comment { color: #6e7781; font-style: italic; } \
+    .hl-keyword, .hl-operator { color: #cf222e; } \
+    .hl-string, .hl-string-special, .hl-escape { color: #0a3069; } \
+    .hl-number, .hl-boolean, .hl-constant, .hl-constant-builtin { color: #0550ae; } \
+    .hl-function, .hl-function-call, .hl-method { color: #8250df; } \
+    .hl-function-builtin, .hl-variable-builtin, .hl-type, .hl-constructor { color: #953800; } \
+    .hl-property, .hl-field, .hl-attribute { color: #116329; } \
+    .hl-label { color: #6639ba; } \
+    .hl-punctuation, .hl-punctuation-bracket, .hl-punctuation-delimiter { color: #57606a; }";
+
+struct Language {
+    names: &'static [&'static str],
+    config: HighlightConfiguration,
+}
+
+static LANGUAGES: LazyLock<Vec<Language>> = LazyLock::new(|| {
+    let mut languages = Vec::new();
+    match HighlightConfiguration::new(
+        tree_sitter_lua::LANGUAGE.into(),
+        "lua",
+        tree_sitter_lua::HIGHLIGHTS_QUERY,
+        tree_sitter_lua::INJECTIONS_QUERY,
+        tree_sitter_lua::LOCALS_QUERY,
+    ) {
+        Ok(mut config) => {
+            config.configure(NAMES);
+            languages.push(Language {
+                names: &["lua"],
+                config,
+            });
+        }
+        Err(err) => tracing::error!(%err, "error loading the lua grammar"),
+    }
+    languages
+});
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+    globals.set(
+        "highlight",
+        lua.create_function(|_, (code, lang): (String, Option<String>)| {
+            Ok(html(&code, lang.as_deref().unwrap_or_default()))
+        })?,
+    )?;
+    globals.set("highlight_css", lua.create_function(|_, ()| Ok(THEME_CSS))?)?;
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn config(lang: &str) -> Option<&'static HighlightConfiguration> {
+    LANGUAGES
+        .iter()
+        .find(|language| language.names.contains(&lang))
+        .map(|language| &language.config)
+}
+
+/// the spans for code, or None if the grammar couldn't parse it
+fn spans(config: &HighlightConfiguration, code: &str) -> Option<String> {
+    let mut highlighter = Highlighter::new();
+    let events = highlighter
+        .highlight(config, code.as_bytes(), None, |_| None)
+        .ok()?;
+    let mut html = String::with_capacity(code.len() * 2);
+    for event in events {
+        match event.ok()? {
+            HighlightEvent::HighlightStart(highlight) => {
+                let name = NAMES.get(highlight.0)?;
+                let _ = write!(html, "<span class=\"hl-{}\">", name.replace('.', "-"));
+            }
+            HighlightEvent::Source { start, end } => html.push_str(&escape(code.get(start..end)?)),
+            HighlightEvent::HighlightEnd => html.push_str("</span>"),
+        }
+    }
+    Some(html)
+}
+
+/// code in a <pre><code> block, highlighted if lang is one there is a grammar for
+pub fn html(code: &str, lang: &str) -> String {
+    let lang = lang.trim().to_ascii_lowercase();
+    let body = config(&lang)
+        .and_then(|config| spans(config, code))
+        .unwrap_or_else(|| escape(code));
+    if lang.is_empty() {
+        return format!("<pre class=\"highlight\"><code>{body}</code></pre>");
+    }
+    format!(
+        "<pre class=\"highlight\"><code class=\"language-{}\">{body}</code></pre>",
+        escape(&lang)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_lua() {
+        let html = html("local x = \"<hi>\" -- note", "Lua");
+        assert!(html.starts_with("<pre class=\"highlight\"><code class=\"language-lua\">"));
+        assert!(html.contains("<span class=\"hl-keyword\">local</span>"));
+        assert!(html.contains("&lt;hi&gt;"));
+        assert!(html.contains("<span class=\"hl-comment\">-- note</span>"));
+    }
+
+    #[test]
+    fn escapes_other_languages() {
+        assert_eq!(
+            html("a < b", "python"),
+            "<pre class=\"highlight\"><code class=\"language-python\">a &lt; b</code></pre>"
+        );
+        assert_eq!(
+            html("x", ""),
+            "<pre class=\"highlight\"><code>x</code></pre>"
+        );
+    }
+}
@@ -1,48 +1,75 @@
+pub mod body;
+pub mod body_stream;
+pub mod client;
+pub mod decode;
+pub mod deferred;
+pub mod fetch;
+pub mod multipart;
+pub mod range;
+pub mod retry;
+pub mod send_file;
+pub mod session;
+pub mod sse;
+pub mod trailers;
 pub mod websocket;
 
 use axum::{
     body::{to_bytes, Body},
-    http::{HeaderMap, HeaderName, HeaderValue},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
 };
 use bytes::Bytes;
 use cookie::{Cookie, CookieJar, Key};
 use http::{header::ToStrError, Request};
 use mlua::prelude::*;
 use parking_lot::Mutex;
-use reqwest::{Client, Method, RequestBuilder};
 use rusqlite::OptionalExtension;
 use serde::{ser::SerializeMap, Serialize};
-use std::{ops::Deref, sync::Arc};
+use std::sync::Arc;
 
 use crate::database::Database;
 
+use body::LuaBody;
+use body_stream::{LuaBodyStream, RequestBody};
+pub use fetch::FetchConfig;
+pub use multipart::UploadsConfig;
 pub use websocket::LuaWebSocket;
 
-const FETCH_CLIENT: &str = "fetch_client";
 const REQUEST_MT: &str = "request_mt";
+const RAW_BODIES: &str = "raw_bodies";
 const RESPONSE_MT: &str = "response_mt";
+const CONTEXT: &str = "context";
 const COOKIE_KEY: &str = "cookie_key";
 
-pub fn register(lua: &Lua) -> LuaResult<()> {
+pub fn register(
+    lua: &Lua,
+    fetch_config: &FetchConfig,
+    uploads_config: &UploadsConfig,
+    database: &Database,
+) -> LuaResult<()> {
     let globals = lua.globals();
 
-    let client = Client::builder()
-        .user_agent(format!("lilguy/{}", env!("CARGO_PKG_VERSION")))
-        .build()
-        .into_lua_err()?;
-    let fetch_client = FetchClient::from(client);
-    lua.set_named_registry_value(FETCH_CLIENT, fetch_client)?;
+    // raw bodies of parsed requests, keyed weakly by the request table
+    let raw_bodies = lua.create_table()?;
+    let raw_bodies_mt = lua.create_table()?;
+    raw_bodies_mt.set("__mode", "k")?;
+    raw_bodies.set_metatable(Some(raw_bodies_mt))?;
+    lua.set_named_registry_value(RAW_BODIES, raw_bodies)?;
 
     let request_mt = lua.create_table()?;
-    request_mt.set("__index", globals.get::<Option<LuaTable>>("Request")?)?;
+    request_mt.set("__index", lua.create_function(request_index)?)?;
 
     let response_mt = lua.create_table()?;
     response_mt.set("__index", globals.get::<Option<LuaTable>>("Response")?)?;
 
     lua.set_named_registry_value(REQUEST_MT, request_mt)?;
     lua.set_named_registry_value(RESPONSE_MT, response_mt)?;
+    lua.set_named_registry_value(CONTEXT, globals.get::<Option<LuaTable>>("Context")?)?;
 
-    globals.set("fetch", lua.create_async_function(fetch)?)?;
+    fetch::register(lua, fetch_config, database)?;
+    multipart::register(lua, uploads_config)?;
+    send_file::register(lua)?;
+    session::register(lua)?;
+    sse::register(lua)?;
 
     Ok(())
 }
@@ -102,34 +129,93 @@ impl LuaHeaders {
     pub fn into_inner(self) -> HeaderMap {
         self.0
     }
+
+    /// replace any existing values for name
+    pub fn insert(&mut self, name: HeaderName, value: HeaderValue) {
+        self.0.insert(name, value);
+    }
+
+    pub fn contains_key(&self, name: &HeaderName) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// a function that returns each name and value in turn, then nil
+    fn iter(&self, lua: &Lua) -> LuaResult<LuaFunction> {
+        let mut entries = self
+            .0
+            .iter()
+            .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        lua.create_function_mut(move |lua, _: LuaMultiValue| match entries.next() {
+            Some((name, value)) => Ok((
+                LuaValue::String(lua.create_string(name)?),
+                LuaValue::String(lua.create_string(value)?),
+            )),
+            None => Ok((LuaNil, LuaNil)),
+        })
+    }
+}
+
+impl From<HeaderMap> for LuaHeaders {
+    fn from(headers: HeaderMap) -> Self {
+        Self(headers)
+    }
 }
 
+fn header_name(name: &[u8]) -> LuaResult<HeaderName> {
+    HeaderName::from_bytes(name).map_err(|_| LuaError::external("invalid header name"))
+}
+
+/// headers["name"] is the first value, or "" without one. values are byte strings, so
+/// headers that are not utf-8 come through as they were sent.
+///
+/// headers["name"] = value adds a value, and headers["name"] = nil removes them all.
+/// headers:get_all(name) returns every value, and headers:remove(name) removes them.
+/// pairs(headers) gives each name, in lowercase, with each of its values.
 impl LuaUserData for LuaHeaders {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        methods.add_meta_method(LuaMetaMethod::Index, |_lua, this, key: String| {
-            let key = HeaderName::from_bytes(key.as_bytes())
-                .map_err(|_| LuaError::external("invalid header name"))?;
-            let value = this
-                .0
-                .get(key)
-                .map(|v| v.to_str().unwrap_or(""))
-                .unwrap_or("");
-            Ok(value.to_string())
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| {
+            let key = header_name(&key.as_bytes())?;
+            let value = this.0.get(key).map(|v| v.as_bytes()).unwrap_or(b"");
+            lua.create_string(value)
         });
         methods.add_meta_method_mut(
             LuaMetaMethod::NewIndex,
-            |_lua, this, (key, value): (String, String)| {
-                let key = HeaderName::from_bytes(key.as_bytes())
-                    .map_err(|_| LuaError::external("invalid header name"))?;
+            |_lua, this, (key, value): (LuaString, Option<LuaString>)| {
+                let key = header_name(&key.as_bytes())?;
+                let Some(value) = value else {
+                    this.0.remove(key);
+                    return Ok(());
+                };
                 this.0.append(
                     key,
-                    value
-                        .parse()
+                    HeaderValue::from_bytes(&value.as_bytes())
                         .map_err(|_| LuaError::external("invalid header value"))?,
                 );
                 Ok(())
             },
         );
+        // for name, value in pairs(req.headers), or for name, value in req.headers in luau,
+        // whose pairs() ignores __pairs
+        methods.add_meta_method(LuaMetaMethod::Pairs, |lua, this, ()| this.iter(lua));
+        #[cfg(feature = "luau")]
+        methods.add_meta_method(LuaMetaMethod::Iter, |lua, this, ()| this.iter(lua));
+        methods.add_method("get_all", |lua, this, name: LuaString| {
+            let name = header_name(&name.as_bytes())?;
+            let values = this
+                .0
+                .get_all(name)
+                .iter()
+                .map(|value| lua.create_string(value.as_bytes()))
+                .collect::<LuaResult<Vec<_>>>()?;
+            lua.create_sequence_from(values)
+        });
+        methods.add_method_mut("remove", |_, this, name: LuaString| {
+            let name = header_name(&name.as_bytes())?;
+            this.0.remove(name);
+            Ok(())
+        });
     }
 }
 
@@ -281,48 +367,6 @@ impl LuaUserData for LuaCookieJar {
     }
 }
 
-/// fetch(url [, options])
-///
-/// this is intended to be largely compatible with fetch() in the browser supporting:
-/// - method: GET, POST, PUT, DELETE, etc
-/// - headers: { ["Content-Type"] = "application/json" }
-/// - body: string or someething with __tostring
-#[allow(unused)]
-async fn fetch(lua: Lua, (url, options): (String, Option<LuaTable>)) -> LuaResult<LuaTable> {
-    let client = lua.named_registry_value::<LuaUserDataRef<FetchClient>>("fetch_client")?;
-    let mut request: RequestBuilder = match options {
-        Some(options) => {
-            let method = options
-                .get::<Option<String>>("method")?
-                .unwrap_or("get".to_string());
-            let method = Method::from_bytes(method.as_bytes()).into_lua_err()?;
-            let mut request = client.request(method, &url);
-            if let Some(headers) = options.get::<Option<LuaTable>>("headers")? {
-                let headers = headers
-                    .pairs::<String, String>()
-                    .map(|(pair)| {
-                        let (key, value) = pair?;
-                        Ok((
-                            HeaderName::from_bytes(key.as_bytes()).into_lua_err()?,
-                            HeaderValue::from_str(&value).into_lua_err()?,
-                        ))
-                    })
-                    .collect::<LuaResult<HeaderMap>>()?;
-                request = request.headers(headers);
-            }
-            if let Some(body) = options.get::<Option<String>>("body")? {
-                request = request.body(body);
-            }
-            request
-        }
-        None => client.get(&url),
-    };
-    let response = request.send().await.into_lua_err()?;
-    let res = create_fetch_response(&lua, response).await?;
-
-    Ok(res)
-}
-
 pub async fn create_request(lua: &Lua, request: Request<Body>) -> Result<LuaTable, LuaError> {
     let (parts, body) = request.into_parts();
     let req = lua.create_table()?;
@@ -334,13 +378,20 @@ pub async fn create_request(lua: &Lua, request: Request<Body>) -> Result<LuaTabl
         .unwrap_or("")
         .to_owned();
 
+    let id = request_id(&parts.headers);
     let key = lua
         .named_registry_value::<LuaUserDataRef<LuaCookieKey>>(COOKIE_KEY)?
         .key();
     let cookie_jar = lua.create_userdata(LuaCookieJar::new(key, &parts.headers).into_lua_err()?)?;
+    let content_length = parts
+        .headers
+        .get("content-length")
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok());
     let headers = lua.create_ser_userdata(LuaHeaders(parts.headers))?;
-    let body = to_bytes(body, 1024 * 1024 * 16).await.into_lua_err()?;
+    let body = body_stream::read(body, content_length).await?;
 
+    req.set("id", id)?;
     req.set("method", method)?;
     req.set("headers", headers)?;
     req.set("path", parts.uri.path())?;
@@ -348,54 +399,202 @@ pub async fn create_request(lua: &Lua, request: Request<Body>) -> Result<LuaTabl
         serde_qs::from_str(parts.uri.query().unwrap_or("")).into_lua_err()?;
     req.set("query", lua.to_value(&query)?)?;
     req.set("cookie_jar", &cookie_jar)?;
+    req.set("ctx", new_context(lua)?)?;
+    req.set_metatable(lua.named_registry_value::<LuaTable>(REQUEST_MT)?.into())?;
+    let database = Database::clone(&*lua.globals().get::<LuaUserDataRef<Database>>("database")?);
+    session::load(lua, &database, &req).await?;
 
-    match content_type.as_str() {
-        "application/x-www-form-urlencoded" => {
-            let body: serde_json::Value = serde_urlencoded::from_bytes(&body).into_lua_err()?;
-            req.set("body", lua.to_value(&body)?)
+    if let Some(boundary) = multipart::boundary(&content_type) {
+        let max_size = multipart::max_size(lua)?;
+        if content_length.is_some_and(|length| length > max_size) {
+            return Err(LuaError::external(multipart::TooLarge(max_size)));
         }
-        _ => req.set("body", lua.create_string(&body)?),
-    }?;
+        let parts = multipart::read(body, &boundary, max_size).await?;
+        let (form, files) = multipart::form(lua, parts)?;
+        req.set("body_stream", LuaBodyStream::buffered(Bytes::new()))?;
+        req.set("body", form)?;
+        req.set("files", files)?;
+        return Ok(req);
+    }
 
-    req.set_metatable(lua.named_registry_value::<LuaTable>(REQUEST_MT)?.into())?;
+    let body = match body {
+        RequestBody::Buffered(body) => {
+            req.set("body_stream", LuaBodyStream::buffered(body.clone()))?;
+            body
+        }
+        RequestBody::Streamed(stream) => {
+            req.set("body_stream", stream)?;
+            return Ok(req);
+        }
+    };
+
+    if content_type == "application/x-www-form-urlencoded" {
+        let parsed: serde_json::Value = serde_urlencoded::from_bytes(&body).into_lua_err()?;
+        req.set("body", lua.to_value(&parsed)?)?;
+    }
+    // everything else about the body is made when it is used, see request_index
+    lua.named_registry_value::<LuaTable>(RAW_BODIES)?
+        .set(&req, LuaBody(body))?;
 
     Ok(req)
 }
 
+/// a request for a routes:raw() handler. nothing is parsed: there is no cookie jar,
+/// req.query_string is the query as it was sent, and the body is only req.body_stream.
+pub fn create_raw_request(lua: &Lua, request: Request<Body>) -> LuaResult<LuaTable> {
+    let (parts, body) = request.into_parts();
+    let req = lua.create_table()?;
+    req.set("id", request_id(&parts.headers))?;
+    req.set("method", parts.method.as_str())?;
+    req.set("path", parts.uri.path())?;
+    req.set("query_string", parts.uri.query().unwrap_or(""))?;
+    req.set(
+        "headers",
+        lua.create_ser_userdata(LuaHeaders(parts.headers))?,
+    )?;
+    req.set("ctx", new_context(lua)?)?;
+    req.set("body_stream", LuaBodyStream::new(body))?;
+    req.set_metatable(lua.named_registry_value::<LuaTable>(REQUEST_MT)?.into())?;
+    Ok(req)
+}
+
+tokio::task_local! {
+    /// the id of the request the current task is handling
+    static REQUEST_ID: String;
+}
+
+/// run a request handler with its id available to current_request_id
+pub async fn with_request_id<F: std::future::Future>(id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(id, future).await
+}
+
+/// the id of the request being handled, so things like audit logs can refer to it
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// req.id: the client's X-Request-Id if it sent a sensible one, or a random id
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+/// __index for requests: methods come from the Request table. req.body_data is the
+/// body as bytes, and req.raw_body (and req.body, if it wasn't parsed) are turned into a
+/// lua string the first time they are read.
+fn request_index(lua: &Lua, (req, key): (LuaTable, LuaValue)) -> LuaResult<LuaValue> {
+    if let LuaValue::String(ref name) = key {
+        let name = name.as_bytes();
+        if matches!(&*name, b"raw_body" | b"body" | b"body_data") {
+            let raw_bodies = lua.named_registry_value::<LuaTable>(RAW_BODIES)?;
+            let Some(raw_body) = raw_bodies.get::<Option<LuaAnyUserData>>(&req)? else {
+                return Ok(LuaValue::Nil);
+            };
+            if &*name == b"body_data" {
+                return Ok(LuaValue::UserData(raw_body));
+            }
+            let body = lua.create_string(&raw_body.borrow::<LuaBody>()?.0)?;
+            req.raw_set("raw_body", &body)?;
+            if req.raw_get::<LuaValue>("body")?.is_nil() {
+                req.raw_set("body", &body)?;
+            }
+            return req.raw_get(key.clone());
+        }
+        // the request's own handle, so its calls take turns with other requests'
+        if &*name == b"database" {
+            let database = lua
+                .globals()
+                .get::<LuaUserDataRef<Database>>("database")?
+                .for_request();
+            req.raw_set("database", database)?;
+            return req.raw_get(key.clone());
+        }
+    }
+
+    match lua.globals().get::<Option<LuaTable>>("Request")? {
+        Some(request) => request.get(key),
+        None => Ok(LuaValue::Nil),
+    }
+}
+
+/// req.ctx is a plain table for middleware and handlers to share per-request state.
+/// functions passed to ctx:defer() are kept in its metatable until run_deferred is called.
+pub fn new_context(lua: &Lua) -> LuaResult<LuaTable> {
+    let ctx = lua.create_table()?;
+    let mt = lua.create_table()?;
+    mt.set("__index", lua.named_registry_value::<LuaValue>(CONTEXT)?)?;
+    mt.set("__deferred", lua.create_table()?)?;
+    ctx.set_metatable(Some(mt))?;
+    Ok(ctx)
+}
+
+/// call everything registered with ctx:defer(), in order. see deferred.rs for when.
+/// errors are logged and do not stop the remaining callbacks.
+pub async fn run_deferred(ctx: LuaTable) {
+    let Some(deferred) = ctx
+        .metatable()
+        .and_then(|mt| mt.get::<Option<LuaTable>>("__deferred").ok().flatten())
+    else {
+        return;
+    };
+
+    let callbacks = match deferred
+        .sequence_values::<LuaFunction>()
+        .collect::<LuaResult<Vec<_>>>()
+    {
+        Ok(callbacks) => callbacks,
+        Err(err) => {
+            tracing::error!(
+                ?err,
+                "ctx:defer() was given something other than a function"
+            );
+            return;
+        }
+    };
+
+    for callback in callbacks {
+        if let Err(err) = callback.call_async::<()>(&ctx).await {
+            tracing::error!(?err, "error in deferred callback");
+        }
+    }
+}
+
 pub fn new_response(lua: &Lua) -> Result<LuaTable, LuaError> {
     let res = lua.create_table()?;
     res.set("status", 200)?;
     res.set("headers", lua.create_ser_userdata(LuaHeaders::new())?)?;
+    res.set("trailers", lua.create_ser_userdata(LuaHeaders::new())?)?;
     res.set("body", "")?;
     res.set_metatable(lua.named_registry_value::<LuaTable>(RESPONSE_MT)?.into())?;
     Ok(res)
 }
 
-async fn create_fetch_response(
+pub async fn create_response(
     lua: &Lua,
-    response: reqwest::Response,
+    response: axum::http::Response<Body>,
 ) -> Result<LuaTable, LuaError> {
-    let response = axum::http::Response::from(response);
     let (parts, body) = response.into_parts();
-    let body = Body::from(Bytes::copy_from_slice(body.as_bytes().unwrap_or_default()));
-    let response = axum::http::Response::from_parts(parts, body);
+    let body = to_bytes(body, 1024 * 1024 * 16).await.into_lua_err()?;
 
-    create_response(lua, response).await
+    create_response_parts(lua, parts.status, parts.headers, &body)
 }
 
-pub async fn create_response(
+pub fn create_response_parts(
     lua: &Lua,
-    response: axum::http::Response<Body>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: &[u8],
 ) -> Result<LuaTable, LuaError> {
-    let (parts, body) = response.into_parts();
     let res = lua.create_table()?;
-    let status = parts.status.as_u16();
-    let headers = lua.create_ser_userdata(LuaHeaders(parts.headers))?;
-    let body = to_bytes(body, 1024 * 1024 * 16).await.into_lua_err()?;
+    let headers = lua.create_ser_userdata(LuaHeaders(headers))?;
 
-    res.set("status", status)?;
+    res.set("status", status.as_u16())?;
     res.set("headers", headers)?;
-    res.set("body", lua.create_string(&body)?)?;
+    res.set("body", lua.create_string(body)?)?;
     res.set_metatable(lua.named_registry_value::<LuaTable>(RESPONSE_MT)?.into())?;
 
     Ok(res)
@@ -407,20 +606,33 @@ pub fn not_found(_: &Lua, (_, res): (LuaTable, LuaTable)) -> LuaResult<()> {
     Ok(())
 }
 
-pub struct FetchClient(Client);
-
-impl From<Client> for FetchClient {
-    fn from(client: Client) -> Self {
-        Self(client)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let lua = Lua::new();
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.append("x-raw", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        let headers = lua.create_ser_userdata(LuaHeaders(headers)).unwrap();
+        lua.globals().set("headers", headers).unwrap();
+
+        let code = r#"
+            local cookies = headers:get_all("Set-Cookie")
+            local seen = 0
+            for name, value in pairs(headers) do seen = seen + 1 end
+            headers["Set-Cookie"] = nil
+            headers:remove("x-missing")
+            return cookies[1], cookies[2], #headers["X-Raw"], seen, headers["set-cookie"]
+        "#;
+        let (first, second, raw, seen, removed): (String, String, usize, usize, String) =
+            lua.load(code).eval().unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("a=1", "b=2"));
+        assert_eq!(raw, 4);
+        assert_eq!(seen, 3);
+        assert_eq!(removed, "");
     }
 }
-
-impl Deref for FetchClient {
-    type Target = Client;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl LuaUserData for FetchClient {}
@@ -0,0 +1,73 @@
+// a request or response body that stays as bytes until lua needs a string, so bodies
+// that are only passed along are never copied into lua.
+//
+//   res.body = req.body_data       -- echo a body without reading it
+//   fetch(url, { method = "post", body = req.body_data })
+//
+// it acts like a string where it can: #body, tostring(body), body .. "", and the string
+// methods, like body:sub(1, 10) or body:find("needle"), which copy it into a string
+// when they are called.
+use bytes::Bytes;
+use mlua::prelude::*;
+
+#[derive(Debug, Clone)]
+pub struct LuaBody(pub Bytes);
+
+impl LuaBody {
+    pub fn bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+}
+
+/// the bytes of a string or body
+pub fn body_bytes(value: &LuaValue) -> LuaResult<Option<Bytes>> {
+    match value {
+        LuaValue::Nil => Ok(None),
+        LuaValue::String(string) => Ok(Some(Bytes::copy_from_slice(&string.as_bytes()))),
+        LuaValue::UserData(body) if body.is::<LuaBody>() => {
+            Ok(Some(body.borrow::<LuaBody>()?.bytes()))
+        }
+        value => Err(LuaError::runtime(format!(
+            "body must be a string, not {}",
+            value.type_name()
+        ))),
+    }
+}
+
+impl LuaUserData for LuaBody {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::ToString, |lua, this, ()| {
+            lua.create_string(&this.0)
+        });
+        methods.add_meta_method(LuaMetaMethod::Len, |_, this, ()| Ok(this.0.len()));
+        methods.add_meta_function(
+            LuaMetaMethod::Concat,
+            |lua, (left, right): (LuaValue, LuaValue)| {
+                let mut joined = Vec::new();
+                for side in [left, right] {
+                    match side {
+                        LuaValue::UserData(body) if body.is::<LuaBody>() => {
+                            joined.extend_from_slice(&body.borrow::<LuaBody>()?.0)
+                        }
+                        side => {
+                            joined.extend_from_slice(&LuaString::from_lua(side, lua)?.as_bytes())
+                        }
+                    }
+                }
+                lua.create_string(&joined)
+            },
+        );
+        // body:sub(...) is string.sub(tostring(body), ...)
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: String| {
+            let string = lua.globals().get::<LuaTable>("string")?;
+            let Some(function) = string.get::<Option<LuaFunction>>(key.as_str())? else {
+                return Ok(LuaNil);
+            };
+            let body = lua.create_string(&this.0)?;
+            let bound = lua.create_function(move |_, (_, args): (LuaValue, LuaMultiValue)| {
+                function.call::<LuaMultiValue>((body.clone(), args))
+            })?;
+            Ok(LuaValue::Function(bound))
+        });
+    }
+}
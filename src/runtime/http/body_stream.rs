@@ -0,0 +1,163 @@
+// req.body_stream reads a request body a piece at a time, so large uploads don't have
+// to fit in memory.
+//
+//   local stream = req.body_stream
+//   while true do
+//       local chunk = stream:read(65536)  -- up to n bytes, or the next chunk without n
+//       if not chunk then break end
+//       upload:write(chunk)
+//   end
+//
+//   req.body_stream:save("uploads/" .. id)  -- returns the number of bytes written
+//   req.body_stream:read_all()  -- the rest of the body
+//
+// bodies up to 16MB are read before the handler runs, as req.body, and body_stream
+// reads them from memory. larger bodies, including ones whose Content-Length says they
+// will be, are left for body_stream to read, and req.body is nil. a stream can only be
+// read once.
+use axum::body::{Body, BodyDataStream};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use mlua::prelude::*;
+use tokio::io::AsyncWriteExt;
+
+use crate::runtime::file::resolve;
+
+/// the largest body that is read before the handler runs
+pub const MAX_BUFFERED: usize = 16 * 1024 * 1024;
+
+pub struct LuaBodyStream {
+    /// bytes read from the body that the stream has not returned yet
+    pending: BytesMut,
+    rest: Option<BodyDataStream>,
+}
+
+/// a request body, read or left to be streamed
+pub enum RequestBody {
+    Buffered(Bytes),
+    Streamed(LuaBodyStream),
+}
+
+/// read body unless it is larger than MAX_BUFFERED
+pub async fn read(body: Body, content_length: Option<u64>) -> LuaResult<RequestBody> {
+    if content_length.is_some_and(|length| length > MAX_BUFFERED as u64) {
+        return Ok(RequestBody::Streamed(LuaBodyStream::new(body)));
+    }
+    let mut stream = body.into_data_stream();
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk.into_lua_err()?);
+        if buffer.len() > MAX_BUFFERED {
+            return Ok(RequestBody::Streamed(LuaBodyStream {
+                pending: buffer,
+                rest: Some(stream),
+            }));
+        }
+    }
+    Ok(RequestBody::Buffered(buffer.freeze()))
+}
+
+impl LuaBodyStream {
+    /// a stream over a body that has not been read
+    pub fn new(body: Body) -> Self {
+        Self {
+            pending: BytesMut::new(),
+            rest: Some(body.into_data_stream()),
+        }
+    }
+
+    /// a stream over a body that was already read
+    pub fn buffered(body: Bytes) -> Self {
+        Self {
+            pending: BytesMut::from(body),
+            rest: None,
+        }
+    }
+
+    async fn next_chunk(&mut self) -> LuaResult<Option<Bytes>> {
+        match self.rest {
+            Some(ref mut rest) => rest.next().await.transpose().into_lua_err(),
+            None => Ok(None),
+        }
+    }
+
+    /// up to n bytes, or the next chunk without n. None at the end of the body, and an
+    /// empty string for n = 0 like lua's file:read(0).
+    pub async fn read(&mut self, n: Option<usize>) -> LuaResult<Option<Bytes>> {
+        let Some(n) = n else {
+            if self.pending.is_empty() {
+                return self.next_chunk().await;
+            }
+            return Ok(Some(self.pending.split().freeze()));
+        };
+        if n == 0 {
+            return Ok(Some(Bytes::new()));
+        }
+        while self.pending.len() < n {
+            let Some(chunk) = self.next_chunk().await? else {
+                break;
+            };
+            self.pending.extend_from_slice(&chunk);
+        }
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let size = n.min(self.pending.len());
+        Ok(Some(self.pending.split_to(size).freeze()))
+    }
+}
+
+impl LuaUserData for LuaBodyStream {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method_mut("read", |lua, mut this, n: Option<usize>| async move {
+            match this.read(n).await? {
+                Some(chunk) => Ok(LuaValue::String(lua.create_string(&chunk)?)),
+                None => Ok(LuaNil),
+            }
+        });
+
+        methods.add_async_method_mut("read_all", |lua, mut this, ()| async move {
+            let mut body = BytesMut::new();
+            while let Some(chunk) = this.read(None).await? {
+                body.extend_from_slice(&chunk);
+            }
+            lua.create_string(&body)
+        });
+
+        methods.add_async_method_mut("save", |lua, mut this, path: String| async move {
+            let path = resolve(&lua, path);
+            let mut file = tokio::fs::File::create(&path).await.into_lua_err()?;
+            let mut written = 0;
+            while let Some(chunk) = this.read(None).await? {
+                file.write_all(&chunk).await.into_lua_err()?;
+                written += chunk.len();
+            }
+            file.flush().await.into_lua_err()?;
+            Ok(written)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_pieces() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let body = Body::from_stream(futures_util::stream::iter(
+                ["hel", "lo", " world"].map(Ok::<_, std::io::Error>),
+            ));
+            let mut stream = LuaBodyStream::new(body);
+            assert_eq!(stream.read(Some(0)).await.unwrap().unwrap(), "");
+            assert_eq!(stream.read(Some(4)).await.unwrap().unwrap(), "hell");
+            assert_eq!(stream.read(None).await.unwrap().unwrap(), "o");
+            assert_eq!(stream.read(Some(100)).await.unwrap().unwrap(), " world");
+            assert!(stream.read(Some(1)).await.unwrap().is_none());
+            assert!(stream.read(None).await.unwrap().is_none());
+        });
+    }
+}
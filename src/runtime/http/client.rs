@@ -0,0 +1,32 @@
+// the address a request came from, as the listener saw it. apps behind a proxy see the
+// proxy's address.
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, Extensions},
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// requests that didn't come through the listener, like the shell's, are from 0.0.0.0
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        let ip = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Self(ip)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_extensions(&parts.extensions))
+    }
+}
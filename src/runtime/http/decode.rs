@@ -0,0 +1,60 @@
+// request bodies sent with Content-Encoding: gzip or deflate, as webhooks and small
+// devices often do, are decoded as they are read, so form parsing and handlers see the
+// bytes that were compressed. routes:raw() handlers get the body as it was sent.
+//
+// a body that decodes to more than MAX_DECODED fails when it is read, so a small upload
+// can't expand without limit.
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder};
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH},
+        Request, StatusCode,
+    },
+};
+use futures_util::{StreamExt, TryStreamExt};
+use std::io;
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// the most a compressed request body may decode to
+pub const MAX_DECODED: u64 = 64 * 1024 * 1024;
+
+/// request with its body decoded, or 415 for an encoding that isn't understood
+pub fn decode(request: Request<Body>) -> Result<Request<Body>, StatusCode> {
+    let Some(encoding) = request.headers().get(CONTENT_ENCODING) else {
+        return Ok(request);
+    };
+    let encoding = encoding
+        .to_str()
+        .map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?
+        .trim()
+        .to_ascii_lowercase();
+    if encoding == "identity" {
+        return Ok(request);
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let compressed = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let decoded: Box<dyn AsyncRead + Send + Unpin> = match encoding.as_str() {
+        "gzip" | "x-gzip" => Box::new(GzipDecoder::new(compressed)),
+        "deflate" => Box::new(ZlibDecoder::new(compressed)),
+        _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+
+    let mut total = 0;
+    let stream = ReaderStream::new(decoded).map(move |chunk| {
+        let chunk = chunk?;
+        total += chunk.len() as u64;
+        if total > MAX_DECODED {
+            return Err(io::Error::other(format!(
+                "request body decodes to more than {MAX_DECODED} bytes"
+            )));
+        }
+        Ok(chunk)
+    });
+
+    Ok(Request::from_parts(parts, Body::from_stream(stream)))
+}
@@ -0,0 +1,89 @@
+// ctx:defer(fn) runs fn after the response has been sent, so work the client doesn't wait
+// for, like sending an email, doesn't hold it up. the response body says when it is done:
+// when its last frame has been read, or when it is dropped because the client went away.
+// a handler that fails has no body to send, and its deferred functions run right away.
+use axum::body::{Body, HttpBody};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use mlua::prelude::*;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::oneshot;
+use tokio_util::task::TaskTracker;
+
+use super::run_deferred;
+
+/// run ctx's deferred functions once the returned sender is used or dropped
+pub fn after_response(tracker: &TaskTracker, ctx: LuaTable) -> oneshot::Sender<()> {
+    let (sent, was_sent) = oneshot::channel();
+    tracker.spawn(async move {
+        let _ = was_sent.await;
+        run_deferred(ctx).await;
+    });
+    sent
+}
+
+/// a body that lets go of sent when it has been read to the end
+pub struct UntilSent {
+    body: Body,
+    sent: Option<oneshot::Sender<()>>,
+}
+
+impl UntilSent {
+    pub fn wrap(body: Body, sent: oneshot::Sender<()>) -> Body {
+        Body::new(Self {
+            body,
+            sent: Some(sent),
+        })
+    }
+}
+
+impl HttpBody for UntilSent {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.body).poll_frame(cx);
+        if matches!(frame, Poll::Ready(None | Some(Err(_)))) {
+            this.sent.take();
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    #[test]
+    fn waits_for_the_body() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (sent, mut was_sent) = oneshot::channel::<()>();
+            let body = UntilSent::wrap(Body::from("hello"), sent);
+            assert_eq!(
+                was_sent.try_recv(),
+                Err(oneshot::error::TryRecvError::Empty)
+            );
+            assert_eq!(to_bytes(body, usize::MAX).await.unwrap(), "hello");
+            assert!(was_sent.await.is_err());
+        });
+    }
+}
@@ -0,0 +1,742 @@
+use axum::http::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    HeaderMap, HeaderName, HeaderValue, StatusCode,
+};
+use indexmap::IndexMap;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use reqwest::{
+    cookie::{CookieStore, Jar},
+    Client, Method, RequestBuilder, Url,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    body::body_bytes,
+    create_response_parts,
+    retry::{CircuitBreaker, CircuitBreakerConfig, RetryPolicy},
+};
+use crate::{
+    database::{self, Database},
+    runtime::seconds,
+};
+
+mod mock;
+
+const FETCH_CLIENT: &str = "fetch_client";
+
+/// seconds a request may take when the config doesn't say, so a stuck api can't hold a
+/// handler forever. 0 in the config means no limit.
+const DEFAULT_TIMEOUT: f64 = 30.0;
+
+/// the [fetch] section of config.toml
+///
+/// the top level settings apply to every request, and [fetch.hosts."api.example.com"]
+/// gets its own client for requests to that host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FetchConfig {
+    #[serde(flatten)]
+    pub client: FetchClientConfig,
+
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub hosts: IndexMap<String, FetchClientConfig>,
+}
+
+/// settings for a single reqwest client. durations are in seconds, and timeout is 30
+/// unless it is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FetchClientConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
+    /// disables TLS certificate verification, only useful for local development
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub accept_invalid_certs: bool,
+
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub headers: IndexMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// keep cookies from responses and send them back on later requests
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cookies: bool,
+}
+
+impl FetchClientConfig {
+    pub fn build(&self) -> LuaResult<Client> {
+        self.build_with(None)
+    }
+
+    /// a client that keeps cookies in jar, on every redirect as well as the last response
+    pub fn build_with(&self, jar: Option<Arc<Jar>>) -> LuaResult<Client> {
+        let user_agent = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| format!("lilguy/{}", env!("CARGO_PKG_VERSION")));
+        let mut builder = Client::builder()
+            .user_agent(user_agent)
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        if timeout > 0.0 {
+            builder = builder.timeout(seconds("timeout", timeout)?);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(seconds("connect_timeout", connect_timeout)?);
+        }
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(seconds("pool_idle_timeout", pool_idle_timeout)?);
+        }
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        if let Some(ref proxy) = self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).into_lua_err()?);
+        }
+        if !self.headers.is_empty() {
+            builder = builder.default_headers(to_header_map(
+                self.headers.iter().map(|(k, v)| Ok((k.clone(), v.clone()))),
+            )?);
+        }
+        if let Some(jar) = jar {
+            builder = builder.cookie_provider(jar);
+        }
+
+        builder.build().into_lua_err()
+    }
+}
+
+/// ids for configured clients, which jars from fetch.cookie_jar() keep their clients by
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// a reqwest client and the circuit breaker that guards it, if one is configured.
+/// reqwest keeps the cookies itself, so there is a client for each jar it could use:
+/// client is the one requests are sent with, plain has no jar and shared has the jar
+/// every client shares.
+#[derive(Debug, Clone)]
+pub struct ConfiguredClient {
+    id: u64,
+    config: Arc<FetchClientConfig>,
+    client: Client,
+    plain: Client,
+    shared: Client,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl ConfiguredClient {
+    fn new(config: &FetchClientConfig, jar: &Arc<Jar>) -> LuaResult<Self> {
+        let plain = config.build()?;
+        let shared = config.build_with(Some(jar.clone()))?;
+        Ok(Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            config: Arc::new(config.clone()),
+            client: if config.cookies {
+                shared.clone()
+            } else {
+                plain.clone()
+            },
+            plain,
+            shared,
+            breaker: config
+                .circuit_breaker
+                .clone()
+                .map(CircuitBreaker::new)
+                .transpose()?,
+        })
+    }
+}
+
+/// a default client plus any per-host clients from the config.
+/// all of them share one cookie jar.
+pub struct FetchClient {
+    client: ConfiguredClient,
+    hosts: HashMap<String, ConfiguredClient>,
+}
+
+impl FetchClient {
+    pub fn new(config: &FetchConfig) -> LuaResult<Self> {
+        let jar = Arc::new(Jar::default());
+        let client = ConfiguredClient::new(&config.client, &jar)?;
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(host, config)| Ok((host.to_lowercase(), ConfiguredClient::new(config, &jar)?)))
+            .collect::<LuaResult<_>>()?;
+
+        Ok(Self { client, hosts })
+    }
+
+    pub fn for_url(&self, url: &str) -> &ConfiguredClient {
+        Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().and_then(|host| self.hosts.get(host)))
+            .unwrap_or(&self.client)
+    }
+}
+
+impl LuaUserData for FetchClient {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_meta_method(
+            LuaMetaMethod::Call,
+            |lua, this, (url, options): (String, Option<LuaTable>)| async move {
+                let client = select_jar(options.as_ref(), this.for_url(&url).clone())?;
+                fetch_with(lua, client, url, options).await
+            },
+        );
+    }
+}
+
+pub fn register(lua: &Lua, config: &FetchConfig, database: &Database) -> LuaResult<()> {
+    lua.set_named_registry_value(FETCH_CLIENT, FetchClient::new(config)?)?;
+
+    let fetch = lua.create_table()?;
+    fetch.set(
+        "cached",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| fetch_cached(lua, database.clone(), args)
+        })?,
+    )?;
+    fetch.set("client", lua.create_function(fetch_client)?)?;
+    fetch.set("cookie_jar", lua.create_function(fetch_cookie_jar)?)?;
+    fetch.set("cookies", false)?;
+    mock::register(lua, &fetch)?;
+
+    let fetch_mt = lua.create_table()?;
+    fetch_mt.set(
+        "__call",
+        lua.create_async_function(
+            |lua, (fetch_table, url, options): (LuaTable, String, Option<LuaTable>)| async move {
+                let cookies = fetch_table.get::<Option<bool>>("cookies")?.unwrap_or(false);
+                fetch(lua, url, options, cookies).await
+            },
+        )?,
+    )?;
+    fetch.set_metatable(Some(fetch_mt))?;
+
+    lua.globals().set("fetch", fetch)?;
+
+    Ok(())
+}
+
+/// fetch.client { timeout = 120, headers = { ... } }
+///
+/// builds a separate client that is called the same way as fetch()
+fn fetch_client(lua: &Lua, options: LuaTable) -> LuaResult<FetchClient> {
+    let client: FetchClientConfig = lua.from_value(LuaValue::Table(options))?;
+    FetchClient::new(&FetchConfig {
+        client,
+        ..Default::default()
+    })
+}
+
+/// fetch.cookie_jar()
+///
+/// a cookie jar that can be passed to fetch as options.cookies
+fn fetch_cookie_jar(_lua: &Lua, _: ()) -> LuaResult<LuaFetchCookieJar> {
+    Ok(LuaFetchCookieJar {
+        jar: Arc::new(Jar::default()),
+        clients: Arc::default(),
+    })
+}
+
+/// a jar and the clients that keep cookies in it, by the configured client they are for
+pub struct LuaFetchCookieJar {
+    jar: Arc<Jar>,
+    clients: Arc<Mutex<HashMap<u64, Client>>>,
+}
+
+impl LuaFetchCookieJar {
+    /// configured, sending its requests with this jar
+    fn with(&self, mut configured: ConfiguredClient) -> LuaResult<ConfiguredClient> {
+        let mut clients = self.clients.lock();
+        configured.client = match clients.get(&configured.id) {
+            Some(client) => client.clone(),
+            None => {
+                let client = configured.config.build_with(Some(self.jar.clone()))?;
+                clients.insert(configured.id, client.clone());
+                client
+            }
+        };
+        Ok(configured)
+    }
+}
+
+impl LuaUserData for LuaFetchCookieJar {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // jar:get(url) returns the cookie header that would be sent to url
+        methods.add_method("get", |_, this, url: String| {
+            let url = Url::parse(&url).into_lua_err()?;
+            Ok(this
+                .jar
+                .cookies(&url)
+                .map(|cookies| String::from_utf8_lossy(cookies.as_bytes()).to_string()))
+        });
+
+        // jar:set(url, "name=value; Path=/")
+        methods.add_method("set", |_, this, (url, cookie): (String, String)| {
+            let url = Url::parse(&url).into_lua_err()?;
+            this.jar.add_cookie_str(&cookie, &url);
+            Ok(())
+        });
+    }
+}
+
+/// fetch(url [, options])
+///
+/// this is intended to be largely compatible with fetch() in the browser supporting:
+/// - method: GET, POST, PUT, DELETE, etc
+/// - headers: { ["Content-Type"] = "application/json" }
+/// - body: a string, or a body like req.body_data
+/// - cookies: a jar from fetch.cookie_jar(), or true/false to use or ignore the shared jar
+///
+/// setting fetch.cookies = true makes the shared jar the default
+async fn fetch(
+    lua: Lua,
+    url: String,
+    options: Option<LuaTable>,
+    cookies: bool,
+) -> LuaResult<LuaTable> {
+    let client = client_for(&lua, &url, options.as_ref(), cookies)?;
+    fetch_with(lua, client, url, options).await
+}
+
+/// the configured client for url, for requests that don't go through fetch()
+pub fn default_client(lua: &Lua, url: &str) -> LuaResult<Client> {
+    let fetch_client = lua.named_registry_value::<LuaUserDataRef<FetchClient>>(FETCH_CLIENT)?;
+    Ok(fetch_client.for_url(url).client.clone())
+}
+
+/// the configured client for url, with the cookie jar fetch() would use
+fn client_for(
+    lua: &Lua,
+    url: &str,
+    options: Option<&LuaTable>,
+    cookies: bool,
+) -> LuaResult<ConfiguredClient> {
+    let fetch_client = lua.named_registry_value::<LuaUserDataRef<FetchClient>>(FETCH_CLIENT)?;
+    let mut client = fetch_client.for_url(url).clone();
+    if cookies {
+        client.client = client.shared.clone();
+    }
+    select_jar(options, client)
+}
+
+/// options.cookies overrides the jar the client would otherwise use
+fn select_jar(
+    options: Option<&LuaTable>,
+    mut client: ConfiguredClient,
+) -> LuaResult<ConfiguredClient> {
+    let cookies = options
+        .map(|options| options.get::<LuaValue>("cookies"))
+        .transpose()?
+        .unwrap_or(LuaValue::Nil);
+
+    match cookies {
+        LuaValue::Nil => {}
+        LuaValue::Boolean(false) => client.client = client.plain.clone(),
+        LuaValue::Boolean(true) => client.client = client.shared.clone(),
+        LuaValue::UserData(ud) => return ud.borrow::<LuaFetchCookieJar>()?.with(client),
+        _ => {
+            return Err(LuaError::runtime(
+                "cookies must be a boolean or a jar from fetch.cookie_jar()",
+            ))
+        }
+    }
+    Ok(client)
+}
+
+async fn fetch_with(
+    lua: Lua,
+    client: ConfiguredClient,
+    url: String,
+    options: Option<LuaTable>,
+) -> LuaResult<LuaTable> {
+    let response = send(&lua, &client, &url, options, HeaderMap::new()).await?;
+    create_fetch_response(&lua, response).await
+}
+
+/// send a request, retrying as options.retry says. headers are added to the ones
+/// in options. fetch.on_fetch is told how it went.
+#[tracing::instrument(level = "info", name = "fetch", skip_all, fields(%url))]
+async fn send(
+    lua: &Lua,
+    client: &ConfiguredClient,
+    url: &str,
+    options: Option<LuaTable>,
+    headers: HeaderMap,
+) -> LuaResult<reqwest::Response> {
+    let retry = options
+        .as_ref()
+        .map(|options| options.get::<Option<LuaTable>>("retry"))
+        .transpose()?
+        .flatten()
+        .map(|retry| RetryPolicy::from_lua_table(&retry))
+        .transpose()?
+        .unwrap_or_default();
+    let request = build_request(&client.client, url, options)?
+        .headers(headers)
+        .build()
+        .into_lua_err()?;
+    if let Some(response) = mock::respond(lua, &request).await? {
+        return Ok(response);
+    }
+    let host = request.url().host_str().unwrap_or_default().to_string();
+    let method = request.method().to_string();
+    let start = Instant::now();
+
+    let mut attempt = 1;
+    loop {
+        if let Some(ref breaker) = client.breaker {
+            breaker.check(&host)?;
+        }
+        let attempt_request = request
+            .try_clone()
+            .ok_or_else(|| LuaError::runtime("request body cannot be retried"))?;
+        let result = client.client.execute(attempt_request).await;
+
+        if let Some(ref breaker) = client.breaker {
+            match result {
+                Ok(ref response) if !response.status().is_server_error() => breaker.success(&host),
+                _ => breaker.failure(&host),
+            }
+        }
+
+        let retryable = match result {
+            Ok(ref response) => retry.retry_status(response.status().as_u16()),
+            Err(ref err) => err.is_connect() || err.is_timeout(),
+        };
+        if !retryable || attempt >= retry.attempts {
+            let status = result
+                .as_ref()
+                .ok()
+                .map(|response| response.status().as_u16());
+            let error = result.as_ref().err().map(|err| err.to_string());
+            let elapsed = start.elapsed();
+            tracing::info!(status, error, ?elapsed, attempts = attempt, "fetched");
+            on_fetch(
+                lua,
+                FetchEvent {
+                    method,
+                    url: url.to_string(),
+                    host,
+                    status,
+                    error,
+                    duration: elapsed.as_secs_f64(),
+                    attempts: attempt,
+                },
+            )
+            .await;
+            return result.into_lua_err();
+        }
+
+        let delay = retry.delay(attempt);
+        tracing::debug!(%url, attempt, ?delay, "retrying fetch");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// what fetch.on_fetch is called with
+#[derive(Debug, Serialize)]
+struct FetchEvent {
+    method: String,
+    url: String,
+    host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// seconds from the first attempt to the last response
+    duration: f64,
+    attempts: u32,
+}
+
+/// fetch.on_fetch = function(event) ... end sees every request fetch makes, once it is
+/// done, with { method, url, host, status, error, duration, attempts }.
+/// errors in it are logged, so a broken hook can't break the request.
+async fn on_fetch(lua: &Lua, event: FetchEvent) {
+    let hook = lua
+        .globals()
+        .get::<LuaTable>("fetch")
+        .and_then(|fetch| fetch.get::<Option<LuaFunction>>("on_fetch"));
+    let hook = match hook {
+        Ok(Some(hook)) => hook,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::error!(?err, "error finding fetch.on_fetch");
+            return;
+        }
+    };
+    let result = match lua.to_value(&event) {
+        Ok(event) => hook.call_async::<()>(event).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        tracing::error!(?err, "error in fetch.on_fetch");
+    }
+}
+
+fn build_request(
+    client: &Client,
+    url: &str,
+    options: Option<LuaTable>,
+) -> LuaResult<RequestBuilder> {
+    let Some(options) = options else {
+        return Ok(client.get(url));
+    };
+
+    let method = options
+        .get::<Option<String>>("method")?
+        .unwrap_or("get".to_string());
+    let method = Method::from_bytes(method.to_uppercase().as_bytes()).into_lua_err()?;
+    let mut request = client.request(method, url);
+    if let Some(headers) = options.get::<Option<LuaTable>>("headers")? {
+        request = request.headers(to_header_map(headers.pairs::<String, String>())?);
+    }
+    if let Some(body) = body_bytes(&options.get::<LuaValue>("body")?)? {
+        request = request.body(body);
+    }
+
+    Ok(request)
+}
+
+fn to_header_map<I>(pairs: I) -> LuaResult<HeaderMap>
+where
+    I: IntoIterator<Item = LuaResult<(String, String)>>,
+{
+    pairs
+        .into_iter()
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((
+                HeaderName::from_bytes(key.as_bytes()).into_lua_err()?,
+                HeaderValue::from_str(&value).into_lua_err()?,
+            ))
+        })
+        .collect()
+}
+
+async fn create_fetch_response(lua: &Lua, response: reqwest::Response) -> LuaResult<LuaTable> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await.into_lua_err()?;
+
+    create_response_parts(lua, status, headers, &body)
+}
+
+/// a response stored by fetch.cached
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Vec<u8>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CachedResponse {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaTable> {
+        let res = create_response_parts(lua, StatusCode::OK, self.headers, &self.body)?;
+        res.set("cached", true)?;
+        Ok(res)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// fetch.cached(url [, options])
+///
+/// a GET whose response is stored in the database for options.ttl seconds (0 by
+/// default). after that it is revalidated with If-None-Match and If-Modified-Since,
+/// so when nothing changed the other server only sends a 304. only 200 responses are
+/// stored, keyed by url alone. res.cached is true when the body came from the database.
+async fn fetch_cached(
+    lua: Lua,
+    database: Database,
+    (url, options): (String, Option<LuaTable>),
+) -> LuaResult<LuaTable> {
+    let method = options
+        .as_ref()
+        .map(|options| options.get::<Option<String>>("method"))
+        .transpose()?
+        .flatten();
+    if method.is_some_and(|method| !method.eq_ignore_ascii_case("get")) {
+        return Err(LuaError::runtime("fetch.cached only caches GET requests"));
+    }
+    let ttl = options
+        .as_ref()
+        .map(|options| options.get::<Option<i64>>("ttl"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(0)
+        .max(0);
+
+    let stored = database
+        .call({
+            let url = url.clone();
+            move |conn| load_cached(conn, &url, now())
+        })
+        .await
+        .into_lua_err()?;
+    let stored = match stored {
+        Some((stored, true)) => return stored.into_lua(&lua),
+        Some((stored, false)) => Some(stored),
+        None => None,
+    };
+
+    let mut conditions = HeaderMap::new();
+    if let Some(ref stored) = stored {
+        let etag = stored.etag.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(etag)) = etag {
+            conditions.insert(IF_NONE_MATCH, etag);
+        }
+        let last_modified = stored.last_modified.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(last_modified)) = last_modified {
+            conditions.insert(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let client = client_for(&lua, &url, options.as_ref(), false)?;
+    let response = send(&lua, &client, &url, options, conditions).await?;
+    match (response.status(), stored) {
+        (StatusCode::NOT_MODIFIED, Some(stored)) => {
+            database
+                .call(move |conn| {
+                    conn.prepare_cached("UPDATE lg_fetch_cache SET expires = ? WHERE url = ?")?
+                        .execute(params![now() + ttl, url])?;
+                    Ok(())
+                })
+                .await
+                .into_lua_err()?;
+            stored.into_lua(&lua)
+        }
+        (StatusCode::OK, _) => {
+            let headers = response.headers().clone();
+            let body = response.bytes().await.into_lua_err()?;
+            let res = create_response_parts(&lua, StatusCode::OK, headers.clone(), &body)?;
+            res.set("cached", false)?;
+            let stored = CachedResponse {
+                etag: header_str(&headers, ETAG),
+                last_modified: header_str(&headers, LAST_MODIFIED),
+                headers,
+                body: body.to_vec(),
+            };
+            database
+                .call(move |conn| store_cached(conn, &url, &stored, now() + ttl))
+                .await
+                .into_lua_err()?;
+            Ok(res)
+        }
+        _ => create_fetch_response(&lua, response).await,
+    }
+}
+
+fn json_error(err: serde_json::Error) -> database::Error {
+    database::Error::Other(Box::new(err))
+}
+
+/// the stored response for url, and whether it is still fresh
+fn load_cached(
+    conn: &Connection,
+    url: &str,
+    now: i64,
+) -> database::Result<Option<(CachedResponse, bool)>> {
+    let row = conn
+        .prepare_cached(
+            "SELECT headers, body, etag, last_modified, expires FROM lg_fetch_cache WHERE url = ?",
+        )?
+        .query_row([url], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })
+        .optional()?;
+    let Some((headers, body, etag, last_modified, expires)) = row else {
+        return Ok(None);
+    };
+    let headers: Vec<(String, String)> = serde_json::from_str(&headers).map_err(json_error)?;
+    let headers = headers
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(&value).ok()?,
+            ))
+        })
+        .collect();
+    let stored = CachedResponse {
+        headers,
+        body,
+        etag,
+        last_modified,
+    };
+    Ok(Some((stored, expires > now)))
+}
+
+fn store_cached(
+    conn: &Connection,
+    url: &str,
+    stored: &CachedResponse,
+    expires: i64,
+) -> database::Result<()> {
+    let headers: Vec<(&str, &str)> = stored
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    let headers = serde_json::to_string(&headers).map_err(json_error)?;
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO lg_fetch_cache (url, headers, body, etag, last_modified, expires)
+         VALUES (?, ?, ?, ?, ?, ?)",
+    )?
+    .execute(params![
+        url,
+        headers,
+        stored.body,
+        stored.etag,
+        stored.last_modified,
+        expires
+    ])?;
+    Ok(())
+}
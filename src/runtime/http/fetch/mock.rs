@@ -0,0 +1,133 @@
+// canned responses for fetch, so tests of handlers don't use the network.
+//
+//   fetch.mock("^https://api.example.com/users", { status = 200, body = '{"users":[]}' })
+//   fetch.mock("/slow", { delay = 2, status = 504 })
+//   fetch.mock("/down", { error = "connection refused" })
+//   fetch.mock("/echo", function(req) return { body = req.body } end)
+//   fetch.unmock()
+//
+// patterns are lua patterns matched against the url, and the newest mock that matches is
+// used. a function is called with { method, url, headers, body } and returns the response.
+// with fetch.offline = true, or lilguy run --offline, fetching a url with no mock fails.
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use mlua::prelude::*;
+
+use crate::runtime::seconds;
+
+const FETCH_MOCKS: &str = "fetch_mocks";
+
+pub fn register(lua: &Lua, fetch: &LuaTable) -> LuaResult<()> {
+    lua.set_named_registry_value(FETCH_MOCKS, lua.create_table()?)?;
+    fetch.set("offline", false)?;
+    fetch.set(
+        "mock",
+        lua.create_function(|lua, (pattern, response): (String, LuaValue)| {
+            if !matches!(response, LuaValue::Table(_) | LuaValue::Function(_)) {
+                return Err(LuaError::runtime(
+                    "a mock response must be a table or a function",
+                ));
+            }
+            let mock = lua.create_table()?;
+            mock.set("pattern", pattern)?;
+            mock.set("response", response)?;
+            lua.named_registry_value::<LuaTable>(FETCH_MOCKS)?
+                .push(mock)
+        })?,
+    )?;
+    fetch.set(
+        "unmock",
+        lua.create_function(|lua, ()| {
+            lua.set_named_registry_value(FETCH_MOCKS, lua.create_table()?)
+        })?,
+    )?;
+    Ok(())
+}
+
+/// the mocked response to request, if a mock matches it. when none does and fetch.offline
+/// is set, an error.
+pub async fn respond(
+    lua: &Lua,
+    request: &reqwest::Request,
+) -> LuaResult<Option<reqwest::Response>> {
+    let url = request.url().as_str();
+    let find = lua
+        .globals()
+        .get::<LuaTable>("string")?
+        .get::<LuaFunction>("find")?;
+    let mocks = lua.named_registry_value::<LuaTable>(FETCH_MOCKS)?;
+    let mut found = None;
+    for mock in mocks.sequence_values::<LuaTable>() {
+        let mock = mock?;
+        let pattern = mock.get::<String>("pattern")?;
+        if find.call::<Option<i64>>((url, pattern))?.is_some() {
+            found = Some(mock.get::<LuaValue>("response")?);
+        }
+    }
+
+    let response = match found {
+        Some(LuaValue::Function(function)) => {
+            function
+                .call_async::<LuaTable>(mock_request(lua, request)?)
+                .await?
+        }
+        Some(LuaValue::Table(response)) => response,
+        Some(_) => return Err(LuaError::runtime("invalid mock response")),
+        None if fetch_offline(lua)? => {
+            return Err(LuaError::runtime(format!(
+                "fetch is offline and no mock matches {url}"
+            )))
+        }
+        None => return Ok(None),
+    };
+
+    if let Some(delay) = response.get::<Option<f64>>("delay")? {
+        tokio::time::sleep(seconds("mock delay", delay)?).await;
+    }
+    if let Some(error) = response.get::<Option<String>>("error")? {
+        return Err(LuaError::runtime(error));
+    }
+
+    let status = response.get::<Option<u16>>("status")?.unwrap_or(200);
+    let mut builder =
+        axum::http::Response::builder().status(StatusCode::from_u16(status).into_lua_err()?);
+    if let Some(headers) = response.get::<Option<LuaTable>>("headers")? {
+        for pair in headers.pairs::<String, String>() {
+            let (name, value) = pair?;
+            builder = builder.header(
+                HeaderName::from_bytes(name.as_bytes()).into_lua_err()?,
+                HeaderValue::from_str(&value).into_lua_err()?,
+            );
+        }
+    }
+    let body = response
+        .get::<Option<LuaString>>("body")?
+        .map(|body| body.as_bytes().to_vec())
+        .unwrap_or_default();
+    let response = builder.body(body).into_lua_err()?;
+
+    Ok(Some(reqwest::Response::from(response)))
+}
+
+fn fetch_offline(lua: &Lua) -> LuaResult<bool> {
+    Ok(lua
+        .globals()
+        .get::<LuaTable>("fetch")?
+        .get::<Option<bool>>("offline")?
+        .unwrap_or(false))
+}
+
+/// what a mock function is called with
+fn mock_request(lua: &Lua, request: &reqwest::Request) -> LuaResult<LuaTable> {
+    let req = lua.create_table()?;
+    req.set("method", request.method().as_str())?;
+    req.set("url", request.url().as_str())?;
+    let headers = lua.create_table()?;
+    for (name, value) in request.headers() {
+        headers.set(name.as_str(), lua.create_string(value.as_bytes())?)?;
+    }
+    req.set("headers", headers)?;
+    if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+        req.set("body", lua.create_string(body)?)?;
+    }
+    Ok(req)
+}
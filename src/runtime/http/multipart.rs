@@ -0,0 +1,565 @@
+// multipart/form-data request bodies, the kind forms with file inputs send.
+//
+//   <form method="post" enctype="multipart/form-data">
+//       <input name="title"> <input type="file" name="photo">
+//   </form>
+//
+//   routes.post["/photos"] = function(req, res)
+//       local title = req.body.title        -- fields that aren't files are in req.body
+//       local photo = req.files.photo       -- and files are in req.files, by field name
+//       photo.name                          -- "photo"
+//       photo.filename                      -- "cat.jpg", as the browser sent it
+//       photo.content_type                  -- "image/jpeg"
+//       photo.size                          -- in bytes
+//       photo:save("uploads/" .. id)        -- returns the number of bytes written
+//       local bytes = photo:read()
+//   end
+//
+//   for _, file in ipairs(req.files) do ... end  -- every file, for <input type="file" multiple>
+//
+// req.files.photo is the first file sent as photo; req.files also lists all of them in
+// order. a file input left empty isn't in req.files. files in bodies up to 16MB are kept
+// in memory; in larger bodies they are written to temp files, photo.path, which are
+// removed once the upload is garbage collected, so save the ones to keep. either way the
+// body is read before the handler runs, and req.body_stream is empty.
+//
+//   [uploads]
+//   max_mb = 100   # the default; a larger multipart body gets a 413
+//
+// the limit is checked against content-length before anything is read, and again as the
+// body streams in, so a client can't fill the disk by leaving content-length out.
+use bytes::{Bytes, BytesMut};
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use super::body_stream::{RequestBody, MAX_BUFFERED};
+use crate::runtime::file::resolve;
+
+/// the most header bytes a part can have
+const MAX_HEADERS: usize = 16 * 1024;
+const DEFAULT_MAX_MB: u64 = 100;
+/// the most bytes a multipart body can have, in the registry
+const MAX_SIZE: &str = "uploads.max_size";
+
+/// the [uploads] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadsConfig {
+    /// the largest multipart body, in megabytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_mb: Option<u64>,
+}
+
+impl UploadsConfig {
+    pub fn max_size(&self) -> u64 {
+        self.max_mb
+            .unwrap_or(DEFAULT_MAX_MB)
+            .saturating_mul(1024 * 1024)
+    }
+}
+
+/// a multipart body larger than [uploads] allows
+#[derive(Debug, thiserror::Error)]
+#[error("multipart body is larger than {0} bytes")]
+pub struct TooLarge(pub u64);
+
+/// true if err is from a multipart body that was too large, for a 413
+pub fn is_too_large(err: &LuaError) -> bool {
+    match err {
+        LuaError::CallbackError { cause, .. } => is_too_large(cause),
+        LuaError::WithContext { cause, .. } => is_too_large(cause),
+        LuaError::ExternalError(err) => err.downcast_ref::<TooLarge>().is_some(),
+        _ => false,
+    }
+}
+
+pub fn register(lua: &Lua, config: &UploadsConfig) -> LuaResult<()> {
+    lua.set_named_registry_value(MAX_SIZE, config.max_size())
+}
+
+/// the largest multipart body the app takes
+pub fn max_size(lua: &Lua) -> LuaResult<u64> {
+    Ok(lua
+        .named_registry_value::<Option<u64>>(MAX_SIZE)?
+        .unwrap_or(DEFAULT_MAX_MB * 1024 * 1024))
+}
+
+/// the boundary of a multipart/form-data content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+/// the headers of a part that matter for a form
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PartHeaders {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl PartHeaders {
+    fn parse(headers: &[u8]) -> Result<Self, String> {
+        let headers = String::from_utf8_lossy(headers);
+        let mut part = PartHeaders::default();
+        let mut disposition = false;
+        for line in headers.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("content-disposition") {
+                disposition = true;
+                for param in split_params(value).into_iter().skip(1) {
+                    let Some((name, value)) = param.split_once('=') else {
+                        continue;
+                    };
+                    match name.trim().to_ascii_lowercase().as_str() {
+                        "name" => part.name = unquote(value.trim()),
+                        "filename" => part.filename = Some(unquote(value.trim())),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if !disposition {
+            return Err("multipart part without a content-disposition".to_string());
+        }
+        Ok(part)
+    }
+}
+
+/// split on the semicolons that aren't in quotes, since filenames can have them
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(value[start..].trim());
+    params
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// a part starts
+    Part(PartHeaders),
+    /// some of the body of the current part
+    Data(Bytes),
+    /// the current part is over
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    Headers,
+    Body,
+    Done,
+}
+
+/// splits a multipart body into events as its bytes arrive
+#[derive(Debug)]
+pub struct Parser {
+    /// CRLF, two dashes and the boundary: what comes before every part but the first
+    delimiter: Vec<u8>,
+    buffer: BytesMut,
+    state: State,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl Parser {
+    pub fn new(boundary: &str) -> Self {
+        let delimiter = format!("\r\n--{boundary}").into_bytes();
+        // the first delimiter starts the body without a CRLF
+        let buffer = BytesMut::from(&b"\r\n"[..]);
+        Self {
+            delimiter,
+            buffer,
+            state: State::Preamble,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        if self.state != State::Done {
+            self.buffer.extend_from_slice(chunk);
+        }
+    }
+
+    /// true once the closing delimiter has been read
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// after a delimiter at the start of the buffer: -- for the end, CRLF for another part
+    fn after_delimiter(&mut self) -> Result<bool, String> {
+        let end = self.delimiter.len() + 2;
+        if self.buffer.len() < end {
+            return Ok(false);
+        }
+        self.state = match &self.buffer[self.delimiter.len()..end] {
+            b"--" => State::Done,
+            b"\r\n" => State::Headers,
+            _ => return Err("malformed multipart delimiter".to_string()),
+        };
+        let _ = self.buffer.split_to(end);
+        if self.state == State::Done {
+            self.buffer.clear();
+        }
+        Ok(true)
+    }
+
+    /// the next event, or None until more of the body is pushed
+    pub fn next_event(&mut self) -> Result<Option<Event>, String> {
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buffer, &self.delimiter) {
+                    Some(at) => {
+                        let _ = self.buffer.split_to(at);
+                        if !self.after_delimiter()? {
+                            return Ok(None);
+                        }
+                        if self.state == State::Done {
+                            return Ok(None);
+                        }
+                    }
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        if self.buffer.len() > keep {
+                            let _ = self.buffer.split_to(self.buffer.len() - keep);
+                        }
+                        return Ok(None);
+                    }
+                },
+                State::Headers => {
+                    let Some(at) = find(&self.buffer, b"\r\n\r\n") else {
+                        if self.buffer.len() > MAX_HEADERS {
+                            return Err("multipart part headers are too large".to_string());
+                        }
+                        return Ok(None);
+                    };
+                    let headers = self.buffer.split_to(at + 4);
+                    self.state = State::Body;
+                    return PartHeaders::parse(&headers[..at]).map(|part| Some(Event::Part(part)));
+                }
+                State::Body => match find(&self.buffer, &self.delimiter) {
+                    Some(0) => {
+                        if !self.after_delimiter()? {
+                            return Ok(None);
+                        }
+                        return Ok(Some(Event::End));
+                    }
+                    Some(at) => return Ok(Some(Event::Data(self.buffer.split_to(at).freeze()))),
+                    None => {
+                        // the end of the buffer could be the start of a delimiter
+                        let keep = self.delimiter.len() - 1;
+                        if self.buffer.len() <= keep {
+                            return Ok(None);
+                        }
+                        let data = self.buffer.split_to(self.buffer.len() - keep);
+                        return Ok(Some(Event::Data(data.freeze())));
+                    }
+                },
+                State::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+/// where the bytes of a part are
+#[derive(Debug, Clone)]
+pub enum PartData {
+    Memory(Bytes),
+    File(Arc<NamedTempFile>),
+}
+
+/// a part of a multipart body
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub headers: PartHeaders,
+    pub data: PartData,
+    pub size: usize,
+}
+
+/// the part being read, and where its bytes go
+enum Sink {
+    Memory(BytesMut),
+    File(NamedTempFile, tokio::fs::File),
+}
+
+/// read the parts of a multipart body of up to max_size bytes. files in bodies too large
+/// to buffer are written to temp files; everything else is kept in memory, up to
+/// MAX_BUFFERED in all.
+pub async fn read(body: RequestBody, boundary: &str, max_size: u64) -> LuaResult<Vec<Part>> {
+    let mut parser = Parser::new(boundary);
+    let mut received = 0;
+    let mut receive = |parser: &mut Parser, chunk: &[u8]| {
+        received += chunk.len() as u64;
+        if received > max_size {
+            return Err(LuaError::external(TooLarge(max_size)));
+        }
+        parser.push(chunk);
+        Ok(())
+    };
+    let mut stream = match body {
+        RequestBody::Buffered(body) => {
+            receive(&mut parser, &body)?;
+            None
+        }
+        RequestBody::Streamed(stream) => Some(stream),
+    };
+    let mut parts = Vec::new();
+    let mut current: Option<(PartHeaders, Sink, usize)> = None;
+    let mut in_memory = 0;
+    loop {
+        while let Some(event) = parser.next_event().map_err(LuaError::runtime)? {
+            match event {
+                Event::Part(headers) => {
+                    let sink = if stream.is_some() && headers.filename.is_some() {
+                        let temp = NamedTempFile::new().into_lua_err()?;
+                        let file = tokio::fs::File::from_std(temp.reopen().into_lua_err()?);
+                        Sink::File(temp, file)
+                    } else {
+                        Sink::Memory(BytesMut::new())
+                    };
+                    current = Some((headers, sink, 0));
+                }
+                Event::Data(data) => {
+                    let Some((_, sink, size)) = current.as_mut() else {
+                        continue;
+                    };
+                    *size += data.len();
+                    match sink {
+                        Sink::Memory(buffer) => {
+                            in_memory += data.len();
+                            if in_memory > MAX_BUFFERED {
+                                return Err(LuaError::runtime("multipart form is too large"));
+                            }
+                            buffer.extend_from_slice(&data);
+                        }
+                        Sink::File(_, file) => file.write_all(&data).await.into_lua_err()?,
+                    }
+                }
+                Event::End => {
+                    let Some((headers, sink, size)) = current.take() else {
+                        continue;
+                    };
+                    let data = match sink {
+                        Sink::Memory(buffer) => PartData::Memory(buffer.freeze()),
+                        Sink::File(temp, mut file) => {
+                            file.flush().await.into_lua_err()?;
+                            PartData::File(Arc::new(temp))
+                        }
+                    };
+                    parts.push(Part {
+                        headers,
+                        data,
+                        size,
+                    });
+                }
+            }
+        }
+        if parser.is_done() {
+            return Ok(parts);
+        }
+        let chunk = match stream.as_mut() {
+            Some(stream) => stream.read(None).await?,
+            None => None,
+        };
+        match chunk {
+            Some(chunk) => receive(&mut parser, &chunk)?,
+            None => return Err(LuaError::runtime("multipart body ended early")),
+        }
+    }
+}
+
+/// an uploaded file, in req.files
+#[derive(Debug, Clone)]
+pub struct LuaUpload(Part);
+
+impl LuaUserData for LuaUpload {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("name", |_, this| Ok(this.0.headers.name.clone()));
+        fields.add_field_method_get("filename", |_, this| Ok(this.0.headers.filename.clone()));
+        fields.add_field_method_get("content_type", |_, this| {
+            Ok(this.0.headers.content_type.clone())
+        });
+        fields.add_field_method_get("size", |_, this| Ok(this.0.size));
+        fields.add_field_method_get("path", |_, this| match &this.0.data {
+            PartData::Memory(_) => Ok(None),
+            PartData::File(temp) => Ok(Some(temp.path().to_string_lossy().into_owned())),
+        });
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("read", |lua, this, ()| async move {
+            match &this.0.data {
+                PartData::Memory(bytes) => lua.create_string(bytes),
+                PartData::File(temp) => {
+                    let bytes = tokio::fs::read(temp.path()).await.into_lua_err()?;
+                    lua.create_string(&bytes)
+                }
+            }
+        });
+        methods.add_async_method("save", |lua, this, path: String| async move {
+            let path = resolve(&lua, path);
+            match &this.0.data {
+                PartData::Memory(bytes) => tokio::fs::write(&path, bytes).await.into_lua_err()?,
+                PartData::File(temp) => {
+                    tokio::fs::copy(temp.path(), &path).await.into_lua_err()?;
+                }
+            }
+            Ok(this.0.size)
+        });
+    }
+}
+
+/// req.body for the fields and req.files for the files
+pub fn form(lua: &Lua, parts: Vec<Part>) -> LuaResult<(LuaTable, LuaTable)> {
+    let body = lua.create_table()?;
+    let files = lua.create_table()?;
+    for part in parts {
+        let chosen = part.headers.filename.as_ref().map(|name| !name.is_empty());
+        match chosen {
+            // a file input with nothing chosen
+            Some(false) if part.size == 0 => {}
+            Some(_) => {
+                let name = part.headers.name.clone();
+                let upload = lua.create_userdata(LuaUpload(part))?;
+                if !files.contains_key(name.as_str())? {
+                    files.set(name, &upload)?;
+                }
+                files.push(upload)?;
+            }
+            None => {
+                let PartData::Memory(bytes) = &part.data else {
+                    continue;
+                };
+                body.set(part.headers.name.as_str(), lua.create_string(bytes)?)?;
+            }
+        }
+    }
+    Ok((body, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        hello\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"a;b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--Xy\r\n--XyZ--\r\n";
+
+    fn events(chunk_size: usize) -> Vec<Event> {
+        let mut parser = Parser::new("XyZ");
+        let mut events = Vec::new();
+        for chunk in BODY.chunks(chunk_size) {
+            parser.push(chunk);
+            while let Some(event) = parser.next_event().unwrap() {
+                events.push(event);
+            }
+        }
+        assert!(parser.is_done());
+        // join the data events, which depend on how the body was split
+        let mut joined: Vec<Event> = Vec::new();
+        for event in events {
+            match (joined.last_mut(), event) {
+                (Some(Event::Data(last)), Event::Data(data)) => {
+                    *last = [&last[..], &data[..]].concat().into();
+                }
+                (_, event) => joined.push(event),
+            }
+        }
+        joined
+    }
+
+    #[test]
+    fn parses_parts() {
+        let expected = vec![
+            Event::Part(PartHeaders {
+                name: "title".to_string(),
+                filename: None,
+                content_type: None,
+            }),
+            Event::Data(Bytes::from_static(b"hello")),
+            Event::End,
+            Event::Part(PartHeaders {
+                name: "photo".to_string(),
+                filename: Some("a;b.txt".to_string()),
+                content_type: Some("text/plain".to_string()),
+            }),
+            Event::Data(Bytes::from_static(b"line one\r\n--Xy")),
+            Event::End,
+        ];
+        for chunk_size in [1, 3, 7, BODY.len()] {
+            assert_eq!(events(chunk_size), expected, "chunks of {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn finds_boundaries() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=----abc").as_deref(),
+            Some("----abc")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("application/json"), None);
+    }
+
+    #[test]
+    fn limits_size() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let read = |max_size| {
+            let body = RequestBody::Buffered(Bytes::from_static(BODY));
+            runtime.block_on(read(body, "XyZ", max_size))
+        };
+        assert_eq!(read(BODY.len() as u64).unwrap().len(), 2);
+        assert!(is_too_large(&read(BODY.len() as u64 - 1).unwrap_err()));
+    }
+}
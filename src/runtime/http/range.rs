@@ -0,0 +1,92 @@
+// single byte ranges (Range: bytes=0-1023) for file and blob responses, so media can seek.
+// requests for several ranges at once are answered with the whole body, which the
+// spec allows.
+use axum::http::{
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use std::ops::Range;
+
+/// what part of a body of `size` bytes to send, and the status to send it with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeResponse {
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
+}
+
+impl RangeResponse {
+    pub fn new(range: Option<&HeaderValue>, size: usize) -> Self {
+        match range.and_then(|range| range.to_str().ok()) {
+            Some(range) => parse(range, size),
+            None => Self::Full,
+        }
+    }
+
+    /// the status and headers for the response; returns the byte range to send
+    pub fn apply(
+        &self,
+        status: &mut StatusCode,
+        headers: &mut HeaderMap,
+        size: usize,
+    ) -> Range<usize> {
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        match self {
+            Self::Full => {
+                headers.insert(CONTENT_LENGTH, size.into());
+                0..size
+            }
+            Self::Partial(range) => {
+                *status = StatusCode::PARTIAL_CONTENT;
+                headers.insert(CONTENT_LENGTH, range.len().into());
+                let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+                if let Ok(value) = HeaderValue::from_str(&content_range) {
+                    headers.insert(CONTENT_RANGE, value);
+                }
+                range.clone()
+            }
+            Self::Unsatisfiable => {
+                *status = StatusCode::RANGE_NOT_SATISFIABLE;
+                headers.insert(CONTENT_LENGTH, 0.into());
+                if let Ok(value) = HeaderValue::from_str(&format!("bytes */{size}")) {
+                    headers.insert(CONTENT_RANGE, value);
+                }
+                0..0
+            }
+        }
+    }
+}
+
+fn parse(range: &str, size: usize) -> RangeResponse {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return RangeResponse::Full;
+    };
+    if spec.contains(',') {
+        return RangeResponse::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeResponse::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=500-999
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(size),
+        // bytes=500-
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        // bytes=-500, the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeResponse::Unsatisfiable;
+            }
+            size.saturating_sub(suffix)..size
+        }
+        _ => return RangeResponse::Full,
+    };
+
+    if range.start >= size {
+        RangeResponse::Unsatisfiable
+    } else {
+        RangeResponse::Partial(range)
+    }
+}
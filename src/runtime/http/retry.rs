@@ -0,0 +1,156 @@
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::runtime::seconds;
+
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const MAX_COOLDOWN: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    Fixed,
+    Exponential,
+}
+
+/// options.retry = { attempts = 3, backoff = "exponential", delay = 0.5, on = { 502, 503 } }
+///
+/// connection errors and timeouts are always retried, responses only when their
+/// status is listed in `on`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Backoff,
+    pub delay: Duration,
+    pub on: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    /// no retries, which is what fetch does without options.retry
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Backoff::Exponential,
+            delay: Duration::from_millis(500),
+            on: vec![502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_lua_table(table: &LuaTable) -> LuaResult<Self> {
+        let backoff = match table.get::<Option<String>>("backoff")?.as_deref() {
+            Some("exponential") | None => Backoff::Exponential,
+            Some("fixed") => Backoff::Fixed,
+            Some(other) => {
+                return Err(LuaError::runtime(format!(
+                    "invalid backoff: {other}, must be fixed or exponential"
+                )))
+            }
+        };
+        let defaults = Self::default();
+
+        Ok(Self {
+            attempts: table.get::<Option<u32>>("attempts")?.unwrap_or(3).max(1),
+            backoff,
+            delay: table
+                .get::<Option<f64>>("delay")?
+                .map(|delay| seconds("delay", delay))
+                .transpose()?
+                .unwrap_or(defaults.delay),
+            on: table.get::<Option<Vec<u16>>>("on")?.unwrap_or(defaults.on),
+        })
+    }
+
+    /// how long to wait after the given (1-based) attempt failed
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed => self.delay,
+            Backoff::Exponential => self
+                .delay
+                .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))),
+        };
+        delay.min(MAX_DELAY)
+    }
+
+    pub fn retry_status(&self, status: u16) -> bool {
+        self.on.contains(&status)
+    }
+}
+
+/// circuit_breaker = { failures = 5, cooldown = 30 }
+///
+/// after `failures` consecutive failures to a host, requests to it fail immediately
+/// for `cooldown` seconds. the first request after that is let through and a single
+/// failure opens the circuit again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+
+    #[serde(default = "default_cooldown")]
+    pub cooldown: f64,
+}
+
+fn default_failures() -> u32 {
+    5
+}
+
+fn default_cooldown() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    cooldown: Duration,
+    hosts: Arc<Mutex<HashMap<String, BreakerState>>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> LuaResult<Self> {
+        let cooldown = seconds("cooldown", config.cooldown)?.min(MAX_COOLDOWN);
+        Ok(Self {
+            config,
+            cooldown,
+            hosts: Default::default(),
+        })
+    }
+
+    pub fn check(&self, host: &str) -> LuaResult<()> {
+        let hosts = self.hosts.lock();
+        match hosts.get(host).and_then(|state| state.open_until) {
+            Some(open_until) if open_until > Instant::now() => Err(LuaError::runtime(format!(
+                "circuit open for {host}, not sending request"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn success(&self, host: &str) {
+        self.hosts.lock().remove(host);
+    }
+
+    pub fn failure(&self, host: &str) {
+        let threshold = self.config.failures.max(1);
+        let mut hosts = self.hosts.lock();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.failures += 1;
+        if state.failures >= threshold {
+            tracing::warn!(host, "circuit opened after {} failures", state.failures);
+            state.failures = threshold - 1;
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
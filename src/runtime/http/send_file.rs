@@ -0,0 +1,186 @@
+// res:send_file(path, { download_name = "report.pdf" }) streams a file from disk as the
+// response body. the content type is guessed from the file name, and the etag and
+// last-modified headers let clients make conditional and range requests.
+use axum::{
+    body::Body,
+    http::{
+        header::{
+            CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+            LAST_MODIFIED,
+        },
+        HeaderMap, HeaderValue,
+    },
+};
+use futures_util::{stream, TryStreamExt};
+use mlua::prelude::*;
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::LuaHeaders;
+use crate::runtime::file::resolve;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let response = lua.globals().get::<LuaTable>("Response")?;
+    response.set("send_file", lua.create_async_function(response_send_file)?)?;
+    Ok(())
+}
+
+async fn response_send_file(
+    lua: Lua,
+    (res, path, options): (LuaTable, String, Option<LuaTable>),
+) -> LuaResult<()> {
+    let path = resolve(&lua, path);
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => {
+            res.set("status", 404)?;
+            res.set("body", "Not Found")?;
+            return Ok(());
+        }
+    };
+    let download_name = options
+        .map(|options| options.get::<Option<String>>("download_name"))
+        .transpose()?
+        .flatten();
+
+    let size = metadata.len() as usize;
+    // http dates have a resolution of one second
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| UNIX_EPOCH + Duration::from_secs(since.as_secs()));
+    let etag = modified.map(|modified| {
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!("W/\"{size:x}-{secs:x}\"")
+    });
+
+    {
+        let headers = res.get::<LuaAnyUserData>("headers")?;
+        let mut headers = headers.borrow_mut::<LuaHeaders>()?;
+        if !headers.contains_key(&CONTENT_TYPE) {
+            let mime = mime_guess::from_path(&path).first_or_octet_stream();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(mime.as_ref()).into_lua_err()?,
+            );
+        }
+        if let Some(name) = download_name {
+            headers.insert(CONTENT_DISPOSITION, content_disposition(&name)?);
+        }
+        if let Some(etag) = &etag {
+            headers.insert(ETAG, HeaderValue::from_str(etag).into_lua_err()?);
+        }
+        if let Some(modified) = modified {
+            let modified = httpdate::fmt_http_date(modified);
+            headers.insert(
+                LAST_MODIFIED,
+                HeaderValue::from_str(&modified).into_lua_err()?,
+            );
+        }
+    }
+
+    res.set(
+        "body",
+        FileBody {
+            path,
+            size,
+            etag,
+            modified,
+        },
+    )?;
+    Ok(())
+}
+
+/// attachment; filename="report.pdf", with filename* for names that are not plain ascii
+fn content_disposition(name: &str) -> LuaResult<HeaderValue> {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    let mut value = format!("attachment; filename=\"{fallback}\"");
+    if fallback != name {
+        value.push_str("; filename*=UTF-8''");
+        for byte in name.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                value.push(byte as char);
+            } else {
+                value.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    HeaderValue::from_str(&value).into_lua_err()
+}
+
+/// a response body that is read from disk when the response is sent
+#[derive(Debug)]
+pub struct FileBody {
+    path: PathBuf,
+    size: usize,
+    etag: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+impl FileBody {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// true when the request's If-None-Match or If-Modified-Since shows the client
+    /// already has this version of the file
+    pub fn not_modified(&self, conditions: &HeaderMap) -> bool {
+        if let Some(if_none_match) = conditions.get(IF_NONE_MATCH) {
+            let Some(etag) = &self.etag else {
+                return false;
+            };
+            let etag = etag.trim_start_matches("W/");
+            return if_none_match
+                .to_str()
+                .unwrap_or_default()
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag);
+        }
+
+        let since = conditions
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| httpdate::parse_http_date(since).ok());
+        match (since, self.modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// the response body, streamed from the file. the file is opened when the
+    /// body is first polled.
+    pub fn into_body(self, range: Range<usize>) -> Body {
+        let open = async move {
+            let mut file = tokio::fs::File::open(&self.path).await?;
+            if range.start > 0 {
+                file.seek(SeekFrom::Start(range.start as u64)).await?;
+            }
+            Ok::<_, std::io::Error>(ReaderStream::new(file.take(range.len() as u64)))
+        };
+        Body::from_stream(stream::once(open).try_flatten())
+    }
+}
+
+impl LuaUserData for FileBody {}
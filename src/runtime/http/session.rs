@@ -0,0 +1,285 @@
+// per-visitor sessions, kept in the app's database.
+//
+//   routes.post["/login"] = function(req, res)
+//       local user = check_password(req.body.email, req.body.password)
+//       req:reset_session()         -- a new id on login, so one from before can't be reused
+//       req.session.user_id = user.id
+//       res:redirect("/")
+//   end
+//
+//   routes["/"] = function(req, res)
+//       local user_id = req.session.user_id   -- nil for a visitor who never had one
+//   end
+//
+//   req.session.user_id = nil       -- the session is removed once it is empty
+//
+// res:flash("Saved", "success") keeps a message in the session for the next request,
+// which has it in req.flash and in its templates as request.flash. req:csrf_token() is
+// a token for the session, in templates as request.csrf_token, that forms send back as
+// a csrf_token field or scripts as an X-CSRF-Token header, and req:verify_csrf() is true
+// when a request did. both are kept in the session under keys starting with _, so
+// rendering a template, which asks for the token, gives a visitor a session.
+//
+// req.session is a plain table, saved when the handler returns if it changed, so its
+// values need to be things json can hold. the session's id is in a signed cookie,
+// lg_session, made with the same key as the rest of the cookie jar; a visitor only gets
+// one once something is put in their session. a session that isn't changed for 30 days
+// is gone.
+use cookie::{time::Duration, Cookie, SameSite};
+use mlua::prelude::*;
+use rusqlite::{params, OptionalExtension};
+use subtle::ConstantTimeEq;
+
+use super::{LuaCookieJar, LuaHeaders};
+use crate::database::Database;
+
+const COOKIE: &str = "lg_session";
+/// where res:flash() keeps messages for the next request
+const FLASH: &str = "_flash";
+/// where req:csrf_token() keeps the session's token
+const CSRF: &str = "_csrf";
+/// how long a session lasts after it was last changed
+const MAX_AGE_DAYS: i64 = 30;
+/// the sessions of requests, keyed weakly by the request table
+const SESSIONS: &str = "sessions";
+
+/// the session a request was loaded with
+#[derive(Debug, Clone, Default)]
+struct Loaded {
+    /// the session's id, if it had one
+    id: Option<String>,
+    /// null when there was no session
+    data: serde_json::Value,
+    /// set by req:reset_session()
+    reset: bool,
+}
+
+impl LuaUserData for Loaded {}
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let sessions = lua.create_table()?;
+    let sessions_mt = lua.create_table()?;
+    sessions_mt.set("__mode", "k")?;
+    sessions.set_metatable(Some(sessions_mt))?;
+    lua.set_named_registry_value(SESSIONS, sessions)?;
+
+    let request = lua.globals().get::<LuaTable>("Request")?;
+    request.set(
+        "reset_session",
+        lua.create_function(|lua, req: LuaTable| {
+            let sessions = lua.named_registry_value::<LuaTable>(SESSIONS)?;
+            if let Some(loaded) = sessions.get::<Option<LuaAnyUserData>>(&req)? {
+                loaded.borrow_mut::<Loaded>()?.reset = true;
+            }
+            req.raw_set("session", lua.create_table()?)?;
+            Ok(())
+        })?,
+    )?;
+    let response = lua.globals().get::<LuaTable>("Response")?;
+    response.set(
+        "flash",
+        lua.create_function(
+            |lua, (res, message, kind): (LuaTable, String, Option<String>)| {
+                let session = res
+                    .get::<LuaTable>("request")?
+                    .raw_get::<LuaTable>("session")?;
+                let flash = match session.raw_get::<Option<LuaTable>>(FLASH)? {
+                    Some(flash) => flash,
+                    None => lua.create_table()?,
+                };
+                let entry = lua.create_table()?;
+                entry.set("kind", kind.as_deref().unwrap_or("info"))?;
+                entry.set("message", message)?;
+                flash.push(entry)?;
+                session.raw_set(FLASH, flash)
+            },
+        )?,
+    )?;
+    request.set(
+        "csrf_token",
+        lua.create_function(|_, req: LuaTable| {
+            let session = req.raw_get::<LuaTable>("session")?;
+            if let Some(token) = session.raw_get::<Option<String>>(CSRF)? {
+                return Ok(token);
+            }
+            let token = new_id();
+            session.raw_set(CSRF, token.as_str())?;
+            Ok(token)
+        })?,
+    )?;
+    request.set("verify_csrf", lua.create_function(verify_csrf)?)?;
+    Ok(())
+}
+
+/// true if req sent back its session's csrf token, in the X-CSRF-Token header or a
+/// csrf_token form field
+fn verify_csrf(_lua: &Lua, req: LuaTable) -> LuaResult<bool> {
+    let session = req.raw_get::<LuaTable>("session")?;
+    let Some(expected) = session.raw_get::<Option<String>>(CSRF)? else {
+        return Ok(false);
+    };
+    let header = req
+        .get::<LuaUserDataRef<LuaHeaders>>("headers")?
+        .0
+        .get("x-csrf-token")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let sent = match header {
+        Some(header) => Some(header),
+        None => match req.get::<LuaValue>("body")? {
+            LuaValue::Table(form) => form.get::<Option<String>>("csrf_token")?,
+            _ => None,
+        },
+    };
+    Ok(sent.is_some_and(|sent| sent.as_bytes().ct_eq(expected.as_bytes()).into()))
+}
+
+fn new_id() -> String {
+    format!(
+        "{:032x}{:032x}",
+        rand::random::<u128>(),
+        rand::random::<u128>()
+    )
+}
+
+/// set req.session from the session cookie
+pub async fn load(lua: &Lua, database: &Database, req: &LuaTable) -> LuaResult<()> {
+    let jar = req.get::<LuaAnyUserData>("cookie_jar")?;
+    let id = {
+        let jar = jar.borrow::<LuaCookieJar>()?;
+        let cookies = jar.jar.lock();
+        cookies
+            .signed(&jar.key)
+            .get(COOKIE)
+            .map(|cookie| cookie.value().to_string())
+    };
+    let stored = match id.clone() {
+        Some(id) => database
+            .call(move |conn| {
+                let data = conn
+                    .query_row(
+                        "SELECT json(data) FROM lg_sessions WHERE id = ? AND expires > unixepoch()",
+                        [id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                Ok(data)
+            })
+            .await
+            .into_lua_err()?,
+        None => None,
+    };
+    let (session, loaded) = match stored {
+        Some(json) => {
+            let data: serde_json::Value = serde_json::from_str(&json).into_lua_err()?;
+            let session = lua.to_value(&data)?;
+            let loaded = Loaded {
+                id,
+                data,
+                reset: false,
+            };
+            (session, loaded)
+        }
+        None => (LuaValue::Table(lua.create_table()?), Loaded::default()),
+    };
+    // the messages from res:flash on the last request are this one's, and are gone once
+    // the session is saved
+    let flash = match &session {
+        LuaValue::Table(session) => {
+            let flash = session.raw_get::<Option<LuaTable>>(FLASH)?;
+            session.raw_set(FLASH, LuaNil)?;
+            flash
+        }
+        _ => None,
+    };
+    let flash = match flash {
+        Some(flash) => flash,
+        None => lua.create_table()?,
+    };
+    flash.set_metatable(Some(lua.array_metatable()))?;
+    req.raw_set("flash", flash)?;
+    req.raw_set("session", session)?;
+    lua.named_registry_value::<LuaTable>(SESSIONS)?
+        .set(req, loaded)?;
+    Ok(())
+}
+
+/// save req.session if the handler changed it, and set or remove the cookie in res
+pub async fn save(lua: &Lua, database: &Database, req: &LuaTable, res: &LuaTable) -> LuaResult<()> {
+    let Some(loaded) = lua
+        .named_registry_value::<LuaTable>(SESSIONS)?
+        .get::<Option<LuaAnyUserData>>(req)?
+    else {
+        return Ok(());
+    };
+    let loaded = loaded.borrow::<Loaded>()?.clone();
+    let data = match req.raw_get::<LuaValue>("session")? {
+        LuaValue::Table(session) if !session.is_empty() => lua
+            .from_value(LuaValue::Table(session))
+            .map_err(|err| LuaError::runtime(format!("session values must be json: {err}")))?,
+        _ => serde_json::Value::Null,
+    };
+    // compared as values, since the order of a table's keys can change
+    if !loaded.reset && data == loaded.data {
+        return Ok(());
+    }
+    let json = (!data.is_null()).then(|| data.to_string());
+
+    let id = match (&json, loaded.id.clone()) {
+        (Some(_), Some(id)) if !loaded.reset => Some(id),
+        (Some(_), _) => Some(new_id()),
+        (None, _) => None,
+    };
+    let removed = loaded.id.clone().filter(|_| loaded.reset || json.is_none());
+    let saved = id.clone().zip(json);
+    database
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            if let Some(removed) = removed {
+                tx.execute("DELETE FROM lg_sessions WHERE id = ?", [removed])?;
+            }
+            if let Some((id, data)) = saved {
+                tx.execute(
+                    "INSERT INTO lg_sessions (id, data, expires) \
+                     VALUES (?1, jsonb(?2), unixepoch() + ?3 * 86400) \
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data, expires = excluded.expires",
+                    params![id, data, MAX_AGE_DAYS],
+                )?;
+            }
+            tx.execute("DELETE FROM lg_sessions WHERE expires <= unixepoch()", [])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        .into_lua_err()?;
+
+    let Some(jar) = res.get::<Option<LuaAnyUserData>>("cookie_jar")? else {
+        return Ok(());
+    };
+    let jar = jar.borrow::<LuaCookieJar>()?;
+    let mut cookies = jar.jar.lock();
+    match id {
+        Some(id) => {
+            let cookie = Cookie::build((COOKIE, id))
+                .same_site(SameSite::Lax)
+                .path("/")
+                .max_age(Duration::days(MAX_AGE_DAYS))
+                .http_only(true)
+                .secure(jar.secure)
+                .build();
+            cookies.signed_mut(&jar.key).add(cookie);
+        }
+        None if loaded.id.is_some() => {
+            let cookie = Cookie::build(COOKIE)
+                .same_site(SameSite::Lax)
+                .path("/")
+                .http_only(true)
+                .secure(jar.secure)
+                .removal()
+                .build();
+            cookies.add(cookie);
+        }
+        None => {}
+    }
+    Ok(())
+}
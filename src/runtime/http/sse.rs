@@ -0,0 +1,209 @@
+// server-sent events: res:sse() makes the response a text/event-stream and returns the
+// stream to send events on.
+//
+//   routes["/events"] = function(req, res)
+//       res:sse(function(sse)
+//           local news = channel.subscribe("news")
+//           repeat
+//               local message = news:recv()
+//           until not sse:send("news", message)  -- false once the client has left
+//       end)
+//   end
+//
+//   local sse = res:sse()                       -- or keep it, and send from elsewhere
+//   sse:send("update", { id = 1 }, { id = "42", retry = 5000 })  -- tables are sent as json
+//   sse:send(nil, "a plain message event")
+//   sse:comment("still here")
+//   sse:close()
+//
+// a function passed to res:sse() runs in the background while the response is sent, and
+// the stream closes when it returns. a kept stream stays open until it is closed or the
+// client goes away. a comment is sent every 15 seconds so proxies don't close a quiet
+// stream. this is what the htmx sse extension connects to, with sse-swap naming the event.
+use axum::{
+    body::Body,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderValue,
+    },
+};
+use bytes::Bytes;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, Instant},
+};
+
+use super::LuaHeaders;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// events that can be waiting for a slow client before send waits too
+const BUFFER: usize = 64;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let response = lua.globals().get::<LuaTable>("Response")?;
+    response.set("sse", lua.create_function(response_sse)?)?;
+    Ok(())
+}
+
+/// the body of a res:sse() response
+#[derive(Debug)]
+pub struct SseBody(mpsc::Receiver<Bytes>);
+
+impl LuaUserData for SseBody {}
+
+impl SseBody {
+    pub fn into_body(self) -> Body {
+        let keep_alive = interval_at(Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+        let stream = futures_util::stream::unfold(
+            (self.0, keep_alive),
+            |(mut events, mut keep_alive)| async move {
+                let event = tokio::select! {
+                    event = events.recv() => event?,
+                    _ = keep_alive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+                };
+                Some((Ok::<_, std::io::Error>(event), (events, keep_alive)))
+            },
+        );
+        Body::from_stream(stream)
+    }
+}
+
+/// the stream res:sse() returns
+#[derive(Debug, Clone)]
+pub struct LuaSse(Arc<Mutex<Option<mpsc::Sender<Bytes>>>>);
+
+impl LuaSse {
+    /// queue an event, false if the stream is closed
+    async fn send(&self, event: Bytes) -> bool {
+        let sender = self.0.lock().clone();
+        match sender {
+            Some(sender) => sender.send(event).await.is_ok(),
+            None => false,
+        }
+    }
+
+    fn close(&self) {
+        self.0.lock().take();
+    }
+}
+
+/// a field for one line, without the newlines that would end it
+fn field(name: &str, value: &str) -> String {
+    format!("{name}: {}\n", value.replace(['\r', '\n'], " "))
+}
+
+/// an event in the text/event-stream format
+fn event(name: Option<&str>, data: &str, id: Option<&str>, retry: Option<u64>) -> String {
+    let mut event = String::new();
+    if let Some(id) = id {
+        event.push_str(&field("id", id));
+    }
+    if let Some(name) = name {
+        event.push_str(&field("event", name));
+    }
+    if let Some(retry) = retry {
+        event.push_str(&format!("retry: {retry}\n"));
+    }
+    for line in data.split('\n') {
+        event.push_str("data: ");
+        event.push_str(line.strip_suffix('\r').unwrap_or(line));
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+impl LuaUserData for LuaSse {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("closed", |_, this| {
+            Ok(this
+                .0
+                .lock()
+                .as_ref()
+                .is_none_or(|sender| sender.is_closed()))
+        });
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method(
+            "send",
+            |lua, this, (name, data, options): (Option<String>, LuaValue, Option<LuaTable>)| async move {
+                let data = match data {
+                    LuaValue::Nil => String::new(),
+                    LuaValue::String(data) => data.to_str()?.to_string(),
+                    LuaValue::Table(_) => {
+                        let data: serde_json::Value = lua.from_value(data)?;
+                        data.to_string()
+                    }
+                    data => data.to_string()?,
+                };
+                let (id, retry) = match options {
+                    Some(options) => (
+                        options.get::<Option<String>>("id")?,
+                        options.get::<Option<u64>>("retry")?,
+                    ),
+                    None => (None, None),
+                };
+                let event = event(name.as_deref(), &data, id.as_deref(), retry);
+                Ok(this.send(Bytes::from(event)).await)
+            },
+        );
+        methods.add_async_method("comment", |_, this, text: String| async move {
+            let comment: String = text
+                .split('\n')
+                .map(|line| format!(": {}\n", line.trim_end_matches('\r')))
+                .collect();
+            Ok(this.send(Bytes::from(comment + "\n")).await)
+        });
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+    }
+}
+
+/// res:sse(function(sse) ... end)
+fn response_sse(
+    lua: &Lua,
+    (res, callback): (LuaTable, Option<LuaFunction>),
+) -> LuaResult<LuaAnyUserData> {
+    {
+        let headers = res.get::<LuaAnyUserData>("headers")?;
+        let mut headers = headers.borrow_mut::<LuaHeaders>()?;
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        // so nginx sends each event as it comes instead of buffering them
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    }
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    res.set("body", SseBody(receiver))?;
+    let sse = LuaSse(Arc::new(Mutex::new(Some(sender))));
+    let userdata = lua.create_userdata(sse.clone())?;
+    if let Some(callback) = callback {
+        let stream = userdata.clone();
+        tokio::spawn(async move {
+            if let Err(err) = callback.call_async::<()>(stream).await {
+                tracing::error!(?err, "error in sse function");
+            }
+            sse.close();
+        });
+    }
+    Ok(userdata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_events() {
+        assert_eq!(event(None, "hi", None, None), "data: hi\n\n");
+        assert_eq!(
+            event(Some("news\nx"), "a\r\nb", Some("7"), Some(3000)),
+            "id: 7\nevent: news x\nretry: 3000\ndata: a\ndata: b\n\n"
+        );
+    }
+}
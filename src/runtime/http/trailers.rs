@@ -0,0 +1,71 @@
+// trailers are headers sent after the body, for values only known once it is written,
+// like a checksum or server timing:
+//
+//   res.trailers["server-timing"] = "db;dur=" .. elapsed
+//
+// they need a chunked http/1.1 response or http/2, so a response with trailers has no
+// Content-Length, and http/1.1 clients only get them if they ask with TE: trailers.
+use axum::{
+    body::{Body, HttpBody},
+    http::{
+        header::{CONTENT_LENGTH, TRAILER},
+        HeaderMap, HeaderValue,
+    },
+};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// a body followed by trailers
+pub struct WithTrailers {
+    body: Body,
+    trailers: Option<HeaderMap>,
+}
+
+impl WithTrailers {
+    /// the body for a response with trailers, announcing them in the Trailer header
+    pub fn wrap(body: Body, trailers: HeaderMap, headers: &mut HeaderMap) -> Body {
+        if trailers.is_empty() {
+            return body;
+        }
+        let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+        if let Ok(value) = HeaderValue::from_str(&names.join(", ")) {
+            headers.insert(TRAILER, value);
+        }
+        headers.remove(CONTENT_LENGTH);
+        Body::new(Self {
+            body,
+            trailers: Some(trailers),
+        })
+    }
+}
+
+impl HttpBody for WithTrailers {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_frame(cx) {
+            Poll::Ready(None) => Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t)))),
+            frame => frame,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // an exact size would be sent as Content-Length, which leaves no room for trailers
+        let mut hint = SizeHint::new();
+        hint.set_lower(self.body.size_hint().lower());
+        hint
+    }
+}
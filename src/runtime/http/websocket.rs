@@ -1,31 +1,63 @@
-use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+// websockets from on_ws_connect(ws, path)
+//
+//   ws:send("text") or ws:send(ws.binary(bytes))
+//   ws:send_many({ "a", "b", ws.binary(bytes) })
+//   local message = ws:recv()  -- nil once the client has gone
+//
+// messages wait in a queue of QUEUE_SIZE while they are written to the client, so send
+// returns as soon as a message is queued and one slow client can't hold up a broadcast
+// to the others. ws:buffered() is how many are waiting. a client too slow to keep the
+// queue from filling is disconnected: the socket is closed with code 1008 and the send
+// that found it full raises an error, as do sends after it.
+use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket};
+use bytes::Bytes;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use mlua::prelude::*;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// messages a websocket holds for a client before it is too slow
+const QUEUE_SIZE: usize = 256;
+
+/// the close code for a policy violation, which falling behind is
+const OVERFLOW_CODE: u16 = 1008;
+
+/// how long to try to tell a client it was too slow
+const OVERFLOW_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct LuaMessage(Message);
 
 pub struct LuaWebSocket {
-    sender: Mutex<SplitSink<WebSocket, Message>>,
+    queue: mpsc::Sender<Message>,
     receiver: Mutex<SplitStream<WebSocket>>,
+    /// cancelled when the queue overflows, which closes the socket
+    overflow: CancellationToken,
 }
 
 impl LuaWebSocket {
     pub fn new(ws: WebSocket) -> Self {
         let (sender, receiver) = ws.split();
+        let (queue, messages) = mpsc::channel(QUEUE_SIZE);
+        let overflow = CancellationToken::new();
+        tokio::spawn(write_queue(sender, messages, overflow.clone()));
 
         LuaWebSocket {
-            sender: Mutex::new(sender),
+            queue,
             receiver: Mutex::new(receiver),
+            overflow,
         }
     }
 
-    async fn send(&self, msg: LuaMessage) -> Result<(), LuaError> {
-        let mut sender = self.sender.lock().await;
-        sender.send(msg.into()).await.into_lua_err()
+    fn send_all(&self, messages: Vec<LuaMessage>) -> Result<(), LuaError> {
+        queue_all(&self.queue, &self.overflow, messages)
+    }
+
+    fn buffered(&self) -> usize {
+        QUEUE_SIZE - self.queue.capacity()
     }
 
     async fn recv(&self) -> Result<Option<LuaMessage>, LuaError> {
@@ -35,6 +67,75 @@ impl LuaWebSocket {
     }
 }
 
+/// queue every message or none of them, cancelling overflow if the client is behind
+fn queue_all(
+    queue: &mpsc::Sender<Message>,
+    overflow: &CancellationToken,
+    messages: Vec<LuaMessage>,
+) -> Result<(), LuaError> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    if queue.is_closed() {
+        return Err(LuaError::runtime("websocket is closed"));
+    }
+    // more than the queue holds would never fit, however fast the client is
+    if messages.len() > QUEUE_SIZE {
+        return Err(LuaError::runtime(format!(
+            "can't send more than {QUEUE_SIZE} websocket messages at once"
+        )));
+    }
+    let permits = match queue.try_reserve_many(messages.len()) {
+        Ok(permits) => permits,
+        Err(mpsc::error::TrySendError::Full(())) => {
+            overflow.cancel();
+            return Err(LuaError::runtime(
+                "websocket send queue is full, the client is too slow and was disconnected",
+            ));
+        }
+        Err(mpsc::error::TrySendError::Closed(())) => {
+            return Err(LuaError::runtime("websocket is closed"));
+        }
+    };
+    for (permit, LuaMessage(message)) in permits.zip(messages) {
+        permit.send(message);
+    }
+    Ok(())
+}
+
+/// write queued messages to the client until the queue or the socket closes
+async fn write_queue(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut messages: mpsc::Receiver<Message>,
+    overflow: CancellationToken,
+) {
+    loop {
+        let message = tokio::select! {
+            _ = overflow.cancelled() => break,
+            message = messages.recv() => message,
+        };
+        let Some(message) = message else {
+            return;
+        };
+        tokio::select! {
+            _ = overflow.cancelled() => break,
+            sent = sink.send(message) => if sent.is_err() {
+                return;
+            },
+        }
+    }
+    messages.close();
+    let frame = CloseFrame {
+        code: OVERFLOW_CODE,
+        reason: "too slow".into(),
+    };
+    let _ = tokio::time::timeout(
+        OVERFLOW_CLOSE_TIMEOUT,
+        sink.send(Message::Close(Some(frame))),
+    )
+    .await;
+}
+
 impl From<LuaMessage> for Message {
     fn from(val: LuaMessage) -> Self {
         val.0
@@ -49,10 +150,13 @@ impl From<Message> for LuaMessage {
 
 impl LuaUserData for LuaWebSocket {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        methods.add_async_method("send", |lua, this, msg: LuaValue| async move {
-            let msg = LuaMessage::from_lua(msg, &lua)?;
-            this.send(msg).await
+        methods.add_method("send", |_, this, msg: LuaMessage| this.send_all(vec![msg]));
+        // queues every message or, if they don't all fit, none of them. a batch larger
+        // than the queue is an error, but the client stays connected
+        methods.add_method("send_many", |_, this, messages: Vec<LuaMessage>| {
+            this.send_all(messages)
         });
+        methods.add_method("buffered", |_, this, ()| Ok(this.buffered()));
         methods.add_async_method("recv", |_lua, this, ()| async move { this.recv().await });
     }
 
@@ -113,12 +217,13 @@ impl FromLua for LuaMessage {
             }
             LuaValue::Table(table) => {
                 let msg_type: String = table.get("type")?;
-                let data: String = table.get("data")?;
+                // binary data needn't be utf-8
+                let data = Bytes::copy_from_slice(&table.get::<LuaString>("data")?.as_bytes());
 
                 match msg_type.as_str() {
-                    "binary" => Ok(LuaMessage(Message::Binary(data.into()))),
-                    "ping" => Ok(LuaMessage(Message::Ping(data.into()))),
-                    "pong" => Ok(LuaMessage(Message::Pong(data.into()))),
+                    "binary" => Ok(LuaMessage(Message::Binary(data))),
+                    "ping" => Ok(LuaMessage(Message::Ping(data))),
+                    "pong" => Ok(LuaMessage(Message::Pong(data))),
                     _ => Err(LuaError::RuntimeError("Invalid message type".into())),
                 }
             }
@@ -151,4 +256,20 @@ mod tests {
         let msg = lua.globals().get::<LuaMessage>("msg").unwrap();
         assert_eq!(msg.0, Message::Binary("stuff".into()))
     }
+
+    #[test]
+    fn rejects_batches_larger_than_the_queue() {
+        let (queue, mut messages) = mpsc::channel(QUEUE_SIZE);
+        let overflow = CancellationToken::new();
+        let batch = |n| (0..n).map(|i| LuaMessage(Message::Text(format!("{i}").into())));
+
+        assert!(queue_all(&queue, &overflow, batch(QUEUE_SIZE + 1).collect()).is_err());
+        assert!(!overflow.is_cancelled());
+        assert!(messages.try_recv().is_err());
+
+        queue_all(&queue, &overflow, batch(QUEUE_SIZE).collect()).unwrap();
+        assert!(!overflow.is_cancelled());
+        assert!(queue_all(&queue, &overflow, batch(1).collect()).is_err());
+        assert!(overflow.is_cancelled());
+    }
 }
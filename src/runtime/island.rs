@@ -0,0 +1,70 @@
+// json islands: data from lua put in a page for scripts to read.
+//
+//   res:island("cart", cart)
+//
+// adds <script type="application/json" id="cart">...</script> to the page res renders,
+// before </body>; templates can call island("cart", cart) to put one where they like.
+// an alpine component reads it with
+//
+//   <div x-data="{ cart: JSON.parse(document.getElementById('cart').textContent) }">
+//
+// the json is escaped so nothing in the data can close the script tag, and json.island
+// returns the same html for other uses.
+use mlua::prelude::*;
+use serde_json::Value;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let json = lua.globals().get::<LuaTable>("json")?;
+    json.set(
+        "island",
+        lua.create_function(|lua, (name, data): (String, LuaValue)| {
+            let data: Value = lua.from_value(data)?;
+            html(&name, &data).into_lua_err()
+        })?,
+    )?;
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// the script tag for an island. <, > and & are written as unicode escapes, which are
+/// the same json but can't end the script or start a comment in it.
+pub fn html(name: &str, data: &Value) -> serde_json::Result<String> {
+    let json = serde_json::to_string(data)?
+        .replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026")
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029");
+    Ok(format!(
+        "<script type=\"application/json\" id=\"{}\">{json}</script>\n",
+        escape(name)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_cannot_close_the_script() {
+        let data = serde_json::json!({ "note": "</script><script>alert(1)</script> & more" });
+        let html = html("x\"y", &data).expect("html");
+        assert_eq!(
+            html,
+            "<script type=\"application/json\" id=\"x&quot;y\">\
+             {\"note\":\"\\u003c/script\\u003e\\u003cscript\\u003ealert(1)\\u003c/script\\u003e \\u0026 more\"}\
+             </script>\n"
+        );
+        let json = html
+            .trim_start_matches(|c| c != '>')
+            .trim_start_matches('>')
+            .trim_end_matches("</script>\n");
+        assert_eq!(serde_json::from_str::<Value>(json).expect("json"), data);
+    }
+}
@@ -122,7 +122,7 @@ impl LuaUserData for LuaServiceInfo {
     }
 }
 
-fn get_service_daemon(lua: &Lua) -> LuaResult<ServiceDaemon> {
+pub fn get_service_daemon(lua: &Lua) -> LuaResult<ServiceDaemon> {
     let daemon = lua.named_registry_value::<LuaAnyUserData>(MDNS_SERVICE_DAEMON)?;
     let daemon = daemon
         .borrow::<LuaServiceDaemon>()
@@ -0,0 +1,192 @@
+// net.serve(addr, handler, options) runs a tcp server for line protocols and the like
+//
+//   net.serve("127.0.0.1:7000", function(conn, peer)
+//       while true do
+//           local line = conn:read_line()
+//           if not line then break end
+//           conn:write(line .. "\n")
+//       end
+//   end, { max_connections = 100 })
+//
+// each connection runs in its own task. at shutdown (or when the app is reloaded) the
+// server stops accepting and reads on open connections return nil.
+use mlua::prelude::*;
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener,
+    },
+    sync::Semaphore,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+const NET: &str = "net";
+const DEFAULT_MAX_CONNECTIONS: usize = 100;
+
+/// servers belong to a single lua state; connections are tracked with the in-flight
+/// requests so shutdown waits for them.
+#[derive(Debug, Clone)]
+struct NetState {
+    token: CancellationToken,
+    listeners: TaskTracker,
+    connections: TaskTracker,
+}
+
+impl LuaUserData for NetState {}
+
+pub fn register(lua: &Lua, token: CancellationToken, connections: &TaskTracker) -> LuaResult<()> {
+    lua.set_named_registry_value(
+        NET,
+        NetState {
+            token,
+            listeners: TaskTracker::new(),
+            connections: connections.clone(),
+        },
+    )?;
+
+    let net = lua.create_table()?;
+    net.set("serve", lua.create_async_function(net_serve)?)?;
+    lua.globals().set("net", net)?;
+    Ok(())
+}
+
+/// stop accepting connections on every server started by this lua state and wait for
+/// the listeners to close, so a reloaded app can bind the same address.
+pub async fn stop(lua: &Lua) -> LuaResult<()> {
+    let state = lua.named_registry_value::<LuaUserDataRef<NetState>>(NET)?;
+    let state = NetState::clone(&state);
+    state.token.cancel();
+    state.listeners.close();
+    state.listeners.wait().await;
+    Ok(())
+}
+
+/// net.serve(addr, handler, { max_connections = 100 })
+/// returns the bound address, which is useful when binding to port 0
+async fn net_serve(
+    lua: Lua,
+    (addr, handler, options): (String, LuaFunction, Option<LuaTable>),
+) -> LuaResult<String> {
+    let max_connections = options
+        .map(|options| options.get::<Option<usize>>("max_connections"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS)
+        .max(1);
+    let state = lua.named_registry_value::<LuaUserDataRef<NetState>>(NET)?;
+    let state = NetState::clone(&state);
+    if state.token.is_cancelled() {
+        return Err(LuaError::runtime("net.serve called during shutdown"));
+    }
+
+    let listener = TcpListener::bind(&addr).await.into_lua_err()?;
+    let local_addr = listener.local_addr().into_lua_err()?.to_string();
+    tracing::info!(addr = %local_addr, "net: listening");
+
+    let limit = Arc::new(Semaphore::new(max_connections));
+    state.listeners.spawn(async move {
+        loop {
+            let permit = tokio::select! {
+                _ = state.token.cancelled() => break,
+                permit = limit.clone().acquire_owned() => permit.expect("semaphore is never closed"),
+            };
+            let (stream, peer) = tokio::select! {
+                _ = state.token.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::error!(?err, "net: error accepting connection");
+                        continue;
+                    }
+                },
+            };
+
+            let (reader, writer) = stream.into_split();
+            let conn = LuaConnection {
+                reader: Some(BufReader::new(reader)),
+                writer: Some(writer),
+                token: state.token.clone(),
+            };
+            let handler = handler.clone();
+            state.connections.spawn(async move {
+                if let Err(err) = handler.call_async::<()>((conn, peer.to_string())).await {
+                    tracing::error!(?err, %peer, "net: error handling connection");
+                }
+                drop(permit);
+            });
+        }
+        tracing::info!("net: stopped listening");
+    });
+
+    Ok(local_addr)
+}
+
+pub struct LuaConnection {
+    reader: Option<BufReader<OwnedReadHalf>>,
+    writer: Option<OwnedWriteHalf>,
+    token: CancellationToken,
+}
+
+impl LuaUserData for LuaConnection {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // conn:read_line() returns the next line without its line ending,
+        // or nil when the connection is closed
+        methods.add_async_method_mut("read_line", |lua, mut this, ()| async move {
+            let token = this.token.clone();
+            let Some(reader) = this.reader.as_mut() else {
+                return Ok(None);
+            };
+            let mut line = Vec::new();
+            let read = tokio::select! {
+                _ = token.cancelled() => return Ok(None),
+                read = reader.read_until(b'\n', &mut line) => read.into_lua_err()?,
+            };
+            if read == 0 {
+                return Ok(None);
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+            }
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+            Ok(Some(lua.create_string(line)?))
+        });
+
+        // conn:read(n) returns up to n bytes, or nil when the connection is closed
+        methods.add_async_method_mut("read", |lua, mut this, size: usize| async move {
+            let token = this.token.clone();
+            let Some(reader) = this.reader.as_mut() else {
+                return Ok(None);
+            };
+            let mut buf = vec![0; size.max(1)];
+            let read = tokio::select! {
+                _ = token.cancelled() => return Ok(None),
+                read = reader.read(&mut buf) => read.into_lua_err()?,
+            };
+            if read == 0 {
+                return Ok(None);
+            }
+            Ok(Some(lua.create_string(&buf[..read])?))
+        });
+
+        methods.add_async_method_mut("write", |_, mut this, data: LuaString| async move {
+            let data = data.as_bytes().to_vec();
+            let Some(writer) = this.writer.as_mut() else {
+                return Err(LuaError::runtime("connection is closed"));
+            };
+            writer.write_all(&data).await.into_lua_err()?;
+            Ok(())
+        });
+
+        methods.add_async_method_mut("close", |_, mut this, ()| async move {
+            this.reader.take();
+            if let Some(mut writer) = this.writer.take() {
+                writer.shutdown().await.into_lua_err()?;
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,241 @@
+// open graph tags for link previews, and reading them from other pages.
+//
+//   og.tags { title = post.title, description = post.summary, image = post.cover }
+//
+// returns the <meta> tags as a string; templates can call og_tags(title=..., ...) instead.
+// url, type, site_name and any other key become og: properties too, and the twitter
+// card tags are added for title, description and image.
+//
+//   local preview = og.fetch("https://example.com/post")
+//   preview.title, preview.description, preview.image, preview.url
+//
+// fetch reads at most 1MB of html and gives up after 10 seconds. every og: property on
+// the page is in the result without its prefix, falling back to <title> and the meta
+// description, and relative image urls are made absolute.
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use mlua::prelude::*;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use super::http::fetch::default_client;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PAGE_SIZE: usize = 1024 * 1024;
+
+/// keys with tags of their own, in the order their tags are written
+const KNOWN_KEYS: [&str; 6] = ["title", "description", "image", "url", "type", "site_name"];
+
+static META: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<meta\s[^>]*>").expect("valid regex"));
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).expect("valid regex")
+});
+static TITLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("valid regex"));
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let og = lua.create_table()?;
+    og.set(
+        "tags",
+        lua.create_function(|_, tags: HashMap<String, String>| Ok(tags_html(tags)))?,
+    )?;
+    og.set("fetch", lua.create_async_function(og_fetch)?)?;
+    lua.globals().set("og", og)?;
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// the <meta> tags for title, description, image and any other og properties
+pub fn tags_html(mut tags: HashMap<String, String>) -> String {
+    let mut html = String::new();
+    let mut meta = |attribute: &str, name: &str, content: &str| {
+        let _ = writeln!(
+            html,
+            "<meta {attribute}=\"{}\" content=\"{}\">",
+            escape(name),
+            escape(content)
+        );
+    };
+
+    if !tags.contains_key("type") {
+        tags.insert("type".to_string(), "website".to_string());
+    }
+    let mut others: Vec<&String> = tags
+        .keys()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+        .collect();
+    others.sort();
+    let keys = KNOWN_KEYS
+        .iter()
+        .copied()
+        .chain(others.iter().map(|key| key.as_str()));
+
+    for key in keys {
+        let Some(content) = tags.get(key) else {
+            continue;
+        };
+        meta("property", &format!("og:{key}"), content);
+        match key {
+            "description" => {
+                meta("name", "description", content);
+                meta("name", "twitter:description", content);
+            }
+            "title" => meta("name", "twitter:title", content),
+            "image" => meta("name", "twitter:image", content),
+            _ => {}
+        }
+    }
+    let card = if tags.contains_key("image") {
+        "summary_large_image"
+    } else {
+        "summary"
+    };
+    meta("name", "twitter:card", card);
+    html
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+/// the og properties of a page, and its title and description
+fn parse_html(html: &str) -> HashMap<String, String> {
+    let mut found = HashMap::new();
+    let mut description = None;
+    for tag in META.find_iter(html) {
+        let mut name = None;
+        let mut content = None;
+        for attribute in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = attribute
+                .get(2)
+                .or(attribute.get(3))
+                .or(attribute.get(4))
+                .map(|value| unescape(value.as_str()));
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => name = value.map(|name| name.to_ascii_lowercase()),
+                "content" => content = value,
+                _ => {}
+            }
+        }
+        let (Some(name), Some(content)) = (name, content) else {
+            continue;
+        };
+        if let Some(property) = name.strip_prefix("og:") {
+            found.entry(property.to_string()).or_insert(content);
+        } else if name == "description" {
+            description.get_or_insert(content);
+        }
+    }
+
+    if !found.contains_key("title") {
+        if let Some(title) = TITLE.captures(html) {
+            found.insert("title".to_string(), unescape(&title[1]));
+        }
+    }
+    if let Some(description) = description {
+        found
+            .entry("description".to_string())
+            .or_insert(description);
+    }
+    found
+}
+
+/// og.fetch(url)
+async fn og_fetch(lua: Lua, url: String) -> LuaResult<LuaTable> {
+    let client = default_client(&lua, &url)?;
+    let deadline = Instant::now() + FETCH_TIMEOUT;
+    let mut response = client
+        .get(&url)
+        .header(ACCEPT, "text/html,application/xhtml+xml")
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .into_lua_err()?
+        .error_for_status()
+        .into_lua_err()?;
+
+    // pages without a content type are read too, since many of them are html
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(str::to_ascii_lowercase);
+    if content_type.is_some_and(|content_type| !content_type.contains("html")) {
+        return Err(LuaError::runtime(format!("{url} is not an html page")));
+    }
+
+    let page_url = response.url().clone();
+    let mut page = Vec::new();
+    while page.len() < MAX_PAGE_SIZE {
+        let chunk = tokio::time::timeout_at(deadline.into(), response.chunk())
+            .await
+            .map_err(|_| LuaError::runtime(format!("timed out reading {url}")))?
+            .into_lua_err()?;
+        match chunk {
+            Some(chunk) => page.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    page.truncate(MAX_PAGE_SIZE);
+
+    let mut found = parse_html(&String::from_utf8_lossy(&page));
+    if let Some(image) = found.get_mut("image") {
+        if let Ok(absolute) = page_url.join(image) {
+            *image = absolute.to_string();
+        }
+    }
+    found
+        .entry("url".to_string())
+        .or_insert_with(|| page_url.to_string());
+
+    lua.create_table_from(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_og_properties_and_fallbacks() {
+        let html = r#"<html><head>
+            <title>Plain &amp; Simple</title>
+            <meta name="description" content="the page">
+            <meta property="og:image" content='/cover.png'>
+            <meta content="Article" property="og:type" />
+        </head></html>"#;
+        let found = parse_html(html);
+        assert_eq!(found["title"], "Plain & Simple");
+        assert_eq!(found["description"], "the page");
+        assert_eq!(found["image"], "/cover.png");
+        assert_eq!(found["type"], "Article");
+    }
+
+    #[test]
+    fn tags_are_escaped() {
+        let tags = HashMap::from([("title".to_string(), "a \"quoted\" <title>".to_string())]);
+        let html = tags_html(tags);
+        assert!(html.contains(
+            "<meta property=\"og:title\" content=\"a &quot;quoted&quot; &lt;title&gt;\">"
+        ));
+        assert!(html.contains("<meta name=\"twitter:card\" content=\"summary\">"));
+    }
+}
@@ -1,10 +1,23 @@
 // async version of standard lua os library
+//
+// os.time, os.date and os.clock work as they do in lua, except that os.clock measures
+// the time since the app started rather than cpu time, which every request shares.
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeDelta, TimeZone, Timelike, Utc};
 use mlua::prelude::*;
+use std::{fmt::Write, sync::LazyLock, time::Instant};
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
 
 pub fn register(lua: &Lua) -> LuaResult<()> {
+    LazyLock::force(&STARTED);
+
     let os = lua.create_table()?;
     os.set("execute", lua.create_async_function(os_execute)?)?;
     os.set("getenv", lua.create_function(os_getenv)?)?;
+    os.set("time", lua.create_function(os_time)?)?;
+    os.set("date", lua.create_function(os_date)?)?;
+    os.set("clock", lua.create_function(os_clock)?)?;
+    os.set("difftime", lua.create_function(os_difftime)?)?;
 
     #[cfg(target_os = "windows")]
     os.set("name", "windows")?;
@@ -35,6 +48,94 @@ fn os_getenv(_lua: &Lua, key: String) -> LuaResult<Option<String>> {
     Ok(std::env::var(key).ok())
 }
 
+/// the current time, or the time a table of local date fields names. fields out of
+/// range carry over, so { year = 2024, month = 13, day = 1 } is january 2025.
+fn os_time(_lua: &Lua, date: Option<LuaTable>) -> LuaResult<i64> {
+    let Some(date) = date else {
+        return Ok(Utc::now().timestamp());
+    };
+    let field = |name: &str, default: Option<i64>| -> LuaResult<i64> {
+        date.get::<Option<i64>>(name)?.or(default).ok_or_else(|| {
+            LuaError::runtime(format!("os.time: field '{name}' missing in date table"))
+        })
+    };
+    let out_of_range = || LuaError::runtime("os.time: date out of range");
+
+    let (year, month) = (field("year", None)?, field("month", None)?);
+    let months = year
+        .checked_mul(12)
+        .and_then(|months| months.checked_add(month.checked_sub(1)?))
+        .ok_or_else(out_of_range)?;
+    let year = i32::try_from(months.div_euclid(12)).map_err(|_| out_of_range())?;
+    let first = NaiveDate::from_ymd_opt(year, months.rem_euclid(12) as u32 + 1, 1)
+        .ok_or_else(out_of_range)?;
+    let (day, hour) = (field("day", None)?, field("hour", Some(12))?);
+    let (min, sec) = (field("min", Some(0))?, field("sec", Some(0))?);
+    let seconds = day
+        .checked_sub(1)
+        .and_then(|days| days.checked_mul(86400))
+        .and_then(|s| s.checked_add(hour.checked_mul(3600)?))
+        .and_then(|s| s.checked_add(min.checked_mul(60)?))
+        .and_then(|s| s.checked_add(sec))
+        .ok_or_else(out_of_range)?;
+    let time = first
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.checked_add_signed(TimeDelta::try_seconds(seconds)?))
+        .ok_or_else(out_of_range)?;
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.timestamp())
+        .ok_or_else(out_of_range)
+}
+
+/// a time formatted with strftime, or "*t" for a table of its fields. a format starting
+/// with ! is in utc rather than local time.
+fn os_date(lua: &Lua, (format, time): (Option<String>, Option<i64>)) -> LuaResult<LuaValue> {
+    let format = format.as_deref().unwrap_or("%c");
+    let time = match time {
+        Some(time) => DateTime::from_timestamp(time, 0)
+            .ok_or_else(|| LuaError::runtime("os.date: time out of range"))?,
+        None => Utc::now(),
+    };
+    match format.strip_prefix('!') {
+        Some(format) => format_date(lua, format, time),
+        None => format_date(lua, format, time.with_timezone(&Local)),
+    }
+}
+
+fn format_date<Tz: TimeZone>(lua: &Lua, format: &str, time: DateTime<Tz>) -> LuaResult<LuaValue>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    if format == "*t" {
+        let date = lua.create_table()?;
+        date.set("year", time.year())?;
+        date.set("month", time.month())?;
+        date.set("day", time.day())?;
+        date.set("hour", time.hour())?;
+        date.set("min", time.minute())?;
+        date.set("sec", time.second())?;
+        date.set("wday", time.weekday().number_from_sunday())?;
+        date.set("yday", time.ordinal())?;
+        date.set("isdst", false)?;
+        return Ok(LuaValue::Table(date));
+    }
+    let mut text = String::new();
+    write!(text, "{}", time.format(format))
+        .map_err(|_| LuaError::runtime(format!("os.date: invalid conversion in '{format}'")))?;
+    text.into_lua(lua)
+}
+
+/// seconds since the app started
+fn os_clock(_lua: &Lua, _: ()) -> LuaResult<f64> {
+    Ok(STARTED.elapsed().as_secs_f64())
+}
+
+fn os_difftime(_lua: &Lua, (t2, t1): (i64, Option<i64>)) -> LuaResult<f64> {
+    Ok((t2 - t1.unwrap_or(0)) as f64)
+}
+
 #[cfg(target_os = "windows")]
 async fn os_execute(_lua: Lua, command: String) -> LuaResult<(Option<bool>, String, i32)> {
     let output = tokio::process::Command::new("powershell")
@@ -0,0 +1,202 @@
+// paginate(source, options) returns one page of a global table or a sql query
+//
+//   local page = paginate(global.posts, { page = req.query.page, per_page = 20 })
+//   local page = paginate("SELECT * FROM posts WHERE author = ?", { params = { author } })
+//
+// the result has the rows in items plus page, per_page, total, pages, has_next, has_prev,
+// next_page and prev_page, so templates can render the links. items from a global table
+// are { key = key, value = value } in insertion order. a page past the last one is the
+// last one.
+use mlua::prelude::*;
+use rusqlite::types::Value;
+
+use crate::database::{global::GlobalTable, Database};
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 1000;
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let database = database.clone();
+    let paginate = lua.create_async_function(
+        move |lua, (source, options): (LuaValue, Option<LuaTable>)| {
+            let database = database.clone();
+            async move { paginate(lua, database, source, options).await }
+        },
+    )?;
+    lua.globals().set("paginate", paginate)?;
+    Ok(())
+}
+
+async fn paginate(
+    lua: Lua,
+    database: Database,
+    source: LuaValue,
+    options: Option<LuaTable>,
+) -> LuaResult<LuaTable> {
+    let page = match &options {
+        Some(options) => parse_page(options.get("page")?),
+        None => 1,
+    };
+    let per_page = match &options {
+        Some(options) => options
+            .get::<Option<usize>>("per_page")?
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE),
+        None => DEFAULT_PER_PAGE,
+    };
+
+    let items = lua.create_table()?;
+    let (total, page) = match source {
+        LuaValue::UserData(ud) if ud.is::<GlobalTable>() => {
+            let table = ud.borrow::<GlobalTable>()?;
+            let total = table.count().await.into_lua_err()?;
+            let (page, offset) = clamp_page(page, per_page, total);
+            let rows = table
+                .slice::<serde_json::Value>(offset, per_page)
+                .await
+                .into_lua_err()?;
+            for (key, value) in rows {
+                let item = lua.create_table()?;
+                item.set("key", lua.to_value(&key)?)?;
+                item.set("value", lua.to_value(&value)?)?;
+                items.push(item)?;
+            }
+            (total, page)
+        }
+        LuaValue::String(sql) => {
+            let sql = sql.to_str()?.trim().trim_end_matches(';').to_string();
+            let params = match &options {
+                Some(options) => options.get::<Option<Vec<LuaValue>>>("params")?,
+                None => None,
+            };
+            let params = params
+                .unwrap_or_default()
+                .into_iter()
+                .map(to_sql_value)
+                .collect::<LuaResult<Vec<_>>>()?;
+            let (total, page, rows) = query_page(&database, sql, params, page, per_page)
+                .await
+                .into_lua_err()?;
+            for row in rows {
+                let item = lua.create_table()?;
+                for (column, value) in row {
+                    item.set(column, from_sql_value(&lua, value)?)?;
+                }
+                items.push(item)?;
+            }
+            (total, page)
+        }
+        _ => {
+            return Err(LuaError::runtime(
+                "paginate source must be a global table or a sql query",
+            ))
+        }
+    };
+
+    let pages = total.div_ceil(per_page).max(1);
+    let result = lua.create_table()?;
+    result.set("items", items)?;
+    result.set("page", page)?;
+    result.set("per_page", per_page)?;
+    result.set("total", total)?;
+    result.set("pages", pages)?;
+    result.set("has_prev", page > 1)?;
+    result.set("has_next", page < pages)?;
+    result.set("prev_page", (page > 1).then(|| page - 1))?;
+    result.set("next_page", (page < pages).then(|| page + 1))?;
+
+    Ok(result)
+}
+
+/// page, past the last page brought back to it, and the offset of its first item
+fn clamp_page(page: usize, per_page: usize, total: usize) -> (usize, usize) {
+    let pages = total.div_ceil(per_page).max(1);
+    let page = page.min(pages);
+    (page, (page - 1) * per_page)
+}
+
+/// page usually comes straight from req.query, so it may be a string or missing.
+/// anything that is not a positive integer is treated as the first page.
+fn parse_page(page: LuaValue) -> usize {
+    let page = match page {
+        LuaValue::Integer(page) => Some(page),
+        LuaValue::Number(page) => Some(page as i64),
+        LuaValue::String(page) => page.to_str().ok().and_then(|p| p.trim().parse().ok()),
+        _ => None,
+    };
+    page.filter(|page| *page > 0).unwrap_or(1) as usize
+}
+
+async fn query_page(
+    database: &Database,
+    sql: String,
+    params: Vec<Value>,
+    page: usize,
+    limit: usize,
+) -> Result<(usize, usize, Vec<Vec<(String, Value)>>), crate::database::Error> {
+    database
+        .call(move |conn| {
+            let count_sql = format!("SELECT count(*) FROM ({sql})");
+            let total: usize = conn.query_row(
+                &count_sql,
+                rusqlite::params_from_iter(params.iter()),
+                |row| row.get(0),
+            )?;
+            let (page, offset) = clamp_page(page, limit, total);
+
+            let page_sql = format!("SELECT * FROM ({sql}) LIMIT {limit} OFFSET {offset}");
+            let mut stmt = conn.prepare(&page_sql)?;
+            let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+            let mut query = stmt.query(rusqlite::params_from_iter(params.iter()))?;
+            let mut rows = vec![];
+            while let Some(row) = query.next()? {
+                let mut values = Vec::with_capacity(columns.len());
+                for (index, column) in columns.iter().enumerate() {
+                    values.push((column.clone(), row.get::<_, Value>(index)?));
+                }
+                rows.push(values);
+            }
+
+            Ok((total, page, rows))
+        })
+        .await
+}
+
+pub fn to_sql_value(value: LuaValue) -> LuaResult<Value> {
+    Ok(match value {
+        LuaValue::Nil => Value::Null,
+        LuaValue::Boolean(b) => Value::Integer(b as i64),
+        LuaValue::Integer(i) => Value::Integer(i),
+        LuaValue::Number(n) => Value::Real(n),
+        LuaValue::String(s) => Value::Text(s.to_str()?.to_string()),
+        other => {
+            return Err(LuaError::runtime(format!(
+                "cannot use a {} as a sql parameter",
+                other.type_name()
+            )))
+        }
+    })
+}
+
+pub fn from_sql_value(lua: &Lua, value: Value) -> LuaResult<LuaValue> {
+    Ok(match value {
+        Value::Null => LuaValue::Nil,
+        Value::Integer(i) => LuaValue::Integer(i),
+        Value::Real(n) => LuaValue::Number(n),
+        Value::Text(s) => LuaValue::String(lua.create_string(s)?),
+        Value::Blob(b) => LuaValue::String(lua.create_string(b)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_pages() {
+        assert_eq!(clamp_page(1, 20, 0), (1, 0));
+        assert_eq!(clamp_page(2, 20, 45), (2, 20));
+        assert_eq!(clamp_page(9, 20, 45), (3, 40));
+        assert_eq!(clamp_page(usize::MAX, MAX_PER_PAGE, 10), (1, 0));
+    }
+}
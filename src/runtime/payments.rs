@@ -0,0 +1,345 @@
+// checkout sessions and webhooks for stripe, or anything with the same api.
+//
+//   payments.config {
+//       secret_key = os.getenv("STRIPE_SECRET_KEY"),
+//       webhook_secret = os.getenv("STRIPE_WEBHOOK_SECRET"),
+//       -- api_base = "https://api.stripe.com", tolerance = 300
+//   }
+//
+//   local session = payments.checkout {
+//       mode = "payment",
+//       line_items = { { price = "price_123", quantity = 1 } },
+//       success_url = "https://example.com/thanks?session={CHECKOUT_SESSION_ID}",
+//       cancel_url = "https://example.com/cart",
+//       metadata = { order = order.id },
+//       idempotency_key = "order-" .. order.id,  -- optional, sent as a header
+//   }
+//   res:redirect(session.url)
+//
+//   payments.session(id)  -- a checkout session, to check it was paid
+//
+//   payments.on("checkout.session.completed", function(event)
+//       fulfill(event.data.object.metadata.order)
+//   end)
+//   payments.on("*", function(event) end)  -- every event
+//   payments.events({ type = "checkout.session.completed", limit = 10 })
+//
+// point the webhook at /_lilguy/payments/webhook. events are checked against the
+// Stripe-Signature header, saved in lg_payment_events and handed to the handlers. if a
+// handler fails, the webhook returns 500 so the event is sent again; events that were
+// handled are only saved once. apps with their own route can use payments.verify(body,
+// signature), which returns the event or nil.
+use axum::http::{HeaderMap, StatusCode};
+use hmac::{Hmac, Mac};
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde_json::Value;
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+
+use crate::database::{payments as store, Database};
+
+const PAYMENTS: &str = "payments";
+const PAYMENTS_HANDLERS: &str = "payments_handlers";
+
+/// where the payment provider sends events, relative to the app
+pub const WEBHOOK_PATH: &str = "/_lilguy/payments/webhook";
+
+const DEFAULT_API_BASE: &str = "https://api.stripe.com";
+/// how old a signed webhook may be, in seconds
+const DEFAULT_TOLERANCE: u64 = 300;
+const DEFAULT_EVENTS_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Default)]
+struct Config {
+    secret_key: Option<String>,
+    webhook_secret: Option<String>,
+    api_base: Option<String>,
+    tolerance: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct PaymentsState {
+    database: Database,
+    client: reqwest::Client,
+    config: Arc<Mutex<Config>>,
+}
+
+impl LuaUserData for PaymentsState {}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(format!("lilguy/{}", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .into_lua_err()?;
+    lua.set_named_registry_value(
+        PAYMENTS,
+        PaymentsState {
+            database: database.clone(),
+            client,
+            config: Arc::default(),
+        },
+    )?;
+    lua.set_named_registry_value(PAYMENTS_HANDLERS, lua.create_table()?)?;
+
+    let payments = lua.create_table()?;
+    payments.set("config", lua.create_function(payments_config)?)?;
+    payments.set("checkout", lua.create_async_function(payments_checkout)?)?;
+    payments.set("session", lua.create_async_function(payments_session)?)?;
+    payments.set("on", lua.create_function(payments_on)?)?;
+    payments.set("verify", lua.create_function(payments_verify)?)?;
+    payments.set("events", lua.create_async_function(payments_events)?)?;
+    lua.globals().set("payments", payments)?;
+    Ok(())
+}
+
+fn state(lua: &Lua) -> LuaResult<PaymentsState> {
+    let state = lua.named_registry_value::<LuaUserDataRef<PaymentsState>>(PAYMENTS)?;
+    Ok(PaymentsState::clone(&state))
+}
+
+/// payments.config { secret_key, webhook_secret, api_base, tolerance }
+fn payments_config(lua: &Lua, options: LuaTable) -> LuaResult<()> {
+    let state = state(lua)?;
+    let mut config = state.config.lock();
+    config.secret_key = options.get("secret_key")?;
+    config.webhook_secret = options.get("webhook_secret")?;
+    config.api_base = options.get("api_base")?;
+    config.tolerance = options.get("tolerance")?;
+    Ok(())
+}
+
+/// payments.on(type, function(event) end), or "*" for every event
+fn payments_on(lua: &Lua, (kind, handler): (String, Option<LuaFunction>)) -> LuaResult<()> {
+    let handlers = lua.named_registry_value::<LuaTable>(PAYMENTS_HANDLERS)?;
+    handlers.set(kind, handler)
+}
+
+impl PaymentsState {
+    /// a request to the api, authorized with the secret key
+    fn request(&self, method: reqwest::Method, path: &str) -> LuaResult<reqwest::RequestBuilder> {
+        let config = self.config.lock();
+        let secret_key = config
+            .secret_key
+            .as_deref()
+            .ok_or_else(|| LuaError::runtime("payments.config needs a secret_key"))?;
+        let api_base = config.api_base.as_deref().unwrap_or(DEFAULT_API_BASE);
+        let url = format!("{}{path}", api_base.trim_end_matches('/'));
+        Ok(self.client.request(method, url).bearer_auth(secret_key))
+    }
+}
+
+/// the json of a response, or an error with the api's message
+async fn api_response(response: reqwest::Response) -> LuaResult<Value> {
+    let status = response.status();
+    let body: Value = response.json().await.into_lua_err()?;
+    if !status.is_success() {
+        let message = body["error"]["message"]
+            .as_str()
+            .unwrap_or_else(|| status.canonical_reason().unwrap_or("request failed"));
+        return Err(LuaError::runtime(format!("payments: {message} ({status})")));
+    }
+    Ok(body)
+}
+
+/// payments.checkout { mode, line_items, success_url, cancel_url, ... }
+///
+/// the options are sent as they are, in the bracketed form encoding the api expects
+async fn payments_checkout(lua: Lua, options: LuaTable) -> LuaResult<LuaValue> {
+    let state = state(&lua)?;
+    let mut params: Value = lua.from_value(LuaValue::Table(options))?;
+    let idempotency_key = params
+        .as_object_mut()
+        .and_then(|params| params.remove("idempotency_key"));
+    let idempotency_key = idempotency_key.as_ref().and_then(Value::as_str);
+    let form = serde_qs::to_string(&params).into_lua_err()?;
+
+    let mut request = state
+        .request(reqwest::Method::POST, "/v1/checkout/sessions")?
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(form);
+    if let Some(key) = idempotency_key {
+        request = request.header("idempotency-key", key);
+    }
+    let session = api_response(request.send().await.into_lua_err()?).await?;
+    lua.to_value(&session)
+}
+
+/// payments.session(id)
+async fn payments_session(lua: Lua, id: String) -> LuaResult<LuaValue> {
+    let state = state(&lua)?;
+    if !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(LuaError::runtime(format!(
+            "invalid checkout session id: {id}"
+        )));
+    }
+    let response = state
+        .request(reqwest::Method::GET, &format!("/v1/checkout/sessions/{id}"))?
+        .send()
+        .await
+        .into_lua_err()?;
+    lua.to_value(&api_response(response).await?)
+}
+
+/// payments.events { type, limit }
+async fn payments_events(lua: Lua, options: Option<LuaTable>) -> LuaResult<LuaValue> {
+    let state = state(&lua)?;
+    let (kind, limit) = match options {
+        Some(options) => (
+            options.get::<Option<String>>("type")?,
+            options.get::<Option<usize>>("limit")?,
+        ),
+        None => (None, None),
+    };
+    let limit = limit.unwrap_or(DEFAULT_EVENTS_LIMIT);
+    let events = state
+        .database
+        .call(move |conn| store::events(conn, kind.as_deref(), limit))
+        .await
+        .into_lua_err()?;
+    lua.to_value(&events)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// true if a Stripe-Signature header (t=timestamp,v1=signature,...) signs body with
+/// secret, and is no older than tolerance seconds
+fn signature_matches(secret: &str, header: &str, body: &[u8], now: u64, tolerance: u64) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    if now.abs_diff(timestamp) > tolerance {
+        return false;
+    }
+
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let expected = hex(&mac.finalize().into_bytes());
+    // constant time, so the time taken says nothing about the signature
+    signatures
+        .iter()
+        .any(|signature| signature.as_bytes().ct_eq(expected.as_bytes()).into())
+}
+
+/// the event in body, if it is signed with the webhook secret
+fn verified_event(lua: &Lua, body: &[u8], signature: &str) -> LuaResult<Option<Value>> {
+    let state = state(lua)?;
+    let (secret, tolerance) = {
+        let config = state.config.lock();
+        let secret = config
+            .webhook_secret
+            .clone()
+            .ok_or_else(|| LuaError::runtime("payments.config needs a webhook_secret"))?;
+        (secret, config.tolerance.unwrap_or(DEFAULT_TOLERANCE))
+    };
+    if !signature_matches(&secret, signature, body, now(), tolerance) {
+        return Ok(None);
+    }
+    Ok(serde_json::from_slice(body).ok())
+}
+
+/// payments.verify(body, signature)
+fn payments_verify(lua: &Lua, (body, signature): (LuaString, String)) -> LuaResult<LuaValue> {
+    match verified_event(lua, &body.as_bytes(), &signature)? {
+        Some(event) => lua.to_value(&event),
+        None => Ok(LuaNil),
+    }
+}
+
+/// POST /_lilguy/payments/webhook
+pub async fn webhook(lua: &Lua, headers: &HeaderMap, body: &[u8]) -> LuaResult<StatusCode> {
+    let signature = headers
+        .get("stripe-signature")
+        .and_then(|signature| signature.to_str().ok())
+        .unwrap_or_default();
+    let Some(event) = verified_event(lua, body, signature)? else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+    let Some(id) = event["id"].as_str().map(String::from) else {
+        return Ok(StatusCode::BAD_REQUEST);
+    };
+
+    let state = state(lua)?;
+    let saved = event.clone();
+    let handled = state
+        .database
+        .call(move |conn| store::save_event(conn, &saved))
+        .await
+        .into_lua_err()?;
+    if handled {
+        return Ok(StatusCode::OK);
+    }
+
+    let handlers = lua.named_registry_value::<LuaTable>(PAYMENTS_HANDLERS)?;
+    let kind = event["type"].as_str().unwrap_or_default();
+    let event = lua.to_value(&event)?;
+    for key in [kind, "*"] {
+        let Some(handler) = handlers.get::<Option<LuaFunction>>(key)? else {
+            continue;
+        };
+        if let Err(err) = handler.call_async::<()>(event.clone()).await {
+            tracing::error!(%id, %err, "error handling payment event");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    state
+        .database
+        .call(move |conn| store::mark_handled(conn, &id))
+        .await
+        .into_lua_err()?;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("key");
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        format!("t={timestamp},v1={}", hex(&mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let body = br#"{"id":"evt_1","type":"checkout.session.completed"}"#;
+        let header = sign("whsec_test", 1000, body);
+        assert!(signature_matches("whsec_test", &header, body, 1100, 300));
+        assert!(!signature_matches("whsec_other", &header, body, 1100, 300));
+        assert!(!signature_matches("whsec_test", &header, b"{}", 1100, 300));
+        // too old
+        assert!(!signature_matches("whsec_test", &header, body, 2000, 300));
+        // any v1 signature may match, as when the secret is being rolled
+        let rolled = format!("t=1000,v1=00,{}", &header["t=1000,".len()..]);
+        assert!(signature_matches("whsec_test", &rolled, body, 1000, 300));
+    }
+}
@@ -0,0 +1,224 @@
+// a sampling profiler for lua handlers.
+//
+//   lilguy serve --profile
+//
+// every SAMPLE_INSTRUCTIONS vm instructions the hook records the lua stack, prefixed
+// with the route being handled. the samples are shown at /_lilguy/profile and can be
+// downloaded from /_lilguy/profile.folded in the folded-stacks format that flamegraph.pl
+// and speedscope read. samples only count time spent running lua, so requests and
+// template renders are also timed by wall clock.
+use mlua::prelude::*;
+#[cfg(not(feature = "luau"))]
+use mlua::{HookTriggers, VmState};
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Arc,
+    time::Duration,
+};
+
+const DEBUG_LIB: &str = "lilguy.profiler.debug";
+const PROFILER: &str = "lilguy.profiler";
+const SAMPLE_INSTRUCTIONS: u32 = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    inner: Arc<Mutex<Profile>>,
+}
+
+#[derive(Debug, Default)]
+struct Profile {
+    /// folded stacks to sample counts
+    stacks: HashMap<String, u64>,
+    routes: BTreeMap<String, Timing>,
+    templates: BTreeMap<String, Timing>,
+    /// handler functions, by source and first line, to the route they serve
+    handlers: HashMap<(String, i64), String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Timing {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Timing {
+    fn add(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    fn mean(&self) -> Duration {
+        self.total / self.count.max(1) as u32
+    }
+}
+
+impl LuaUserData for Profiler {}
+
+impl Profiler {
+    /// install the sampling hook on a new lua state, which must have the debug library
+    /// loaded. like the debugger, the library is moved into the registry.
+    #[cfg(not(feature = "luau"))]
+    pub fn attach(&self, lua: &Lua) -> LuaResult<()> {
+        let debug = lua.globals().get::<LuaTable>("debug")?;
+        lua.set_named_registry_value(DEBUG_LIB, debug)?;
+        lua.globals().set("debug", LuaNil)?;
+        lua.set_named_registry_value(PROFILER, self.clone())?;
+
+        let profiler = self.clone();
+        let triggers = HookTriggers::new().every_nth_instruction(SAMPLE_INSTRUCTIONS);
+        lua.set_hook(triggers, move |lua, _debug| {
+            if let Err(err) = profiler.sample(lua) {
+                tracing::warn!(?err, "profiler error");
+            }
+            Ok(VmState::Continue)
+        })?;
+        Ok(())
+    }
+
+    /// luau has no hooks for the profiler to use
+    #[cfg(feature = "luau")]
+    pub fn attach(&self, _lua: &Lua) -> LuaResult<()> {
+        Err(LuaError::runtime(
+            "the profiler needs lilguy built with luajit",
+        ))
+    }
+
+    fn sample(&self, lua: &Lua) -> LuaResult<()> {
+        let getinfo = lua
+            .named_registry_value::<LuaTable>(DEBUG_LIB)?
+            .get::<LuaFunction>("getinfo")?;
+        let mut frames = vec![];
+        let mut outermost = None;
+        let mut level = 1;
+        while let Some(info) = getinfo.call::<Option<LuaTable>>((level, "Sn"))? {
+            level += 1;
+            if info.get::<String>("what")? == "C" {
+                continue;
+            }
+            let source = info.get::<String>("source")?;
+            let short_src = info.get::<String>("short_src")?;
+            let line = info.get::<i64>("linedefined")?;
+            let name = info
+                .get::<Option<String>>("name")?
+                .unwrap_or_else(|| "?".to_string());
+            frames.push(format!("{name} ({short_src}:{line})"));
+            outermost = Some((source, line));
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+
+        let mut profile = self.inner.lock();
+        let route = outermost
+            .and_then(|handler| profile.handlers.get(&handler).cloned())
+            .unwrap_or_else(|| "lua".to_string());
+        let mut stack = route;
+        for frame in frames.iter().rev() {
+            stack.push(';');
+            // ; separates frames and spaces separate the count in folded stacks
+            stack.push_str(&frame.replace([';', ' '], "_"));
+        }
+        *profile.stacks.entry(stack).or_default() += 1;
+        Ok(())
+    }
+
+    /// remember which route a handler serves, so its samples are grouped under it
+    pub fn handler(&self, handler: &LuaFunction, route: &str) {
+        let info = handler.info();
+        let (Some(source), Some(line)) = (info.source, info.line_defined) else {
+            return;
+        };
+        self.inner
+            .lock()
+            .handlers
+            .entry((source.to_string(), line as i64))
+            .or_insert_with(|| route.to_string());
+    }
+
+    pub fn time_route(&self, route: &str, elapsed: Duration) {
+        let mut profile = self.inner.lock();
+        profile
+            .routes
+            .entry(route.to_string())
+            .or_default()
+            .add(elapsed);
+    }
+
+    /// the samples in folded-stacks format, one stack per line
+    pub fn folded(&self) -> String {
+        let profile = self.inner.lock();
+        let mut stacks: Vec<_> = profile.stacks.iter().collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, count) in stacks {
+            let _ = writeln!(out, "{stack} {count}");
+        }
+        out
+    }
+
+    /// an html summary of route and template timings and the hottest stacks
+    pub fn report(&self) -> String {
+        let profile = self.inner.lock();
+        let mut out = String::from(
+            "<!doctype html><html><head><meta charset=\"utf-8\"><title>profile</title>\
+             <style>body{font-family:sans-serif;margin:2em}td,th{padding:.2em 1em;text-align:left}\
+             td.n{text-align:right}code{font-size:.9em}</style></head><body>\
+             <h1>profile</h1><p><a href=\"/_lilguy/profile.folded\">download folded stacks</a></p>",
+        );
+        for (title, timings) in [
+            ("routes", &profile.routes),
+            ("templates", &profile.templates),
+        ] {
+            let _ = write!(
+                out,
+                "<h2>{title}</h2><table><tr><th>name</th><th>count</th><th>mean</th><th>max</th></tr>"
+            );
+            for (name, timing) in timings {
+                let _ = write!(
+                    out,
+                    "<tr><td>{}</td><td class=n>{}</td><td class=n>{:.2?}</td><td class=n>{:.2?}</td></tr>",
+                    escape(name),
+                    timing.count,
+                    timing.mean(),
+                    timing.max
+                );
+            }
+            out.push_str("</table>");
+        }
+
+        let mut stacks: Vec<_> = profile.stacks.iter().collect();
+        stacks.sort_by(|a, b| b.1.cmp(a.1));
+        out.push_str("<h2>hottest stacks</h2><table><tr><th>samples</th><th>stack</th></tr>");
+        for (stack, count) in stacks.into_iter().take(50) {
+            let _ = write!(
+                out,
+                "<tr><td class=n>{count}</td><td><code>{}</code></td></tr>",
+                escape(&stack.replace(';', " → "))
+            );
+        }
+        out.push_str("</table></body></html>");
+        out
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// record how long a template took to render, if the profiler is running
+pub fn time_template(lua: &Lua, name: &str, elapsed: Duration) {
+    if let Ok(profiler) = lua.named_registry_value::<LuaUserDataRef<Profiler>>(PROFILER) {
+        let mut profile = profiler.inner.lock();
+        profile
+            .templates
+            .entry(name.to_string())
+            .or_default()
+            .add(elapsed);
+    }
+}
@@ -0,0 +1,436 @@
+// sitemap.xml and robots.txt.
+//
+//   seo.sitemap({
+//       "https://example.com/",
+//       { loc = "https://example.com/about", lastmod = "2024-05-01", changefreq = "monthly", priority = 0.8 },
+//   })
+//   seo.robots({ disallow = { "/admin" }, sitemap = "https://example.com/sitemap.xml" })
+//
+// both return strings. routes marked for the sitemap get both files served for them,
+// unless the app has its own /sitemap.xml or /robots.txt routes:
+//
+//   routes:meta("/about", { sitemap = true })
+//   routes:meta("/posts/:slug", {
+//       sitemap = {
+//           changefreq = "weekly",
+//           -- patterns with params list their pages, as paths or entries like the ones above
+//           paths = function() return post_paths() end,
+//       },
+//   })
+//
+// paths are made absolute with seo.base_url, or with the request's host if it is not set.
+//
+// search engines can be told when the sitemap changes:
+//
+//   [seo]
+//   base_url = "https://example.com"  # the default for seo.base_url
+//   ping = ["https://search.example/ping?sitemap={sitemap}"]
+//   watch = ["posts"]                 # global tables whose changes ping
+//   ping_delay = 300                  # seconds, the default
+//
+//   seo.ping()  -- request each ping url now, { { url = ..., status = 200 }, ... }
+//
+// when ping urls are configured and the app isn't reloading on change, a change to a page
+// in content/ or to a watched table pings once things have been quiet for ping_delay, so
+// a burst of edits is one ping.
+use axum::http::{header::HOST, HeaderMap};
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval, sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::Runtime;
+use crate::database::global::global_name;
+
+const DEFAULT_PING_DELAY: u64 = 300;
+/// how often content/ is checked for changes
+const CONTENT_CHECK: Duration = Duration::from_secs(60);
+
+/// the [seo] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeoConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+
+    /// urls seo.ping() requests, with {sitemap} replaced by the sitemap's url
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ping: Vec<String>,
+
+    /// global tables whose changes ping, as well as content/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watch: Vec<String>,
+
+    /// seconds to wait after a change before pinging
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_delay: Option<u64>,
+}
+
+pub fn register(lua: &Lua, config: &SeoConfig) -> LuaResult<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(format!("lilguy/{}", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .into_lua_err()?;
+    let seo = lua.create_table()?;
+    seo.set(
+        "sitemap",
+        lua.create_function(|_, entries: Vec<SitemapEntry>| Ok(sitemap_xml(&entries)))?,
+    )?;
+    seo.set("robots", lua.create_function(robots_txt)?)?;
+    seo.set("base_url", config.base_url.clone())?;
+    seo.set(
+        "ping",
+        lua.create_async_function({
+            let endpoints = config.ping.clone();
+            move |lua, sitemap: Option<String>| {
+                seo_ping(lua, client.clone(), endpoints.clone(), sitemap)
+            }
+        })?,
+    )?;
+    lua.globals().set("seo", seo)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+struct SitemapEntry {
+    loc: String,
+    lastmod: Option<String>,
+    changefreq: Option<String>,
+    priority: Option<f64>,
+}
+
+impl SitemapEntry {
+    /// an entry for loc, with the other fields from a route's sitemap options
+    fn with_defaults(loc: String, defaults: Option<&LuaTable>) -> LuaResult<Self> {
+        let mut entry = Self {
+            loc,
+            ..Self::default()
+        };
+        if let Some(defaults) = defaults {
+            entry.fill(defaults)?;
+        }
+        Ok(entry)
+    }
+
+    /// set the fields that are missing from table
+    fn fill(&mut self, table: &LuaTable) -> LuaResult<()> {
+        if self.lastmod.is_none() {
+            self.lastmod = table.get("lastmod")?;
+        }
+        if self.changefreq.is_none() {
+            self.changefreq = table.get("changefreq")?;
+        }
+        if self.priority.is_none() {
+            self.priority = table.get("priority")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromLua for SitemapEntry {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Table(table) => {
+                let mut entry = Self::with_defaults(table.get("loc")?, None)?;
+                entry.fill(&table)?;
+                Ok(entry)
+            }
+            value => Self::with_defaults(String::from_lua(value, lua)?, None),
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn sitemap_xml(entries: &[SitemapEntry]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for entry in entries {
+        let _ = writeln!(xml, "  <url>\n    <loc>{}</loc>", escape(&entry.loc));
+        if let Some(ref lastmod) = entry.lastmod {
+            let _ = writeln!(xml, "    <lastmod>{}</lastmod>", escape(lastmod));
+        }
+        if let Some(ref changefreq) = entry.changefreq {
+            let _ = writeln!(xml, "    <changefreq>{}</changefreq>", escape(changefreq));
+        }
+        if let Some(priority) = entry.priority {
+            let _ = writeln!(
+                xml,
+                "    <priority>{:.1}</priority>",
+                priority.clamp(0.0, 1.0)
+            );
+        }
+        xml.push_str("  </url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+/// a string or a list of strings
+fn strings(value: LuaValue) -> LuaResult<Vec<String>> {
+    match value {
+        LuaValue::Nil => Ok(Vec::new()),
+        LuaValue::Table(table) => table.sequence_values().collect(),
+        value => Ok(vec![value.to_string()?]),
+    }
+}
+
+/// seo.robots { user_agent = "*", allow = { ... }, disallow = { ... }, crawl_delay = 10, sitemap = url }
+///
+/// rules for other crawlers can be listed in the table, each with their own user_agent:
+/// seo.robots { { user_agent = "GPTBot", disallow = "/" }, sitemap = url }
+fn robots_txt(_lua: &Lua, options: Option<LuaTable>) -> LuaResult<String> {
+    let Some(options) = options else {
+        return Ok("User-agent: *\nDisallow:\n".to_string());
+    };
+    let mut groups: Vec<LuaTable> = options.sequence_values().collect::<LuaResult<_>>()?;
+    let mut has_rules = false;
+    for key in ["user_agent", "allow", "disallow", "crawl_delay"] {
+        has_rules |= options.contains_key(key)?;
+    }
+    if groups.is_empty() || has_rules {
+        groups.insert(0, options.clone());
+    }
+
+    let mut robots = String::new();
+    for group in groups {
+        let mut user_agents = strings(group.get("user_agent")?)?;
+        if user_agents.is_empty() {
+            user_agents.push("*".to_string());
+        }
+        for user_agent in user_agents {
+            let _ = writeln!(robots, "User-agent: {user_agent}");
+        }
+        let allow = strings(group.get("allow")?)?;
+        let disallow = strings(group.get("disallow")?)?;
+        for path in &allow {
+            let _ = writeln!(robots, "Allow: {path}");
+        }
+        for path in &disallow {
+            let _ = writeln!(robots, "Disallow: {path}");
+        }
+        if allow.is_empty() && disallow.is_empty() {
+            robots.push_str("Disallow:\n");
+        }
+        if let Some(delay) = group.get::<Option<f64>>("crawl_delay")? {
+            let _ = writeln!(robots, "Crawl-delay: {delay}");
+        }
+        robots.push('\n');
+    }
+    for sitemap in strings(options.get("sitemap")?)? {
+        let _ = writeln!(robots, "Sitemap: {sitemap}");
+    }
+    Ok(robots.trim_end().to_string() + "\n")
+}
+
+/// seo.base_url, or the scheme and host the request was made to
+fn base_url(lua: &Lua, headers: &HeaderMap) -> LuaResult<String> {
+    let seo = lua.globals().get::<LuaTable>("seo")?;
+    if let Some(base_url) = seo.get::<Option<String>>("base_url")? {
+        return Ok(base_url.trim_end_matches('/').to_string());
+    }
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("x-forwarded-proto").unwrap_or("http");
+    let host = header(HOST.as_str()).unwrap_or("localhost");
+    Ok(format!("{scheme}://{host}"))
+}
+
+/// percent-encode text for a query string
+fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+/// seo.ping(sitemap), the sitemap's url defaulting to the one at seo.base_url
+async fn seo_ping(
+    lua: Lua,
+    client: reqwest::Client,
+    endpoints: Vec<String>,
+    sitemap: Option<String>,
+) -> LuaResult<LuaTable> {
+    let sitemap = match sitemap {
+        Some(sitemap) => sitemap,
+        None => {
+            let seo = lua.globals().get::<LuaTable>("seo")?;
+            let Some(base_url) = seo.get::<Option<String>>("base_url")? else {
+                return Err(LuaError::runtime(
+                    "seo.ping needs seo.base_url, or base_url in [seo]",
+                ));
+            };
+            format!("{}/sitemap.xml", base_url.trim_end_matches('/'))
+        }
+    };
+    let results = lua.create_table()?;
+    for endpoint in endpoints {
+        let url = endpoint.replace("{sitemap}", &encode(&sitemap));
+        let result = lua.create_table()?;
+        match client.get(&url).send().await {
+            Ok(response) => {
+                tracing::info!(url, status = %response.status(), "pinged");
+                result.set("status", response.status().as_u16())?;
+            }
+            Err(err) => {
+                tracing::warn!(%err, url, "error pinging");
+                result.set("error", err.to_string())?;
+            }
+        }
+        result.set("url", url)?;
+        results.push(result)?;
+    }
+    Ok(results)
+}
+
+/// when anything in dir was last changed, directories included so removals count
+async fn last_modified(dir: PathBuf) -> Option<SystemTime> {
+    tokio::task::spawn_blocking(move || {
+        walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+            .max()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// call seo.ping() after content or one of the watched tables changes
+pub async fn ping_on_change(
+    runtime: Runtime,
+    config: SeoConfig,
+    content: &Path,
+    token: CancellationToken,
+) {
+    let Ok(database) = runtime.database() else {
+        return;
+    };
+    let mut changes = database.changes();
+    let delay = Duration::from_secs(config.ping_delay.unwrap_or(DEFAULT_PING_DELAY));
+    let mut check = interval(CONTENT_CHECK);
+    let mut modified = last_modified(content.to_path_buf()).await;
+    let mut due = None;
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            change = changes.recv() => match change {
+                Ok(table) => {
+                    if global_name(&table).is_some_and(|name| config.watch.iter().any(|w| w == name)) {
+                        due.get_or_insert_with(|| Instant::now() + delay);
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    if !config.watch.is_empty() {
+                        due.get_or_insert_with(|| Instant::now() + delay);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = check.tick() => {
+                let now = last_modified(content.to_path_buf()).await;
+                if now != modified {
+                    modified = now;
+                    due.get_or_insert_with(|| Instant::now() + delay);
+                }
+            }
+            _ = sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                due = None;
+                if let Err(err) = ping(&runtime).await {
+                    tracing::warn!(%err, "error pinging search engines");
+                }
+            }
+        }
+    }
+}
+
+async fn ping(runtime: &Runtime) -> eyre::Result<()> {
+    let lua = runtime.lua()?;
+    let ping = lua
+        .globals()
+        .get::<LuaTable>("seo")?
+        .get::<LuaFunction>("ping")?;
+    ping.call_async::<LuaValue>(()).await?;
+    Ok(())
+}
+
+/// true for the files serve() generates
+pub fn is_seo_file(path: &str) -> bool {
+    matches!(path, "/sitemap.xml" | "/robots.txt")
+}
+
+/// /robots.txt, or else /sitemap.xml, for the routes marked with
+/// routes:meta(pattern, { sitemap = ... }). marked is each route's pattern and sitemap option,
+/// and disallow the paths routes:block_bots() keeps crawlers out of.
+pub async fn serve(
+    lua: &Lua,
+    path: &str,
+    marked: Vec<(String, LuaValue)>,
+    disallow: &[&str],
+    headers: &HeaderMap,
+) -> LuaResult<(&'static str, String)> {
+    let base_url = base_url(lua, headers)?;
+    if path == "/robots.txt" {
+        let mut robots = String::from("User-agent: *\n");
+        for path in disallow {
+            let _ = writeln!(robots, "Disallow: {path}");
+        }
+        if disallow.is_empty() {
+            robots.push_str("Disallow:\n");
+        }
+        if !marked.is_empty() {
+            let _ = writeln!(robots, "\nSitemap: {base_url}/sitemap.xml");
+        }
+        return Ok(("text/plain; charset=utf-8", robots));
+    }
+
+    let mut entries = Vec::new();
+    for (pattern, sitemap) in marked {
+        let defaults = match sitemap {
+            LuaValue::Table(ref table) => Some(table),
+            _ => None,
+        };
+        let paths = defaults
+            .map(|defaults| defaults.get::<Option<LuaFunction>>("paths"))
+            .transpose()?
+            .flatten();
+        if let Some(paths) = paths {
+            for mut entry in paths.call_async::<Vec<SitemapEntry>>(()).await? {
+                if let Some(defaults) = defaults {
+                    entry.fill(defaults)?;
+                }
+                entries.push(entry);
+            }
+        } else if pattern.contains([':', '*']) {
+            tracing::warn!(%pattern, "sitemap routes with params need a paths function");
+        } else {
+            entries.push(SitemapEntry::with_defaults(pattern, defaults)?);
+        }
+    }
+    for entry in &mut entries {
+        if entry.loc.starts_with('/') {
+            entry.loc = format!("{base_url}{}", entry.loc);
+        }
+    }
+    Ok(("application/xml", sitemap_xml(&entries)))
+}
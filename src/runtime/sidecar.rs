@@ -0,0 +1,298 @@
+// helper programs in any language, spoken to with json-rpc over stdio
+//
+//   local resize = sidecar.spawn("./bin/resize", { args = { "--quality", "80" } })
+//   local thumb = resize:call("thumbnail", { path = "photo.jpg", width = 200 })
+//   resize:notify("flush")
+//   resize:close()
+//
+// requests go to the program's stdin and responses are read from its stdout, one json
+// object per line, as in json-rpc 2.0. calls can overlap; responses are matched to them
+// by id, in any order. lines from the program's stderr are logged. a sidecar is stopped
+// when it is closed or garbage collected, and calls waiting on it fail if it exits.
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::oneshot,
+};
+
+use super::{file::resolve, seconds};
+
+/// how long a call waits for its response unless spawn is given a timeout
+const DEFAULT_TIMEOUT: f64 = 30.0;
+
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Result<Value, String>>>>>>;
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let sidecar = lua.create_table()?;
+    sidecar.set("spawn", lua.create_function(sidecar_spawn)?)?;
+    lua.globals().set("sidecar", sidecar)?;
+    Ok(())
+}
+
+pub struct LuaSidecar {
+    program: String,
+    child: Mutex<Option<Child>>,
+    stdin: Arc<tokio::sync::Mutex<Option<ChildStdin>>>,
+    /// calls waiting for a response; None once the program has exited
+    pending: Pending,
+    next_id: AtomicU64,
+    timeout: Option<Duration>,
+}
+
+/// sidecar.spawn(program, { args = {...}, env = {...}, timeout = 30 })
+fn sidecar_spawn(
+    lua: &Lua,
+    (program, options): (String, Option<LuaTable>),
+) -> LuaResult<LuaSidecar> {
+    let mut command = Command::new(resolve_program(lua, &program));
+    command
+        .current_dir(resolve(lua, "."))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut timeout = DEFAULT_TIMEOUT;
+    if let Some(options) = options {
+        if let Some(args) = options.get::<Option<Vec<String>>>("args")? {
+            command.args(args);
+        }
+        if let Some(env) = options.get::<Option<HashMap<String, String>>>("env")? {
+            command.envs(env);
+        }
+        timeout = options.get::<Option<f64>>("timeout")?.unwrap_or(timeout);
+    }
+    // checked before the program starts, so a bad timeout doesn't leave it running
+    let timeout = match timeout {
+        timeout if timeout > 0.0 => Some(seconds("timeout", timeout)?),
+        _ => None,
+    };
+
+    let mut child = command
+        .spawn()
+        .map_err(|err| LuaError::runtime(format!("sidecar {program}: {err}")))?;
+    let (Some(stdin), Some(stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        return Err(LuaError::runtime(format!("sidecar {program}: no stdio")));
+    };
+
+    let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+    tokio::spawn(read_responses(
+        program.clone(),
+        BufReader::new(stdout),
+        pending.clone(),
+    ));
+    tokio::spawn({
+        let program = program.clone();
+        async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                tracing::info!(sidecar = %program, "{line}");
+            }
+        }
+    });
+
+    Ok(LuaSidecar {
+        program,
+        child: Mutex::new(Some(child)),
+        stdin: Arc::new(tokio::sync::Mutex::new(Some(stdin))),
+        pending,
+        next_id: AtomicU64::new(1),
+        timeout,
+    })
+}
+
+/// programs named with a path are found relative to the app, others on the PATH
+fn resolve_program(lua: &Lua, program: &str) -> std::path::PathBuf {
+    if program.contains('/') || program.contains('\\') {
+        resolve(lua, program)
+    } else {
+        program.into()
+    }
+}
+
+/// hand each response to the call waiting for it, until the program's stdout closes
+async fn read_responses(
+    program: String,
+    stdout: BufReader<tokio::process::ChildStdout>,
+    pending: Pending,
+) {
+    let mut lines = stdout.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!(sidecar = %program, ?err, "invalid json-rpc response");
+                continue;
+            }
+        };
+        // requests and notifications from the program aren't supported
+        let Some(id) = response.get("id").and_then(Value::as_u64) else {
+            tracing::debug!(sidecar = %program, "ignoring message without an id");
+            continue;
+        };
+        let result = match response.get("error") {
+            Some(error) => Err(error_message(error)),
+            None => Ok(response.get("result").cloned().unwrap_or(Value::Null)),
+        };
+        let waiting = pending
+            .lock()
+            .as_mut()
+            .and_then(|pending| pending.remove(&id));
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(result);
+        }
+    }
+    // dropping the senders fails the calls still waiting
+    pending.lock().take();
+}
+
+/// a json-rpc request, or a notification without an id. params are left out when nil.
+fn message(lua: &Lua, id: Option<u64>, method: &str, params: LuaValue) -> LuaResult<Value> {
+    let mut message = json!({ "jsonrpc": "2.0", "method": method });
+    if let Some(id) = id {
+        message["id"] = id.into();
+    }
+    if !params.is_nil() {
+        message["params"] = lua.from_value::<Value>(params)?;
+    }
+    Ok(message)
+}
+
+fn error_message(error: &Value) -> String {
+    let message = error
+        .get("message")
+        .and_then(Value::as_str)
+        .unwrap_or("error");
+    match error.get("code").and_then(Value::as_i64) {
+        Some(code) => format!("{message} ({code})"),
+        None => message.to_string(),
+    }
+}
+
+impl LuaSidecar {
+    async fn send(&self, message: Value) -> LuaResult<()> {
+        let mut line = serde_json::to_vec(&message).into_lua_err()?;
+        line.push(b'\n');
+        let mut stdin = self.stdin.lock().await;
+        let Some(writer) = stdin.as_mut() else {
+            return Err(LuaError::runtime(format!(
+                "sidecar {} is closed",
+                self.program
+            )));
+        };
+        let written = async {
+            writer.write_all(&line).await?;
+            writer.flush().await
+        };
+        written
+            .await
+            .map_err(|err| LuaError::runtime(format!("sidecar {}: {err}", self.program)))
+    }
+
+    async fn call(&self, lua: &Lua, method: String, params: LuaValue) -> LuaResult<LuaValue> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => {
+                return Err(LuaError::runtime(format!(
+                    "sidecar {} has exited",
+                    self.program
+                )))
+            }
+        };
+        let request = message(lua, Some(id), &method, params)?;
+        if let Err(err) = self.send(request).await {
+            self.forget(id);
+            return Err(err);
+        }
+
+        let response = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, rx).await {
+                Ok(response) => response,
+                Err(_) => {
+                    self.forget(id);
+                    return Err(LuaError::runtime(format!(
+                        "sidecar {}: {method} timed out",
+                        self.program
+                    )));
+                }
+            },
+            None => rx.await,
+        };
+        match response {
+            Ok(Ok(result)) => lua.to_value(&result),
+            Ok(Err(message)) => Err(LuaError::runtime(format!(
+                "sidecar {}: {method}: {message}",
+                self.program
+            ))),
+            Err(_) => Err(LuaError::runtime(format!(
+                "sidecar {} has exited",
+                self.program
+            ))),
+        }
+    }
+
+    fn forget(&self, id: u64) {
+        if let Some(pending) = self.pending.lock().as_mut() {
+            pending.remove(&id);
+        }
+    }
+
+    /// close stdin so the program can finish, then stop it if it doesn't
+    async fn close(&self) -> LuaResult<Option<i32>> {
+        self.stdin.lock().await.take();
+        let Some(mut child) = self.child.lock().take() else {
+            return Ok(None);
+        };
+        let status = match tokio::time::timeout(Duration::from_secs(5), child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                child.kill().await?;
+                child.wait().await?
+            }
+        };
+        Ok(status.code())
+    }
+}
+
+impl LuaUserData for LuaSidecar {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method(
+            "call",
+            |lua, this, (method, params): (String, LuaValue)| async move {
+                this.call(&lua, method, params).await
+            },
+        );
+
+        methods.add_async_method(
+            "notify",
+            |lua, this, (method, params): (String, LuaValue)| async move {
+                this.send(message(&lua, None, &method, params)?).await
+            },
+        );
+
+        // the program's exit code, or nil if it was killed or already closed
+        methods.add_async_method("close", |_, this, ()| async move { this.close().await });
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!("sidecar {}", this.program))
+        });
+    }
+}
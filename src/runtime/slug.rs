@@ -0,0 +1,244 @@
+// slugs for pretty urls, and redirects from the ones a page used to have.
+//
+//   slugify("Héllo, Wörld!")                  -- "hello-world"
+//   slugify("北京 Travel Notes")              -- "bei-jing-travel-notes"
+//   slugify("Привет мир", { ascii = false })  -- "привет-мир"
+//
+//   routes:slug("/blog/:slug", function(req, res) ... end)
+//   slugs.rename("/blog/:slug", "old-title", "new-title")
+//   slugs.current("/blog/:slug", "old-title")  -- "new-title"
+//
+// a route added with routes:slug() answers a GET for a slug that was renamed with a 301
+// to the url with the slug it has now, before its handler runs. renames are kept in the
+// lg_slug table, so links from other sites keep working after a title changes; renaming
+// a → b and then b → c sends a straight to c. the slug is the :slug param, or the last
+// param in the pattern if there is none called that.
+use mlua::prelude::*;
+use rusqlite::{params, OptionalExtension};
+use std::borrow::Cow;
+
+use crate::database::{self, Database};
+
+/// the slug for text: words lowercased and joined by dashes. with ascii, other scripts
+/// are transliterated, otherwise their letters are kept as they are.
+pub fn slugify(text: &str, ascii: bool) -> String {
+    let text: Cow<str> = if ascii {
+        deunicode::deunicode(text).into()
+    } else {
+        text.into()
+    };
+    let mut slug = String::with_capacity(text.len());
+    let mut dash = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() || (!slug.is_empty() && is_combining(c) && !dash) {
+            if dash && !slug.is_empty() {
+                slug.push('-');
+            }
+            dash = false;
+            slug.extend(c.to_lowercase());
+        } else if !matches!(c, '\'' | '\u{2019}') {
+            dash = true;
+        }
+    }
+    slug
+}
+
+/// a combining mark, like the accent in a decomposed é
+fn is_combining(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036f}' | '\u{1ab0}'..='\u{1aff}' | '\u{20d0}'..='\u{20ff}')
+}
+
+/// the param of a routes:slug() pattern that holds the slug
+pub fn param(pattern: &str) -> Option<String> {
+    let params: Vec<&str> = pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .map(|name| name.trim_end_matches(['?', '+', '*']))
+        .collect();
+    params
+        .iter()
+        .find(|name| **name == "slug")
+        .or(params.last())
+        .map(|name| name.to_string())
+}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    lua.globals().set(
+        "slugify",
+        lua.create_function(|_, (text, options): (String, Option<LuaTable>)| {
+            let ascii = match options {
+                Some(options) => options.get::<Option<bool>>("ascii")?.unwrap_or(true),
+                None => true,
+            };
+            Ok(slugify(&text, ascii))
+        })?,
+    )?;
+
+    let slugs = lua.create_table()?;
+    slugs.set(
+        "rename",
+        lua.create_async_function({
+            let database = database.clone();
+            move |_, (pattern, old, new): (String, String, String)| {
+                let database = database.clone();
+                async move { rename(&database, pattern, old, new).await.into_lua_err() }
+            }
+        })?,
+    )?;
+    slugs.set(
+        "current",
+        lua.create_async_function({
+            let database = database.clone();
+            move |_, (pattern, slug): (String, String)| {
+                let database = database.clone();
+                async move {
+                    let current = current(&database, pattern, slug.clone())
+                        .await
+                        .into_lua_err()?;
+                    Ok(current.unwrap_or(slug))
+                }
+            }
+        })?,
+    )?;
+    lua.globals().set("slugs", slugs)?;
+    Ok(())
+}
+
+/// record that the page at old is now at new
+async fn rename(
+    database: &Database,
+    pattern: String,
+    old: String,
+    new: String,
+) -> database::Result<()> {
+    if old == new {
+        return Ok(());
+    }
+    database
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            // a slug in use again is no longer an old one
+            tx.execute(
+                "DELETE FROM lg_slug WHERE pattern = ? AND old = ?",
+                params![pattern, new],
+            )?;
+            tx.execute(
+                "UPDATE lg_slug SET new = ? WHERE pattern = ? AND new = ?",
+                params![new, pattern, old],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO lg_slug (pattern, old, new) VALUES (?, ?, ?)",
+                params![pattern, old, new],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+}
+
+/// the slug a renamed page has now, or None if slug wasn't renamed
+pub async fn current(
+    database: &Database,
+    pattern: String,
+    slug: String,
+) -> database::Result<Option<String>> {
+    database
+        .call(move |conn| {
+            let new = conn
+                .query_row(
+                    "SELECT new FROM lg_slug WHERE pattern = ? AND old = ?",
+                    params![pattern, slug],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(new)
+        })
+        .await
+}
+
+/// percent-decode a path param
+pub fn decode(param: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(param.len());
+    let mut raw = param.bytes();
+    while let Some(byte) = raw.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [raw.next()?, raw.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn encode(slug: &str) -> String {
+    let mut encoded = String::with_capacity(slug.len());
+    for byte in slug.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// path with the slug param of pattern swapped for slug, keeping the query
+pub fn location(
+    path: &str,
+    query: Option<&str>,
+    pattern: &str,
+    param: &str,
+    slug: &str,
+) -> Option<String> {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let mut path: Vec<String> = path.split('/').map(String::from).collect();
+    if pattern.len() != path.len() {
+        return None;
+    }
+    let index = pattern.iter().position(|segment| {
+        segment
+            .strip_prefix(':')
+            .is_some_and(|name| name.trim_end_matches(['?', '+', '*']) == param)
+    })?;
+    path[index] = encode(slug);
+    let path = path.join("/");
+    Some(match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn makes_slugs() {
+        assert_eq!(slugify("Héllo, Wörld!", true), "hello-world");
+        assert_eq!(slugify("  Don't Panic  ", true), "dont-panic");
+        assert_eq!(slugify("Ærøskøbing", true), "aeroskobing");
+        assert_eq!(slugify("Привет, мир", false), "привет-мир");
+        assert_eq!(slugify("cafe\u{301} au lait", false), "cafe\u{301}-au-lait");
+        assert_eq!(slugify("---", true), "");
+    }
+
+    #[test]
+    fn finds_the_slug() {
+        assert_eq!(param("/blog/:slug").as_deref(), Some("slug"));
+        assert_eq!(param("/:slug/:page").as_deref(), Some("slug"));
+        assert_eq!(param("/posts/:year/:title").as_deref(), Some("title"));
+        assert_eq!(param("/about"), None);
+        assert_eq!(
+            location(
+                "/posts/2024/old",
+                Some("a=1"),
+                "/posts/:year/:title",
+                "title",
+                "new post"
+            ),
+            Some("/posts/2024/new%20post?a=1".to_string())
+        );
+        assert_eq!(decode("caf%C3%A9").as_deref(), Some("café"));
+    }
+}
@@ -0,0 +1,141 @@
+// links to files in the assets directory that browsers can check, and cache for longer.
+//
+//   {{ script("htmx.min.js") }}
+//   <script src="/assets/htmx.min.js?v=1c67f3b6" integrity="sha384-HGfztofotfshcF7+..."
+//       crossorigin="anonymous"></script>
+//
+//   {{ stylesheet("pico.css") }}    -- a <link rel="stylesheet"> the same way
+//   {{ asset("logo.png") }}         -- /assets/logo.png?v=5e0c2a91
+//   {{ integrity("app.js") }}       -- sha384-...
+//
+// the integrity attribute is the file's sha384, so a browser won't run a script or apply
+// a stylesheet that isn't the file the page was rendered with. the version in the url
+// changes whenever the file does, which busts caches without renaming it; a fingerprinted
+// file, like app.3f9a2c1e.js, is linked as it is. hashes are kept until a file's size or
+// modification time changes. a file that isn't in assets is a template error, so a typo
+// doesn't quietly become a broken link.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha384};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+    time::SystemTime,
+};
+
+use crate::routes::assets::is_fingerprinted;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hash {
+    /// the integrity attribute, like sha384-...
+    pub integrity: String,
+    /// the first bytes of the hash in hex, for ?v=
+    pub version: String,
+}
+
+impl Hash {
+    pub fn of(bytes: &[u8]) -> Self {
+        let digest = Sha384::digest(bytes);
+        let mut version = String::with_capacity(8);
+        for byte in &digest[..4] {
+            let _ = write!(version, "{byte:02x}");
+        }
+        Self {
+            integrity: format!("sha384-{}", STANDARD.encode(digest)),
+            version,
+        }
+    }
+}
+
+/// hashes by file, with the size and modification time they were made for
+static HASHES: LazyLock<Mutex<HashMap<PathBuf, (u64, SystemTime, Hash)>>> =
+    LazyLock::new(Default::default);
+
+/// the file at path in dir, if path stays in it
+fn file(dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let path = Path::new(path.trim_start_matches('/'));
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "asset paths can't leave the assets directory",
+        ));
+    }
+    Ok(dir.join(path))
+}
+
+/// the hash of an asset
+pub fn hash(dir: &Path, path: &str) -> io::Result<Hash> {
+    let file = file(dir, path)?;
+    let metadata = std::fs::metadata(&file)?;
+    let modified = metadata.modified()?;
+    if let Some((size, at, hash)) = HASHES.lock().get(&file) {
+        if *size == metadata.len() && *at == modified {
+            return Ok(hash.clone());
+        }
+    }
+    let hash = Hash::of(&std::fs::read(&file)?);
+    HASHES
+        .lock()
+        .insert(file, (metadata.len(), modified, hash.clone()));
+    Ok(hash)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// the url of an asset, with its version unless its name already has one
+pub fn url(path: &str, hash: &Hash) -> String {
+    let path = path.trim_start_matches('/');
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if is_fingerprinted(name) {
+        format!("/assets/{path}")
+    } else {
+        format!("/assets/{path}?v={}", hash.version)
+    }
+}
+
+pub fn script_html(path: &str, hash: &Hash) -> String {
+    format!(
+        r#"<script src="{}" integrity="{}" crossorigin="anonymous"></script>"#,
+        escape(&url(path, hash)),
+        hash.integrity
+    )
+}
+
+pub fn stylesheet_html(path: &str, hash: &Hash) -> String {
+    format!(
+        r#"<link rel="stylesheet" href="{}" integrity="{}" crossorigin="anonymous">"#,
+        escape(&url(path, hash)),
+        hash.integrity
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_assets() {
+        let hash = Hash::of(b"alert(1)");
+        assert!(hash.integrity.starts_with("sha384-"));
+        assert_eq!(hash.version.len(), 8);
+        assert_eq!(
+            url("js/app.js", &hash),
+            format!("/assets/js/app.js?v={}", hash.version)
+        );
+        assert_eq!(url("app.3f9a2c1e.js", &hash), "/assets/app.3f9a2c1e.js");
+        assert!(script_html("a&b.js", &hash).contains(r#"src="/assets/a&amp;b.js?v="#));
+        assert!(file(Path::new("assets"), "../app.db").is_err());
+        assert!(file(Path::new("assets"), "/css/site.css").is_ok());
+    }
+}
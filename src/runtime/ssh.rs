@@ -0,0 +1,230 @@
+// ssh client for deploy scripts
+//
+//   local server = ssh.connect("example.com", { user = "deploy", key = "~/.ssh/id_ed25519" })
+//   server:upload("dist/app.tar.gz", "/srv/app/app.tar.gz")
+//   local result = server:exec("tar -C /srv/app -xzf /srv/app/app.tar.gz")
+//   if result.status ~= 0 then error(result.stderr) end
+//   server:close()
+//
+// this drives the system openssh client with a shared control connection, so every
+// exec, upload and download reuses a single authenticated session. authentication is
+// non-interactive: use a key or an agent.
+//
+// the host has to be in known_hosts already, since a script can't ask whether to trust
+// a key it hasn't seen. { accept_new_host = true } adds the key of a host that isn't
+// there yet, which trusts whoever answers the first connection; a key that changed is
+// still refused.
+use mlua::prelude::*;
+use std::{
+    path::PathBuf,
+    process::{Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::process::Command;
+
+use super::file::resolve;
+
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let ssh = lua.create_table()?;
+    ssh.set("connect", lua.create_async_function(ssh_connect)?)?;
+    lua.globals().set("ssh", ssh)?;
+    Ok(())
+}
+
+/// ssh.connect(host, { user = "deploy", key = "~/.ssh/id_ed25519", port = 22 })
+/// with accept_new_host = true, a host that isn't in known_hosts yet is added to it
+async fn ssh_connect(_lua: Lua, (host, options): (String, Option<LuaTable>)) -> LuaResult<LuaSsh> {
+    let get = |name: &str| -> LuaResult<Option<String>> {
+        match &options {
+            Some(options) => options.get(name),
+            None => Ok(None),
+        }
+    };
+    let user = get("user")?;
+    let key = get("key")?.map(|key| expand_home(&key));
+    let port = match &options {
+        Some(options) => options.get::<Option<u16>>("port")?,
+        None => None,
+    };
+    let accept_new_host = match &options {
+        Some(options) => options.get::<Option<bool>>("accept_new_host")?,
+        None => None,
+    }
+    .unwrap_or(false);
+
+    let control_path = std::env::temp_dir().join(format!(
+        "lilguy-ssh-{}-{}",
+        std::process::id(),
+        SESSIONS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let destination = match user {
+        Some(user) => format!("{user}@{host}"),
+        None => host,
+    };
+    let session = LuaSsh {
+        destination,
+        port,
+        key,
+        accept_new_host,
+        control_path,
+    };
+
+    // start the control connection, which also checks that we can log in.
+    // with ControlPersist the master detaches once `true` has run.
+    let output = session
+        .command("ssh")
+        .args(["-o", "ControlMaster=yes", "-o", "ControlPersist=yes"])
+        .arg(&session.destination)
+        .arg("true")
+        .output()
+        .await
+        .into_lua_err()?;
+    if !output.status.success() {
+        return Err(LuaError::runtime(format!(
+            "ssh connect to {} failed: {}",
+            session.destination,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(session)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+pub struct LuaSsh {
+    destination: String,
+    port: Option<u16>,
+    key: Option<PathBuf>,
+    accept_new_host: bool,
+    control_path: PathBuf,
+}
+
+impl LuaSsh {
+    /// ssh or scp with the options shared by every command on this session
+    fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        command
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.control_path.display()))
+            .args(["-o", "BatchMode=yes"])
+            .kill_on_drop(true);
+        let strict = if self.accept_new_host {
+            "accept-new"
+        } else {
+            "yes"
+        };
+        command
+            .arg("-o")
+            .arg(format!("StrictHostKeyChecking={strict}"));
+        if let Some(key) = &self.key {
+            command
+                .arg("-i")
+                .arg(key)
+                .args(["-o", "IdentitiesOnly=yes"]);
+        }
+        if let Some(port) = self.port {
+            // scp spells it -P
+            let flag = if program == "scp" { "-P" } else { "-p" };
+            command.arg(flag).arg(port.to_string());
+        }
+        command
+    }
+
+    async fn copy(&self, from: String, to: String) -> LuaResult<()> {
+        let output = self
+            .command("scp")
+            .args(["-q", "-r", "--"])
+            .arg(&from)
+            .arg(&to)
+            .output()
+            .await
+            .into_lua_err()?;
+        if !output.status.success() {
+            return Err(LuaError::runtime(format!(
+                "copy {from} to {to} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    fn remote(&self, path: &str) -> String {
+        format!("{}:{path}", self.destination)
+    }
+}
+
+fn exec_result(lua: &Lua, output: Output) -> LuaResult<LuaTable> {
+    let result = lua.create_table()?;
+    result.set("status", output.status.code())?;
+    result.set("stdout", lua.create_string(&output.stdout)?)?;
+    result.set("stderr", lua.create_string(&output.stderr)?)?;
+    Ok(result)
+}
+
+impl LuaUserData for LuaSsh {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // server:exec(cmd) returns { status, stdout, stderr }.
+        // status is nil if the command was killed by a signal.
+        methods.add_async_method("exec", |lua, this, cmd: String| async move {
+            let output = this
+                .command("ssh")
+                .arg(&this.destination)
+                .arg("--")
+                .arg(&cmd)
+                .output()
+                .await
+                .into_lua_err()?;
+            exec_result(&lua, output)
+        });
+
+        methods.add_async_method(
+            "upload",
+            |lua, this, (local, remote): (String, String)| async move {
+                let local = resolve(&lua, local).to_string_lossy().into_owned();
+                this.copy(local, this.remote(&remote)).await
+            },
+        );
+
+        methods.add_async_method(
+            "download",
+            |lua, this, (remote, local): (String, String)| async move {
+                let local = resolve(&lua, local).to_string_lossy().into_owned();
+                this.copy(this.remote(&remote), local).await
+            },
+        );
+
+        methods.add_async_method("close", |_, this, ()| async move {
+            this.command("ssh")
+                .args(["-O", "exit"])
+                .arg(&this.destination)
+                .output()
+                .await
+                .into_lua_err()?;
+            Ok(())
+        });
+    }
+}
+
+impl Drop for LuaSsh {
+    fn drop(&mut self) {
+        // stop the control connection if the script never called close()
+        if self.control_path.exists() {
+            let _ = std::process::Command::new("ssh")
+                .arg("-o")
+                .arg(format!("ControlPath={}", self.control_path.display()))
+                .args(["-O", "exit"])
+                .arg(&self.destination)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+        }
+    }
+}
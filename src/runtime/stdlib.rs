@@ -0,0 +1,51 @@
+// parts of the lua standard library that lilguy leaves out unless config.toml asks for
+// them, for lua code written for other runtimes.
+//
+//   [stdlib]
+//   utf8 = true  # lua 5.3's utf8 library
+//   io = true    # lua's io library, built on the file module
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+mod utf8;
+
+const IO_SHIM: &str = include_str!("stdlib/io.lua");
+
+/// the [stdlib] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StdlibConfig {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub utf8: bool,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub io: bool,
+}
+
+/// add the parts config turns on; io needs the file module registered first
+pub fn register(lua: &Lua, config: &StdlibConfig) -> LuaResult<()> {
+    if config.utf8 {
+        utf8::register(lua)?;
+    }
+    if config.io {
+        let file = lua.globals().get::<LuaTable>("file")?;
+        let stdout = lua.create_function(|_, args: LuaVariadic<LuaString>| {
+            write_all(std::io::stdout().lock(), args)
+        })?;
+        let stderr = lua.create_function(|_, args: LuaVariadic<LuaString>| {
+            write_all(std::io::stderr().lock(), args)
+        })?;
+        lua.load(IO_SHIM)
+            .set_name("=io")
+            .call::<()>((file, stdout, stderr))?;
+    }
+    Ok(())
+}
+
+fn write_all(mut out: impl Write, args: LuaVariadic<LuaString>) -> LuaResult<()> {
+    for arg in args {
+        out.write_all(&arg.as_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
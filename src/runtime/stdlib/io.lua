@@ -0,0 +1,135 @@
+-- lua's io library, on top of the async file module. handles support read, write,
+-- lines, seek, flush and close; there is no stdin, and io.write goes to stdout.
+local file, write_stdout, write_stderr = ...
+
+local handle = {}
+handle.__index = handle
+
+local function open(path, mode)
+    local ok, f = pcall(file.open, path, mode and (mode:gsub("b", "")))
+    if not ok then
+        return nil, tostring(f)
+    end
+    return setmetatable({ file = f }, handle)
+end
+
+local function read_one(f, format)
+    if type(format) == "number" then
+        local ok, data = pcall(f.read_exact, f, format)
+        return ok and data or nil
+    end
+    format = format:gsub("^%*", ""):sub(1, 1)
+    if format == "l" or format == "L" then
+        local line = f:read_line()
+        if line and format == "l" then
+            line = line:gsub("\n$", "")
+        end
+        return line
+    elseif format == "a" then
+        return f:read_to_end() or ""
+    elseif format == "n" then
+        local line = f:read_line()
+        return line and tonumber(line)
+    end
+    error("invalid format: " .. format, 3)
+end
+
+function handle:read(...)
+    local formats = { ... }
+    if #formats == 0 then
+        formats[1] = "l"
+    end
+    local results = {}
+    for i, format in ipairs(formats) do
+        results[i] = read_one(self.file, format)
+        if results[i] == nil then
+            break
+        end
+    end
+    return unpack(results, 1, #formats)
+end
+
+function handle:lines(...)
+    local formats = { ... }
+    return function()
+        return self:read(unpack(formats))
+    end
+end
+
+function handle:write(...)
+    self.file:write(...)
+    return self
+end
+
+function handle:seek(whence, offset)
+    return self.file:seek(whence, offset)
+end
+
+function handle:flush()
+    self.file:flush()
+    return self
+end
+
+function handle:close()
+    self.closed = true
+    self.file:close()
+    return true
+end
+
+local function stream(write)
+    return {
+        write = function(self, ...)
+            write(...)
+            return self
+        end,
+        flush = function(self)
+            return self
+        end,
+        close = function()
+            return true
+        end,
+    }
+end
+
+io = {
+    stdout = stream(write_stdout),
+    stderr = stream(write_stderr),
+    open = open,
+}
+
+function io.write(...)
+    return io.stdout:write(...)
+end
+
+function io.read()
+    error("io.read: there is no stdin", 2)
+end
+
+function io.lines(path, ...)
+    if path == nil then
+        error("io.lines: there is no stdin", 2)
+    end
+    local f = assert(open(path))
+    local formats = { ... }
+    return function()
+        if f.closed then
+            return nil
+        end
+        local value = f:read(unpack(formats))
+        if value == nil then
+            f:close()
+        end
+        return value
+    end
+end
+
+function io.close(f)
+    return (f or io.stdout):close()
+end
+
+function io.type(value)
+    if getmetatable(value) == handle then
+        return value.closed and "closed file" or "file"
+    end
+    return nil
+end
@@ -0,0 +1,177 @@
+// the utf8 library from lua 5.3, which luajit doesn't have. enabled with
+// `utf8 = true` in the [stdlib] section of config.toml.
+//
+//   for pos, code in utf8.codes("héllo") do print(pos, code) end
+//   print(utf8.len("héllo"), utf8.char(104, 233))
+//
+// positions are byte positions, and negative ones count from the end, as in lua.
+use mlua::prelude::*;
+
+const CHARPATTERN: &[u8] = b"[\x00-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let utf8 = lua.create_table()?;
+    utf8.set("char", lua.create_function(utf8_char)?)?;
+    utf8.set("charpattern", lua.create_string(CHARPATTERN)?)?;
+    utf8.set("codepoint", lua.create_function(utf8_codepoint)?)?;
+    utf8.set("codes", lua.create_function(utf8_codes)?)?;
+    utf8.set("len", lua.create_function(utf8_len)?)?;
+    utf8.set("offset", lua.create_function(utf8_offset)?)?;
+    lua.globals().set("utf8", utf8)?;
+    Ok(())
+}
+
+/// a lua position as a 1-based index, where negative positions count from the end
+fn position(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() as usize > len {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+/// the character starting at bytes[at], and how many bytes it takes
+fn decode(bytes: &[u8], at: usize) -> Option<(u32, usize)> {
+    let width = match bytes.get(at)? {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => return None,
+    };
+    let text = std::str::from_utf8(bytes.get(at..at + width)?).ok()?;
+    text.chars().next().map(|c| (c as u32, width))
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+fn utf8_char(lua: &Lua, codes: LuaVariadic<u32>) -> LuaResult<LuaString> {
+    let text = codes
+        .iter()
+        .map(|&code| {
+            char::from_u32(code)
+                .ok_or_else(|| LuaError::runtime(format!("utf8.char: value out of range: {code}")))
+        })
+        .collect::<LuaResult<String>>()?;
+    lua.create_string(text)
+}
+
+fn utf8_codepoint(
+    _lua: &Lua,
+    (text, i, j): (LuaString, Option<i64>, Option<i64>),
+) -> LuaResult<LuaVariadic<u32>> {
+    let bytes = text.as_bytes();
+    let start = position(i.unwrap_or(1), bytes.len());
+    let end = position(j.unwrap_or(start), bytes.len());
+    if start < 1 {
+        return Err(LuaError::runtime("utf8.codepoint: out of bounds"));
+    }
+    if end > bytes.len() as i64 {
+        return Err(LuaError::runtime("utf8.codepoint: out of bounds"));
+    }
+    let mut codes = LuaVariadic::new();
+    let mut at = start as usize - 1;
+    while at < end as usize {
+        let (code, width) = decode(&bytes, at)
+            .ok_or_else(|| LuaError::runtime("utf8.codepoint: invalid UTF-8 code"))?;
+        codes.push(code);
+        at += width;
+    }
+    Ok(codes)
+}
+
+fn utf8_codes(lua: &Lua, text: LuaString) -> LuaResult<(LuaFunction, LuaString, i64)> {
+    let next = lua.create_function(|_, (text, pos): (LuaString, i64)| {
+        let bytes = text.as_bytes();
+        // skip the character at pos, which the last call returned
+        let mut at = pos.max(0) as usize;
+        while at > 0 && at < bytes.len() && is_continuation(bytes[at]) {
+            at += 1;
+        }
+        if at >= bytes.len() {
+            return Ok((None, None));
+        }
+        let (code, _) = decode(&bytes, at)
+            .ok_or_else(|| LuaError::runtime("utf8.codes: invalid UTF-8 code"))?;
+        Ok((Some(at as i64 + 1), Some(code)))
+    })?;
+    Ok((next, text, 0))
+}
+
+/// the number of characters between i and j, or nil and the position of the first
+/// invalid byte
+fn utf8_len(
+    _lua: &Lua,
+    (text, i, j): (LuaString, Option<i64>, Option<i64>),
+) -> LuaResult<(Option<i64>, Option<i64>)> {
+    let bytes = text.as_bytes();
+    let start = position(i.unwrap_or(1), bytes.len());
+    let end = position(j.unwrap_or(-1), bytes.len());
+    if start < 1 || start > bytes.len() as i64 + 1 {
+        return Err(LuaError::runtime(
+            "utf8.len: initial position out of bounds",
+        ));
+    }
+    if end > bytes.len() as i64 {
+        return Err(LuaError::runtime("utf8.len: final position out of bounds"));
+    }
+    let mut count = 0;
+    let mut at = start as usize - 1;
+    while (at as i64) < end {
+        match decode(&bytes, at) {
+            Some((_, width)) => at += width,
+            None => return Ok((None, Some(at as i64 + 1))),
+        }
+        count += 1;
+    }
+    Ok((Some(count), None))
+}
+
+/// the byte position where the n-th character counting from position i starts
+fn utf8_offset(_lua: &Lua, (text, n, i): (LuaString, i64, Option<i64>)) -> LuaResult<Option<i64>> {
+    let bytes = text.as_bytes();
+    let len = bytes.len();
+    let default = if n >= 0 { 1 } else { len as i64 + 1 };
+    let start = position(i.unwrap_or(default), len);
+    if start < 1 || start > len as i64 + 1 {
+        return Err(LuaError::runtime("utf8.offset: position out of bounds"));
+    }
+    let continuation = |at: usize| at < len && is_continuation(bytes[at]);
+    let mut at = start as usize - 1;
+    let mut n = n;
+    if n == 0 {
+        // the start of the character containing byte i
+        while at > 0 && continuation(at) {
+            at -= 1;
+        }
+        return Ok(Some(at as i64 + 1));
+    }
+    if continuation(at) {
+        return Err(LuaError::runtime(
+            "utf8.offset: initial position is a continuation byte",
+        ));
+    }
+    if n < 0 {
+        while n < 0 && at > 0 {
+            at -= 1;
+            while at > 0 && continuation(at) {
+                at -= 1;
+            }
+            n += 1;
+        }
+    } else {
+        n -= 1;
+        while n > 0 && at < len {
+            at += 1;
+            while continuation(at) {
+                at += 1;
+            }
+            n -= 1;
+        }
+    }
+    Ok((n == 0).then_some(at as i64 + 1))
+}
@@ -0,0 +1,599 @@
+// sync global tables between lilguy instances, so an app on a laptop and the same app on
+// a home server can both change data and converge.
+//
+//   sync.tables({ "docs", "tasks" })
+//   sync.token(os.getenv("SYNC_TOKEN"))   -- let peers that share this token sync with us
+//   sync.peer("https://home.example.com", { token = os.getenv("SYNC_TOKEN"), interval = 30 })
+//   sync.merge("docs", function(key, mine, theirs)
+//       -- called when both sides changed key without seeing the other's change.
+//       -- mine or theirs is nil if that side deleted it; returning nil deletes it.
+//       return { title = theirs.title, tags = mine.tags }
+//   end)
+//   sync.now()  -- sync with every peer right away, returns the number of changes received
+//
+//   -- on a local network, instances of the same app can find each other instead
+//   sync.advertise("notes", { port = 8000 })
+//   sync.discover("notes", { token = os.getenv("SYNC_TOKEN") })
+//
+// peers pull and push changes over http at /_lilguy/sync, encrypted with a key derived
+// from the token (see sync/seal.rs), so plain http on an untrusted network is safe. each
+// key carries a vector clock (see database/sync.rs); conflicts without a merge function
+// go to the later write.
+mod seal;
+
+use axum::http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use mdns_sd::{ResolvedService, ServiceEvent, ServiceInfo};
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use seal::Purpose;
+pub use seal::SyncKey;
+
+use super::{mdns::get_service_daemon, seconds};
+use crate::database::{
+    sync::{self, Change, Resolution},
+    Database,
+};
+
+const SYNC: &str = "sync";
+const SYNC_MERGE: &str = "sync_merge";
+
+/// where peers find each other, relative to the app
+pub const SYNC_PATH: &str = "/_lilguy/sync";
+
+/// the most changes sent in one request
+pub const BATCH_SIZE: usize = 500;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the mdns service type instances advertise, with the app name in a txt property
+const SERVICE_TYPE: &str = "_lilguy-sync._tcp.local.";
+const DEFAULT_PORT: u16 = 8000;
+
+/// how many times to resolve a change that keeps losing a race with local writes
+const MAX_ATTEMPTS: usize = 3;
+
+/// the content type of sealed change sets
+pub const SEALED_CONTENT_TYPE: &str = "application/x-lilguy-sync";
+
+/// what a peer sends or returns at /_lilguy/sync, sealed with the shared key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeSet {
+    pub node: String,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone)]
+struct Peer {
+    url: String,
+    key: Arc<SyncKey>,
+    interval: Duration,
+    /// the peer's node id, if it was found with mdns
+    node: Option<String>,
+    /// stops syncing with this peer
+    stop: CancellationToken,
+}
+
+impl Peer {
+    /// what the peer's sync progress is saved under. discovered peers keep theirs when
+    /// their address changes.
+    fn cursor(&self) -> &str {
+        self.node.as_deref().unwrap_or(&self.url)
+    }
+}
+
+/// peer tasks belong to a single lua state and stop when it is reloaded or shut down
+#[derive(Debug, Clone)]
+struct SyncState {
+    database: Database,
+    client: reqwest::Client,
+    token: CancellationToken,
+    tasks: TaskTracker,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    accept: Arc<Mutex<Option<Arc<SyncKey>>>>,
+    /// the full names of the services registered by sync.advertise
+    advertised: Arc<Mutex<Vec<String>>>,
+}
+
+impl LuaUserData for SyncState {}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(format!("lilguy/{}", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(60))
+        .build()
+        .into_lua_err()?;
+    lua.set_named_registry_value(
+        SYNC,
+        SyncState {
+            database: database.clone(),
+            client,
+            token: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            peers: Arc::default(),
+            accept: Arc::default(),
+            advertised: Arc::default(),
+        },
+    )?;
+    lua.set_named_registry_value(SYNC_MERGE, lua.create_table()?)?;
+
+    let sync = lua.create_table()?;
+    sync.set("tables", lua.create_async_function(sync_tables)?)?;
+    sync.set("token", lua.create_function(sync_token)?)?;
+    sync.set("peer", lua.create_function(sync_peer)?)?;
+    sync.set("merge", lua.create_function(sync_merge)?)?;
+    sync.set("now", lua.create_async_function(sync_now)?)?;
+    sync.set("advertise", lua.create_async_function(sync_advertise)?)?;
+    sync.set("discover", lua.create_async_function(sync_discover)?)?;
+    lua.globals().set("sync", sync)?;
+    Ok(())
+}
+
+fn state(lua: &Lua) -> LuaResult<SyncState> {
+    let state = lua.named_registry_value::<LuaUserDataRef<SyncState>>(SYNC)?;
+    Ok(SyncState::clone(&state))
+}
+
+/// stop syncing with peers, waiting for a sync in progress to finish
+pub async fn stop(lua: &Lua) -> LuaResult<()> {
+    let state = state(lua)?;
+    let advertised = std::mem::take(&mut *state.advertised.lock());
+    if !advertised.is_empty() {
+        let daemon = get_service_daemon(lua)?;
+        for fullname in advertised {
+            if let Err(err) = daemon.unregister(&fullname) {
+                tracing::warn!(?err, fullname, "sync: error unregistering service");
+            }
+        }
+    }
+    state.token.cancel();
+    state.tasks.close();
+    state.tasks.wait().await;
+    Ok(())
+}
+
+/// sync.tables({ "docs", ... }) starts recording changes to these global tables
+async fn sync_tables(lua: Lua, tables: Vec<String>) -> LuaResult<()> {
+    let state = state(&lua)?;
+    state
+        .database
+        .call(move |conn| {
+            for table in tables {
+                sync::enable(conn, &table)?;
+            }
+            Ok(())
+        })
+        .await
+        .into_lua_err()
+}
+
+/// sync.token(secret) accepts peers that share the secret. without it, this instance
+/// only syncs with the peers it is told about.
+fn sync_token(lua: &Lua, token: Option<String>) -> LuaResult<()> {
+    let key = token
+        .filter(|token| !token.is_empty())
+        .map(|token| Arc::new(SyncKey::new(&token)));
+    *state(lua)?.accept.lock() = key;
+    Ok(())
+}
+
+/// sync.merge(table, function(key, mine, theirs) ... end)
+fn sync_merge(lua: &Lua, (table, merge): (String, Option<LuaFunction>)) -> LuaResult<()> {
+    let merges = lua.named_registry_value::<LuaTable>(SYNC_MERGE)?;
+    merges.set(table, merge)
+}
+
+/// sync.peer(url, { token, interval }) syncs with another instance every interval seconds
+fn sync_peer(lua: &Lua, (url, options): (String, LuaTable)) -> LuaResult<()> {
+    let state = state(lua)?;
+    if state.token.is_cancelled() {
+        return Err(LuaError::runtime("sync.peer called during shutdown"));
+    }
+    let (key, interval) = peer_options(&options)?;
+    spawn_peer(
+        lua,
+        &state,
+        Peer {
+            url: url.trim_end_matches('/').to_string(),
+            key,
+            interval,
+            node: None,
+            stop: state.token.child_token(),
+        },
+    );
+    Ok(())
+}
+
+fn peer_options(options: &LuaTable) -> LuaResult<(Arc<SyncKey>, Duration)> {
+    let token: String = options.get("token")?;
+    if token.is_empty() {
+        return Err(LuaError::runtime("sync peers need a token"));
+    }
+    let interval = options
+        .get::<Option<f64>>("interval")?
+        .map(|interval| seconds("interval", interval).map(|interval| interval.max(MIN_INTERVAL)))
+        .transpose()?
+        .unwrap_or(DEFAULT_INTERVAL);
+    Ok((Arc::new(SyncKey::new(&token)), interval))
+}
+
+/// sync with peer until it or the lua state is stopped
+fn spawn_peer(lua: &Lua, state: &SyncState, peer: Peer) {
+    state.peers.lock().push(peer.clone());
+
+    let weak = lua.weak();
+    let task_state = state.clone();
+    state.tasks.spawn(async move {
+        let state = task_state;
+        loop {
+            // the lua state is only borrowed while syncing, so a reload can drop it
+            let Some(lua) = weak.try_upgrade() else {
+                break;
+            };
+            if let Err(err) = sync_with(&lua, &state, &peer).await {
+                tracing::warn!(?err, url = %peer.url, "sync: error syncing with peer");
+            }
+            drop(lua);
+            tokio::select! {
+                _ = peer.stop.cancelled() => break,
+                _ = tokio::time::sleep(peer.interval) => {}
+            }
+        }
+    });
+}
+
+/// sync.advertise(app, { port }) announces this instance on the local network.
+/// port is the one serve listens on, 8000 by default.
+async fn sync_advertise(lua: Lua, (app, options): (String, Option<LuaTable>)) -> LuaResult<()> {
+    let state = state(&lua)?;
+    let port = options
+        .map(|options| options.get::<Option<u16>>("port"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_PORT);
+    let node = node_id(&state).await?;
+    let properties = HashMap::from([
+        ("app".to_string(), app.clone()),
+        ("node".to_string(), node.clone()),
+    ]);
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &format!("{app}-{node}"),
+        &format!("lilguy-{node}.local."),
+        "",
+        port,
+        properties,
+    )
+    .into_lua_err()?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_string();
+
+    get_service_daemon(&lua)?.register(service).into_lua_err()?;
+    state.advertised.lock().push(fullname);
+    Ok(())
+}
+
+/// sync.discover(app, { token, interval }) syncs with every instance of app that
+/// advertises itself on the local network, for as long as it is there
+async fn sync_discover(lua: Lua, (app, options): (String, LuaTable)) -> LuaResult<()> {
+    let state = state(&lua)?;
+    let (key, interval) = peer_options(&options)?;
+    let node = node_id(&state).await?;
+    let daemon = get_service_daemon(&lua)?;
+    let receiver = daemon.browse(SERVICE_TYPE).into_lua_err()?;
+
+    let weak = lua.weak();
+    let task_state = state.clone();
+    state.tasks.spawn(async move {
+        let state = task_state;
+        // peers by the full name of their service
+        let mut found: HashMap<String, Peer> = HashMap::new();
+        loop {
+            let event = tokio::select! {
+                _ = state.token.cancelled() => break,
+                event = receiver.recv_async() => match event {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
+            match event {
+                ServiceEvent::ServiceResolved(service) => {
+                    let Some((peer_node, url)) = discovered(&service, &app) else {
+                        continue;
+                    };
+                    if peer_node == node {
+                        continue;
+                    }
+                    if let Some(peer) = found.get(&service.fullname) {
+                        if peer.url == url {
+                            continue;
+                        }
+                        forget(&state, peer);
+                    }
+                    let Some(lua) = weak.try_upgrade() else {
+                        break;
+                    };
+                    tracing::info!(%url, node = %peer_node, "sync: discovered peer");
+                    let peer = Peer {
+                        url,
+                        key: key.clone(),
+                        interval,
+                        node: Some(peer_node),
+                        stop: state.token.child_token(),
+                    };
+                    spawn_peer(&lua, &state, peer.clone());
+                    found.insert(service.fullname.clone(), peer);
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    if let Some(peer) = found.remove(&fullname) {
+                        tracing::info!(url = %peer.url, "sync: peer left");
+                        forget(&state, &peer);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Err(err) = daemon.stop_browse(SERVICE_TYPE) {
+            tracing::warn!(?err, "sync: error stopping mdns browse");
+        }
+    });
+    Ok(())
+}
+
+/// the node id and sync url of an advertised instance of app
+fn discovered(service: &ResolvedService, app: &str) -> Option<(String, String)> {
+    let properties = &service.txt_properties;
+    if properties.get_property_val_str("app")? != app {
+        return None;
+    }
+    let node = properties.get_property_val_str("node")?.to_string();
+    let addresses: Vec<_> = service
+        .addresses
+        .iter()
+        .map(|address| address.to_ip_addr())
+        .collect();
+    let address = addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or_else(|| addresses.first())?;
+    let url = match address {
+        std::net::IpAddr::V4(address) => format!("http://{address}:{}", service.port),
+        std::net::IpAddr::V6(address) => format!("http://[{address}]:{}", service.port),
+    };
+    Some((node, url))
+}
+
+/// stop syncing with a peer that has gone away or moved
+fn forget(state: &SyncState, peer: &Peer) {
+    peer.stop.cancel();
+    state.peers.lock().retain(|other| other.url != peer.url);
+}
+
+/// sync.now() syncs with every peer and returns the number of changes received
+async fn sync_now(lua: Lua, _: ()) -> LuaResult<usize> {
+    let state = state(&lua)?;
+    let peers = state.peers.lock().clone();
+    let mut received = 0;
+    for peer in peers {
+        received += sync_with(&lua, &state, &peer).await?;
+    }
+    Ok(received)
+}
+
+/// pull the peer's changes since we last asked, then push ours
+async fn sync_with(lua: &Lua, state: &SyncState, peer: &Peer) -> LuaResult<usize> {
+    let cursor = peer.cursor().to_string();
+    let (mut pulled, mut pushed) = state
+        .database
+        .call(move |conn| sync::peer_cursor(conn, &cursor))
+        .await
+        .into_lua_err()?;
+    let endpoint = format!("{}{SYNC_PATH}", peer.url);
+    let node = node_id(state).await?;
+
+    let mut received = 0;
+    loop {
+        let sealed = state
+            .client
+            .get(format!("{endpoint}?since={pulled}"))
+            .bearer_auth(peer.key.auth())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .into_lua_err()?
+            .bytes()
+            .await
+            .into_lua_err()?;
+        let batch: ChangeSet = peer.key.open(Purpose::Pull, &sealed).into_lua_err()?;
+        let count = batch.changes.len();
+        let Some(last) = batch.changes.last().map(|change| change.seq) else {
+            break;
+        };
+        received += receive(lua, batch.changes).await?;
+        pulled = last;
+        save_cursor(state, peer.cursor(), pulled, pushed).await?;
+        if count < BATCH_SIZE {
+            break;
+        }
+    }
+
+    loop {
+        let changes = state
+            .database
+            .call(move |conn| sync::changes_since(conn, pushed, BATCH_SIZE))
+            .await
+            .into_lua_err()?;
+        let count = changes.len();
+        let Some(last) = changes.last().map(|change| change.seq) else {
+            break;
+        };
+        let sealed = peer
+            .key
+            .seal(
+                Purpose::Push,
+                &ChangeSet {
+                    node: node.clone(),
+                    changes,
+                },
+            )
+            .into_lua_err()?;
+        state
+            .client
+            .post(&endpoint)
+            .bearer_auth(peer.key.auth())
+            .header(CONTENT_TYPE, SEALED_CONTENT_TYPE)
+            .body(sealed)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .into_lua_err()?;
+        pushed = last;
+        save_cursor(state, peer.cursor(), pulled, pushed).await?;
+        if count < BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok(received)
+}
+
+async fn save_cursor(state: &SyncState, peer: &str, pulled: i64, pushed: i64) -> LuaResult<()> {
+    let peer = peer.to_string();
+    state
+        .database
+        .call(move |conn| sync::set_peer_cursor(conn, &peer, pulled, pushed))
+        .await
+        .into_lua_err()
+}
+
+async fn node_id(state: &SyncState) -> LuaResult<String> {
+    state
+        .database
+        .call(|conn| sync::node_id(conn))
+        .await
+        .into_lua_err()
+}
+
+/// check a peer's bearer token, returning the key its changes are sealed with.
+/// sync is hidden unless sync.token() has been called.
+pub fn authorize(lua: &Lua, headers: &HeaderMap) -> Result<Arc<SyncKey>, StatusCode> {
+    let state = state(lua).map_err(|_| StatusCode::NOT_FOUND)?;
+    let key = state.accept.lock().clone().ok_or(StatusCode::NOT_FOUND)?;
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if key.authorizes(token) {
+        Ok(key)
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// the sealed changes a peer asked for with GET /_lilguy/sync?since=seq
+pub async fn changes(lua: &Lua, key: &SyncKey, since: i64) -> LuaResult<Vec<u8>> {
+    let state = state(lua)?;
+    let node = node_id(&state).await?;
+    let changes = state
+        .database
+        .call(move |conn| sync::changes_since(conn, since, BATCH_SIZE))
+        .await
+        .into_lua_err()?;
+    key.seal(Purpose::Pull, &ChangeSet { node, changes })
+        .into_lua_err()
+}
+
+/// the changes a peer pushed with POST /_lilguy/sync, or None if they aren't sealed
+/// with the key
+pub fn open_changes(key: &SyncKey, sealed: &[u8]) -> Option<ChangeSet> {
+    key.open(Purpose::Push, sealed)
+        .inspect_err(|err| tracing::warn!(?err, "sync: rejected pushed changes"))
+        .ok()
+}
+
+/// apply changes from a peer, returning how many replaced what we had
+pub async fn receive(lua: &Lua, changes: Vec<Change>) -> LuaResult<usize> {
+    let state = state(lua)?;
+    let mut applied = 0;
+    for change in changes {
+        for _ in 0..MAX_ATTEMPTS {
+            let (table, key) = (change.table.clone(), change.key.clone());
+            let (synced, local) = state
+                .database
+                .call(move |conn| {
+                    Ok((
+                        sync::is_synced(conn, &table)?,
+                        sync::current(conn, &table, &key)?,
+                    ))
+                })
+                .await
+                .into_lua_err()?;
+            // peers can only change the tables this app syncs
+            if !synced {
+                break;
+            }
+            let resolved = match sync::resolve(local.as_ref(), &change) {
+                Resolution::Ignore => break,
+                Resolution::Apply => change.clone(),
+                Resolution::Conflict => {
+                    let local = local.as_ref().expect("conflicts have a local change");
+                    merge(lua, &state, local, &change).await?
+                }
+            };
+            let written = state
+                .database
+                .call(move |conn| sync::apply(conn, &resolved, local.as_ref()))
+                .await
+                .into_lua_err()?;
+            if written {
+                applied += 1;
+                break;
+            }
+        }
+    }
+    Ok(applied)
+}
+
+/// resolve a conflict with the table's merge function, or by last writer wins
+async fn merge(lua: &Lua, state: &SyncState, local: &Change, remote: &Change) -> LuaResult<Change> {
+    let merges = lua.named_registry_value::<LuaTable>(SYNC_MERGE)?;
+    let Some(merge) = merges.get::<Option<LuaFunction>>(remote.table.as_str())? else {
+        return Ok(sync::last_writer_wins(local, remote));
+    };
+
+    let to_lua = |value: &Option<serde_json::Value>| match value {
+        Some(value) => lua.to_value(value),
+        None => Ok(LuaValue::Nil),
+    };
+    let value: LuaValue = merge
+        .call_async((
+            lua.to_value(&remote.key)?,
+            to_lua(&local.value)?,
+            to_lua(&remote.value)?,
+        ))
+        .await?;
+    let value = match value {
+        LuaValue::Nil => None,
+        value => Some(lua.from_value::<serde_json::Value>(value)?),
+    };
+
+    // the merged value is a new change here, which follows both sides
+    let node = node_id(state).await?;
+    let mut clock = sync::merge_clocks(&local.clock, &remote.clock);
+    *clock.entry(node.clone()).or_default() += 1;
+    Ok(Change {
+        value,
+        clock,
+        node,
+        updated: sync::now(),
+        ..remote.clone()
+    })
+}
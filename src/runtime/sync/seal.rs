@@ -0,0 +1,162 @@
+// end-to-end encryption for sync.
+//
+// peers share a token, which is never sent. two keys are derived from it with hkdf: one
+// that peers present as a bearer token, and one that encrypts every batch of changes
+// with xchacha20-poly1305. someone watching the network (or a proxy terminating tls)
+// sees the bearer token but can neither read changes nor forge them. replaying an old
+// batch does nothing, since changes a peer already has are ignored.
+//
+// the token is the only secret, so it should be long and random, e.g. openssl rand -hex 32.
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+const NONCE_LEN: usize = 24;
+
+/// what a sealed batch is for, so a pulled batch can't be pushed back as if it were new
+#[derive(Debug, Clone, Copy)]
+pub enum Purpose {
+    Pull,
+    Push,
+}
+
+impl Purpose {
+    fn aad(self) -> &'static [u8] {
+        match self {
+            Purpose::Pull => b"lilguy sync v1 pull",
+            Purpose::Push => b"lilguy sync v1 push",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SealError {
+    #[error("could not encrypt sync message")]
+    Encrypt,
+
+    #[error("sealed sync message is too short")]
+    TooShort,
+
+    #[error("could not decrypt sync message, is the token the same on both sides?")]
+    Decrypt,
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub struct SyncKey {
+    auth: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for SyncKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncKey").finish_non_exhaustive()
+    }
+}
+
+impl SyncKey {
+    pub fn new(token: &str) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(b"lilguy sync"), token.as_bytes());
+        let mut auth = [0u8; 32];
+        let mut key = [0u8; 32];
+        hkdf.expand(b"auth", &mut auth)
+            .expect("32 bytes is a valid hkdf output length");
+        hkdf.expand(b"encryption", &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+
+        Self {
+            auth: auth.iter().map(|byte| format!("{byte:02x}")).collect(),
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// the bearer token peers present
+    pub fn auth(&self) -> &str {
+        &self.auth
+    }
+
+    /// true if token is this key's bearer token, compared in constant time
+    pub fn authorizes(&self, token: &str) -> bool {
+        token.as_bytes().ct_eq(self.auth.as_bytes()).into()
+    }
+
+    /// encrypt value as json, prefixed by a random nonce
+    pub fn seal<T: Serialize>(&self, purpose: Purpose, value: &T) -> Result<Vec<u8>, SealError> {
+        let plaintext = serde_json::to_vec(value)?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let sealed = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: purpose.aad(),
+                },
+            )
+            .map_err(|_| SealError::Encrypt)?;
+
+        let mut message = nonce.to_vec();
+        message.extend(sealed);
+        Ok(message)
+    }
+
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        purpose: Purpose,
+        message: &[u8],
+    ) -> Result<T, SealError> {
+        if message.len() < NONCE_LEN {
+            return Err(SealError::TooShort);
+        }
+        let (nonce, sealed) = message.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: purpose.aad(),
+                },
+            )
+            .map_err(|_| SealError::Decrypt)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = SyncKey::new("correct horse battery staple");
+        let message = key.seal(Purpose::Push, &[1, 2, 3]).expect("seal");
+        let opened: Vec<i32> = key.open(Purpose::Push, &message).expect("open");
+        assert_eq!(opened, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn wrong_key_or_purpose_fails() {
+        let key = SyncKey::new("one");
+        let message = key.seal(Purpose::Pull, &"secret").expect("seal");
+        assert!(SyncKey::new("two")
+            .open::<String>(Purpose::Pull, &message)
+            .is_err());
+        assert!(key.open::<String>(Purpose::Push, &message).is_err());
+        assert!(!String::from_utf8_lossy(&message).contains("secret"));
+    }
+
+    #[test]
+    fn auth_is_not_the_token() {
+        let key = SyncKey::new("token");
+        assert_ne!(key.auth(), "token");
+        assert!(key.authorizes(SyncKey::new("token").auth()));
+        assert!(!key.authorizes("token"));
+    }
+}
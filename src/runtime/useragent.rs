@@ -0,0 +1,242 @@
+// what a user-agent header says about the browser, os and device.
+//
+//   local ua = useragent.parse(req.headers["user-agent"])
+//   ua.browser          -- "Chrome", "Safari", "Firefox", "Edge", ... or nil
+//   ua.browser_version  -- "120.0.6099.109"
+//   ua.os               -- "Windows", "macOS", "iOS", "Android", "Linux", "ChromeOS"
+//   ua.os_version       -- "17.2"
+//   ua.device           -- "desktop", "mobile", "tablet" or "bot"
+//   ua.bot              -- "Googlebot", "curl", ... for crawlers and scripts, otherwise nil
+//
+// it knows the common browsers and crawlers, not every user agent there is; anything it
+// doesn't recognize is a desktop with no browser or os.
+use mlua::prelude::*;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    pub browser: Option<&'static str>,
+    pub browser_version: Option<String>,
+    pub os: Option<&'static str>,
+    pub os_version: Option<String>,
+    pub device: &'static str,
+    pub bot: Option<String>,
+}
+
+/// browsers by the product token that names them, most specific first since most
+/// also claim to be chrome or safari
+const BROWSERS: &[(&str, &str)] = &[
+    ("Edg/", "Edge"),
+    ("EdgA/", "Edge"),
+    ("EdgiOS/", "Edge"),
+    ("Edge/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Opera/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("YaBrowser/", "Yandex"),
+    ("Vivaldi/", "Vivaldi"),
+    ("DuckDuckGo/", "DuckDuckGo"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+];
+
+/// tools that identify themselves without saying they are bots
+const CLIENTS: &[&str] = &[
+    "curl/",
+    "Wget/",
+    "python-requests/",
+    "Python-urllib/",
+    "aiohttp/",
+    "httpx/",
+    "Go-http-client/",
+    "okhttp/",
+    "axios/",
+    "node-fetch/",
+    "Java/",
+    "libwww-perl/",
+    "Scrapy/",
+    "HeadlessChrome/",
+    "facebookexternalhit/",
+    "Slurp",
+];
+
+/// the version after a token, up to the next space, semicolon or paren
+fn version_after(ua: &str, token: &str) -> Option<String> {
+    let start = ua.find(token)? + token.len();
+    let version: String = ua[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_'))
+        .collect();
+    (!version.is_empty()).then(|| version.replace('_', "."))
+}
+
+/// the name of the crawler or script, if it is one
+fn bot(ua: &str) -> Option<String> {
+    if ua.trim().is_empty() {
+        return Some("unknown".to_string());
+    }
+    if let Some(client) = CLIENTS.iter().find(|client| ua.contains(*client)) {
+        return Some(client.trim_end_matches('/').to_string());
+    }
+    let lower = ua.to_ascii_lowercase();
+    let at = ["bot", "crawl", "spider"]
+        .iter()
+        .filter_map(|word| lower.find(word))
+        .min()?;
+    // the product token the word is in, like Googlebot in "compatible; Googlebot/2.1;"
+    let start = ua[..at]
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .map_or(0, |i| i + 1);
+    let name: String = ua[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .collect();
+    Some(name)
+}
+
+fn os(ua: &str) -> (Option<&'static str>, Option<String>) {
+    if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iPod") {
+        let version = version_after(ua, "iPhone OS ").or_else(|| version_after(ua, "CPU OS "));
+        return (Some("iOS"), version);
+    }
+    if ua.contains("Android") {
+        return (Some("Android"), version_after(ua, "Android "));
+    }
+    if ua.contains("CrOS") {
+        return (Some("ChromeOS"), None);
+    }
+    if ua.contains("Windows") {
+        let version = version_after(ua, "Windows NT ").map(|nt| match nt.as_str() {
+            "10.0" => "10".to_string(),
+            "6.3" => "8.1".to_string(),
+            "6.2" => "8".to_string(),
+            "6.1" => "7".to_string(),
+            _ => nt,
+        });
+        return (Some("Windows"), version);
+    }
+    if ua.contains("Mac OS X") || ua.contains("Macintosh") {
+        return (Some("macOS"), version_after(ua, "Mac OS X "));
+    }
+    if ua.contains("Linux") || ua.contains("X11") {
+        return (Some("Linux"), None);
+    }
+    (None, None)
+}
+
+fn browser(ua: &str) -> (Option<&'static str>, Option<String>) {
+    for (token, name) in BROWSERS {
+        if ua.contains(token) {
+            return (Some(*name), version_after(ua, token));
+        }
+    }
+    if ua.contains("Safari/") {
+        return (Some("Safari"), version_after(ua, "Version/"));
+    }
+    if ua.contains("MSIE ") {
+        return (Some("Internet Explorer"), version_after(ua, "MSIE "));
+    }
+    if ua.contains("Trident/") {
+        return (Some("Internet Explorer"), version_after(ua, "rv:"));
+    }
+    (None, None)
+}
+
+pub fn parse(ua: &str) -> UserAgent {
+    let (os, os_version) = os(ua);
+    let (browser, browser_version) = browser(ua);
+    let bot = bot(ua);
+    let device = if bot.is_some() {
+        "bot"
+    } else if ua.contains("iPad")
+        || ua.contains("Tablet")
+        || (ua.contains("Android") && !ua.contains("Mobile"))
+    {
+        "tablet"
+    } else if ua.contains("Mobi") || ua.contains("iPhone") || ua.contains("iPod") {
+        "mobile"
+    } else {
+        "desktop"
+    };
+    UserAgent {
+        browser,
+        browser_version,
+        os,
+        os_version,
+        device,
+        bot,
+    }
+}
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    let useragent = lua.create_table()?;
+    useragent.set(
+        "parse",
+        lua.create_function(|lua, ua: Option<String>| {
+            let ua = parse(ua.as_deref().unwrap_or_default());
+            let table = lua.create_table()?;
+            table.set("browser", ua.browser)?;
+            table.set("browser_version", ua.browser_version)?;
+            table.set("os", ua.os)?;
+            table.set("os_version", ua.os_version)?;
+            table.set("device", ua.device)?;
+            table.set("bot", ua.bot)?;
+            Ok(table)
+        })?,
+    )?;
+    lua.globals().set("useragent", useragent)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_browsers() {
+        let ua = parse(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+             Chrome/120.0.6099.109 Safari/537.36 Edg/120.0.2210.77",
+        );
+        assert_eq!(ua.browser, Some("Edge"));
+        assert_eq!(ua.browser_version.as_deref(), Some("120.0.2210.77"));
+        assert_eq!(
+            (ua.os, ua.os_version.as_deref()),
+            (Some("Windows"), Some("10"))
+        );
+        assert_eq!(ua.device, "desktop");
+
+        let ua = parse(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1",
+        );
+        assert_eq!(ua.browser, Some("Safari"));
+        assert_eq!(ua.browser_version.as_deref(), Some("17.2"));
+        assert_eq!(
+            (ua.os, ua.os_version.as_deref()),
+            (Some("iOS"), Some("17.2"))
+        );
+        assert_eq!(ua.device, "mobile");
+
+        let ua = parse(
+            "Mozilla/5.0 (Linux; Android 14; SM-X710) AppleWebKit/537.36 (KHTML, like Gecko) \
+             Chrome/120.0.0.0 Safari/537.36",
+        );
+        assert_eq!((ua.browser, ua.os), (Some("Chrome"), Some("Android")));
+        assert_eq!(ua.device, "tablet");
+    }
+
+    #[test]
+    fn finds_bots() {
+        let ua = parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!(ua.bot.as_deref(), Some("Googlebot"));
+        assert_eq!(ua.device, "bot");
+        assert_eq!(parse("curl/8.4.0").bot.as_deref(), Some("curl"));
+        assert_eq!(parse("").bot.as_deref(), Some("unknown"));
+        assert_eq!(
+            parse("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0").bot,
+            None
+        );
+    }
+}
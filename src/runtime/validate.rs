@@ -0,0 +1,358 @@
+// validate(value, rules) checks a table (usually req.body) against a table of rules
+//
+//   local ok, errors = validate(req.body, {
+//       email = "string:email",
+//       age = "number:min=18",
+//       tags = "array?",
+//       address = { city = "string:min=1" },
+//   })
+//
+// a rule is a type, optionally followed by ? when the field may be missing, then any
+// number of :modifiers. a table in place of a rule validates a nested table.
+// errors maps field names (dotted for nested tables) to a message.
+use mlua::prelude::*;
+use regex::Regex;
+use std::sync::LazyLock;
+
+static EMAIL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").expect("valid regex"));
+
+static UUID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$")
+        .expect("valid regex")
+});
+
+pub fn register(lua: &Lua) -> LuaResult<()> {
+    lua.globals()
+        .set("validate", lua.create_function(validate)?)?;
+    Ok(())
+}
+
+fn validate(lua: &Lua, (value, rules): (LuaValue, LuaTable)) -> LuaResult<(bool, LuaTable)> {
+    let errors = lua.create_table()?;
+    match value {
+        LuaValue::Table(table) => validate_table(&table, &rules, "", &errors)?,
+        LuaValue::Nil => validate_table(&lua.create_table()?, &rules, "", &errors)?,
+        _ => errors.set("", "must be a table")?,
+    }
+
+    Ok((errors.is_empty(), errors))
+}
+
+fn validate_table(
+    table: &LuaTable,
+    rules: &LuaTable,
+    prefix: &str,
+    errors: &LuaTable,
+) -> LuaResult<()> {
+    for pair in rules.pairs::<String, LuaValue>() {
+        let (field, rule) = pair?;
+        let name = if prefix.is_empty() {
+            field.clone()
+        } else {
+            format!("{prefix}.{field}")
+        };
+        let value: LuaValue = table.get(field.as_str())?;
+
+        match rule {
+            LuaValue::String(rule) => {
+                let rule = Rule::parse(&rule.to_str()?)?;
+                if let Err(message) = rule.check(&value) {
+                    errors.set(name, message)?;
+                }
+            }
+            LuaValue::Table(nested) => match value {
+                LuaValue::Table(value) => validate_table(&value, &nested, &name, errors)?,
+                LuaValue::Nil => errors.set(name, "is required")?,
+                _ => errors.set(name, "must be a table")?,
+            },
+            _ => {
+                return Err(LuaError::runtime(format!(
+                    "rule for {name} must be a string or a table"
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Any,
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Table,
+    Array,
+}
+
+#[derive(Debug)]
+enum Modifier {
+    Min(f64),
+    Max(f64),
+    Len(usize),
+    Email,
+    Url,
+    Uuid,
+    Pattern(Regex),
+    OneOf(Vec<String>),
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    kind: Kind,
+    optional: bool,
+    modifiers: Vec<Modifier>,
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> LuaResult<Self> {
+        let (kind, mut rest) = rule.split_once(':').unwrap_or((rule, ""));
+        let (kind, optional) = match kind.strip_suffix('?') {
+            Some(kind) => (kind, true),
+            None => (kind, false),
+        };
+        let kind = match kind {
+            "any" => Kind::Any,
+            "string" => Kind::String,
+            "number" => Kind::Number,
+            "integer" => Kind::Integer,
+            "boolean" => Kind::Boolean,
+            "table" => Kind::Table,
+            "array" => Kind::Array,
+            other => return Err(LuaError::runtime(format!("unknown rule type: {other}"))),
+        };
+
+        let mut modifiers = vec![];
+        while !rest.is_empty() {
+            // pattern= takes the rest of the rule so the regex may contain colons
+            if let Some(pattern) = rest.strip_prefix("pattern=") {
+                modifiers.push(Modifier::Pattern(Regex::new(pattern).into_lua_err()?));
+                break;
+            }
+            let (modifier, next) = rest.split_once(':').unwrap_or((rest, ""));
+            rest = next;
+            let modifier = match modifier.split_once('=') {
+                Some(("min", n)) => Modifier::Min(parse_number(n)?),
+                Some(("max", n)) => Modifier::Max(parse_number(n)?),
+                Some(("len", n)) => Modifier::Len(parse_number(n)? as usize),
+                Some(("oneof", values)) => {
+                    Modifier::OneOf(values.split('|').map(str::to_string).collect())
+                }
+                None if modifier == "email" => Modifier::Email,
+                None if modifier == "url" => Modifier::Url,
+                None if modifier == "uuid" => Modifier::Uuid,
+                _ => return Err(LuaError::runtime(format!("unknown rule: {modifier}"))),
+            };
+            modifiers.push(modifier);
+        }
+
+        Ok(Self {
+            kind,
+            optional,
+            modifiers,
+        })
+    }
+
+    /// convert text from a form into the type the rule asks for.
+    /// values that cannot be converted are returned unchanged so check() reports them.
+    /// forms send inputs left empty as "", which is nil for anything but a string.
+    pub fn coerce(&self, lua: &Lua, value: LuaValue) -> LuaResult<LuaValue> {
+        let text = match value {
+            LuaValue::String(ref s) => s.to_str()?.trim().to_string(),
+            LuaValue::Nil if self.kind == Kind::Boolean => return Ok(LuaValue::Boolean(false)),
+            _ => return Ok(value),
+        };
+        if text.is_empty() && !matches!(self.kind, Kind::String | Kind::Any | Kind::Boolean) {
+            return Ok(LuaValue::Nil);
+        }
+
+        let coerced = match self.kind {
+            Kind::Number => text.parse::<f64>().ok().map(LuaValue::Number),
+            Kind::Integer => text.parse::<i64>().ok().map(LuaValue::Integer),
+            Kind::Boolean => match text.to_lowercase().as_str() {
+                "true" | "on" | "yes" | "1" => Some(LuaValue::Boolean(true)),
+                "false" | "off" | "no" | "0" | "" => Some(LuaValue::Boolean(false)),
+                _ => None,
+            },
+            Kind::Array => Some(LuaValue::Table(lua.create_sequence_from([value.clone()])?)),
+            Kind::String | Kind::Any | Kind::Table => None,
+        };
+
+        Ok(coerced.unwrap_or(value))
+    }
+
+    pub fn check(&self, value: &LuaValue) -> Result<(), String> {
+        if value.is_nil() {
+            return if self.optional {
+                Ok(())
+            } else {
+                Err("is required".to_string())
+            };
+        }
+
+        // form bodies are all strings, so numbers and booleans may be given as text
+        let number = match value {
+            LuaValue::Integer(i) => Some(*i as f64),
+            LuaValue::Number(n) => Some(*n),
+            LuaValue::String(s) => s.to_str().ok().and_then(|s| s.trim().parse().ok()),
+            _ => None,
+        };
+        let text = match value {
+            LuaValue::String(s) => s.to_str().ok().map(|s| s.to_string()),
+            _ => None,
+        };
+        let length = match value {
+            LuaValue::Table(t) => Some(t.raw_len()),
+            LuaValue::String(s) => Some(s.to_str().map(|s| s.chars().count()).unwrap_or(0)),
+            _ => None,
+        };
+
+        let measure = match self.kind {
+            Kind::Any => None,
+            Kind::String => {
+                if text.is_none() {
+                    return Err("must be a string".to_string());
+                }
+                length.map(|l| l as f64)
+            }
+            Kind::Number => {
+                if number.is_none() {
+                    return Err("must be a number".to_string());
+                }
+                number
+            }
+            Kind::Integer => match number {
+                Some(n) if n.fract() == 0.0 => Some(n),
+                _ => return Err("must be an integer".to_string()),
+            },
+            Kind::Boolean => match value {
+                LuaValue::Boolean(_) => None,
+                LuaValue::String(_) if matches!(text.as_deref(), Some("true" | "false")) => None,
+                _ => return Err("must be a boolean".to_string()),
+            },
+            Kind::Table => {
+                if !value.is_table() {
+                    return Err("must be a table".to_string());
+                }
+                length.map(|l| l as f64)
+            }
+            Kind::Array => match value {
+                LuaValue::Table(t) if t.pairs::<LuaValue, LuaValue>().count() == t.raw_len() => {
+                    length.map(|l| l as f64)
+                }
+                _ => return Err("must be an array".to_string()),
+            },
+        };
+        let unit = match self.kind {
+            Kind::Number | Kind::Integer => "",
+            Kind::String => " characters",
+            _ => " items",
+        };
+
+        for modifier in &self.modifiers {
+            match modifier {
+                Modifier::Min(min) => {
+                    if measure.is_some_and(|m| m < *min) {
+                        return Err(format!("must be at least {min}{unit}"));
+                    }
+                }
+                Modifier::Max(max) => {
+                    if measure.is_some_and(|m| m > *max) {
+                        return Err(format!("must be at most {max}{unit}"));
+                    }
+                }
+                Modifier::Len(len) => {
+                    if length.is_some_and(|l| l != *len) {
+                        return Err(format!("must be exactly {len}{unit}"));
+                    }
+                }
+                Modifier::Email => {
+                    if !text.as_deref().is_some_and(|t| EMAIL.is_match(t)) {
+                        return Err("must be a valid email address".to_string());
+                    }
+                }
+                Modifier::Url => {
+                    let valid = text.as_deref().and_then(|t| reqwest::Url::parse(t).ok());
+                    if !valid.is_some_and(|url| matches!(url.scheme(), "http" | "https")) {
+                        return Err("must be a valid url".to_string());
+                    }
+                }
+                Modifier::Uuid => {
+                    if !text.as_deref().is_some_and(|t| UUID.is_match(t)) {
+                        return Err("must be a valid uuid".to_string());
+                    }
+                }
+                Modifier::Pattern(regex) => {
+                    if !text.as_deref().is_some_and(|t| regex.is_match(t)) {
+                        return Err(format!("must match {}", regex.as_str()));
+                    }
+                }
+                Modifier::OneOf(values) => {
+                    let value = text.clone().or_else(|| number.map(|n| n.to_string()));
+                    if !value.is_some_and(|v| values.contains(&v)) {
+                        return Err(format!("must be one of {}", values.join(", ")));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_number(value: &str) -> LuaResult<f64> {
+    value
+        .parse()
+        .map_err(|_| LuaError::runtime(format!("invalid number in rule: {value}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+
+        let code = r#"
+            return validate({ email = "dylan@example.com", age = "17", tags = { "a", "b" } }, {
+                email = "string:email",
+                age = "number:min=18",
+                tags = "array?:max=1",
+                name = "string?",
+                address = { city = "string" },
+            })
+        "#;
+        let (ok, errors): (bool, LuaTable) = lua.load(code).eval().unwrap();
+        assert!(!ok);
+        assert_eq!(errors.get::<Option<String>>("email").unwrap(), None);
+        assert_eq!(errors.get::<String>("age").unwrap(), "must be at least 18");
+        assert_eq!(
+            errors.get::<String>("tags").unwrap(),
+            "must be at most 1 items"
+        );
+        assert_eq!(errors.get::<Option<String>>("name").unwrap(), None);
+        assert_eq!(errors.get::<String>("address").unwrap(), "is required");
+    }
+
+    #[test]
+    fn blank_fields_are_nil() {
+        let lua = Lua::new();
+        let blank = || LuaValue::String(lua.create_string(" ").unwrap());
+        let optional = Rule::parse("number?").unwrap();
+        let value = optional.coerce(&lua, blank()).unwrap();
+        assert!(value.is_nil());
+        assert_eq!(optional.check(&value), Ok(()));
+
+        let required = Rule::parse("integer").unwrap();
+        let value = required.coerce(&lua, blank()).unwrap();
+        assert_eq!(required.check(&value), Err("is required".to_string()));
+
+        let text = Rule::parse("string?").unwrap();
+        assert!(text.coerce(&lua, blank()).unwrap().is_string());
+    }
+}
@@ -0,0 +1,596 @@
+// passkeys: passwordless login with webauthn. built with --features passkeys, since the
+// webauthn library links openssl.
+//
+//   webauthn.origin = "https://example.com"  -- or seo.base_url, one of them must be set
+//
+//   -- adding a passkey, for a user who is signed in
+//   routes.post["/passkeys/new"] = function(req, res)
+//       local user = users[req.session.user_id]
+//       res:json(webauthn.register_start(req, { id = user.id, name = user.email }))
+//   end
+//   routes.post["/passkeys"] = function(req, res)
+//       local passkey = webauthn.register_finish(req, req.body)  -- { id = ..., user = ... }
+//   end
+//
+//   -- signing in with one
+//   routes.post["/login/passkey"] = function(req, res)
+//       local options = webauthn.login_start(req, user_id)  -- nil if they have no passkeys
+//       res:json(options)
+//   end
+//   routes.post["/login/passkey/finish"] = function(req, res)
+//       local user_id = webauthn.login_finish(req, req.body)
+//       req:reset_session()
+//       req.session.user_id = user_id
+//   end
+//
+//   webauthn.passkeys(user_id)  -- { { id = ..., created = ..., used = ... }, ... }
+//   webauthn.remove(id)
+//
+// the options the _start functions return go to the browser, which passes options.publicKey
+// through PublicKeyCredential.parseCreationOptionsFromJSON() or
+// parseRequestOptionsFromJSON() to navigator.credentials.create() or .get(), and posts
+// back credential.toJSON(); the _finish functions take that as json text or a table. the
+// challenge in between is kept in req.session, so each ceremony has to finish in the same
+// session it started in, and only once. passkeys are kept in the lg_passkeys table.
+//
+// the relying party is the host of webauthn.origin, or of seo.base_url, which is what
+// browsers check passkeys against: one registered on localhost doesn't work anywhere else.
+// it is never taken from the request, where the Host header is whatever the client says.
+use mlua::prelude::*;
+use rusqlite::{params, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use webauthn_rs::prelude::*;
+
+use crate::database::Database;
+
+/// where a ceremony's state is kept in req.session
+const STATE: &str = "_webauthn";
+
+/// a ceremony that was started, and who for
+#[derive(Debug, Serialize, Deserialize)]
+struct Ceremony<S> {
+    user: String,
+    state: S,
+}
+
+pub fn register(lua: &Lua, database: &Database) -> LuaResult<()> {
+    let webauthn = lua.create_table()?;
+    webauthn.set(
+        "register_start",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| register_start(lua, database.clone(), args)
+        })?,
+    )?;
+    webauthn.set(
+        "register_finish",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| register_finish(lua, database.clone(), args)
+        })?,
+    )?;
+    webauthn.set(
+        "login_start",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| login_start(lua, database.clone(), args)
+        })?,
+    )?;
+    webauthn.set(
+        "login_finish",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| login_finish(lua, database.clone(), args)
+        })?,
+    )?;
+    webauthn.set(
+        "passkeys",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| list_passkeys(lua, database.clone(), args)
+        })?,
+    )?;
+    webauthn.set(
+        "remove",
+        lua.create_async_function({
+            let database = database.clone();
+            move |lua, args| remove_passkey(lua, database.clone(), args)
+        })?,
+    )?;
+    lua.globals().set("webauthn", webauthn)?;
+    Ok(())
+}
+
+/// the relying party, from webauthn.origin or seo.base_url
+fn relying_party(lua: &Lua) -> LuaResult<Webauthn> {
+    let globals = lua.globals();
+    let origin = match globals
+        .get::<LuaTable>("webauthn")?
+        .get::<Option<String>>("origin")?
+    {
+        Some(origin) => Some(origin),
+        None => match globals.get::<Option<LuaTable>>("seo")? {
+            Some(seo) => seo.get::<Option<String>>("base_url")?,
+            None => None,
+        },
+    };
+    let Some(origin) = origin else {
+        return Err(LuaError::runtime(
+            "set webauthn.origin, or seo.base_url, to the url passkeys are for",
+        ));
+    };
+    let origin = Url::parse(origin.trim_end_matches('/')).into_lua_err()?;
+    let rp_id = origin
+        .host_str()
+        .ok_or_else(|| LuaError::runtime(format!("{origin} has no host for passkeys")))?
+        .to_string();
+    WebauthnBuilder::new(&rp_id, &origin)
+        .into_lua_err()?
+        .rp_name(&rp_id)
+        .build()
+        .into_lua_err()
+}
+
+/// the stable, opaque id browsers keep a user's passkeys under
+fn user_handle(user: &str) -> Uuid {
+    let hash = Sha256::digest(user.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+/// the base64url id of a credential
+fn credential_id(id: &CredentialID) -> LuaResult<String> {
+    match serde_json::to_value(id).into_lua_err()? {
+        serde_json::Value::String(id) => Ok(id),
+        id => Err(LuaError::runtime(format!("unexpected credential id {id}"))),
+    }
+}
+
+/// keep a ceremony's state in the session until it finishes
+fn stash<S: Serialize>(req: &LuaTable, user: String, state: S) -> LuaResult<()> {
+    let session = req.get::<LuaTable>("session")?;
+    let json = serde_json::to_string(&Ceremony { user, state }).into_lua_err()?;
+    session.set(STATE, json)
+}
+
+/// the ceremony started in the session, which can only be finished once
+fn take<S: DeserializeOwned>(req: &LuaTable) -> LuaResult<Ceremony<S>> {
+    let session = req.get::<LuaTable>("session")?;
+    let Some(json) = session.get::<Option<String>>(STATE)? else {
+        return Err(LuaError::runtime(
+            "no passkey ceremony was started in this session",
+        ));
+    };
+    session.set(STATE, LuaNil)?;
+    serde_json::from_str(&json).into_lua_err()
+}
+
+/// what the browser sent, as json text or a table
+fn credential<T: DeserializeOwned>(lua: &Lua, value: LuaValue) -> LuaResult<T> {
+    let value: serde_json::Value = match value {
+        LuaValue::String(text) => serde_json::from_slice(&text.as_bytes()).into_lua_err()?,
+        value => lua.from_value(value)?,
+    };
+    serde_json::from_value(value).into_lua_err()
+}
+
+async fn passkeys(database: &Database, user: String) -> LuaResult<Vec<Passkey>> {
+    let rows = database
+        .call(move |conn| {
+            let mut statement = conn
+                .prepare("SELECT json(passkey) FROM lg_passkeys WHERE user = ? ORDER BY created")?;
+            let rows = statement
+                .query_map([user], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+        .await
+        .into_lua_err()?;
+    rows.iter()
+        .map(|json| serde_json::from_str(json).into_lua_err())
+        .collect()
+}
+
+/// webauthn.register_start(req, { id = ..., name = ..., display_name = ... })
+async fn register_start(
+    lua: Lua,
+    database: Database,
+    (req, user): (LuaTable, LuaTable),
+) -> LuaResult<LuaValue> {
+    let id: String = user.get("id")?;
+    let name: String = user.get("name")?;
+    let display_name = user.get::<Option<String>>("display_name")?;
+    let exclude = passkeys(&database, id.clone())
+        .await?
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect::<Vec<_>>();
+    let (options, state) = relying_party(&lua)?
+        .start_passkey_registration(
+            user_handle(&id),
+            &name,
+            display_name.as_deref().unwrap_or(&name),
+            (!exclude.is_empty()).then_some(exclude),
+        )
+        .into_lua_err()?;
+    stash(&req, id, state)?;
+    lua.to_value(&options)
+}
+
+/// webauthn.register_finish(req, credential)
+async fn register_finish(
+    lua: Lua,
+    database: Database,
+    (req, value): (LuaTable, LuaValue),
+) -> LuaResult<LuaTable> {
+    let Ceremony { user, state } = take::<PasskeyRegistration>(&req)?;
+    let response: RegisterPublicKeyCredential = credential(&lua, value)?;
+    let passkey = relying_party(&lua)?
+        .finish_passkey_registration(&response, &state)
+        .into_lua_err()?;
+    let id = credential_id(passkey.cred_id())?;
+    let json = serde_json::to_string(&passkey).into_lua_err()?;
+    let (saved_id, saved_user) = (id.clone(), user.clone());
+    database
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO lg_passkeys (id, user, passkey) VALUES (?, ?, jsonb(?))",
+                params![saved_id, saved_user, json],
+            )?;
+            Ok(())
+        })
+        .await
+        .into_lua_err()?;
+    let table = lua.create_table()?;
+    table.set("id", id)?;
+    table.set("user", user)?;
+    Ok(table)
+}
+
+/// webauthn.login_start(req, user)
+async fn login_start(
+    lua: Lua,
+    database: Database,
+    (req, user): (LuaTable, String),
+) -> LuaResult<LuaValue> {
+    let passkeys = passkeys(&database, user.clone()).await?;
+    if passkeys.is_empty() {
+        return Ok(LuaNil);
+    }
+    let (options, state) = relying_party(&lua)?
+        .start_passkey_authentication(&passkeys)
+        .into_lua_err()?;
+    stash(&req, user, state)?;
+    lua.to_value(&options)
+}
+
+/// webauthn.login_finish(req, credential), the user who signed in
+async fn login_finish(
+    lua: Lua,
+    database: Database,
+    (req, value): (LuaTable, LuaValue),
+) -> LuaResult<String> {
+    let Ceremony { user, state } = take::<PasskeyAuthentication>(&req)?;
+    let response: PublicKeyCredential = credential(&lua, value)?;
+    let result = relying_party(&lua)?
+        .finish_passkey_authentication(&response, &state)
+        .into_lua_err()?;
+    let id = credential_id(result.cred_id())?;
+    let owner = user.clone();
+    let stored = database
+        .call(move |conn| {
+            let stored = conn
+                .query_row(
+                    "SELECT json(passkey) FROM lg_passkeys WHERE id = ? AND user = ?",
+                    params![id, owner],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            Ok(stored.map(|stored| (id, stored)))
+        })
+        .await
+        .into_lua_err()?;
+    let Some((id, stored)) = stored else {
+        return Err(LuaError::runtime("that passkey was removed"));
+    };
+    // the signature counter and backup state change as a passkey is used
+    let mut passkey: Passkey = serde_json::from_str(&stored).into_lua_err()?;
+    passkey.update_credential(&result);
+    let json = serde_json::to_string(&passkey).into_lua_err()?;
+    database
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE lg_passkeys SET passkey = jsonb(?), used = unixepoch() WHERE id = ?",
+                params![json, id],
+            )?;
+            Ok(())
+        })
+        .await
+        .into_lua_err()?;
+    Ok(user)
+}
+
+/// webauthn.passkeys(user)
+async fn list_passkeys(lua: Lua, database: Database, user: String) -> LuaResult<LuaTable> {
+    let rows = database
+        .call(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT id, created, used FROM lg_passkeys WHERE user = ? ORDER BY created",
+            )?;
+            let rows = statement
+                .query_map([user], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+        .await
+        .into_lua_err()?;
+    let list = lua.create_table()?;
+    for (id, created, used) in rows {
+        let passkey = lua.create_table()?;
+        passkey.set("id", id)?;
+        passkey.set("created", created)?;
+        passkey.set("used", used)?;
+        list.push(passkey)?;
+    }
+    Ok(list)
+}
+
+/// webauthn.remove(id)
+async fn remove_passkey(_lua: Lua, database: Database, id: String) -> LuaResult<bool> {
+    database
+        .call(move |conn| Ok(conn.execute("DELETE FROM lg_passkeys WHERE id = ?", [id])? > 0))
+        .await
+        .into_lua_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use serde_json::{json, Value};
+
+    const ORIGIN: &str = "https://example.com";
+
+    /// a cbor item's head, for the few kinds of items an authenticator sends
+    fn cbor(major: u8, len: usize, out: &mut Vec<u8>) {
+        match len {
+            0..24 => out.push(major << 5 | len as u8),
+            24..256 => out.extend([major << 5 | 24, len as u8]),
+            _ => {
+                out.push(major << 5 | 25);
+                out.extend((len as u16).to_be_bytes());
+            }
+        }
+    }
+
+    fn cbor_int(n: i64, out: &mut Vec<u8>) {
+        match n {
+            0.. => cbor(0, n as usize, out),
+            _ => cbor(1, (-1 - n) as usize, out),
+        }
+    }
+
+    fn cbor_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+        cbor(2, bytes.len(), out);
+        out.extend(bytes);
+    }
+
+    fn cbor_text(text: &str, out: &mut Vec<u8>) {
+        cbor(3, text.len(), out);
+        out.extend(text.as_bytes());
+    }
+
+    /// a software authenticator with one es256 key
+    struct Authenticator {
+        key: EcdsaKeyPair,
+        id: Vec<u8>,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            Self {
+                key,
+                id: b"a test credential".to_vec(),
+            }
+        }
+
+        fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+            let challenge = options["publicKey"]["challenge"].as_str().unwrap();
+            serde_json::to_vec(&json!({
+                "type": kind,
+                "challenge": challenge,
+                "origin": ORIGIN,
+                "crossOrigin": false,
+            }))
+            .unwrap()
+        }
+
+        /// the rp id hash, flags for a present and verified user, and the counter
+        fn auth_data(flags: u8, counter: u32) -> Vec<u8> {
+            let mut data = Sha256::digest(b"example.com").to_vec();
+            data.push(flags);
+            data.extend(counter.to_be_bytes());
+            data
+        }
+
+        fn create(&self, options: &Value) -> Value {
+            let client_data = Self::client_data("webauthn.create", options);
+            let mut auth_data = Self::auth_data(0x45, 0);
+            auth_data.extend([0; 16]);
+            auth_data.extend((self.id.len() as u16).to_be_bytes());
+            auth_data.extend(&self.id);
+            // the public key as a cose ec2 key: kty, alg, crv, x and y
+            let point = self.key.public_key().as_ref();
+            cbor(5, 5, &mut auth_data);
+            for (label, value) in [(1, 2), (3, -7), (-1, 1)] {
+                cbor_int(label, &mut auth_data);
+                cbor_int(value, &mut auth_data);
+            }
+            cbor_int(-2, &mut auth_data);
+            cbor_bytes(&point[1..33], &mut auth_data);
+            cbor_int(-3, &mut auth_data);
+            cbor_bytes(&point[33..], &mut auth_data);
+
+            let mut attestation = Vec::new();
+            cbor(5, 3, &mut attestation);
+            cbor_text("fmt", &mut attestation);
+            cbor_text("none", &mut attestation);
+            cbor_text("attStmt", &mut attestation);
+            cbor(5, 0, &mut attestation);
+            cbor_text("authData", &mut attestation);
+            cbor_bytes(&auth_data, &mut attestation);
+
+            json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.id),
+                "rawId": URL_SAFE_NO_PAD.encode(&self.id),
+                "type": "public-key",
+                "response": {
+                    "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+                    "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                },
+                "extensions": {},
+            })
+        }
+
+        fn get(&self, options: &Value, counter: u32) -> Value {
+            let client_data = Self::client_data("webauthn.get", options);
+            let auth_data = Self::auth_data(0x05, counter);
+            let mut signed = auth_data.clone();
+            signed.extend(Sha256::digest(&client_data));
+            let signature = self.key.sign(&SystemRandom::new(), &signed).unwrap();
+            json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.id),
+                "rawId": URL_SAFE_NO_PAD.encode(&self.id),
+                "type": "public-key",
+                "response": {
+                    "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                    "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                    "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                },
+                "extensions": {},
+            })
+        }
+    }
+
+    #[test]
+    fn user_handles_are_stable() {
+        assert_eq!(user_handle("42"), user_handle("42"));
+        assert_ne!(user_handle("42"), user_handle("43"));
+    }
+
+    #[test]
+    fn needs_an_origin() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("webauthn", lua.create_table().unwrap())
+            .unwrap();
+        assert!(relying_party(&lua).is_err());
+
+        let seo = lua.create_table().unwrap();
+        seo.set("base_url", "https://blog.example.com/").unwrap();
+        lua.globals().set("seo", seo).unwrap();
+        assert!(relying_party(&lua).is_ok());
+    }
+
+    #[test]
+    fn registers_and_logs_in() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let database = Database::open_in_memory().unwrap();
+            database
+                .call(|conn| {
+                    conn.execute_batch(include_str!("../schema.sql"))?;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            let lua = Lua::new();
+            register(&lua, &database).unwrap();
+            lua.globals()
+                .get::<LuaTable>("webauthn")
+                .unwrap()
+                .set("origin", ORIGIN)
+                .unwrap();
+            let req = lua.create_table().unwrap();
+            req.set("session", lua.create_table().unwrap()).unwrap();
+            let to_json = |value: LuaValue| lua.from_value::<Value>(value).unwrap();
+            let authenticator = Authenticator::new();
+
+            let user = lua.create_table().unwrap();
+            user.set("id", "42").unwrap();
+            user.set("name", "alice@example.com").unwrap();
+            let options = register_start(lua.clone(), database.clone(), (req.clone(), user))
+                .await
+                .unwrap();
+            let options = to_json(options);
+            assert_eq!(options["publicKey"]["rp"]["id"], "example.com");
+            let credential = lua.to_value(&authenticator.create(&options)).unwrap();
+            let passkey = register_finish(lua.clone(), database.clone(), (req.clone(), credential))
+                .await
+                .unwrap();
+            assert_eq!(passkey.get::<String>("user").unwrap(), "42");
+
+            // a ceremony can only be finished once
+            let credential = lua.to_value(&authenticator.create(&options)).unwrap();
+            assert!(
+                register_finish(lua.clone(), database.clone(), (req.clone(), credential))
+                    .await
+                    .is_err()
+            );
+
+            let nobody = login_start(lua.clone(), database.clone(), (req.clone(), "7".into()))
+                .await
+                .unwrap();
+            assert!(nobody.is_nil());
+
+            let options = login_start(lua.clone(), database.clone(), (req.clone(), "42".into()))
+                .await
+                .unwrap();
+            let options = to_json(options);
+            let credential = serde_json::to_string(&authenticator.get(&options, 1)).unwrap();
+            let credential = LuaValue::String(lua.create_string(credential).unwrap());
+            let user = login_finish(lua.clone(), database.clone(), (req.clone(), credential))
+                .await
+                .unwrap();
+            assert_eq!(user, "42");
+
+            // a signature over someone else's challenge isn't accepted
+            let stale = authenticator.get(&options, 2);
+            login_start(lua.clone(), database.clone(), (req.clone(), "42".into()))
+                .await
+                .unwrap();
+            let credential = lua.to_value(&stale).unwrap();
+            assert!(
+                login_finish(lua.clone(), database.clone(), (req.clone(), credential))
+                    .await
+                    .is_err()
+            );
+
+            let passkeys = list_passkeys(lua.clone(), database.clone(), "42".into())
+                .await
+                .unwrap();
+            assert_eq!(passkeys.raw_len(), 1);
+        });
+    }
+}
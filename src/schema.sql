@@ -5,9 +5,155 @@ CREATE TABLE IF NOT EXISTS lg_internal (
     value TEXT NOT NULL
 );
 
--- session is just a json blob store (using JSONB)
-CREATE TABLE IF NOT EXISTS lg_session (
-    uuid TEXT PRIMARY KEY,
-    data JSONB NOT NULL
-)
+-- lg_session came before lg_sessions, and nothing ever wrote to it
+DROP TABLE IF EXISTS lg_session;
 
+-- req.session data, by the id in the visitor's session cookie. see runtime/http/session.rs
+CREATE TABLE IF NOT EXISTS lg_sessions (
+    id TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    created INTEGER NOT NULL DEFAULT (unixepoch()),
+    expires INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS lg_sessions_expires ON lg_sessions (expires);
+
+-- passkeys by credential id, for the app's own user ids. see runtime/webauthn.rs
+CREATE TABLE IF NOT EXISTS lg_passkeys (
+    id TEXT PRIMARY KEY,
+    user TEXT NOT NULL,
+    passkey JSONB NOT NULL,
+    created INTEGER NOT NULL DEFAULT (unixepoch()),
+    used INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS lg_passkeys_user ON lg_passkeys (user);
+
+-- large binary values, stored as-is rather than as JSONB
+CREATE TABLE IF NOT EXISTS lg_blob (
+    key TEXT PRIMARY KEY,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+
+-- append-only log written by audit.log(), pruned by audit.retention()
+CREATE TABLE IF NOT EXISTS lg_audit (
+    id INTEGER PRIMARY KEY,
+    created INTEGER NOT NULL,
+    actor TEXT,
+    action TEXT NOT NULL,
+    subject TEXT,
+    details JSONB,
+    request_id TEXT
+);
+
+CREATE INDEX IF NOT EXISTS lg_audit_created ON lg_audit (created);
+
+-- sync between instances: the synced global tables, the latest change to each of their
+-- keys, and how far each peer has been synced. see database/sync.rs
+CREATE TABLE IF NOT EXISTS lg_sync_tables (
+    name TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS lg_sync (
+    seq INTEGER NOT NULL UNIQUE,
+    tbl TEXT NOT NULL,
+    key NOT NULL,
+    value JSONB,
+    clock TEXT NOT NULL,
+    node TEXT NOT NULL,
+    updated INTEGER NOT NULL,
+    PRIMARY KEY (tbl, key)
+);
+
+-- peers are identified by url, or by node id when they were found with mdns
+CREATE TABLE IF NOT EXISTS lg_sync_peers (
+    peer TEXT PRIMARY KEY,
+    pulled INTEGER NOT NULL DEFAULT 0,
+    pushed INTEGER NOT NULL DEFAULT 0
+);
+
+-- crdt documents, see database/crdt.rs
+CREATE TABLE IF NOT EXISTS lg_crdt (
+    name TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    state BLOB NOT NULL,
+    updated INTEGER NOT NULL
+);
+
+-- responses stored by fetch.cached(), revalidated once they expire
+CREATE TABLE IF NOT EXISTS lg_fetch_cache (
+    url TEXT PRIMARY KEY,
+    headers TEXT NOT NULL,
+    body BLOB NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    expires INTEGER NOT NULL
+);
+
+-- activitypub: the key each local actor signs with, their followers, what they have
+-- published, and the documents of remote actors. see database/activitypub.rs
+CREATE TABLE IF NOT EXISTS lg_activitypub_keys (
+    actor TEXT PRIMARY KEY,
+    private_key TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS lg_activitypub_followers (
+    actor TEXT NOT NULL,
+    follower TEXT NOT NULL,
+    inbox TEXT NOT NULL,
+    created INTEGER NOT NULL DEFAULT (unixepoch()),
+    PRIMARY KEY (actor, follower)
+);
+
+CREATE TABLE IF NOT EXISTS lg_activitypub_outbox (
+    id TEXT PRIMARY KEY,
+    actor TEXT NOT NULL,
+    activity JSONB NOT NULL,
+    created INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX IF NOT EXISTS lg_activitypub_outbox_actor ON lg_activitypub_outbox (actor, created);
+
+CREATE TABLE IF NOT EXISTS lg_activitypub_remote (
+    id TEXT PRIMARY KEY,
+    document JSONB NOT NULL,
+    fetched INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+-- webhook events from payments, kept so retried deliveries are only handled once.
+-- see database/payments.rs
+CREATE TABLE IF NOT EXISTS lg_payment_events (
+    id TEXT PRIMARY KEY,
+    type TEXT NOT NULL,
+    event JSONB NOT NULL,
+    created INTEGER NOT NULL,
+    received INTEGER NOT NULL DEFAULT (unixepoch()),
+    handled INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS lg_payment_events_type ON lg_payment_events (type, created);
+
+-- slugs renamed with slugs.rename(), which routes:slug() routes redirect from.
+-- see runtime/slug.rs
+CREATE TABLE IF NOT EXISTS lg_slug (
+    pattern TEXT NOT NULL,
+    old TEXT NOT NULL,
+    new TEXT NOT NULL,
+    created INTEGER NOT NULL DEFAULT (unixepoch()),
+    PRIMARY KEY (pattern, old)
+);
+
+-- page views, when [analytics] is enabled. see routes/analytics.rs
+CREATE TABLE IF NOT EXISTS lg_analytics (
+    id INTEGER PRIMARY KEY,
+    created INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    referrer TEXT,
+    country TEXT,
+    visitor TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS lg_analytics_created ON lg_analytics (created);
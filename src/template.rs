@@ -1,16 +1,66 @@
-use minijinja::{path_loader, Environment};
+use minijinja::{context, path_loader, value::Kwargs, Environment, Value};
 use mlua::prelude::*;
-use std::{path::Path, thread};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     oneshot,
 };
 
+use crate::{
+    routes::live,
+    runtime::{highlight, island, og, profiler, sri},
+};
+
 #[derive(Debug, Clone)]
 pub struct Template {
+    workers: Arc<[Worker]>,
+
+    /// set with template.layout = "layout.html". res:render wraps pages in it,
+    /// passing the rendered page as `content`.
+    layout: Option<String>,
+}
+
+/// a thread with its own environment
+#[derive(Debug)]
+struct Worker {
     sender: UnboundedSender<Message>,
+
+    /// calls sent to this worker that have not finished
+    pending: Arc<AtomicUsize>,
 }
 
+/// the [template] section of config.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TemplateConfig {
+    /// how many threads render templates, defaults to the number of cpus up to 4
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+}
+
+impl TemplateConfig {
+    pub fn workers(&self) -> usize {
+        self.workers
+            .unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|cpus| cpus.get().min(DEFAULT_MAX_WORKERS))
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+}
+
+const DEFAULT_MAX_WORKERS: usize = 4;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -26,30 +76,91 @@ type CallFn = Box<dyn FnOnce(&mut Environment<'static>) + Send + 'static>;
 
 enum Message {
     Execute(CallFn),
+    Close,
+}
+
+fn environment(directory: &Path) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_loader(path_loader(directory));
+    env.add_function("og_tags", og_tags);
+    env.add_function("island", island_tag);
+    env.add_function("highlight", highlight_code);
+    env.add_function("highlight_css", || {
+        Value::from_safe_string(highlight::THEME_CSS.into())
+    });
+    env.add_filter("highlight", highlight_code);
+    env.add_filter("live", live::filter);
+
+    // the assets directory is next to the templates one; see runtime/sri.rs
+    let assets = directory.with_file_name("assets");
+    let helpers: [(&str, fn(&str, &sri::Hash) -> String, bool); 4] = [
+        ("asset", sri::url, false),
+        ("integrity", |_, hash| hash.integrity.clone(), false),
+        ("script", sri::script_html, true),
+        ("stylesheet", sri::stylesheet_html, true),
+    ];
+    for (name, helper, html) in helpers {
+        let assets = assets.clone();
+        env.add_function(name, move |path: String| {
+            let hash = sri::hash(&assets, &path).map_err(|err| {
+                minijinja::Error::new(
+                    minijinja::ErrorKind::InvalidOperation,
+                    format!("can't read asset {path}"),
+                )
+                .with_source(err)
+            })?;
+            let text = helper(&path, &hash);
+            Ok::<_, minijinja::Error>(if html {
+                Value::from_safe_string(text)
+            } else {
+                Value::from(text)
+            })
+        });
+    }
+    env
 }
 
 impl Template {
-    pub fn new<P>(directory: P) -> Self
+    pub fn new<P>(directory: P, workers: usize) -> Self
     where
         P: AsRef<Path>,
     {
-        let mut env = Environment::new();
-        env.set_loader(path_loader(directory));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let env = environment(directory.as_ref());
+                let (sender, receiver) = unbounded_channel::<Message>();
+                let pending = Arc::new(AtomicUsize::new(0));
+                let worker = Worker {
+                    sender,
+                    pending: pending.clone(),
+                };
+                thread::spawn(move || event_loop(env, receiver, pending));
+                worker
+            })
+            .collect();
 
-        let (sender, receiver) = unbounded_channel::<Message>();
-        thread::spawn(move || event_loop(env, receiver));
-
-        Self { sender }
+        Self {
+            workers,
+            layout: None,
+        }
     }
 
+    /// run function on the worker with the fewest calls waiting
     pub async fn call<F, R>(&self, function: F) -> Result<R>
     where
         F: FnOnce(&mut Environment) -> Result<R> + 'static + Send,
         R: Send + 'static,
     {
+        let worker = self
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.pending.load(Ordering::Relaxed))
+            .ok_or(Error::ConnectionClosed)?;
         let (sender, receiver) = oneshot::channel::<Result<R>>();
 
-        self.sender
+        worker.pending.fetch_add(1, Ordering::Relaxed);
+        worker
+            .sender
             .send(Message::Execute(Box::new(move |conn| {
                 let value = function(conn);
                 let _ = sender.send(value);
@@ -58,25 +169,132 @@ impl Template {
 
         receiver.await.map_err(|_| Error::ConnectionClosed)?
     }
+
+    /// run function on every worker, for changes to the environment like clearing the
+    /// templates after they are edited. each worker finishes the calls it already has first.
+    pub async fn call_each<F>(&self, function: F) -> Result<()>
+    where
+        F: Fn(&mut Environment) -> Result<()> + Clone + 'static + Send,
+    {
+        let mut receivers = Vec::with_capacity(self.workers.len());
+        for worker in self.workers.iter() {
+            let (sender, receiver) = oneshot::channel::<Result<()>>();
+            let function = function.clone();
+            worker.pending.fetch_add(1, Ordering::Relaxed);
+            worker
+                .sender
+                .send(Message::Execute(Box::new(move |conn| {
+                    let _ = sender.send(function(conn));
+                })))
+                .map_err(|_| Error::ConnectionClosed)?;
+            receivers.push(receiver);
+        }
+        for receiver in receivers {
+            receiver.await.map_err(|_| Error::ConnectionClosed)??;
+        }
+        Ok(())
+    }
+
+    /// a markdown file as an html page, in the template called name. the template gets the
+    /// rendered markdown as `content`, its first heading as `title` and the page's `path`.
+    /// if there is no such template, the page is plain html.
+    pub async fn render_markdown(
+        &self,
+        name: String,
+        path: String,
+        markdown: String,
+    ) -> Result<String> {
+        let content = comrak::markdown_to_html(&markdown, &comrak::ComrakOptions::default());
+        let title = markdown
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map_or_else(|| path.clone(), |title| title.trim().to_string());
+        self.call(move |env| match env.get_template(&name) {
+            Ok(template) => Ok(template.render(context! {
+                content => Value::from_safe_string(content),
+                title,
+                path,
+            })?),
+            Err(err) if err.kind() == minijinja::ErrorKind::TemplateNotFound => Ok(format!(
+                "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                 <title>{}</title>\n</head>\n<body>\n{content}</body>\n</html>\n",
+                escape(&title)
+            )),
+            Err(err) => Err(err.into()),
+        })
+        .await
+    }
+
+    /// Stop the template threads. Any calls made after this return `ConnectionClosed`.
+    pub fn close(&self) {
+        for worker in self.workers.iter() {
+            let _ = worker.sender.send(Message::Close);
+        }
+    }
 }
 
-fn event_loop(mut env: Environment<'static>, mut receiver: UnboundedReceiver<Message>) {
+fn event_loop(
+    mut env: Environment<'static>,
+    mut receiver: UnboundedReceiver<Message>,
+    pending: Arc<AtomicUsize>,
+) {
     while let Some(message) = receiver.blocking_recv() {
         match message {
-            Message::Execute(f) => f(&mut env),
+            Message::Execute(f) => {
+                f(&mut env);
+                pending.fetch_sub(1, Ordering::Relaxed);
+            }
+            Message::Close => break,
         }
     }
 }
 
 impl LuaUserData for Template {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("layout", |_, this| Ok(this.layout.clone()));
+        fields.add_field_method_set("layout", |_, this, layout: Option<String>| {
+            this.layout = layout;
+            Ok(())
+        });
+    }
+
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        // render(name, context)
+        // render(name, context, layout)
+        // with a layout, the page is rendered first and passed to the layout as `content`
         methods.add_async_method(
             "render",
-            |_, this, (name, context): (String, LuaValue)| async move {
+            |lua, this, (name, context, layout): (String, LuaValue, Option<String>)| async move {
+                let start = Instant::now();
+                let timed = name.clone();
+                let rendered = this
+                    .call(move |env| {
+                        let template = env.get_template(name.as_str())?;
+                        let rendered = template.render(&context)?;
+                        let Some(layout) = layout else {
+                            return Ok(rendered);
+                        };
+                        let layout = env.get_template(layout.as_str())?;
+                        let rendered = layout.render(context! {
+                            content => Value::from_safe_string(rendered),
+                            ..Value::from_serialize(&context)
+                        })?;
+                        Ok(rendered)
+                    })
+                    .await
+                    .into_lua_err();
+                profiler::time_template(&lua, &timed, start.elapsed());
+                rendered
+            },
+        );
+
+        // render_block(name, block, context)
+        // renders a single block of a template, such as one row of a table for htmx
+        methods.add_async_method(
+            "render_block",
+            |_, this, (name, block, context): (String, String, LuaValue)| async move {
                 this.call(move |env| {
                     let template = env.get_template(name.as_str())?;
-                    let rendered = template.render(context)?;
+                    let rendered = template.eval_to_state(context)?.render_block(&block)?;
                     Ok(rendered)
                 })
                 .await
@@ -85,3 +303,94 @@ impl LuaUserData for Template {
         );
     }
 }
+
+/// {{ og_tags(title=post.title, description=post.summary, image=post.cover) }}
+fn og_tags(kwargs: Kwargs) -> std::result::Result<Value, minijinja::Error> {
+    let mut tags = HashMap::new();
+    for key in kwargs.args() {
+        let value: Value = kwargs.get(key)?;
+        if !value.is_undefined() && !value.is_none() {
+            tags.insert(key.to_string(), value.to_string());
+        }
+    }
+    Ok(Value::from_safe_string(og::tags_html(tags)))
+}
+
+fn highlight_code(code: String, lang: Option<String>) -> Value {
+    Value::from_safe_string(highlight::html(&code, lang.as_deref().unwrap_or_default()))
+}
+
+fn island_tag(name: String, data: Value) -> std::result::Result<Value, minijinja::Error> {
+    let html = serde_json::to_value(&data)
+        .and_then(|data| island::html(&name, &data))
+        .map_err(|err| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                "island data isn't json",
+            )
+            .with_source(err)
+        })?;
+    Ok(Value::from_safe_string(html))
+}
+
+/// find the template error behind a lua error, if there is one
+pub fn find_error(err: &LuaError) -> Option<&minijinja::Error> {
+    match err {
+        LuaError::CallbackError { cause, .. } => find_error(cause),
+        LuaError::WithContext { cause, .. } => find_error(cause),
+        LuaError::ExternalError(err) => match err.downcast_ref::<Error>() {
+            Some(Error::Template(err)) => Some(err),
+            _ => err.downcast_ref::<minijinja::Error>(),
+        },
+        _ => None,
+    }
+}
+
+const ERROR_PAGE_STYLE: &str = "body { font-family: sans-serif; margin: 2em; } \
+    pre { background: #f6f6f6; padding: 1em; } .error { background: #fdd; font-weight: bold; }";
+
+/// an html page describing a template error, with the source around the failing line.
+/// only shown during development.
+pub fn error_page(err: &minijinja::Error) -> String {
+    let name = err.name().unwrap_or("<unknown>");
+    let location = match err.line() {
+        Some(line) => format!("{name}:{line}"),
+        None => name.to_string(),
+    };
+    let mut page = String::from(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>template error</title>\n",
+    );
+    page.push_str(&format!(
+        "<style>{ERROR_PAGE_STYLE}</style>\n</head>\n<body>\n"
+    ));
+    page.push_str(&format!("<h1>{}</h1>\n", escape(&err.kind().to_string())));
+    if let Some(detail) = err.detail() {
+        page.push_str(&format!("<p>{}</p>\n", escape(detail)));
+    }
+    page.push_str(&format!("<p><code>{}</code></p>\n", escape(&location)));
+
+    if let (Some(source), Some(line)) = (err.template_source(), err.line()) {
+        page.push_str("<pre>");
+        let first = line.saturating_sub(4);
+        for (number, text) in source.lines().enumerate().skip(first).take(7) {
+            let number = number + 1;
+            let text = format!("{number:>4} | {}", escape(text));
+            if number == line {
+                page.push_str(&format!("<span class=\"error\">{text}</span>\n"));
+            } else {
+                page.push_str(&format!("{text}\n"));
+            }
+        }
+        page.push_str("</pre>\n");
+    }
+
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
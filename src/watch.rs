@@ -15,6 +15,8 @@ use tokio::{
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::Instrument;
 
+use crate::platform::{has_extension, simplify};
+
 struct EventHandler {
     checksums: HashMap<&'static str, HashMap<PathBuf, u32>>,
     matchers: Matchers,
@@ -42,7 +44,7 @@ impl Match {
     fn is_match(&self, path: &Path) -> bool {
         match self {
             Match::StartsWith(prefix) => path.starts_with(prefix),
-            Match::Extension(ext) => path.extension().is_some_and(|e| e == ext.as_str()),
+            Match::Extension(ext) => has_extension(path, ext),
         }
     }
 }
@@ -72,8 +74,19 @@ pub async fn watch(
     let directory = app
         .canonicalize()
         .wrap_err_with(|| format!("cannot canonicalize {}", app.display()))?;
-    let directory = directory.parent().expect("parent").to_path_buf();
-
+    // watchers report plain paths, not the verbatim ones canonicalize gives on windows
+    let directory = simplify(directory.parent().expect("parent"));
+
+    // prefixes are relative to the app's directory
+    let matchers = matchers
+        .into_iter()
+        .map(|(name, matcher)| match matcher {
+            Match::StartsWith(prefix) if prefix.is_relative() => {
+                (name, Match::StartsWith(directory.join(prefix)))
+            }
+            matcher => (name, matcher),
+        })
+        .collect();
     let matchers = Matchers(matchers);
     let (tx, rx) = channel(5);
 
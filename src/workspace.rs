@@ -0,0 +1,89 @@
+// a lilguy.toml workspace serves several apps from one process and port.
+//
+//   [[app]]
+//   app = "site/app.lua"
+//   hosts = ["example.com", "www.example.com"]
+//
+//   [[app]]
+//   app = "api/app.lua"
+//   prefix = "/api"
+//
+//   [[app]]
+//   app = "admin/app.lua"
+//   prefix = "/admin"
+//
+// requests for one of an app's hosts go to that app. other requests are matched by
+// prefix, which is removed from the path the app sees. at most one app may have neither,
+// and it gets everything else. each app has its own database, templates and lua state.
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const WORKSPACE_FILE: &str = "lilguy.toml";
+
+#[derive(Debug, Deserialize)]
+pub struct Workspace {
+    #[serde(rename = "app", default)]
+    pub apps: Vec<WorkspaceApp>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorkspaceApp {
+    /// the app's lua file, relative to lilguy.toml
+    pub app: PathBuf,
+
+    #[serde(default)]
+    pub prefix: Option<String>,
+
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+impl WorkspaceApp {
+    /// a single app that handles every request
+    pub fn new(app: PathBuf) -> Self {
+        Self {
+            app,
+            prefix: None,
+            hosts: vec![],
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.hosts.is_empty() && matches!(self.prefix.as_deref(), None | Some("/"))
+    }
+}
+
+impl Workspace {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let source = tokio::fs::read_to_string(path).await?;
+        let mut workspace: Workspace = toml::from_str(&source)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        if workspace.apps.is_empty() {
+            return Err(eyre!("{} does not declare any apps", path.display()));
+        }
+        if workspace.apps.iter().filter(|app| app.is_default()).count() > 1 {
+            return Err(eyre!(
+                "only one app in {} can have no prefix or hosts",
+                path.display()
+            ));
+        }
+        for app in &mut workspace.apps {
+            app.app = dir.join(&app.app);
+            app.hosts = app
+                .hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect();
+            if let Some(prefix) = &app.prefix {
+                if !prefix.starts_with('/') {
+                    return Err(eyre!("app prefix must start with /: {prefix}"));
+                }
+                app.prefix = Some(prefix.trim_end_matches('/').to_string())
+                    .filter(|prefix| !prefix.is_empty());
+            }
+        }
+        Ok(workspace)
+    }
+}